@@ -0,0 +1,64 @@
+/*
+Decoding speed of the instruction parsers, over every opcode and over the
+opcodes of a ROM. Run with `cargo bench --no-default-features`.
+*/
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use chip8::instructions::InstructionParser;
+use chip8::lookup::LookupTableParser;
+use chip8::opcodes::OpcodeMaskParser;
+use chip8::opcodesv2::OpcodeTable;
+
+const ROUNDS: u32 = 20;
+
+// Decodes the opcodes ROUNDS times and returns the time taken per opcode
+fn bench<T: InstructionParser>(parser: &T, opcodes: &[u16]) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        for &opcode in opcodes {
+            let _ = black_box(parser.try_from(black_box(opcode)));
+        }
+    }
+    start.elapsed() / (ROUNDS * opcodes.len() as u32)
+}
+
+fn report(name: &str, opcodes: &[u16]) {
+    println!("{} ({} opcodes):", name, opcodes.len());
+    let start = Instant::now();
+    let lookup = LookupTableParser::new();
+    println!(
+        "  {:<20} {:?} to build",
+        "LookupTableParser",
+        start.elapsed()
+    );
+    println!(
+        "  {:<20} {:?}",
+        "OpcodeMaskParser",
+        bench(&OpcodeMaskParser {}, opcodes)
+    );
+    println!(
+        "  {:<20} {:?}",
+        "OpcodeTable",
+        bench(&OpcodeTable {}, opcodes)
+    );
+    println!(
+        "  {:<20} {:?}",
+        "LookupTableParser",
+        bench(&lookup, opcodes)
+    );
+}
+
+fn main() {
+    let all: Vec<u16> = (0..=u16::MAX).collect();
+    report("every opcode", &all);
+
+    let rom = std::fs::read("roms/games/Pong.ch8").unwrap_or_default();
+    let words: Vec<u16> = rom
+        .chunks_exact(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]))
+        .collect();
+    if !words.is_empty() {
+        report("Pong", &words);
+    }
+}
@@ -0,0 +1,1240 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use crate::core::PROGRAM_OFFSET;
+use crate::error::Chip8Error;
+use crate::instructions::Instruction;
+
+/*
+An assembler for the language of Octo (https://github.com/JohnEarnest/Octo):
+
+    : main               labels, called by naming them
+    :const name 10       constants
+    :alias x v3          names for registers
+    :calc name { 2 * HERE }
+    :byte 0x3C           data, as are bare numbers
+    :macro name a b { ... }
+
+along with loop/while/again, if ... then and if ... begin/else/end. The
+comparisons <, >, <= and >= are built with VF, which they overwrite, the same
+as in Octo. Expressions in :calc have no precedence and are evaluated from
+right to left, also as in Octo.
+
+Execution starts at 0x200 as always; unless main is defined before any code
+or data, a jump to main is put there.
+*/
+
+// Expanding macros further than this is taken for a macro that expands itself
+const MAX_EXPANSIONS: usize = 100_000;
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+fn error(token: &Token, reason: String) -> Chip8Error {
+    Chip8Error::Assembly {
+        line: token.line,
+        column: token.column,
+        reason,
+    }
+}
+
+// Tokens are separated by whitespace, `#` starts a comment
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+    for (n, line) in source.lines().enumerate() {
+        let code = line.split('#').next().unwrap_or("");
+        let mut start = None;
+        for (column, c) in code.chars().chain(Some(' ')).enumerate() {
+            match (start, c.is_whitespace()) {
+                (None, false) => start = Some(column),
+                (Some(first), true) => {
+                    tokens.push_back(Token {
+                        text: code.chars().skip(first).take(column - first).collect(),
+                        line: n + 1,
+                        column: first + 1,
+                    });
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+    }
+    tokens
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()?
+    } else {
+        digits.parse().ok()?
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn parse_register(text: &str) -> Option<u8> {
+    let x = text.strip_prefix('v').or_else(|| text.strip_prefix('V'))?;
+    match x.len() {
+        1 => u8::from_str_radix(x, 16).ok(),
+        _ => None,
+    }
+}
+
+// The skip that does the opposite of `skip`
+fn invert_skip(skip: Instruction) -> Instruction {
+    use Instruction::*;
+    match skip {
+        SkipEqualsByte(x, kk) => SkipNotEqualsByte(x, kk),
+        SkipNotEqualsByte(x, kk) => SkipEqualsByte(x, kk),
+        SkipEqualsRegister(x, y) => SkipNotEqualRegister(x, y),
+        SkipNotEqualRegister(x, y) => SkipEqualsRegister(x, y),
+        SkipKeyPress(x) => SkipNotKeyPress(x),
+        SkipNotKeyPress(x) => SkipKeyPress(x),
+        _ => unreachable!("{} is not a skip", skip),
+    }
+}
+
+// Where a label that isn't defined yet has to be filled in
+#[derive(Debug)]
+enum Patch {
+    Address(fn(u16) -> Instruction), // the instruction, given the address
+    Word,                            // a whole word, for :pointer
+    UnpackHigh(u8),
+    UnpackLow,
+}
+
+#[derive(Debug)]
+struct Fixup {
+    pos: usize,
+    patch: Patch,
+    label: Token,
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+}
+
+// An operand that is either known or a label defined further down
+enum Target {
+    Known(u16),
+    Forward(Token),
+}
+
+struct Loop {
+    start: u16,
+    token: Token,
+    whiles: Vec<usize>, // jumps out of the loop, to be pointed past `again`
+}
+
+struct Branch {
+    jump: usize, // the jump to else or end
+    token: Token,
+    has_else: bool,
+}
+
+/*
+An assembled program: the bytes to load at PROGRAM_OFFSET and the address of
+every label.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assembly {
+    pub rom: Vec<u8>,
+    pub labels: BTreeMap<String, u16>,
+}
+
+impl Assembly {
+    // One label per line as `ADDR name`, in the order of the addresses
+    pub fn symbol_file(&self) -> String {
+        let mut symbols: Vec<(&u16, &String)> = self
+            .labels
+            .iter()
+            .map(|(name, addr)| (addr, name))
+            .collect();
+        symbols.sort();
+        symbols
+            .iter()
+            .map(|(addr, name)| format!("{:04X} {}\n", addr, name))
+            .collect()
+    }
+}
+
+struct Assembler {
+    tokens: VecDeque<Token>,
+    last: Token, // the last token taken, for errors at the end of the source
+    rom: Vec<u8>,
+    here: usize, // offset into rom of the next byte
+    labels: BTreeMap<String, u16>,
+    consts: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+    started: bool, // whether anything has been emitted yet
+    fixups: Vec<Fixup>,
+    loops: Vec<Loop>,
+    branches: Vec<Branch>,
+}
+
+pub fn assemble(source: &str) -> Result<Assembly, Chip8Error> {
+    let tokens = tokenize(source);
+    let assembler = Assembler {
+        last: Token {
+            text: String::new(),
+            line: 1,
+            column: 1,
+        },
+        tokens,
+        rom: Vec::new(),
+        here: 0,
+        labels: BTreeMap::new(),
+        consts: HashMap::new(),
+        aliases: HashMap::new(),
+        macros: HashMap::new(),
+        expansions: 0,
+        started: false,
+        fixups: Vec::new(),
+        loops: Vec::new(),
+        branches: Vec::new(),
+    };
+    assembler.program()
+}
+
+impl Assembler {
+    fn program(mut self) -> Result<Assembly, Chip8Error> {
+        while let Some(token) = self.next() {
+            self.statement(token)?;
+        }
+        if let Some(open) = self.loops.last() {
+            return Err(error(&open.token, String::from("loop without again")));
+        }
+        if let Some(open) = self.branches.last() {
+            return Err(error(&open.token, String::from("begin without end")));
+        }
+        if !self.labels.contains_key("main") {
+            return Err(error(&self.last, String::from("there is no main label")));
+        }
+        for fixup in std::mem::take(&mut self.fixups) {
+            let addr = match self.labels.get(&fixup.label.text) {
+                Some(addr) => *addr,
+                None => {
+                    let reason = format!("undefined name {}", fixup.label.text);
+                    return Err(error(&fixup.label, reason));
+                }
+            };
+            self.patch(fixup.pos, &fixup.patch, addr, &fixup.label)?;
+        }
+        Ok(Assembly {
+            rom: self.rom,
+            labels: self.labels,
+        })
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.pop_front()?;
+        self.last = token.clone();
+        Some(token)
+    }
+
+    fn expect(&mut self, what: &str) -> Result<Token, Chip8Error> {
+        match self.next() {
+            Some(token) => Ok(token),
+            None => Err(error(&self.last, format!("expected {}", what))),
+        }
+    }
+
+    fn expect_text(&mut self, text: &str) -> Result<(), Chip8Error> {
+        let token = self.expect(text)?;
+        if token.text != text {
+            return Err(error(
+                &token,
+                format!("expected {}, found {}", text, token.text),
+            ));
+        }
+        Ok(())
+    }
+
+    fn peek_is(&self, text: &str) -> bool {
+        self.tokens.front().is_some_and(|token| token.text == text)
+    }
+
+    fn addr(&self) -> u16 {
+        (PROGRAM_OFFSET + self.here) as u16
+    }
+
+    // Puts the jump to main in front of the first code, data or label after it
+    fn start(&mut self) -> Result<(), Chip8Error> {
+        if self.started {
+            return Ok(());
+        }
+        self.started = true;
+        if self.labels.contains_key("main") {
+            return Ok(());
+        }
+        let main = Token {
+            text: String::from("main"),
+            ..self.last.clone()
+        };
+        self.emit_address(Instruction::Jump, Target::Forward(main))
+    }
+
+    fn emit_byte(&mut self, byte: u8) -> Result<(), Chip8Error> {
+        self.start()?;
+        if PROGRAM_OFFSET + self.here > 0xFFFF {
+            return Err(error(
+                &self.last,
+                String::from("the program doesn't fit in 64K"),
+            ));
+        }
+        if self.here >= self.rom.len() {
+            self.rom.resize(self.here + 1, 0);
+        }
+        self.rom[self.here] = byte;
+        self.here += 1;
+        Ok(())
+    }
+
+    fn emit(&mut self, word: u16) -> Result<(), Chip8Error> {
+        self.emit_byte((word >> 8) as u8)?;
+        self.emit_byte(word as u8)
+    }
+
+    // Operands are checked as they are read, so encoding only fails on a bug
+    fn encode(&self, instruction: &Instruction) -> Result<Vec<u8>, Chip8Error> {
+        instruction
+            .encode()
+            .map_err(|e| error(&self.last, e.to_string()))
+    }
+
+    fn instruction(&mut self, instruction: Instruction) -> Result<(), Chip8Error> {
+        for byte in self.encode(&instruction)? {
+            self.emit_byte(byte)?;
+        }
+        Ok(())
+    }
+
+    fn write_word(&mut self, pos: usize, word: u16) {
+        self.rom[pos..pos + 2].copy_from_slice(&word.to_be_bytes());
+    }
+
+    fn emit_address(
+        &mut self,
+        instruction: fn(u16) -> Instruction,
+        target: Target,
+    ) -> Result<(), Chip8Error> {
+        // the jump to main has to be in place before the position is taken
+        self.start()?;
+        let addr = match target {
+            Target::Known(addr) => addr,
+            Target::Forward(label) => {
+                self.fixups.push(Fixup {
+                    pos: self.here,
+                    patch: Patch::Address(instruction),
+                    label,
+                });
+                0
+            }
+        };
+        self.instruction(instruction(addr))
+    }
+
+    fn patch(
+        &mut self,
+        pos: usize,
+        patch: &Patch,
+        addr: u16,
+        label: &Token,
+    ) -> Result<(), Chip8Error> {
+        match *patch {
+            Patch::Address(instruction) => {
+                let bytes = instruction(addr).encode().map_err(|_| {
+                    let reason =
+                        format!("{} at {:#X} is out of reach of 12 bits", label.text, addr);
+                    error(label, reason)
+                })?;
+                self.rom[pos..pos + bytes.len()].copy_from_slice(&bytes);
+            }
+            Patch::Word => self.write_word(pos, addr),
+            Patch::UnpackHigh(nibble) => {
+                self.rom[pos + 1] = (nibble << 4) | (addr >> 8) as u8;
+            }
+            Patch::UnpackLow => self.rom[pos + 1] = addr as u8,
+        }
+        Ok(())
+    }
+
+    fn register(&self, token: &Token) -> Option<u8> {
+        self.aliases
+            .get(&token.text)
+            .copied()
+            .or_else(|| parse_register(&token.text))
+    }
+
+    fn expect_register(&mut self) -> Result<u8, Chip8Error> {
+        let token = self.expect("a register")?;
+        self.register(&token)
+            .ok_or_else(|| error(&token, format!("expected a register, found {}", token.text)))
+    }
+
+    // The value of a number, constant or label that is already defined
+    fn lookup(&self, token: &Token) -> Option<f64> {
+        if let Some(value) = parse_number(&token.text) {
+            return Some(value as f64);
+        }
+        if token.text == "HERE" {
+            return Some(f64::from(self.addr()));
+        }
+        self.consts
+            .get(&token.text)
+            .copied()
+            .or_else(|| self.labels.get(&token.text).map(|addr| f64::from(*addr)))
+    }
+
+    fn integer(&self, token: &Token, min: i64, max: i64) -> Result<i64, Chip8Error> {
+        let value = match self.lookup(token) {
+            Some(value) if value.is_finite() => value.floor() as i64,
+            Some(_) => return Err(error(token, format!("{} is not a number", token.text))),
+            None => return Err(error(token, format!("undefined name {}", token.text))),
+        };
+        if value < min || value > max {
+            let reason = format!("{} is out of range {}..={}", token.text, min, max);
+            return Err(error(token, reason));
+        }
+        Ok(value)
+    }
+
+    // A byte, where negative numbers are taken as two's complement
+    fn expect_byte(&mut self) -> Result<u8, Chip8Error> {
+        let token = self.expect("a byte")?;
+        Ok(self.integer(&token, -128, 255)? as u8)
+    }
+
+    fn expect_nibble(&mut self) -> Result<u8, Chip8Error> {
+        let token = self.expect("a nibble")?;
+        Ok(self.integer(&token, 0, 15)? as u8)
+    }
+
+    // An address, which can be a label that is defined later on
+    fn expect_target(&mut self) -> Result<Target, Chip8Error> {
+        let token = self.expect("an address")?;
+        if self.lookup(&token).is_some() {
+            return Ok(Target::Known(self.integer(&token, 0, 0xFFFF)? as u16));
+        }
+        if self.register(&token).is_some() || parse_number(&token.text).is_some() {
+            return Err(error(
+                &token,
+                format!("expected an address, found {}", token.text),
+            ));
+        }
+        Ok(Target::Forward(token))
+    }
+
+    fn expect_address(&mut self, instruction: fn(u16) -> Instruction) -> Result<(), Chip8Error> {
+        let target = self.expect_target()?;
+        if let Target::Known(addr) = target {
+            if addr > 0xFFF {
+                let reason = format!("{:#X} is out of reach of 12 bits", addr);
+                return Err(error(&self.last, reason));
+            }
+        }
+        self.emit_address(instruction, target)
+    }
+
+    fn expect_name(&mut self) -> Result<Token, Chip8Error> {
+        let token = self.expect("a name")?;
+        if parse_number(&token.text).is_some()
+            || parse_register(&token.text).is_some()
+            || token.text.starts_with(':')
+        {
+            return Err(error(
+                &token,
+                format!("{} can't be used as a name", token.text),
+            ));
+        }
+        Ok(token)
+    }
+
+    fn define_label(&mut self, name: Token) -> Result<(), Chip8Error> {
+        if self.labels.contains_key(&name.text) || self.consts.contains_key(&name.text) {
+            return Err(error(&name, format!("{} is already defined", name.text)));
+        }
+        if name.text != "main" {
+            self.start()?;
+        }
+        self.labels.insert(name.text, self.addr());
+        Ok(())
+    }
+
+    // The tokens between { and the matching }
+    fn block(&mut self) -> Result<Vec<Token>, Chip8Error> {
+        self.expect_text("{")?;
+        let mut depth = 0;
+        let mut body = Vec::new();
+        loop {
+            let token = self.expect("}")?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" if depth == 0 => return Ok(body),
+                "}" => depth -= 1,
+                _ => {}
+            }
+            body.push(token);
+        }
+    }
+
+    fn calc(&mut self) -> Result<f64, Chip8Error> {
+        let start = self.last.clone();
+        let tokens = self.block()?;
+        let mut pos = 0;
+        let value = self.expression(&tokens, &mut pos)?;
+        match tokens.get(pos) {
+            Some(token) => Err(error(token, format!("unexpected {}", token.text))),
+            None if tokens.is_empty() => Err(error(&start, String::from("empty expression"))),
+            None => Ok(value),
+        }
+    }
+
+    // A term, optionally followed by an operator and everything to its right
+    fn expression(&self, tokens: &[Token], pos: &mut usize) -> Result<f64, Chip8Error> {
+        let left = self.term(tokens, pos)?;
+        let op = match tokens.get(*pos) {
+            Some(op) if op.text != ")" => op,
+            _ => return Ok(left),
+        };
+        *pos += 1;
+        let right = self.expression(tokens, pos)?;
+        let (a, b) = (left as i64, right as i64);
+        let truth = |c: bool| if c { 1.0 } else { 0.0 };
+        Ok(match op.text.as_str() {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" => left / right,
+            "%" => left % right,
+            "&" => (a & b) as f64,
+            "|" => (a | b) as f64,
+            "^" => (a ^ b) as f64,
+            "<<" => a.checked_shl(b as u32).unwrap_or(0) as f64,
+            ">>" => a.checked_shr(b as u32).unwrap_or(0) as f64,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "<" => truth(left < right),
+            ">" => truth(left > right),
+            "<=" => truth(left <= right),
+            ">=" => truth(left >= right),
+            "==" => truth(left == right),
+            "!=" => truth(left != right),
+            _ => return Err(error(op, format!("unknown operator {}", op.text))),
+        })
+    }
+
+    fn term(&self, tokens: &[Token], pos: &mut usize) -> Result<f64, Chip8Error> {
+        let token = match tokens.get(*pos) {
+            Some(token) => token,
+            None => return Err(error(&self.last, String::from("expected a value"))),
+        };
+        *pos += 1;
+        let unary = |f: fn(f64) -> f64, pos: &mut usize| -> Result<f64, Chip8Error> {
+            Ok(f(self.term(tokens, pos)?))
+        };
+        match token.text.as_str() {
+            "(" => {
+                let value = self.expression(tokens, pos)?;
+                match tokens.get(*pos) {
+                    Some(close) if close.text == ")" => {
+                        *pos += 1;
+                        Ok(value)
+                    }
+                    _ => Err(error(token, String::from("( without )"))),
+                }
+            }
+            "-" => unary(|v| -v, pos),
+            "~" => unary(|v| !(v as i64) as f64, pos),
+            "!" => unary(|v| if v == 0.0 { 1.0 } else { 0.0 }, pos),
+            "floor" => unary(f64::floor, pos),
+            "ceil" => unary(f64::ceil, pos),
+            "abs" => unary(f64::abs, pos),
+            "sqrt" => unary(f64::sqrt, pos),
+            "sin" => unary(f64::sin, pos),
+            "cos" => unary(f64::cos, pos),
+            "PI" => Ok(std::f64::consts::PI),
+            _ => self
+                .lookup(token)
+                .ok_or_else(|| error(token, format!("undefined name {}", token.text))),
+        }
+    }
+
+    fn define_macro(&mut self) -> Result<(), Chip8Error> {
+        let name = self.expect_name()?;
+        let mut params = Vec::new();
+        while !self.peek_is("{") {
+            params.push(self.expect_name()?.text);
+        }
+        let body = self.block()?;
+        self.macros.insert(name.text, Macro { params, body });
+        Ok(())
+    }
+
+    fn expand_macro(&mut self, name: &Token) -> Result<(), Chip8Error> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(error(
+                name,
+                format!("macro {} never stops expanding", name.text),
+            ));
+        }
+        let count = self.macros[&name.text].params.len();
+        let mut args = Vec::with_capacity(count);
+        for _ in 0..count {
+            args.push(self.expect("a macro argument")?.text);
+        }
+        let mac = &self.macros[&name.text];
+        let expanded: Vec<Token> = mac
+            .body
+            .iter()
+            .map(
+                |token| match mac.params.iter().position(|p| *p == token.text) {
+                    Some(n) => Token {
+                        text: args[n].clone(),
+                        ..token.clone()
+                    },
+                    None => token.clone(),
+                },
+            )
+            .collect();
+        for token in expanded.into_iter().rev() {
+            self.tokens.push_front(token);
+        }
+        Ok(())
+    }
+
+    /*
+    Compiles a condition to the instructions that come before the skip and
+    the skip itself, which skips the next instruction when the condition is
+    false.
+    */
+    fn condition(&mut self) -> Result<(Vec<Instruction>, Instruction), Chip8Error> {
+        use Instruction::*;
+        let x = self.expect_register()?;
+        let op = self.expect("a comparison")?;
+        match op.text.as_str() {
+            "key" => return Ok((Vec::new(), SkipNotKeyPress(x))),
+            "-key" => return Ok((Vec::new(), SkipKeyPress(x))),
+            _ => {}
+        }
+        let operand = self.expect("a register or a byte")?;
+        // VF := operand, for the comparisons that need it in a register
+        let (load_vf, register, byte) = match self.register(&operand) {
+            Some(y) => (LoadRegister(0xF, y), Some(y), 0),
+            None => {
+                let byte = self.integer(&operand, -128, 255)? as u8;
+                (LoadByte(0xF, byte), None, byte)
+            }
+        };
+        Ok(match (op.text.as_str(), register) {
+            ("==", Some(y)) => (Vec::new(), SkipNotEqualRegister(x, y)),
+            ("==", None) => (Vec::new(), SkipNotEqualsByte(x, byte)),
+            ("!=", Some(y)) => (Vec::new(), SkipEqualsRegister(x, y)),
+            ("!=", None) => (Vec::new(), SkipEqualsByte(x, byte)),
+            // VF := operand; VF =- x leaves x >= operand in VF
+            ("<", _) => (vec![load_vf, SubNRegister(0xF, x)], SkipEqualsByte(0xF, 1)),
+            (">=", _) => (vec![load_vf, SubNRegister(0xF, x)], SkipEqualsByte(0xF, 0)),
+            // VF := operand; VF -= x leaves operand >= x in VF
+            (">", _) => (vec![load_vf, SubRegister(0xF, x)], SkipEqualsByte(0xF, 1)),
+            ("<=", _) => (vec![load_vf, SubRegister(0xF, x)], SkipEqualsByte(0xF, 0)),
+            _ => return Err(error(&op, format!("unknown comparison {}", op.text))),
+        })
+    }
+
+    fn register_statement(&mut self, x: u8) -> Result<(), Chip8Error> {
+        use Instruction::*;
+        let op = self.expect("an operator")?;
+        let operand = self.expect("an operand")?;
+        let y = self.register(&operand);
+        let byte = |this: &Self| this.integer(&operand, -128, 255).map(|b| b as u8);
+        let instruction = match (op.text.as_str(), y) {
+            (":=", Some(y)) => LoadRegister(x, y),
+            (":=", None) => match operand.text.as_str() {
+                "random" => Random(x, self.expect_byte()?),
+                "delay" => LoadFromDelay(x),
+                "key" => LoadKeyPress(x),
+                _ => LoadByte(x, byte(self)?),
+            },
+            ("+=", Some(y)) => AddRegister(x, y),
+            ("+=", None) => AddByte(x, byte(self)?),
+            ("-=", Some(y)) => SubRegister(x, y),
+            ("-=", None) => AddByte(x, byte(self)?.wrapping_neg()),
+            ("|=", Some(y)) => Or(x, y),
+            ("&=", Some(y)) => And(x, y),
+            ("^=", Some(y)) => Xor(x, y),
+            ("=-", Some(y)) => SubNRegister(x, y),
+            (">>=", Some(y)) => ShiftRight(x, y),
+            ("<<=", Some(y)) => ShiftLeft(x, y),
+            _ => {
+                let reason = format!("can't do {} with {}", op.text, operand.text);
+                return Err(error(&op, reason));
+            }
+        };
+        self.instruction(instruction)
+    }
+
+    fn i_statement(&mut self) -> Result<(), Chip8Error> {
+        let op = self.expect("an operator")?;
+        match op.text.as_str() {
+            "+=" => {
+                let x = self.expect_register()?;
+                self.instruction(Instruction::AddI(x))
+            }
+            ":=" => {
+                let what = self.tokens.front().map(|token| token.text.clone());
+                match what.as_deref() {
+                    Some("hex") => {
+                        self.next();
+                        let x = self.expect_register()?;
+                        self.instruction(Instruction::LoadFontSprite(x))
+                    }
+                    Some("bighex") => {
+                        self.next();
+                        let x = self.expect_register()?;
+                        self.instruction(Instruction::LoadBigFontSprite(x))
+                    }
+                    Some("long") => {
+                        self.next();
+                        let target = self.expect_target()?;
+                        self.emit_address(Instruction::LoadLongImmediate, target)
+                    }
+                    _ => self.expect_address(Instruction::LoadImmediate),
+                }
+            }
+            _ => Err(error(&op, format!("can't do {} with i", op.text))),
+        }
+    }
+
+    // A whole word holding an address, for :pointer
+    fn emit_word(&mut self) -> Result<(), Chip8Error> {
+        self.start()?;
+        match self.expect_target()? {
+            Target::Known(addr) => self.emit(addr),
+            Target::Forward(label) => {
+                self.fixups.push(Fixup {
+                    pos: self.here,
+                    patch: Patch::Word,
+                    label,
+                });
+                self.emit(0)
+            }
+        }
+    }
+
+    // :unpack N label loads v0 and v1 with N << 12 | label
+    fn unpack(&mut self) -> Result<(), Chip8Error> {
+        let nibble = if self.peek_is("long") {
+            self.next();
+            None
+        } else {
+            Some(self.expect_nibble()?)
+        };
+        self.start()?;
+        match (self.expect_target()?, nibble) {
+            (Target::Known(addr), nibble) => {
+                let high = u16::from(nibble.unwrap_or(0)) << 12 | addr;
+                self.instruction(Instruction::LoadByte(0x0, (high >> 8) as u8))?;
+                self.instruction(Instruction::LoadByte(0x1, addr as u8))
+            }
+            (Target::Forward(label), nibble) => {
+                let high = match nibble {
+                    Some(nibble) => Patch::UnpackHigh(nibble),
+                    None => Patch::UnpackHigh(0),
+                };
+                self.fixups.push(Fixup {
+                    pos: self.here,
+                    patch: high,
+                    label: label.clone(),
+                });
+                self.instruction(Instruction::LoadByte(0x0, 0))?;
+                self.fixups.push(Fixup {
+                    pos: self.here,
+                    patch: Patch::UnpackLow,
+                    label,
+                });
+                self.instruction(Instruction::LoadByte(0x1, 0))
+            }
+        }
+    }
+
+    // Control flow jumps, which can only go as far as 12 bits reach
+    fn jump_to(&self, addr: u16, token: &Token) -> Result<Vec<u8>, Chip8Error> {
+        if addr > 0xFFF {
+            let reason = format!("{} at {:#X} is out of reach of 12 bits", token.text, addr);
+            return Err(error(token, reason));
+        }
+        self.encode(&Instruction::Jump(addr))
+    }
+
+    // The jump at `pos` goes to the current address
+    fn land(&mut self, pos: usize, token: &Token) -> Result<(), Chip8Error> {
+        let jump = self.jump_to(self.addr(), token)?;
+        self.rom[pos..pos + jump.len()].copy_from_slice(&jump);
+        Ok(())
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), Chip8Error> {
+        match token.text.as_str() {
+            ":" => {
+                let name = self.expect_name()?;
+                self.define_label(name)
+            }
+            ":const" => {
+                let name = self.expect_name()?;
+                let value = self.expect("a value")?;
+                let value = self.integer(&value, i64::MIN, i64::MAX)?;
+                self.consts.insert(name.text, value as f64);
+                Ok(())
+            }
+            ":calc" => {
+                let name = self.expect_name()?;
+                let value = self.calc()?;
+                self.consts.insert(name.text, value);
+                Ok(())
+            }
+            ":alias" => {
+                let name = self.expect_name()?;
+                let x = self.expect_register()?;
+                self.aliases.insert(name.text, x);
+                Ok(())
+            }
+            ":byte" => {
+                let value = if self.peek_is("{") {
+                    self.calc()?
+                } else {
+                    let token = self.expect("a byte")?;
+                    self.integer(&token, -128, 255)? as f64
+                };
+                if !(-128.0..256.0).contains(&value) {
+                    return Err(error(&token, format!("{} doesn't fit in a byte", value)));
+                }
+                self.emit_byte(value.floor() as i64 as u8)
+            }
+            ":pointer" => self.emit_word(),
+            ":org" => {
+                let addr = self.expect("an address")?;
+                let addr = self.integer(&addr, PROGRAM_OFFSET as i64, 0xFFFF)?;
+                // the jump to main always goes at the start, and nothing may go over it
+                self.start()?;
+                if !self.labels.contains_key("main") && (addr as usize) < PROGRAM_OFFSET + 2 {
+                    let reason = format!("{:#X} is where the jump to main goes", addr);
+                    return Err(error(&self.last, reason));
+                }
+                self.here = addr as usize - PROGRAM_OFFSET;
+                Ok(())
+            }
+            ":macro" => self.define_macro(),
+            ":unpack" => self.unpack(),
+            "clear" => self.instruction(Instruction::ClearScreen),
+            "return" | ";" => self.instruction(Instruction::Return),
+            "scroll-down" => {
+                let n = self.expect_nibble()?;
+                self.instruction(Instruction::ScrollDown(n))
+            }
+            "scroll-up" => {
+                let n = self.expect_nibble()?;
+                self.instruction(Instruction::ScrollUp(n))
+            }
+            "scroll-right" => self.instruction(Instruction::ScrollRight),
+            "scroll-left" => self.instruction(Instruction::ScrollLeft),
+            "exit" => self.instruction(Instruction::Exit),
+            "lores" => self.instruction(Instruction::LowRes),
+            "hires" => self.instruction(Instruction::HighRes),
+            "native" => self.expect_address(Instruction::SYS),
+            "jump" => self.expect_address(Instruction::Jump),
+            "jump0" => self.expect_address(Instruction::JumpBase),
+            "sprite" => {
+                let x = self.expect_register()?;
+                let y = self.expect_register()?;
+                let n = self.expect_nibble()?;
+                self.instruction(Instruction::DisplaySprite(x, y, n))
+            }
+            "save" | "load" => {
+                let x = self.expect_register()?;
+                let store = token.text == "save";
+                if self.peek_is("-") {
+                    self.next();
+                    let y = self.expect_register()?;
+                    return self.instruction(if store {
+                        Instruction::SaveRange(x, y)
+                    } else {
+                        Instruction::LoadRange(x, y)
+                    });
+                }
+                self.instruction(if store {
+                    Instruction::StoreRegisters(x)
+                } else {
+                    Instruction::LoadRegisters(x)
+                })
+            }
+            "bcd" => {
+                let x = self.expect_register()?;
+                self.instruction(Instruction::LoadIBCD(x))
+            }
+            "saveflags" => {
+                let x = self.expect_register()?;
+                self.instruction(Instruction::StoreFlags(x))
+            }
+            "loadflags" => {
+                let x = self.expect_register()?;
+                self.instruction(Instruction::LoadFlags(x))
+            }
+            "plane" => {
+                let n = self.expect_nibble()?;
+                self.instruction(Instruction::SelectPlanes(n))
+            }
+            "audio" => self.instruction(Instruction::LoadAudioPattern),
+            "delay" | "buzzer" | "pitch" => {
+                self.expect_text(":=")?;
+                let x = self.expect_register()?;
+                self.instruction(match token.text.as_str() {
+                    "delay" => Instruction::LoadDelay(x),
+                    "buzzer" => Instruction::LoadSound(x),
+                    _ => Instruction::LoadPitch(x),
+                })
+            }
+            "i" => self.i_statement(),
+            "loop" => {
+                self.start()?;
+                self.loops.push(Loop {
+                    start: self.addr(),
+                    token,
+                    whiles: Vec::new(),
+                });
+                Ok(())
+            }
+            "while" => {
+                let (prefix, skip) = self.condition()?;
+                for instruction in prefix {
+                    self.instruction(instruction)?;
+                }
+                self.instruction(invert_skip(skip))?;
+                let jump = self.here;
+                self.instruction(Instruction::Jump(0))?;
+                match self.loops.last_mut() {
+                    Some(open) => open.whiles.push(jump),
+                    None => return Err(error(&token, String::from("while outside of a loop"))),
+                }
+                Ok(())
+            }
+            "again" => {
+                let open = match self.loops.pop() {
+                    Some(open) => open,
+                    None => return Err(error(&token, String::from("again without loop"))),
+                };
+                for byte in self.jump_to(open.start, &open.token)? {
+                    self.emit_byte(byte)?;
+                }
+                for jump in open.whiles {
+                    self.land(jump, &token)?;
+                }
+                Ok(())
+            }
+            "if" => {
+                let (prefix, skip) = self.condition()?;
+                for instruction in prefix {
+                    self.instruction(instruction)?;
+                }
+                let then = self.expect("then or begin")?;
+                match then.text.clone().as_str() {
+                    "then" => self.instruction(skip),
+                    "begin" => {
+                        self.instruction(invert_skip(skip))?;
+                        self.branches.push(Branch {
+                            jump: self.here,
+                            token: then,
+                            has_else: false,
+                        });
+                        self.instruction(Instruction::Jump(0))
+                    }
+                    _ => Err(error(
+                        &then,
+                        format!("expected then or begin, found {}", then.text),
+                    )),
+                }
+            }
+            "else" => {
+                let open = match self.branches.pop() {
+                    Some(open) if !open.has_else => open,
+                    _ => return Err(error(&token, String::from("else without begin"))),
+                };
+                let jump = self.here;
+                self.instruction(Instruction::Jump(0))?;
+                self.land(open.jump, &token)?;
+                self.branches.push(Branch {
+                    jump,
+                    token,
+                    has_else: true,
+                });
+                Ok(())
+            }
+            "end" => {
+                let open = match self.branches.pop() {
+                    Some(open) => open,
+                    None => return Err(error(&token, String::from("end without begin"))),
+                };
+                self.land(open.jump, &token)
+            }
+            _ => {
+                if let Some(x) = self.register(&token) {
+                    return self.register_statement(x);
+                }
+                if self.macros.contains_key(&token.text) {
+                    return self.expand_macro(&token);
+                }
+                if parse_number(&token.text).is_some() || self.consts.contains_key(&token.text) {
+                    let byte = self.integer(&token, -128, 255)?;
+                    return self.emit_byte(byte as u8);
+                }
+                if token.text.starts_with(':') || token.text.starts_with('{') {
+                    return Err(error(&token, format!("unknown directive {}", token.text)));
+                }
+                // anything else is the name of a subroutine to call
+                self.tokens.push_front(token);
+                self.expect_address(Instruction::Call)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instructions::InstructionParser;
+    use crate::opcodes::OpcodeMaskParser;
+
+    fn words(rom: &[u8]) -> Vec<u16> {
+        rom.chunks(2)
+            .map(|word| u16::from_be_bytes([word[0], word[1]]))
+            .collect()
+    }
+
+    fn assemble_words(source: &str) -> Vec<u16> {
+        words(&assemble(source).unwrap().rom)
+    }
+
+    fn assert_error(source: &str, line: usize, column: usize) {
+        match assemble(source) {
+            Err(Chip8Error::Assembly {
+                line: l, column: c, ..
+            }) => assert_eq!((l, c), (line, column), "{}", source),
+            other => panic!("{:?} for {}", other, source),
+        }
+    }
+
+    #[test]
+    fn test_every_instruction_round_trips() {
+        let source = "
+            : main
+            clear return ; scroll-down 3 scroll-right scroll-left exit lores hires
+            scroll-up 2 native 0x123 jump main sub
+            if v1 != 0x12 then if v2 == 0x34 then if v3 != v4 then
+            save v1 - v2 load v3 - v4
+            v5 := 0x56 v6 += 7 v7 := v8 v7 |= v8 v7 &= v8 v7 ^= v8 v7 += v8
+            v7 -= v8 v7 >>= v8 v7 =- v8 v7 <<= v8 if v9 == va then
+            i := 0x123 i := long 0x4567 jump0 0x200 vb := random 0xCD
+            sprite vc vd 0xE if ve -key then if vf key then
+            plane 3 audio v0 := delay v1 := key delay := v2 buzzer := v3
+            i += v4 i := hex v5 i := bighex v6 bcd v7 pitch := v8
+            save v9 load va saveflags vb loadflags vc
+            : sub return
+        ";
+        let assembly = assemble(source).unwrap();
+        let rom = assembly.rom;
+        let parser = OpcodeMaskParser {};
+        let mut decoded = Vec::new();
+        let mut pos = 0;
+        while pos < rom.len() {
+            let opcode = u16::from_be_bytes([rom[pos], rom[pos + 1]]);
+            let next = match rom.get(pos + 2..pos + 4) {
+                Some(next) if opcode == 0xF000 => u16::from_be_bytes([next[0], next[1]]),
+                _ => 0,
+            };
+            let instruction = parser.try_from_words(opcode, next).unwrap();
+            pos += usize::from(instruction.size());
+            decoded.push(instruction);
+        }
+        use Instruction::*;
+        let expected = vec![
+            ClearScreen,
+            Return,
+            Return,
+            ScrollDown(3),
+            ScrollRight,
+            ScrollLeft,
+            Exit,
+            LowRes,
+            HighRes,
+            ScrollUp(2),
+            SYS(0x123),
+            Jump(0x200),
+            Call(assembly.labels["sub"]),
+            SkipEqualsByte(1, 0x12),
+            SkipNotEqualsByte(2, 0x34),
+            SkipEqualsRegister(3, 4),
+            SaveRange(1, 2),
+            LoadRange(3, 4),
+            LoadByte(5, 0x56),
+            AddByte(6, 7),
+            LoadRegister(7, 8),
+            Or(7, 8),
+            And(7, 8),
+            Xor(7, 8),
+            AddRegister(7, 8),
+            SubRegister(7, 8),
+            ShiftRight(7, 8),
+            SubNRegister(7, 8),
+            ShiftLeft(7, 8),
+            SkipNotEqualRegister(9, 0xA),
+            LoadImmediate(0x123),
+            LoadLongImmediate(0x4567),
+            JumpBase(0x200),
+            Random(0xB, 0xCD),
+            DisplaySprite(0xC, 0xD, 0xE),
+            SkipKeyPress(0xE),
+            SkipNotKeyPress(0xF),
+            SelectPlanes(3),
+            LoadAudioPattern,
+            LoadFromDelay(0),
+            LoadKeyPress(1),
+            LoadDelay(2),
+            LoadSound(3),
+            AddI(4),
+            LoadFontSprite(5),
+            LoadBigFontSprite(6),
+            LoadIBCD(7),
+            LoadPitch(8),
+            StoreRegisters(9),
+            LoadRegisters(0xA),
+            StoreFlags(0xB),
+            LoadFlags(0xC),
+            Return,
+        ];
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn test_labels_and_main() {
+        // main not first: a jump to it comes first, forward references get patched
+        let assembly = assemble(": draw sprite v0 v1 5 ; : main draw jump main").unwrap();
+        assert_eq!(
+            words(&assembly.rom),
+            [0x1206, 0xD015, 0x00EE, 0x2202, 0x1206]
+        );
+        assert_eq!(assembly.labels["draw"], 0x202);
+        assert_eq!(assembly.symbol_file(), "0202 draw\n0206 main\n");
+        assert_eq!(
+            assemble_words(": main i := data ; : data 0xF0 -1"),
+            [0xA204, 0x00EE, 0xF0FF]
+        );
+
+        // the jump to main comes before whatever the first statement is
+        assert_eq!(
+            assemble_words("jump later : main v0 := 1 : later jump main"),
+            [0x1204, 0x1206, 0x6001, 0x1204]
+        );
+        assert_eq!(
+            assemble_words("i := long later :pointer later : main : later"),
+            [0x1208, 0xF000, 0x0208, 0x0208]
+        );
+        assert_eq!(
+            assemble_words(":unpack 1 later : main : later"),
+            [0x1206, 0x6012, 0x6106]
+        );
+        assert_eq!(
+            assemble_words("loop v0 += 1 again : main ;"),
+            [0x1206, 0x7001, 0x1202, 0x00EE]
+        );
+        assert_eq!(
+            assemble_words(":org 0x204 : main jump main"),
+            [0x1204, 0x0000, 0x1204]
+        );
+        assert_error(":org 0x201 : main", 1, 6);
+    }
+
+    #[test]
+    fn test_constants_aliases_and_calc() {
+        let source = "
+            :const speed 3
+            :alias x v4
+            :calc double { speed * 2 }
+            :calc sum { 1 + 2 * 3 }   # right to left: 1 + ( 2 * 3 )
+            :calc paren { ( 1 + 2 ) * 3 }
+            : main
+            x := speed x += double
+            :byte sum :byte { paren - 1 }
+            :unpack 0xA main
+            :pointer main
+        ";
+        assert_eq!(
+            words(&assemble(source).unwrap().rom),
+            [0x6403, 0x7406, 0x0708, 0x60A2, 0x6100, 0x0200]
+        );
+        let here = assemble(": main :byte { HERE - 0x1FF } :byte { HERE - 0x1FF }").unwrap();
+        assert_eq!(here.rom, [1, 2]);
+    }
+
+    #[test]
+    fn test_control_flow() {
+        let source = "
+            : main
+            loop
+                v0 += 1
+                while v0 != 10
+                if v1 == 2 then v2 := 3
+                if v1 < 4 begin v2 := 5 else v2 := 6 end
+            again
+        ";
+        assert_eq!(
+            assemble_words(source),
+            [
+                0x7001, // loop: ADD V0, 1
+                0x400A, // while v0 != 10: skip the exit while it holds
+                0x121A, // JP past again
+                0x4102, // if v1 == 2 then: skip unless it holds
+                0x6203, 0x6F04, // VF := 4
+                0x8F17, // VF =- V1, so VF = v1 >= 4
+                0x4F01, // skip the jump to else if v1 < 4
+                0x1216, 0x6205, 0x1218, // JP end
+                0x6206, // else
+                0x1200, // again
+            ]
+        );
+    }
+
+    #[test]
+    fn test_macros() {
+        let source = "
+            :macro add-twice reg n { reg += n reg += n }
+            : main
+            add-twice v3 2
+        ";
+        assert_eq!(assemble_words(source), [0x7302, 0x7302]);
+        assert_error(":macro forever { forever } : main forever", 1, 18);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_error(": main\n  v0 := 256", 2, 9);
+        assert_error(": main\njump nowhere", 2, 6);
+        assert_error(": main loop v0 += 1", 1, 8);
+        assert_error(": main\n\tsprite v0 v1", 2, 12);
+        assert_error(": main : main", 1, 10);
+        assert_error("v0 := 1", 1, 7);
+        assert_error(": main if v0 ~ 1 then", 1, 14);
+        assert_error(": main\n :frobnicate", 2, 2);
+    }
+}
@@ -1,20 +1,88 @@
-extern crate sdl2;
-
+#[cfg(feature = "sdl")]
 use sdl2::audio::{AudioCallback, AudioSpecDesired};
 
+use crate::core::AUDIO_PATTERN_SIZE;
+
+/*
+Anything that can play the buzzer. The machine only knows whether the sound
+timer is running, so a sink is told to start or stop once per frame.
+*/
+pub trait AudioSink {
+    fn play(&mut self);
+    fn stop(&mut self);
+
+    // XO-CHIP programs replace the buzzer with a pattern of 128 1-bit samples,
+    // played at a rate set by the pitch. Sinks that can't do that keep buzzing.
+    fn set_pattern(&mut self, _pattern: &[u8; AUDIO_PATTERN_SIZE], _pitch: u8) {}
+}
+
+/*
+The number of pattern samples per second for an XO-CHIP pitch, 4000 Hz at the
+default pitch of 64.
+*/
+pub fn pattern_rate(pitch: u8) -> f32 {
+    4000.0 * 2f32.powf((f32::from(pitch) - 64.0) / 48.0)
+}
+
+/*
+An audio sink that stays silent, for running headless.
+*/
+#[derive(Default)]
+pub struct NullAudio;
+
+impl AudioSink for NullAudio {
+    fn play(&mut self) {}
+    fn stop(&mut self) {}
+}
+
+/*
+An audio sink that remembers whether the buzzer was on for every frame.
+*/
+#[derive(Default)]
+pub struct RecordingAudio {
+    pub history: Vec<bool>,
+    pub patterns: Vec<([u8; AUDIO_PATTERN_SIZE], u8)>,
+}
+
+impl AudioSink for RecordingAudio {
+    fn play(&mut self) {
+        self.history.push(true);
+    }
+
+    fn stop(&mut self) {
+        self.history.push(false);
+    }
+
+    fn set_pattern(&mut self, pattern: &[u8; AUDIO_PATTERN_SIZE], pitch: u8) {
+        self.patterns.push((*pattern, pitch));
+    }
+}
+
+#[cfg(feature = "sdl")]
 pub struct SquareWave {
     phase_inc: f32,
     phase: f32,
     volume: f32,
+    freq: f32, // samples per second of the output device
+    // The XO-CHIP pattern that replaces the square wave once a program sets one
+    pattern: Option<[u8; AUDIO_PATTERN_SIZE]>,
 }
 
+#[cfg(feature = "sdl")]
 impl AudioCallback for SquareWave {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
-        // Generate a square wave
+        // Generate a square wave, or loop over the bits of the pattern
         for x in out.iter_mut() {
-            if self.phase > 0.0 && self.phase < 0.5 {
+            let high = match self.pattern {
+                Some(pattern) => {
+                    let bit = (self.phase * (AUDIO_PATTERN_SIZE * 8) as f32) as usize;
+                    pattern[bit / 8] >> (7 - bit % 8) & 1 == 1
+                }
+                None => self.phase > 0.0 && self.phase < 0.5,
+            };
+            if high {
                 *x = self.volume;
             } else {
                 *x = -self.volume;
@@ -24,10 +92,12 @@ impl AudioCallback for SquareWave {
     }
 }
 
+#[cfg(feature = "sdl")]
 pub struct AudioDriver {
     pub device: sdl2::audio::AudioDevice<SquareWave>,
 }
 
+#[cfg(feature = "sdl")]
 impl AudioDriver {
     pub fn new(sdl_context: &sdl2::Sdl) -> Self {
         let audio_subsystem = sdl_context.audio().unwrap();
@@ -43,17 +113,29 @@ impl AudioDriver {
                     phase_inc: 440.0 / spec.freq as f32,
                     phase: 0.0,
                     volume: 0.25,
+                    freq: spec.freq as f32,
+                    pattern: None,
                 }
             })
             .unwrap();
         Self { device }
     }
+}
 
-    pub fn play(&self) {
+#[cfg(feature = "sdl")]
+impl AudioSink for AudioDriver {
+    fn play(&mut self) {
         self.device.resume()
     }
 
-    pub fn stop(&self) {
+    fn stop(&mut self) {
         self.device.pause();
     }
+
+    fn set_pattern(&mut self, pattern: &[u8; AUDIO_PATTERN_SIZE], pitch: u8) {
+        let mut wave = self.device.lock();
+        // one pass over the pattern is a full period
+        wave.phase_inc = pattern_rate(pitch) / (AUDIO_PATTERN_SIZE * 8) as f32 / wave.freq;
+        wave.pattern = Some(*pattern);
+    }
 }
@@ -0,0 +1,545 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt::Write;
+
+use crate::instructions::{Instruction, InstructionParser};
+use crate::platform::InstructionSet;
+
+// How control gets from the end of one block to the start of another
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    Fallthrough, // on to the next instruction, also after a call returns or a skip isn't taken
+    Jump,
+    Skip,     // a skip instruction that is taken
+    Indirect, // JP V0, addr, followed to addr as V0 is not known
+}
+
+impl EdgeKind {
+    fn name(self) -> &'static str {
+        match self {
+            EdgeKind::Fallthrough => "fallthrough",
+            EdgeKind::Jump => "jump",
+            EdgeKind::Skip => "skip",
+            EdgeKind::Indirect => "indirect",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub start: u16,
+    pub end: u16, // the address after the last instruction
+    pub instructions: Vec<(u16, Instruction)>,
+    pub successors: Vec<(u16, EdgeKind)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subroutine {
+    pub entry: u16,
+    pub blocks: BTreeSet<u16>,
+    pub calls: BTreeSet<u16>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteKind {
+    Code,
+    Data,
+}
+
+// What the address a label stands for holds, which decides its name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LabelKind {
+    Main,
+    Subroutine,
+    Code,
+    Sprite,
+    Data,
+}
+
+// The name of a label, the same in the graph, the disassembly and in Octo
+pub fn label_name(kind: LabelKind, addr: u16) -> String {
+    match kind {
+        LabelKind::Main => String::from("main"),
+        LabelKind::Subroutine => format!("sub_{:03X}", addr),
+        LabelKind::Code => format!("label_{:03X}", addr),
+        LabelKind::Sprite => format!("sprite_{:03X}", addr),
+        LabelKind::Data => format!("data_{:03X}", addr),
+    }
+}
+
+/*
+The control flow of a ROM as far as it can be followed without running it:
+the basic blocks, the subroutines made of them and which subroutines call
+which. Bytes that no instruction on the way covers are taken for data.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControlFlowGraph {
+    pub origin: u16,
+    pub blocks: BTreeMap<u16, Block>,
+    pub subroutines: BTreeMap<u16, Subroutine>,
+    pub bytes: Vec<ByteKind>,
+    // Addresses control reaches that don't hold an instruction of the ROM
+    pub dead_ends: BTreeSet<u16>,
+}
+
+// Where an instruction can go next, and whether it ends its block
+struct Flow {
+    successors: Vec<(u16, EdgeKind)>,
+    ends_block: bool,
+    call: Option<u16>,
+}
+
+struct Rom<'a, T: InstructionParser> {
+    parser: &'a T,
+    rom: &'a [u8],
+    origin: u16,
+    set: InstructionSet,
+}
+
+impl<T: InstructionParser> Rom<'_, T> {
+    fn word(&self, addr: u16) -> Option<u16> {
+        let pos = usize::from(addr.checked_sub(self.origin)?);
+        let bytes = self.rom.get(pos..pos + 2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn decode(&self, addr: u16) -> Option<Instruction> {
+        let opcode = self.word(addr)?;
+        let next = if opcode == 0xF000 {
+            self.word(addr.wrapping_add(2))?
+        } else {
+            0
+        };
+        self.parser.try_from_words(opcode, next).ok()
+    }
+
+    /*
+    A skip steps over the whole of the next instruction, which on XO-CHIP can
+    be the 4 byte F000 NNNN. Everywhere else it steps over 2 bytes, whatever
+    they are.
+    */
+    fn flow(&self, addr: u16, instruction: &Instruction) -> Flow {
+        let next = addr.wrapping_add(instruction.size());
+        let (successors, ends_block, call) = match *instruction {
+            Instruction::Jump(target) => (vec![(target, EdgeKind::Jump)], true, None),
+            Instruction::JumpBase(base) => (vec![(base, EdgeKind::Indirect)], true, None),
+            Instruction::Return | Instruction::Exit => (vec![], true, None),
+            Instruction::Call(target) => (vec![(next, EdgeKind::Fallthrough)], true, Some(target)),
+            Instruction::SkipEqualsByte(..)
+            | Instruction::SkipNotEqualsByte(..)
+            | Instruction::SkipEqualsRegister(..)
+            | Instruction::SkipNotEqualRegister(..)
+            | Instruction::SkipKeyPress(_)
+            | Instruction::SkipNotKeyPress(_) => {
+                let skipped = match self.word(next) {
+                    Some(0xF000) if self.set >= InstructionSet::XoChip => 4,
+                    _ => 2,
+                };
+                let successors = vec![
+                    (next, EdgeKind::Fallthrough),
+                    (next.wrapping_add(skipped), EdgeKind::Skip),
+                ];
+                (successors, true, None)
+            }
+            _ => (vec![(next, EdgeKind::Fallthrough)], false, None),
+        };
+        Flow {
+            successors,
+            ends_block,
+            call,
+        }
+    }
+}
+
+/*
+Follows the ROM, loaded at `origin`, from its first instruction through every
+jump, call, return and skip, as a platform with the instruction set `set`
+would run it.
+*/
+pub fn analyze<T: InstructionParser>(
+    parser: &T,
+    rom: &[u8],
+    origin: u16,
+    set: InstructionSet,
+) -> ControlFlowGraph {
+    let rom = Rom {
+        parser,
+        rom,
+        origin,
+        set,
+    };
+    let mut instructions: BTreeMap<u16, (Instruction, Flow)> = BTreeMap::new();
+    let mut leaders = BTreeSet::new();
+    let mut entries = BTreeSet::new();
+    let mut dead_ends = BTreeSet::new();
+    let mut pending = VecDeque::new();
+    leaders.insert(origin);
+    entries.insert(origin);
+    pending.push_back(origin);
+    while let Some(addr) = pending.pop_front() {
+        if instructions.contains_key(&addr) || dead_ends.contains(&addr) {
+            continue;
+        }
+        let instruction = match rom.decode(addr) {
+            Some(instruction) => instruction,
+            None => {
+                dead_ends.insert(addr);
+                continue;
+            }
+        };
+        let flow = rom.flow(addr, &instruction);
+        for &(successor, _) in flow.successors.iter() {
+            if flow.ends_block {
+                leaders.insert(successor);
+            }
+            pending.push_back(successor);
+        }
+        if let Some(target) = flow.call {
+            leaders.insert(target);
+            entries.insert(target);
+            pending.push_back(target);
+        }
+        instructions.insert(addr, (instruction, flow));
+    }
+
+    // A block runs from a leader up to the next leader or the end of the flow
+    let mut blocks = BTreeMap::new();
+    for &start in leaders.iter().filter(|l| instructions.contains_key(l)) {
+        let mut block = Block {
+            start,
+            end: start,
+            instructions: Vec::new(),
+            successors: Vec::new(),
+        };
+        let mut addr = start;
+        while let Some((instruction, flow)) = instructions.get(&addr) {
+            block.instructions.push((addr, instruction.clone()));
+            addr = addr.wrapping_add(instruction.size());
+            block.end = addr;
+            if flow.ends_block || leaders.contains(&addr) {
+                block.successors = flow.successors.clone();
+                break;
+            }
+            if !instructions.contains_key(&addr) {
+                block.successors = vec![(addr, EdgeKind::Fallthrough)];
+            }
+        }
+        blocks.insert(start, block);
+    }
+
+    // A subroutine is everything its entry reaches without following calls
+    let mut subroutines = BTreeMap::new();
+    for &entry in entries.iter().filter(|e| blocks.contains_key(e)) {
+        let mut subroutine = Subroutine {
+            entry,
+            blocks: BTreeSet::new(),
+            calls: BTreeSet::new(),
+        };
+        let mut pending = vec![entry];
+        while let Some(start) = pending.pop() {
+            let block = match blocks.get(&start) {
+                Some(block) if subroutine.blocks.insert(start) => block,
+                _ => continue,
+            };
+            if let Some((_, Instruction::Call(target))) = block.instructions.last() {
+                subroutine.calls.insert(*target);
+            }
+            pending.extend(block.successors.iter().map(|&(to, _)| to));
+        }
+        subroutines.insert(entry, subroutine);
+    }
+
+    let mut bytes = vec![ByteKind::Data; rom.rom.len()];
+    for (&addr, (instruction, _)) in instructions.iter() {
+        let start = usize::from(addr - origin);
+        let end = (start + usize::from(instruction.size())).min(bytes.len());
+        bytes[start..end]
+            .iter_mut()
+            .for_each(|b| *b = ByteKind::Code);
+    }
+
+    ControlFlowGraph {
+        origin,
+        blocks,
+        subroutines,
+        bytes,
+        dead_ends,
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+impl ControlFlowGraph {
+    // What the analysis found at `addr`, data if no instruction covers it
+    pub fn label_kind(&self, addr: u16) -> LabelKind {
+        let pos = addr.checked_sub(self.origin).map(usize::from);
+        if addr == self.origin {
+            LabelKind::Main
+        } else if self.subroutines.contains_key(&addr) {
+            LabelKind::Subroutine
+        } else if pos.and_then(|pos| self.bytes.get(pos)) == Some(&ByteKind::Code) {
+            LabelKind::Code
+        } else {
+            LabelKind::Data
+        }
+    }
+
+    fn name(&self, addr: u16) -> String {
+        label_name(self.label_kind(addr), addr)
+    }
+
+    // The ROM split into runs of code and runs of data, as (kind, start, end) addresses
+    pub fn ranges(&self) -> Vec<(ByteKind, u16, u16)> {
+        let mut ranges: Vec<(ByteKind, u16, u16)> = Vec::new();
+        for (i, &kind) in self.bytes.iter().enumerate() {
+            let addr = self.origin.wrapping_add(i as u16);
+            match ranges.last_mut() {
+                Some(last) if last.0 == kind => last.2 = addr.wrapping_add(1),
+                _ => ranges.push((kind, addr, addr.wrapping_add(1))),
+            }
+        }
+        ranges
+    }
+
+    /*
+    The blocks as Graphviz nodes, with the flow between them as solid edges
+    and calls as dashed edges to the subroutines.
+    */
+    pub fn to_dot(&self) -> String {
+        let mut dot =
+            String::from("digraph rom {\n    node [shape=box, fontname=\"monospace\"];\n");
+        for block in self.blocks.values() {
+            let mut label = format!("{}:\\l", self.name(block.start));
+            for (addr, instruction) in block.instructions.iter() {
+                let _ = write!(label, "{:04X}  {}\\l", addr, instruction);
+            }
+            let _ = writeln!(dot, "    \"{:04X}\" [label=\"{}\"];", block.start, label);
+            for (to, kind) in block.successors.iter() {
+                let _ = writeln!(
+                    dot,
+                    "    \"{:04X}\" -> \"{:04X}\" [label=\"{}\"];",
+                    block.start,
+                    to,
+                    kind.name()
+                );
+            }
+            if let Some((_, Instruction::Call(target))) = block.instructions.last() {
+                let _ = writeln!(
+                    dot,
+                    "    \"{:04X}\" -> \"{:04X}\" [label=\"call\", style=dashed];",
+                    block.start, target
+                );
+            }
+        }
+        for addr in self.dead_ends.iter() {
+            let _ = writeln!(
+                dot,
+                "    \"{:04X}\" [label=\"{:04X}: no instruction\", style=dotted];",
+                addr, addr
+            );
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    // Everything in the graph as JSON, with addresses as numbers
+    pub fn to_json(&self) -> String {
+        let blocks: Vec<String> = self
+            .blocks
+            .values()
+            .map(|block| {
+                let instructions: Vec<String> = block
+                    .instructions
+                    .iter()
+                    .map(|(addr, instruction)| {
+                        format!(
+                            "{{\"addr\":{},\"text\":{}}}",
+                            addr,
+                            json_string(&instruction.to_string())
+                        )
+                    })
+                    .collect();
+                let successors: Vec<String> = block
+                    .successors
+                    .iter()
+                    .map(|(to, kind)| format!("{{\"to\":{},\"kind\":\"{}\"}}", to, kind.name()))
+                    .collect();
+                format!(
+                    "{{\"start\":{},\"end\":{},\"name\":{},\"instructions\":[{}],\"successors\":[{}]}}",
+                    block.start,
+                    block.end,
+                    json_string(&self.name(block.start)),
+                    instructions.join(","),
+                    successors.join(",")
+                )
+            })
+            .collect();
+        let join = |addrs: &BTreeSet<u16>| {
+            addrs
+                .iter()
+                .map(u16::to_string)
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let subroutines: Vec<String> = self
+            .subroutines
+            .values()
+            .map(|subroutine| {
+                format!(
+                    "{{\"entry\":{},\"name\":{},\"blocks\":[{}],\"calls\":[{}]}}",
+                    subroutine.entry,
+                    json_string(&self.name(subroutine.entry)),
+                    join(&subroutine.blocks),
+                    join(&subroutine.calls)
+                )
+            })
+            .collect();
+        let ranges: Vec<String> = self
+            .ranges()
+            .iter()
+            .map(|(kind, start, end)| {
+                let kind = match kind {
+                    ByteKind::Code => "code",
+                    ByteKind::Data => "data",
+                };
+                format!(
+                    "{{\"kind\":\"{}\",\"start\":{},\"end\":{}}}",
+                    kind, start, end
+                )
+            })
+            .collect();
+        format!(
+            "{{\"origin\":{},\"blocks\":[{}],\"subroutines\":[{}],\"bytes\":[{}],\"dead_ends\":[{}]}}\n",
+            self.origin,
+            blocks.join(","),
+            subroutines.join(","),
+            ranges.join(","),
+            join(&self.dead_ends)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::PROGRAM_OFFSET;
+    use crate::instructions::encode_program;
+    use crate::opcodes::OpcodeMaskParser;
+
+    fn analyze_program(program: &[Instruction], data: &[u8]) -> ControlFlowGraph {
+        let mut rom = encode_program(program).unwrap();
+        rom.extend_from_slice(data);
+        analyze(
+            &OpcodeMaskParser {},
+            &rom,
+            PROGRAM_OFFSET as u16,
+            InstructionSet::XoChip,
+        )
+    }
+
+    #[test]
+    fn test_blocks_and_subroutines() {
+        let cfg = analyze_program(
+            &[
+                Instruction::Call(0x208),            // 200
+                Instruction::AddByte(0x0, 1),        // 202
+                Instruction::Jump(0x202),            // 204
+                Instruction::ClearScreen,            // 206, never reached
+                Instruction::LoadImmediate(0x20E),   // 208
+                Instruction::SkipEqualsByte(0x0, 3), // 20A
+                Instruction::Return,                 // 20C
+                Instruction::Return,                 // 20E
+            ],
+            &[0xFF, 0x81],
+        );
+        let starts: Vec<u16> = cfg.blocks.keys().copied().collect();
+        assert_eq!(starts, [0x200, 0x202, 0x208, 0x20C, 0x20E]);
+        assert_eq!(
+            cfg.blocks[&0x200].successors,
+            [(0x202, EdgeKind::Fallthrough)]
+        );
+        assert_eq!(cfg.blocks[&0x202].successors, [(0x202, EdgeKind::Jump)]);
+        assert_eq!(
+            cfg.blocks[&0x208].successors,
+            [(0x20C, EdgeKind::Fallthrough), (0x20E, EdgeKind::Skip)]
+        );
+        assert_eq!(cfg.subroutines.len(), 2);
+        assert_eq!(
+            cfg.subroutines[&0x200].calls,
+            [0x208].iter().copied().collect()
+        );
+        assert_eq!(
+            cfg.subroutines[&0x208].blocks,
+            [0x208, 0x20C, 0x20E].iter().copied().collect()
+        );
+        assert_eq!(
+            cfg.ranges(),
+            [
+                (ByteKind::Code, 0x200, 0x206),
+                (ByteKind::Data, 0x206, 0x208),
+                (ByteKind::Code, 0x208, 0x210),
+                (ByteKind::Data, 0x210, 0x212),
+            ]
+        );
+        assert!(cfg.dead_ends.is_empty());
+
+        let dot = cfg.to_dot();
+        assert!(dot.contains("\"0200\" -> \"0208\" [label=\"call\", style=dashed];"));
+        assert!(dot.contains("[label=\"sub_208:\\l0208  LD I, 0x20E\\l020A  SE V0, 0x03\\l\"]"));
+        let json = cfg.to_json();
+        assert!(
+            json.contains("{\"entry\":512,\"name\":\"main\",\"blocks\":[512,514],\"calls\":[520]}")
+        );
+        assert!(json.contains("{\"kind\":\"data\",\"start\":518,\"end\":520}"));
+    }
+
+    #[test]
+    fn test_skips_over_long_loads_and_dead_ends() {
+        let cfg = analyze_program(
+            &[
+                Instruction::SkipKeyPress(0x1),         // 200
+                Instruction::LoadLongImmediate(0x1234), // 202
+                Instruction::JumpBase(0x300),           // 206
+            ],
+            &[],
+        );
+        assert_eq!(
+            cfg.blocks[&0x200].successors,
+            [(0x202, EdgeKind::Fallthrough), (0x206, EdgeKind::Skip)]
+        );
+        assert_eq!(cfg.blocks[&0x202].end, 0x206);
+        assert_eq!(cfg.blocks[&0x206].successors, [(0x300, EdgeKind::Indirect)]);
+        assert_eq!(cfg.dead_ends, [0x300].iter().copied().collect());
+        assert!(cfg.bytes.iter().all(|&b| b == ByteKind::Code));
+    }
+
+    #[test]
+    fn test_skips_over_two_bytes_before_xo_chip() {
+        let rom = encode_program(&[
+            Instruction::SkipKeyPress(0x1),         // 200
+            Instruction::LoadLongImmediate(0x1234), // 202
+        ])
+        .unwrap();
+        let cfg = analyze(
+            &OpcodeMaskParser {},
+            &rom,
+            PROGRAM_OFFSET as u16,
+            InstructionSet::SuperChip,
+        );
+        assert_eq!(
+            cfg.blocks[&0x200].successors,
+            [(0x202, EdgeKind::Fallthrough), (0x204, EdgeKind::Skip)]
+        );
+    }
+}
@@ -0,0 +1,828 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use chip8::assembler::assemble;
+use chip8::audio::AudioSink;
+use chip8::cfg;
+use chip8::core::{Machine, PROGRAM_OFFSET};
+use chip8::debugger::Debugger;
+use chip8::decompiler::decompile;
+use chip8::disasm::disassemble;
+use chip8::display::Display;
+use chip8::error::Chip8Error;
+use chip8::gdbstub::GdbStub;
+use chip8::instructions::{parser_by_name, InstructionParser, PARSERS};
+use chip8::isa;
+use chip8::keyboard::InputSource;
+use chip8::lint::{self, Severity};
+use chip8::lockstep::Lockstep;
+use chip8::lookup::LookupTableParser;
+use chip8::movie::{self, Movie, MoviePlayer, MovieRecorder};
+use chip8::platform::{Platform, PLATFORMS};
+use chip8::rewind::RewindConfig;
+use chip8::rng::{self, RANDOM_SOURCES};
+use chip8::runner::Runner;
+use chip8::tracer::{TraceFilter, TraceFormat, Tracer};
+
+pub const USAGE: &str = "Usage: chip8 [--platform NAME] [--quirks LIST] [--seed N] [--rng NAME]
+                   [--trace FILE [--trace-format FORMAT] [--trace-range RANGE]
+                   [--trace-kinds LIST]] [--debug | --gdb PORT] ROM
+       chip8 --replay MOVIE ROM
+       chip8 disasm [--platform NAME] ROM
+       chip8 decompile [--platform NAME] ROM
+       chip8 cfg [--platform NAME] ROM [--json]
+       chip8 lint [--platform NAME] ROM
+       chip8 lockstep [--cycles N] [--seed N] [--movie MOVIE] ROM LEFT RIGHT
+       chip8 asm SOURCE [-o ROM] [--symbols FILE]
+       chip8 opcodes
+       chip8 --list-platforms
+
+Options:
+    --platform NAME     Run the ROM the way the named interpreter would
+                        (default: modern)
+    --quirks LIST       Override the quirks of the platform with a comma
+                        separated list of some of
+                        shift,load-store,jump,vf-reset,clip,display-wait
+    --seed N            Seed the random number generator, to make runs
+                        reproducible
+    --rng NAME          The random number generator behind CXKK: xorshift
+                        (default) or vip-like
+    --rewind-memory MB  Memory to keep rewind history in, 0 turns rewinding off
+                        (default: 8)
+    --rewind-interval N Take a rewind snapshot every N frames (default: 1)
+    --record MOVIE      Record the key presses into a movie file, rewinding is
+                        turned off
+    --replay MOVIE      Play a movie back without a window, using its settings,
+                        and check that it stays in sync
+    --trace FILE        Write the state of the machine before every instruction
+                        to a file
+    --trace-format FMT  jsonl (default), one JSON object per line, or binary,
+                        fixed size records after a C8TR header
+    --trace-range RANGE Only trace instructions at addresses in a hex range,
+                        e.g. 200-2FF
+    --trace-kinds LIST  Only trace instructions with one of the comma separated
+                        mnemonics or instruction names, e.g. DRW,LoadImmediate
+    --debug             Start paused in a debugger on the terminal, type help
+                        for its commands. Runs without a window when built
+                        without `sdl`
+    --gdb PORT          Start paused and wait for gdb to connect to the port on
+                        localhost, speaking the GDB remote protocol
+    --list-platforms    Show the available platforms and their settings
+
+disasm prints the ROM as assembly. decompile prints it as Octo, with its loops
+and branches recovered, which asm turns back into the same ROM. cfg prints the
+control flow of the ROM as a Graphviz graph, or as JSON. lint reports code that
+breaks or behaves differently between interpreters, and fails if any of it
+breaks. These four follow the code as the platform would run it, which only
+matters for skips over the 4 byte F000 NNNN of xo-chip.
+
+lockstep runs two machines on the ROM side by side and fails at the first
+instruction after which their states differ, printing both. Each is
+PARSER[:PLATFORM[:QUIRKS]], with PARSER one of mask, table or lookup, e.g.
+`mask table` or `mask:modern mask:modern:shift`. The movie gives both the same key
+presses and seed, its platform and quirks are ignored in favour of LEFT and
+RIGHT. asm assembles Octo source into a ROM, named after the source unless -o is
+given, and writes the address of every label to the symbols file if asked to.
+opcodes prints a reference of the instruction set.
+
+While running, F1 to F9 load the quick-save slots next to the ROM, Shift+F1 to
+Shift+F9 save to them. Holding Backspace rewinds, F12 breaks into the debugger.";
+
+// Frames between the state hashes in recorded movies
+const CHECKPOINT_INTERVAL: u64 = 60;
+
+/*
+Why a command didn't succeed. Some, like lint, have already printed why and
+only need to fail.
+*/
+#[derive(Debug, PartialEq)]
+pub enum Failure {
+    Usage,
+    Message(String),
+    Silent,
+}
+
+impl From<String> for Failure {
+    fn from(message: String) -> Self {
+        Failure::Message(message)
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Usage => f.write_str(USAGE),
+            Failure::Message(message) => f.write_str(message),
+            Failure::Silent => Ok(()),
+        }
+    }
+}
+
+// How to run a ROM in a window, or in the debugger
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunOptions {
+    pub rom_file: String,
+    pub platform: Platform, // with the quirks given on the command line
+    pub seed: Option<u64>,
+    pub rng: String,
+    pub rewind: RewindConfig,
+    pub record_file: Option<String>,
+    pub debug: bool,
+    pub gdb: Option<u16>,
+    pub trace_file: Option<String>,
+    pub trace_format: TraceFormat,
+    pub trace_filter: TraceFilter,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockstepOptions {
+    pub cycles: u64,
+    pub seed: Option<u64>,
+    pub movie_file: Option<String>,
+    pub rom_file: String,
+    pub left: String, // PARSER[:PLATFORM[:QUIRKS]]
+    pub right: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmOptions {
+    pub source_file: String,
+    pub rom_file: Option<String>, // the source with a .ch8 extension if not given
+    pub symbols_file: Option<String>,
+}
+
+// What disasm, decompile, cfg and lint look at
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnalysisOptions {
+    pub rom_file: String,
+    pub platform: Platform, // only its instruction set matters
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Run(RunOptions),
+    Replay {
+        movie_file: String,
+        rom_file: String,
+    },
+    Disasm(AnalysisOptions),
+    Decompile(AnalysisOptions),
+    Cfg {
+        options: AnalysisOptions,
+        json: bool,
+    },
+    Lint(AnalysisOptions),
+    Lockstep(LockstepOptions),
+    Asm(AsmOptions),
+    Opcodes,
+    ListPlatforms,
+    Help,
+}
+
+// The value that has to follow an option
+fn value(args: &mut impl Iterator<Item = String>) -> Result<String, Failure> {
+    args.next().ok_or(Failure::Usage)
+}
+
+fn number<T: FromStr>(args: &mut impl Iterator<Item = String>, what: &str) -> Result<T, Failure> {
+    let value = value(args)?;
+    value
+        .parse()
+        .map_err(|_| Failure::Message(format!("Invalid {}: {}", what, value)))
+}
+
+fn platform_option(args: &mut impl Iterator<Item = String>) -> Result<Platform, Failure> {
+    let name = value(args)?;
+    Platform::by_name(&name).ok_or_else(|| Failure::Message(format!("Unknown platform: {}", name)))
+}
+
+// The options of a subcommand that analyzes a ROM, and whether --json was given if it may be
+fn parse_analysis(
+    mut args: impl Iterator<Item = String>,
+    takes_json: bool,
+) -> Result<(AnalysisOptions, bool), Failure> {
+    let mut rom_file = None;
+    let mut platform_given = None;
+    let mut json = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--platform" => platform_given = Some(platform_option(&mut args)?),
+            "--json" if takes_json => json = true,
+            _ if rom_file.is_none() => rom_file = Some(arg),
+            _ => return Err(Failure::Usage),
+        }
+    }
+    let options = AnalysisOptions {
+        rom_file: rom_file.ok_or(Failure::Usage)?,
+        platform: platform_given.unwrap_or_default(),
+    };
+    Ok((options, json))
+}
+
+// The command line without the name of the program
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Command, Failure> {
+    let mut args = args.into_iter().peekable();
+    let subcommand = args.peek().cloned();
+    match subcommand.as_deref() {
+        Some("disasm") => return Ok(Command::Disasm(parse_analysis(args.skip(1), false)?.0)),
+        Some("decompile") => return Ok(Command::Decompile(parse_analysis(args.skip(1), false)?.0)),
+        Some("lint") => return Ok(Command::Lint(parse_analysis(args.skip(1), false)?.0)),
+        Some("cfg") => {
+            let (options, json) = parse_analysis(args.skip(1), true)?;
+            return Ok(Command::Cfg { options, json });
+        }
+        Some("lockstep") => return parse_lockstep(args.skip(1)),
+        Some("asm") => return parse_asm(args.skip(1)),
+        Some("opcodes") => return Ok(Command::Opcodes),
+        _ => {}
+    }
+    let mut rom_file = None;
+    let mut platform = Platform::default();
+    let mut quirks = None;
+    let mut replay_file = None;
+    // an option of the run, which a replay would ignore
+    let mut run_option = None;
+    let mut options = RunOptions {
+        rom_file: String::new(),
+        platform,
+        seed: None,
+        rng: String::from("xorshift"),
+        rewind: RewindConfig::default(),
+        record_file: None,
+        debug: false,
+        gdb: None,
+        trace_file: None,
+        trace_format: TraceFormat::JsonLines,
+        trace_filter: TraceFilter::default(),
+    };
+    while let Some(arg) = args.next() {
+        if arg.starts_with("--") && arg != "--replay" {
+            run_option = Some(arg.clone());
+        }
+        match arg.as_str() {
+            "--platform" => platform = platform_option(&mut args)?,
+            "--quirks" => quirks = Some(value(&mut args)?.parse()?),
+            "--seed" => options.seed = Some(number(&mut args, "seed")?),
+            "--rng" => {
+                options.rng = value(&mut args)?;
+                if !RANDOM_SOURCES.contains(&options.rng.as_str()) {
+                    let reason = format!("Unknown random number generator: {}", options.rng);
+                    return Err(Failure::Message(reason));
+                }
+            }
+            "--rewind-memory" => {
+                let megabytes: usize = number(&mut args, "rewind memory")?;
+                options.rewind.max_bytes = megabytes << 20;
+            }
+            "--rewind-interval" => {
+                let value = value(&mut args)?;
+                options.rewind.interval = value
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| format!("Invalid rewind interval: {}", value))?;
+            }
+            "--record" => options.record_file = Some(value(&mut args)?),
+            "--replay" => replay_file = Some(value(&mut args)?),
+            "--trace" => options.trace_file = Some(value(&mut args)?),
+            "--trace-format" => options.trace_format = value(&mut args)?.parse()?,
+            "--trace-range" => {
+                let range = TraceFilter::parse_range(&value(&mut args)?)?;
+                options.trace_filter.addresses = Some(range);
+            }
+            "--trace-kinds" => {
+                options.trace_filter.kinds = TraceFilter::parse_kinds(&value(&mut args)?);
+            }
+            "--debug" => options.debug = true,
+            "--gdb" => options.gdb = Some(number(&mut args, "port")?),
+            "--list-platforms" => return Ok(Command::ListPlatforms),
+            "-h" | "--help" => return Ok(Command::Help),
+            _ if arg.starts_with("--") => return Err(Failure::Usage),
+            _ if rom_file.is_none() => rom_file = Some(arg),
+            _ => return Err(Failure::Usage),
+        }
+    }
+    let rom_file = rom_file.ok_or(Failure::Usage)?;
+    if let Some(movie_file) = replay_file {
+        if let Some(option) = run_option {
+            let reason = format!(
+                "{} can't be used with --replay, which takes its settings from the movie",
+                option
+            );
+            return Err(Failure::Message(reason));
+        }
+        return Ok(Command::Replay {
+            movie_file,
+            rom_file,
+        });
+    }
+    if let Some(quirks) = quirks {
+        platform.quirks = quirks;
+    }
+    options.rom_file = rom_file;
+    options.platform = platform;
+    Ok(Command::Run(options))
+}
+
+fn parse_lockstep(mut args: impl Iterator<Item = String>) -> Result<Command, Failure> {
+    let mut cycles = 1_000_000;
+    let mut seed = None;
+    let mut movie_file = None;
+    let mut positional = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--cycles" => cycles = number(&mut args, "cycle count")?,
+            "--seed" => seed = Some(number(&mut args, "seed")?),
+            "--movie" => movie_file = Some(value(&mut args)?),
+            _ => positional.push(arg),
+        }
+    }
+    match positional.as_slice() {
+        [rom_file, left, right] => Ok(Command::Lockstep(LockstepOptions {
+            cycles,
+            seed,
+            movie_file,
+            rom_file: rom_file.clone(),
+            left: left.clone(),
+            right: right.clone(),
+        })),
+        _ => Err(Failure::Usage),
+    }
+}
+
+fn parse_asm(mut args: impl Iterator<Item = String>) -> Result<Command, Failure> {
+    let mut source_file = None;
+    let mut rom_file = None;
+    let mut symbols_file = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => rom_file = Some(value(&mut args)?),
+            "--symbols" => symbols_file = Some(value(&mut args)?),
+            _ if source_file.is_none() => source_file = Some(arg),
+            _ => return Err(Failure::Usage),
+        }
+    }
+    Ok(Command::Asm(AsmOptions {
+        source_file: source_file.ok_or(Failure::Usage)?,
+        rom_file,
+        symbols_file,
+    }))
+}
+
+// Parses the command line and does what it says
+pub fn run(args: impl IntoIterator<Item = String>) -> Result<(), Failure> {
+    match parse(args)? {
+        Command::Run(options) => emulate(options),
+        Command::Replay {
+            movie_file,
+            rom_file,
+        } => replay(&movie_file, &rom_file),
+        Command::Disasm(options) => disasm(&options),
+        Command::Decompile(options) => decompile_rom(&options),
+        Command::Cfg { options, json } => print_cfg(&options, json),
+        Command::Lint(options) => print_lint(&options),
+        Command::Lockstep(options) => lockstep(options),
+        Command::Asm(options) => asm(options),
+        Command::Opcodes => {
+            print!("{}", isa::reference());
+            Ok(())
+        }
+        Command::ListPlatforms => {
+            for platform in PLATFORMS.iter() {
+                println!("{}", platform);
+            }
+            Ok(())
+        }
+        Command::Help => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+    }
+}
+
+fn read_rom(rom_file: &str) -> Result<Vec<u8>, Failure> {
+    fs::read(rom_file).map_err(|e| Failure::Message(format!("Unable to read {}: {}", rom_file, e)))
+}
+
+fn read_movie(movie_file: &str) -> Result<Movie, Failure> {
+    fs::read_to_string(movie_file)
+        .map_err(Chip8Error::from)
+        .and_then(|text| text.parse())
+        .map_err(|e| Failure::Message(format!("Unable to read {}: {}", movie_file, e)))
+}
+
+fn load_rom<T: InstructionParser>(vm: &mut Machine<T>, rom_file: &str) -> Result<(), Failure> {
+    vm.load_rom(rom_file)
+        .map_err(|e| Failure::Message(format!("Unable to load ROM from {}: {}", rom_file, e)))
+}
+
+// What the frontend does around the machine
+#[cfg_attr(not(feature = "sdl"), allow(dead_code))]
+struct Frontend {
+    rom_file: String,
+    rewind: RewindConfig,
+    record: Option<(String, MovieRecorder)>,
+    debug: bool,
+    gdb: Option<u16>,
+}
+
+impl Frontend {
+    fn debugging(&self) -> bool {
+        self.debug || self.gdb.is_some()
+    }
+}
+
+// Hands the runner over to gdb or to the debugger on the terminal
+fn debug<D, A, I>(
+    runner: &mut Runner<LookupTableParser, D, A, I>,
+    frontend: &Frontend,
+    realtime: bool,
+) -> Result<(), Chip8Error>
+where
+    D: Display,
+    A: AudioSink,
+    I: InputSource,
+{
+    if let Some(port) = frontend.gdb {
+        let mut stub = if realtime {
+            GdbStub::realtime()
+        } else {
+            GdbStub::new()
+        };
+        return stub.listen(runner, port);
+    }
+    let mut debugger = if realtime {
+        Debugger::realtime()
+    } else {
+        Debugger::new()
+    };
+    let stdin = io::stdin();
+    debugger.run(runner, stdin.lock(), io::stdout())
+}
+
+fn replay(movie_file: &str, rom_file: &str) -> Result<(), Failure> {
+    let movie = read_movie(movie_file)?;
+    let platform = movie.platform().map_err(|e| e.to_string())?;
+    let mut vm = Machine::with_platform("Chip8", LookupTableParser::new(), &platform);
+    load_rom(&mut vm, rom_file)?;
+    let vm = movie::replay(&movie, vm).map_err(|e| e.to_string())?;
+    println!(
+        "Replayed {} frames, final state {:016X}",
+        movie.frames(),
+        vm.state_hash()
+    );
+    Ok(())
+}
+
+// Prints the basic blocks of the ROM and the flow between them
+fn print_cfg(options: &AnalysisOptions, json: bool) -> Result<(), Failure> {
+    let rom = read_rom(&options.rom_file)?;
+    let graph = cfg::analyze(
+        &LookupTableParser::new(),
+        &rom,
+        PROGRAM_OFFSET as u16,
+        options.platform.instruction_set,
+    );
+    if json {
+        print!("{}", graph.to_json());
+    } else {
+        print!("{}", graph.to_dot());
+    }
+    Ok(())
+}
+
+// Prints what the linter finds, failing if any of it is an error
+fn print_lint(options: &AnalysisOptions) -> Result<(), Failure> {
+    let rom = read_rom(&options.rom_file)?;
+    let findings = lint::lint(
+        &LookupTableParser::new(),
+        &rom,
+        PROGRAM_OFFSET as u16,
+        options.platform.instruction_set,
+    );
+    for finding in findings.iter() {
+        println!("{}: {}", options.rom_file, finding);
+    }
+    if findings
+        .iter()
+        .any(|finding| finding.lint.severity() == Severity::Error)
+    {
+        return Err(Failure::Silent);
+    }
+    Ok(())
+}
+
+// Prints the ROM as assembly, with the addresses and bytes alongside
+fn disasm(options: &AnalysisOptions) -> Result<(), Failure> {
+    let rom = read_rom(&options.rom_file)?;
+    let disassembly = disassemble(
+        &LookupTableParser::new(),
+        &rom,
+        PROGRAM_OFFSET as u16,
+        options.platform.instruction_set,
+    );
+    print!("{}", disassembly);
+    Ok(())
+}
+
+fn decompile_rom(options: &AnalysisOptions) -> Result<(), Failure> {
+    let rom = read_rom(&options.rom_file)?;
+    let source = decompile(
+        &LookupTableParser::new(),
+        &rom,
+        PROGRAM_OFFSET as u16,
+        options.platform.instruction_set,
+    );
+    print!("{}", source);
+    Ok(())
+}
+
+// A machine as lockstep describes it, PARSER[:PLATFORM[:QUIRKS]]
+fn lockstep_machine(spec: &str) -> Result<Machine<Box<dyn InstructionParser>>, Failure> {
+    let mut parts = spec.split(':');
+    let name = parts.next().unwrap_or_default();
+    let parser = parser_by_name(name).ok_or_else(|| {
+        format!(
+            "Unknown parser: {}, expected one of {}",
+            name,
+            PARSERS.join(", ")
+        )
+    })?;
+    let mut platform = Platform::default();
+    if let Some(name) = parts.next() {
+        platform = Platform::by_name(name).ok_or_else(|| format!("Unknown platform: {}", name))?;
+    }
+    if let Some(list) = parts.next() {
+        platform.quirks = list.parse()?;
+    }
+    if parts.next().is_some() {
+        return Err(Failure::Usage);
+    }
+    Ok(Machine::with_platform(spec, parser, &platform))
+}
+
+fn lockstep(options: LockstepOptions) -> Result<(), Failure> {
+    // Without a movie, no key is ever pressed
+    let movie = match &options.movie_file {
+        Some(file) => read_movie(file)?,
+        None => {
+            let seed = options.seed.unwrap_or_else(rand::random);
+            Movie::new(&Platform::default(), "xorshift", seed)
+        }
+    };
+    let rom_file = &options.rom_file;
+    let mut machines = [
+        lockstep_machine(&options.left)?,
+        lockstep_machine(&options.right)?,
+    ];
+    for vm in machines.iter_mut() {
+        load_rom(vm, rom_file)?;
+        movie
+            .check_rom(vm.rom_hash())
+            .map_err(|e| format!("Unable to play {}: {}", rom_file, e))?;
+        vm.set_rng(movie.random_source().map_err(|e| e.to_string())?);
+    }
+    let [left, right] = machines;
+    let mut lockstep = Lockstep::new(
+        left,
+        right,
+        MoviePlayer::new(&movie),
+        MoviePlayer::new(&movie),
+    );
+    match lockstep.run(options.cycles) {
+        Ok(None) => {
+            println!("No divergence in {} cycles", lockstep.cycle());
+            Ok(())
+        }
+        Ok(Some(divergence)) => {
+            print!("{}", divergence);
+            Err(Failure::Silent)
+        }
+        Err(e) => Err(Failure::Message(format!(
+            "Both machines failed at cycle {}: {}",
+            lockstep.cycle(),
+            e
+        ))),
+    }
+}
+
+fn asm(options: AsmOptions) -> Result<(), Failure> {
+    let source_file = &options.source_file;
+    let rom_file = options.rom_file.clone().unwrap_or_else(|| {
+        let path = Path::new(source_file).with_extension("ch8");
+        path.to_string_lossy().into_owned()
+    });
+    let source = fs::read_to_string(source_file)
+        .map_err(|e| format!("Unable to read {}: {}", source_file, e))?;
+    let assembly = assemble(&source).map_err(|e| format!("{}: {}", source_file, e))?;
+    fs::write(&rom_file, &assembly.rom)
+        .map_err(|e| format!("Unable to write {}: {}", rom_file, e))?;
+    if let Some(file) = &options.symbols_file {
+        fs::write(file, assembly.symbol_file())
+            .map_err(|e| format!("Unable to write {}: {}", file, e))?;
+    }
+    Ok(())
+}
+
+fn emulate(options: RunOptions) -> Result<(), Failure> {
+    let platform = options.platform;
+    let mut vm = Machine::with_platform("Chip8", LookupTableParser::new(), &platform);
+    let seed = options.seed.unwrap_or_else(rand::random);
+    vm.set_rng(rng::by_name(&options.rng, seed).unwrap());
+    load_rom(&mut vm, &options.rom_file)?;
+    if let Some(file) = &options.trace_file {
+        let tracer = Tracer::create(file, options.trace_format, options.trace_filter)
+            .map_err(|e| format!("Unable to write the trace to {}: {}", file, e))?;
+        vm.set_tracer(tracer);
+    }
+    debug!("{:#?}", vm);
+    let rng = options.rng;
+    let record = options.record_file.map(|file| {
+        let movie = Movie::new(&platform, &rng, seed);
+        (file, MovieRecorder::new(movie, CHECKPOINT_INTERVAL))
+    });
+    run_frontend(
+        vm,
+        Frontend {
+            rom_file: options.rom_file,
+            rewind: options.rewind,
+            record,
+            debug: options.debug,
+            gdb: options.gdb,
+        },
+    )
+}
+
+// Writes out the rest of the trace, which stops there
+fn finish_trace(vm: &mut Machine<LookupTableParser>) -> Result<(), Failure> {
+    match vm.take_tracer().map(Tracer::finish) {
+        Some(Err(e)) => Err(Failure::Message(format!(
+            "Unable to write the trace: {}",
+            e
+        ))),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(feature = "sdl")]
+fn run_frontend(vm: Machine<LookupTableParser>, mut frontend: Frontend) -> Result<(), Failure> {
+    use chip8::audio::AudioDriver;
+    use chip8::display::VideoDisplay;
+    use chip8::keyboard::SdlInput;
+
+    let sdl_context = sdl2::init().unwrap();
+    let display = VideoDisplay::new(&sdl_context);
+    let audio = AudioDriver::new(&sdl_context);
+    let input = SdlInput::new(&sdl_context);
+    let mut runner = Runner::new(vm, display, audio, input);
+    runner.set_save_path(&frontend.rom_file);
+    let movie_file = match frontend.record.take() {
+        Some((file, recorder)) => {
+            runner.start_recording(recorder);
+            Some(file)
+        }
+        None if frontend.rewind.max_bytes > 0 => {
+            runner.enable_rewind(frontend.rewind);
+            None
+        }
+        None => None,
+    };
+    let result = if frontend.debugging() {
+        debug(&mut runner, &frontend, true)
+    } else {
+        runner.run()
+    };
+    finish_trace(runner.machine_mut())?;
+    if let (Some(file), Some(movie)) = (movie_file, runner.stop_recording()) {
+        fs::write(&file, movie.to_string())
+            .map_err(|e| format!("Unable to write the movie to {}: {}", file, e))?;
+    }
+    match result {
+        Ok(()) | Err(Chip8Error::Quit) => Ok(()),
+        Err(e) => Err(Failure::Message(e.to_string())),
+    }
+}
+
+// Without a window the debugger is all there is
+#[cfg(not(feature = "sdl"))]
+fn run_frontend(vm: Machine<LookupTableParser>, frontend: Frontend) -> Result<(), Failure> {
+    use chip8::audio::NullAudio;
+    use chip8::display::NullDisplay;
+    use chip8::keyboard::NullInput;
+
+    if !frontend.debugging() {
+        return Err(Failure::Message(String::from(
+            "chip8 was built without the `sdl` feature, so it cannot open a window",
+        )));
+    }
+    let mut runner = Runner::new(vm, NullDisplay, NullAudio, NullInput);
+    let result = debug(&mut runner, &frontend, false);
+    finish_trace(runner.machine_mut())?;
+    result.map_err(|e| Failure::Message(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chip8::platform::{COSMAC_VIP, XO_CHIP};
+
+    fn parse_line(line: &str) -> Result<Command, Failure> {
+        parse(line.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn test_parse_run() {
+        let options = match parse_line(
+            "--platform cosmac-vip --quirks shift --seed 7 --rewind-interval 4 --debug game.ch8",
+        ) {
+            Ok(Command::Run(options)) => options,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(options.rom_file, "game.ch8");
+        assert_eq!(options.platform.name, COSMAC_VIP.name);
+        assert!(options.platform.quirks.shift_uses_vy);
+        assert!(!options.platform.quirks.load_store_increments_i);
+        assert_eq!(options.seed, Some(7));
+        assert_eq!(options.rewind.interval, 4);
+        assert!(options.debug);
+
+        assert_eq!(
+            parse_line("--replay run.movie game.ch8"),
+            Ok(Command::Replay {
+                movie_file: String::from("run.movie"),
+                rom_file: String::from("game.ch8"),
+            })
+        );
+        assert!(matches!(
+            parse_line("--replay run.movie --trace run.jsonl game.ch8"),
+            Err(Failure::Message(reason)) if reason.starts_with("--trace can't be used")
+        ));
+        assert_eq!(parse_line("--help"), Ok(Command::Help));
+        assert_eq!(parse_line("--debug"), Err(Failure::Usage));
+        assert_eq!(parse_line("--seed"), Err(Failure::Usage));
+        assert_eq!(parse_line("--speeed 20 game.ch8"), Err(Failure::Usage));
+        assert_eq!(parse_line("game.ch8 other.ch8"), Err(Failure::Usage));
+        assert_eq!(
+            parse_line("--seed x game.ch8"),
+            Err(Failure::Message(String::from("Invalid seed: x")))
+        );
+        assert_eq!(
+            parse_line("--rewind-interval 0 game.ch8"),
+            Err(Failure::Message(String::from("Invalid rewind interval: 0")))
+        );
+        assert_eq!(
+            parse_line("--platform nes game.ch8"),
+            Err(Failure::Message(String::from("Unknown platform: nes")))
+        );
+    }
+
+    #[test]
+    fn test_parse_subcommands() {
+        let game = |platform| AnalysisOptions {
+            rom_file: String::from("game.ch8"),
+            platform,
+        };
+        assert_eq!(
+            parse_line("disasm game.ch8"),
+            Ok(Command::Disasm(game(Platform::default())))
+        );
+        assert_eq!(parse_line("disasm a.ch8 b.ch8"), Err(Failure::Usage));
+        assert_eq!(parse_line("disasm game.ch8 --json"), Err(Failure::Usage));
+        assert_eq!(
+            parse_line("cfg game.ch8 --json --platform xo-chip"),
+            Ok(Command::Cfg {
+                options: game(XO_CHIP),
+                json: true
+            })
+        );
+        assert_eq!(parse_line("cfg game.ch8 --dot"), Err(Failure::Usage));
+        assert_eq!(
+            parse_line("lint --platform nes game.ch8"),
+            Err(Failure::Message(String::from("Unknown platform: nes")))
+        );
+        assert_eq!(
+            parse_line("lockstep --cycles 10 game.ch8 mask table"),
+            Ok(Command::Lockstep(LockstepOptions {
+                cycles: 10,
+                seed: None,
+                movie_file: None,
+                rom_file: String::from("game.ch8"),
+                left: String::from("mask"),
+                right: String::from("table"),
+            }))
+        );
+        assert_eq!(parse_line("lockstep game.ch8 mask"), Err(Failure::Usage));
+        assert_eq!(
+            parse_line("asm game.8o --symbols game.sym"),
+            Ok(Command::Asm(AsmOptions {
+                source_file: String::from("game.8o"),
+                rom_file: None,
+                symbols_file: Some(String::from("game.sym")),
+            }))
+        );
+        assert_eq!(parse_line("opcodes"), Ok(Command::Opcodes));
+    }
+}
@@ -1,33 +1,192 @@
-use rand::Rng;
 use std::fmt;
 use std::fs::File;
 use std::io::Read;
-use std::time::{Duration, Instant};
+use std::ops::Range;
 
-use crate::audio::AudioDriver;
-use crate::display::VideoDisplay;
+use crate::bitmasks::mask_0F00;
+use crate::error::Chip8Error;
+use crate::font::{Font, BIG_FONT_ADDRESS, BIG_FONT_GLYPH_SIZE, FONT_ADDRESS, FONT_GLYPH_SIZE};
 use crate::instructions::{Instruction, InstructionParser};
-use crate::keyboard::KeyMap;
-
-const MEMORY_SIZE: usize = 4096;
-const STACK_SIZE: usize = 16;
-const KEY_SIZE: usize = 16;
-const REGISTER_COUNT: usize = 16;
-const PROGRAM_OFFSET: usize = 512;
+use crate::platform::{InstructionSet, Platform};
+use crate::quirks::Quirks;
+use crate::rng::{RandomSource, XorShift};
+use crate::savestate::{self, Snapshot};
+use crate::tracer::{TraceRecord, Tracer};
+
+pub const MEMORY_SIZE: usize = 4096;
+pub const STACK_SIZE: usize = 16;
+pub const KEY_SIZE: usize = 16;
+pub const REGISTER_COUNT: usize = 16;
+pub const PROGRAM_OFFSET: usize = 512;
 const FLAG_REGISTER: usize = 15;
 const SPRITE_WIDTH: usize = 8;
-const CLOCK_SPEED: u64 = 500; // 500 Hz
-const TIMER_FREQ: u64 = 60; // 60 Hz
+pub const CLOCK_SPEED: u64 = 500; // 500 Hz
+pub const TIMER_FREQ: u64 = 60; // 60 Hz
 
 pub const DISPLAY_WIDTH: usize = 64;
 pub const DISPLAY_HEIGHT: usize = 32;
-
+pub const FLAG_COUNT: usize = 16;
+pub const PLANE_COUNT: usize = 2; // XO-CHIP bitplanes
+pub const ALL_PLANES: u8 = 0b11;
+pub const AUDIO_PATTERN_SIZE: usize = 16; // XO-CHIP audio pattern of 128 1-bit samples
+pub const DEFAULT_PITCH: u8 = 64; // plays the pattern at 4000 samples per second
+const MAX_INSTRUCTION_SIZE: usize = 4; // F000 NNNN
+
+/*
+The RAM, along with the instructions decoded from it so far, so that a loop
+only decodes its instructions the first time around. Every write goes through
+`write`, which forgets the instructions the written bytes were part of, so
+self-modifying programs see their changes. It also counts the writes and
+remembers where the last one went, for the debugger's watches.
+*/
 pub struct Memory {
-    mem: [u8; MEMORY_SIZE],
+    mem: Vec<u8>,
+    decoded: Vec<Option<Instruction>>,
+    writes: u64,
+    last_write: Range<usize>,
+}
+
+impl Memory {
+    fn new(mem: Vec<u8>) -> Self {
+        let decoded = vec![None; mem.len()];
+        Memory {
+            mem,
+            decoded,
+            writes: 0,
+            last_write: 0..0,
+        }
+    }
+
+    fn write(&mut self, addr: usize, bytes: &[u8]) {
+        let end = addr + bytes.len();
+        self.writes = self.writes.wrapping_add(1);
+        self.last_write = addr..end;
+        self.mem[addr..end].copy_from_slice(bytes);
+        let first = addr.saturating_sub(MAX_INSTRUCTION_SIZE - 1);
+        self.decoded[first..end].iter_mut().for_each(|d| *d = None);
+    }
 }
 
+/*
+The VRAM, one byte per pixel. Its size follows the display mode, so SUPER-CHIP
+programs can switch between 64x32 and 128x64 pixels.
+
+Every pixel is a bitmask of the planes it is set in. Only XO-CHIP programs
+draw to more than the first plane, so everything else only ever sees 0 and 1.
+*/
+#[derive(Clone, PartialEq)]
 pub struct GraphicsMemory {
-    pub mem: [[u8; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+    width: usize,
+    height: usize,
+    mem: Vec<u8>,
+}
+
+impl GraphicsMemory {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            mem: vec![0; width * height],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> u8 {
+        self.mem[y * self.width + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, pixel: u8) {
+        self.mem[y * self.width + x] = pixel;
+    }
+
+    // A display of the given size with the given pixels, if they fit
+    pub fn from_pixels(width: usize, height: usize, pixels: Vec<u8>) -> Option<Self> {
+        if pixels.len() != width * height {
+            return None;
+        }
+        Some(Self {
+            width,
+            height,
+            mem: pixels,
+        })
+    }
+
+    // All pixels, row by row
+    pub fn pixels(&self) -> &[u8] {
+        &self.mem
+    }
+
+    pub fn row(&self, y: usize) -> &[u8] {
+        &self.mem[y * self.width..(y + 1) * self.width]
+    }
+
+    pub fn clear(&mut self) {
+        self.clear_planes(ALL_PLANES);
+    }
+
+    pub fn clear_planes(&mut self, planes: u8) {
+        self.mem.iter_mut().for_each(|p| *p &= !planes);
+    }
+
+    // Changes the resolution, which loses whatever was on screen
+    pub fn resize(&mut self, width: usize, height: usize) {
+        *self = Self::new(width, height);
+    }
+
+    /*
+    The scroll instructions only move the selected planes and leave the others
+    where they are, so a shifted copy of the screen is merged back plane by plane.
+    */
+    fn merge_planes(&mut self, shifted: Vec<u8>, planes: u8) {
+        for (pixel, moved) in self.mem.iter_mut().zip(shifted) {
+            *pixel = (*pixel & !planes) | (moved & planes);
+        }
+    }
+
+    pub fn scroll_down(&mut self, rows: usize, planes: u8) {
+        let shift = rows.min(self.height) * self.width;
+        let mut shifted = vec![0; self.mem.len()];
+        shifted[shift..].copy_from_slice(&self.mem[..self.mem.len() - shift]);
+        self.merge_planes(shifted, planes);
+    }
+
+    pub fn scroll_up(&mut self, rows: usize, planes: u8) {
+        let shift = rows.min(self.height) * self.width;
+        let mut shifted = vec![0; self.mem.len()];
+        shifted[..self.mem.len() - shift].copy_from_slice(&self.mem[shift..]);
+        self.merge_planes(shifted, planes);
+    }
+
+    pub fn scroll_right(&mut self, cols: usize, planes: u8) {
+        let cols = cols.min(self.width);
+        let mut shifted = vec![0; self.mem.len()];
+        for (to, from) in shifted
+            .chunks_mut(self.width)
+            .zip(self.mem.chunks(self.width))
+        {
+            to[cols..].copy_from_slice(&from[..from.len() - cols]);
+        }
+        self.merge_planes(shifted, planes);
+    }
+
+    pub fn scroll_left(&mut self, cols: usize, planes: u8) {
+        let cols = cols.min(self.width);
+        let mut shifted = vec![0; self.mem.len()];
+        for (to, from) in shifted
+            .chunks_mut(self.width)
+            .zip(self.mem.chunks(self.width))
+        {
+            to[..from.len() - cols].copy_from_slice(&from[cols..]);
+        }
+        self.merge_planes(shifted, planes);
+    }
 }
 
 impl fmt::Debug for Memory {
@@ -46,10 +205,10 @@ impl fmt::Debug for Memory {
 impl fmt::Debug for GraphicsMemory {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "[[")?;
-        for (i, row) in self.mem.iter().enumerate() {
+        for (i, row) in self.mem.chunks(self.width).enumerate() {
             if !row.is_empty() {
                 write!(f, "{}:", i)?;
-                for (_, byte) in row.iter().enumerate() {
+                for byte in row.iter() {
                     write!(f, "{}", byte)?;
                 }
                 writeln!(f)?;
@@ -61,27 +220,31 @@ impl fmt::Debug for GraphicsMemory {
 
 pub struct Machine<T: InstructionParser> {
     name: String,
-    headless: bool,
     counter: u16,
     stack_ptr: u8,
     mem: Memory,
     graphics: GraphicsMemory,
-    sdl_context: Option<sdl2::Sdl>,
-    display: Option<VideoDisplay>,
-    audio: Option<AudioDriver>,
+    hires: bool,
+    redraw: bool,
+    halted: bool,
+    flags: [u8; FLAG_COUNT], // SUPER-CHIP "RPL user flags"
+    planes: u8,              // XO-CHIP bitplanes that drawing, scrolling and CLS apply to
+    audio_pattern: [u8; AUDIO_PATTERN_SIZE],
+    pitch: u8,
+    audio_changed: bool,
     stack: [u16; STACK_SIZE],
-    keymap: Option<KeyMap>,
     keyboard: [bool; KEY_SIZE],
     v: [u8; REGISTER_COUNT], // registers: v0 to vf
     i: u16,                  // "There is also a 16-bit register called I."
     delay_register: u8,
     sound_register: u8,
     instruction_parser: T,
+    rng: Box<dyn RandomSource>, // feeds CXKK
+    platform: Platform,
+    rom_hash: u64,
     skip_increment: bool,
-    instruction_delay: Duration,
-    timer_delay: Duration,
-    delay_last: Instant,
-    sound_last: Instant,
+    vblank: bool, // no sprite has been drawn since the frame started
+    tracer: Option<Tracer>,
 }
 
 impl<T> fmt::Debug for Machine<T>
@@ -100,7 +263,8 @@ where
             \tDR: {},
             \tSR: {},
             \tSKIP: {},
-            \tKEYBOARD: {:?}
+            \tKEYBOARD: {:?},
+            \tRNG: {:?}
         }}",
             self.name,
             self.counter,
@@ -111,7 +275,8 @@ where
             self.delay_register,
             self.sound_register,
             self.skip_increment,
-            self.keyboard
+            self.keyboard,
+            self.rng
         )
     }
 }
@@ -120,63 +285,43 @@ impl<T> Machine<T>
 where
     T: InstructionParser,
 {
-    pub fn new(name: &str, ins_parser: T, headless: bool, sdl_context: Option<sdl2::Sdl>) -> Self {
-        let mut machine = Self {
+    pub fn new(name: &str, ins_parser: T, quirks: Quirks) -> Self {
+        let platform = Platform {
+            quirks,
+            ..Platform::default()
+        };
+        Self::with_platform(name, ins_parser, &platform)
+    }
+
+    // Sets up the machine the way the given interpreter would
+    pub fn with_platform(name: &str, ins_parser: T, platform: &Platform) -> Self {
+        Self {
             name: name.to_string(),
-            headless,
             counter: 512,
             stack_ptr: 0,
-            mem: Machine::<T>::init_memory(),
-            graphics: GraphicsMemory {
-                mem: [[0; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
-            },
-            sdl_context,
-            display: None,
-            audio: None,
-            keymap: {
-                if headless {
-                    None
-                } else {
-                    Some(KeyMap::new())
-                }
-            },
+            mem: Machine::<T>::init_memory(platform.memory_size, platform.font),
+            graphics: GraphicsMemory::new(DISPLAY_WIDTH, DISPLAY_HEIGHT),
+            hires: false,
+            redraw: false,
+            halted: false,
+            flags: [0; FLAG_COUNT],
+            planes: 1,
+            audio_pattern: [0; AUDIO_PATTERN_SIZE],
+            pitch: DEFAULT_PITCH,
+            audio_changed: false,
             keyboard: [false; KEY_SIZE],
             stack: [0; STACK_SIZE],
             v: [0; REGISTER_COUNT],
             i: 0,
             delay_register: 0,
             sound_register: 0,
-            sound_last: Instant::now(),
-            delay_last: Instant::now(),
             instruction_parser: ins_parser,
+            rng: Box::new(XorShift::from_entropy()),
+            platform: *platform,
+            rom_hash: savestate::fnv1a(&[]),
             skip_increment: false,
-            instruction_delay: Duration::from_millis(1_000 / CLOCK_SPEED),
-            timer_delay: Duration::from_millis(1_000 / TIMER_FREQ),
-        };
-        machine.init_display();
-        machine.init_audio();
-        machine
-    }
-
-    pub fn init_display(&mut self) {
-        self.display = {
-            if self.headless {
-                None
-            } else {
-                let sdl = self.sdl_context.as_ref().unwrap();
-                Some(VideoDisplay::new(sdl))
-            }
-        }
-    }
-
-    pub fn init_audio(&mut self) {
-        self.audio = {
-            if self.headless {
-                None
-            } else {
-                let sdl = self.sdl_context.as_ref().unwrap();
-                Some(AudioDriver::new(sdl))
-            }
+            vblank: true,
+            tracer: None,
         }
     }
 
@@ -184,66 +329,59 @@ where
     Initializes zeroed memory,
     loads fonts in their designated area.
     */
-    pub fn init_memory() -> Memory {
-        let mut memory = [0; MEMORY_SIZE];
-        let fonts = Machine::<T>::get_fonts();
-        memory[..80].clone_from_slice(&fonts[..80]);
-        Memory { mem: memory }
-    }
-
-    /*
-    These are the fonts on chip8.
-    Every font is an 8x5 pixel. They will be loaded into the
-    main memory
-    */
-    pub fn get_fonts() -> [u8; 80] {
-        [
-            0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
-            0x20, 0x60, 0x20, 0x20, 0x70, // 1
-            0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
-            0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
-            0x90, 0x90, 0xF0, 0x10, 0x10, // 4
-            0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
-            0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
-            0xF0, 0x10, 0x20, 0x40, 0x40, // 7
-            0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
-            0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
-            0xF0, 0x90, 0xF0, 0x90, 0x90, // A
-            0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
-            0xF0, 0x80, 0x80, 0x80, 0xF0, // C
-            0xE0, 0x90, 0x90, 0x90, 0xE0, // D
-            0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
-            0xF0, 0x80, 0xF0, 0x80, 0x80, // F
-        ]
+    pub fn init_memory(size: usize, font: Font) -> Memory {
+        let mut memory = vec![0; size];
+        let glyphs = font.glyphs();
+        memory[FONT_ADDRESS..FONT_ADDRESS + glyphs.len()].clone_from_slice(glyphs);
+        let big_glyphs = font.big_glyphs();
+        memory[BIG_FONT_ADDRESS..BIG_FONT_ADDRESS + big_glyphs.len()].clone_from_slice(big_glyphs);
+        Memory::new(memory)
     }
 
-    pub fn load_rom(&mut self, filename: &str) -> Result<(), std::io::Error> {
+    pub fn load_rom(&mut self, filename: &str) -> Result<(), Chip8Error> {
         let mut file = File::open(filename)?;
         self._copy_into_mem(&mut file)?;
         trace!("{:?}", self.mem);
         Ok(())
     }
 
-    fn _copy_into_mem(&mut self, file: &mut File) -> Result<(), std::io::Error> {
-        const BUFSIZE: usize = MEMORY_SIZE - PROGRAM_OFFSET;
-        let mut buffer: [u8; BUFSIZE] = [0; BUFSIZE];
+    /*
+    Loads a program that is already in memory, e.g. one produced by a tool
+    rather than read from a file.
+    */
+    pub fn load_program(&mut self, program: &[u8]) -> Result<(), Chip8Error> {
+        let max = self.mem.mem.len() - PROGRAM_OFFSET;
+        if program.len() > max {
+            return Err(Chip8Error::RomTooLarge {
+                size: program.len(),
+                max,
+            });
+        }
+        let mut image = vec![0; max];
+        image[..program.len()].copy_from_slice(program);
+        self.mem.write(PROGRAM_OFFSET, &image);
+        self.rom_hash = savestate::fnv1a(program);
+        trace!("{:?}", self.mem);
+        Ok(())
+    }
 
+    fn _copy_into_mem(&mut self, file: &mut File) -> Result<(), Chip8Error> {
         // load the ROM into the buffer
-        let _ = file.read(&mut buffer)?;
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer)?;
 
         // Copy the buffer into the VM memory
-        self.mem.mem[PROGRAM_OFFSET..].clone_from_slice(&buffer);
-        Ok(())
+        self.load_program(&buffer)
     }
 
     /**
-    * Create a 16-bit opcode out of 2 bytes
-    * Ref: <https://stackoverflow.com/a/50244328>
-    * Shift the bits by 8 to the left:
-        (XXXXXXXX becomes XXXXXXXX00000000)
-    * THEN bitwise-OR to concatenate them:
-    *   (XXXXXXXX00000000 | YYYYYYYY) = XXXXXXXXYYYYYYYY
-    **/
+     * Create a 16-bit opcode out of 2 bytes
+     * Ref: <https://stackoverflow.com/a/50244328>
+     * Shift the bits by 8 to the left:
+     *   (XXXXXXXX becomes XXXXXXXX00000000)
+     * THEN bitwise-OR to concatenate them:
+     *   (XXXXXXXX00000000 | YYYYYYYY) = XXXXXXXXYYYYYYYY
+     **/
     fn get_opcode(b: &[u8]) -> u16 {
         let mut fb = u16::from(b[0]);
         let sb = u16::from(b[1]);
@@ -251,124 +389,290 @@ where
         fb | sb
     }
 
-    fn inc_pc(&mut self) {
-        self.counter += 2;
+    fn inc_pc(&mut self, bytes: u16) {
+        self.counter = self.counter.wrapping_add(bytes);
+    }
+
+    // The opcode at `addr`, if both of its bytes are in memory
+    fn word_at(&self, addr: usize) -> Option<u16> {
+        self.mem.mem.get(addr..addr + 2).map(Self::get_opcode)
     }
 
-    #[allow(clippy::cast_possible_truncation)]
-    fn add(&mut self, d1: u8, d2: u8) -> u8 {
-        let res: u16 = u16::from(d1) + u16::from(d2);
-        self.v[FLAG_REGISTER] = if res > u16::from(u8::max_value()) {
-            1
+    /*
+    Skips over the instruction after the current one. On XO-CHIP that can be
+    the 4 byte F000 NNNN, which has to be skipped as a whole.
+    */
+    fn skip_next(&mut self) {
+        let next = usize::from(self.counter) + 2;
+        if self.platform.instruction_set >= InstructionSet::XoChip
+            && self.word_at(next) == Some(0xF000)
+        {
+            self.inc_pc(4);
         } else {
-            0
-        };
-        res as u8
+            self.inc_pc(2);
+        }
     }
 
-    #[allow(clippy::cast_possible_truncation)]
-    fn add_16(&mut self, d1: u16, d2: u16) -> u16 {
-        let res: u32 = u32::from(d1) + u32::from(d2);
-        self.v[FLAG_REGISTER] = if res > u32::from(u16::max_value()) {
-            1
+    /*
+    Stores the result of an arithmetic instruction in VX and its flag in VF.
+    VF is written last so that the flag wins when VF itself is the target.
+    */
+    fn set_with_flag(&mut self, reg: u8, value: u8, flag: bool) {
+        self.v[usize::from(reg)] = value;
+        self.v[FLAG_REGISTER] = u8::from(flag);
+    }
+
+    // The first address of a `len` bytes long access starting at `addr` that is not in memory
+    fn check_mem(&self, addr: usize, len: usize) -> Result<(), Chip8Error> {
+        let size = self.mem.mem.len();
+        if addr + len > size {
+            return Err(Chip8Error::MemoryOutOfBounds {
+                addr: addr.max(size),
+            });
+        }
+        Ok(())
+    }
+
+    /*
+    Instructions that only exist in an extension of CHIP-8 are invalid opcodes
+    on platforms without it. The ones that overlap with 0NNN are ignored like
+    any other SYS call instead.
+    */
+    fn require(&self, set: InstructionSet, ins: &Instruction) -> Result<(), Chip8Error> {
+        if self.platform.instruction_set < set {
+            return Err(Chip8Error::UnsupportedInstruction {
+                instruction: ins.clone(),
+                pc: self.counter,
+            });
+        }
+        Ok(())
+    }
+
+    // The registers from VX to VY, which XO-CHIP allows to be in descending order
+    fn register_range(x: u8, y: u8) -> Vec<usize> {
+        let (x, y) = (usize::from(x), usize::from(y));
+        if x <= y {
+            (x..=y).collect()
         } else {
-            0
-        };
-        res as u16
+            (y..=x).rev().collect()
+        }
+    }
+
+    fn graphics_size(&self) -> (usize, usize) {
+        (self.graphics.width(), self.graphics.height())
+    }
+
+    // Switching between the SUPER-CHIP display modes clears the screen
+    fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        if hires {
+            self.graphics
+                .resize(self.platform.display_width, self.platform.display_height);
+        } else {
+            self.graphics.resize(DISPLAY_WIDTH, DISPLAY_HEIGHT);
+        }
+        self.redraw = true;
     }
 
-    fn execute(&mut self, ins: &Instruction) {
+    fn execute(&mut self, ins: &Instruction) -> Result<(), Chip8Error> {
         match *ins {
             Instruction::ClearScreen => {
-                if let Some(ref mut d) = self.display {
-                    d.clear(&mut self.graphics);
-                }
+                self.graphics.clear_planes(self.planes);
+                self.redraw = true;
             }
             Instruction::Return => {
+                if self.stack_ptr == 0 {
+                    return Err(Chip8Error::StackUnderflow);
+                }
                 self.stack_ptr -= 1;
                 self.counter = self.stack[usize::from(self.stack_ptr)];
                 self.skip_increment = true;
             }
-            Instruction::SYS => {}
+            Instruction::SYS(_) => {}
+            Instruction::ScrollDown(rows) => {
+                if self.platform.instruction_set >= InstructionSet::SuperChip {
+                    self.graphics.scroll_down(usize::from(rows), self.planes);
+                    self.redraw = true;
+                }
+            }
+            Instruction::ScrollRight => {
+                if self.platform.instruction_set >= InstructionSet::SuperChip {
+                    self.graphics.scroll_right(4, self.planes);
+                    self.redraw = true;
+                }
+            }
+            Instruction::ScrollLeft => {
+                if self.platform.instruction_set >= InstructionSet::SuperChip {
+                    self.graphics.scroll_left(4, self.planes);
+                    self.redraw = true;
+                }
+            }
+            Instruction::ScrollUp(rows) => {
+                if self.platform.instruction_set >= InstructionSet::XoChip {
+                    self.graphics.scroll_up(usize::from(rows), self.planes);
+                    self.redraw = true;
+                }
+            }
+            Instruction::Exit => {
+                if self.platform.instruction_set >= InstructionSet::SuperChip {
+                    self.halted = true;
+                    self.skip_increment = true;
+                }
+            }
+            Instruction::LowRes => {
+                if self.platform.instruction_set >= InstructionSet::SuperChip {
+                    self.set_hires(false);
+                }
+            }
+            Instruction::HighRes => {
+                if self.platform.instruction_set >= InstructionSet::SuperChip {
+                    self.set_hires(true);
+                }
+            }
             Instruction::Jump(address) => {
                 self.counter = address;
                 self.skip_increment = true;
             }
             Instruction::Call(address) => {
-                self.stack[usize::from(self.stack_ptr)] = self.counter + 2;
+                if usize::from(self.stack_ptr) >= STACK_SIZE {
+                    return Err(Chip8Error::StackOverflow);
+                }
+                self.stack[usize::from(self.stack_ptr)] = self.counter.wrapping_add(2);
                 self.stack_ptr += 1;
                 self.counter = address;
                 self.skip_increment = true;
             }
             Instruction::SkipEqualsByte(reg, byte) => {
                 if self.v[usize::from(reg)] == byte {
-                    self.inc_pc();
+                    self.skip_next();
                 }
             }
             Instruction::SkipNotEqualsByte(reg, byte) => {
                 if self.v[usize::from(reg)] != byte {
-                    self.inc_pc();
+                    self.skip_next();
                 }
             }
             Instruction::SkipEqualsRegister(reg1, reg2) => {
                 if self.v[usize::from(reg1)] == self.v[usize::from(reg2)] {
-                    self.inc_pc();
+                    self.skip_next();
+                }
+            }
+            Instruction::SaveRange(reg1, reg2) => {
+                self.require(InstructionSet::XoChip, ins)?;
+                let registers = Self::register_range(reg1, reg2);
+                self.check_mem(usize::from(self.i), registers.len())?;
+                let bytes: Vec<u8> = registers.into_iter().map(|reg| self.v[reg]).collect();
+                self.mem.write(usize::from(self.i), &bytes);
+            }
+            Instruction::LoadRange(reg1, reg2) => {
+                self.require(InstructionSet::XoChip, ins)?;
+                let registers = Self::register_range(reg1, reg2);
+                self.check_mem(usize::from(self.i), registers.len())?;
+                for (n, reg) in registers.into_iter().enumerate() {
+                    self.v[reg] = self.mem.mem[usize::from(self.i) + n];
                 }
             }
             Instruction::LoadByte(reg, byte) => {
                 self.v[usize::from(reg)] = byte;
             }
             Instruction::AddByte(reg, byte) => {
-                self.v[usize::from(reg)] = self.add(self.v[usize::from(reg)], byte);
+                // Unlike 8XY4 this does not touch the carry flag
+                self.v[usize::from(reg)] = self.v[usize::from(reg)].wrapping_add(byte);
             }
             Instruction::LoadRegister(reg1, reg2) => {
                 self.v[usize::from(reg1)] = self.v[usize::from(reg2)];
             }
             Instruction::Or(reg1, reg2) => {
                 self.v[usize::from(reg1)] |= self.v[usize::from(reg2)];
+                if self.platform.quirks.logic_resets_vf {
+                    self.v[FLAG_REGISTER] = 0;
+                }
             }
             Instruction::And(reg1, reg2) => {
                 self.v[usize::from(reg1)] &= self.v[usize::from(reg2)];
+                if self.platform.quirks.logic_resets_vf {
+                    self.v[FLAG_REGISTER] = 0;
+                }
             }
             Instruction::Xor(reg1, reg2) => {
                 self.v[usize::from(reg1)] ^= self.v[usize::from(reg2)];
+                if self.platform.quirks.logic_resets_vf {
+                    self.v[FLAG_REGISTER] = 0;
+                }
             }
             Instruction::AddRegister(reg1, reg2) => {
-                self.v[usize::from(reg1)] =
-                    self.add(self.v[usize::from(reg1)], self.v[usize::from(reg2)]);
+                let (res, carry) =
+                    self.v[usize::from(reg1)].overflowing_add(self.v[usize::from(reg2)]);
+                self.set_with_flag(reg1, res, carry);
             }
             Instruction::SubNRegister(reg1, reg2) => {
-                self.v[0xf] = if reg2 > reg1 { 1 } else { 0 };
-                self.v[usize::from(reg1)] =
-                    self.v[usize::from(reg2)].wrapping_sub(self.v[usize::from(reg1)]);
+                // VF is NOT borrow: 1 when VY >= VX
+                let (res, borrow) =
+                    self.v[usize::from(reg2)].overflowing_sub(self.v[usize::from(reg1)]);
+                self.set_with_flag(reg1, res, !borrow);
             }
             Instruction::SubRegister(reg1, reg2) => {
-                self.v[0xf] = if reg2 > reg1 { 0 } else { 1 };
-                self.v[usize::from(reg1)] =
-                    self.v[usize::from(reg1)].wrapping_sub(self.v[usize::from(reg2)]);
+                // VF is NOT borrow: 1 when VX >= VY
+                let (res, borrow) =
+                    self.v[usize::from(reg1)].overflowing_sub(self.v[usize::from(reg2)]);
+                self.set_with_flag(reg1, res, !borrow);
             }
-            Instruction::ShiftRight(reg) => {
-                self.v[0xf] = self.v[usize::from(reg)] & 0x1;
-                self.v[usize::from(reg)] /= 2;
+            Instruction::ShiftRight(reg1, reg2) => {
+                let value = if self.platform.quirks.shift_uses_vy {
+                    self.v[usize::from(reg2)]
+                } else {
+                    self.v[usize::from(reg1)]
+                };
+                self.set_with_flag(reg1, value >> 1, value & 0x1 == 1);
             }
-            Instruction::ShiftLeft(reg) => {
-                self.v[0xf] = (self.v[usize::from(reg)] & 0x80) >> 7;
-                self.v[usize::from(reg)] *= 2;
+            Instruction::ShiftLeft(reg1, reg2) => {
+                let value = if self.platform.quirks.shift_uses_vy {
+                    self.v[usize::from(reg2)]
+                } else {
+                    self.v[usize::from(reg1)]
+                };
+                self.set_with_flag(reg1, value << 1, value & 0x80 == 0x80);
             }
             Instruction::SkipNotEqualRegister(reg1, reg2) => {
                 if self.v[usize::from(reg1)] != self.v[usize::from(reg2)] {
-                    self.inc_pc();
+                    self.skip_next();
                 }
             }
             Instruction::LoadImmediate(address) => {
                 self.i = address;
             }
-            Instruction::JumpBase(reg) => {
-                self.counter = self.add_16(reg, u16::from(self.v[0x0]));
+            Instruction::LoadLongImmediate(address) => {
+                self.require(InstructionSet::XoChip, ins)?;
+                self.i = address;
+            }
+            Instruction::JumpBase(address) => {
+                let reg = if self.platform.quirks.jump_uses_vx {
+                    mask_0F00(address)
+                } else {
+                    0x0
+                };
+                self.counter = address + u16::from(self.v[usize::from(reg)]);
+                self.skip_increment = true;
             }
             Instruction::Random(register, data) => {
-                let random_byte = rand::thread_rng().gen_range(0, 255);
-                self.v[usize::from(register)] = random_byte & data;
+                self.v[usize::from(register)] = self.rng.next_byte() & data;
+            }
+            Instruction::SelectPlanes(planes) => {
+                self.require(InstructionSet::XoChip, ins)?;
+                self.planes = planes & ALL_PLANES;
+            }
+            Instruction::LoadAudioPattern => {
+                self.require(InstructionSet::XoChip, ins)?;
+                let i = usize::from(self.i);
+                self.check_mem(i, AUDIO_PATTERN_SIZE)?;
+                self.audio_pattern
+                    .copy_from_slice(&self.mem.mem[i..i + AUDIO_PATTERN_SIZE]);
+                self.audio_changed = true;
+            }
+            Instruction::LoadPitch(register) => {
+                self.require(InstructionSet::XoChip, ins)?;
+                self.pitch = self.v[usize::from(register)];
+                self.audio_changed = true;
             }
             Instruction::LoadFromDelay(register) => {
                 self.v[usize::from(register)] = self.delay_register;
@@ -380,28 +684,55 @@ where
                 self.sound_register = self.v[usize::from(register)];
             }
             Instruction::AddI(register) => {
-                self.i = self.add_16(self.i, u16::from(self.v[usize::from(register)]));
+                self.i = self
+                    .i
+                    .wrapping_add(u16::from(self.v[usize::from(register)]));
             }
             Instruction::LoadFontSprite(register) => {
-                self.i = u16::from(self.v[usize::from(register)]) * 5;
+                let digit = usize::from(self.v[usize::from(register)] & 0xF);
+                self.i = (FONT_ADDRESS + digit * FONT_GLYPH_SIZE) as u16;
+            }
+            Instruction::LoadBigFontSprite(register) => {
+                self.require(InstructionSet::SuperChip, ins)?;
+                let digit = usize::from(self.v[usize::from(register)] & 0xF);
+                self.i = (BIG_FONT_ADDRESS + digit * BIG_FONT_GLYPH_SIZE) as u16;
+            }
+            Instruction::StoreFlags(register) => {
+                self.require(InstructionSet::SuperChip, ins)?;
+                let register: usize = usize::from(register);
+                self.flags[..=register].copy_from_slice(&self.v[..=register]);
+            }
+            Instruction::LoadFlags(register) => {
+                self.require(InstructionSet::SuperChip, ins)?;
+                let register: usize = usize::from(register);
+                self.v[..=register].copy_from_slice(&self.flags[..=register]);
             }
             Instruction::LoadIBCD(register) => {
                 // Store BCD representation of Vx in memory locations I, I+1 and I+2.
-                self.mem.mem[usize::from(self.i)] = register / 100;
-                self.mem.mem[usize::from(self.i) + 1] = (register / 10) % 10;
-                self.mem.mem[usize::from(self.i) + 2] = register % 10;
+                self.check_mem(usize::from(self.i), 3)?;
+                let value = self.v[usize::from(register)];
+                self.mem.write(
+                    usize::from(self.i),
+                    &[value / 100, (value / 10) % 10, value % 10],
+                );
             }
             Instruction::StoreRegisters(register) => {
                 let register: usize = usize::from(register);
-                for n in 0..=register {
-                    self.mem.mem[usize::from(self.i) + n] = self.v[n];
+                self.check_mem(usize::from(self.i), register + 1)?;
+                self.mem.write(usize::from(self.i), &self.v[..=register]);
+                if self.platform.quirks.load_store_increments_i {
+                    self.i = self.i.wrapping_add(register as u16 + 1);
                 }
             }
             Instruction::LoadRegisters(register) => {
                 let register: usize = usize::from(register);
+                self.check_mem(usize::from(self.i), register + 1)?;
                 for n in 0..=register {
                     self.v[n] = self.mem.mem[usize::from(self.i) + n]
                 }
+                if self.platform.quirks.load_store_increments_i {
+                    self.i = self.i.wrapping_add(register as u16 + 1);
+                }
             }
             /*
             Draws a sprite at coordinate (VX, VY) that has a width of 8 pixels
@@ -413,17 +744,42 @@ where
             */
             Instruction::DisplaySprite(reg_x, reg_y, h) => {
                 if h > 15 {
-                    panic!("Sprite Height exceeded maximum limit!");
+                    return Err(Chip8Error::InvalidSpriteHeight(h));
                 }
-                let vx = self.v[usize::from(reg_x)] as usize;
-                let vy = self.v[usize::from(reg_y)] as usize;
-                let height = h as usize;
+                if self.platform.quirks.display_wait {
+                    if !self.vblank {
+                        // Try again once the next frame has started
+                        self.skip_increment = true;
+                        return Ok(());
+                    }
+                    self.vblank = false;
+                }
+                let (width, height) = self.graphics_size();
+                // The starting position always wraps around
+                let vx = self.v[usize::from(reg_x)] as usize % width;
+                let vy = self.v[usize::from(reg_y)] as usize % height;
+                // SUPER-CHIP draws a 16x16 sprite, 2 bytes per row, for DXY0
+                let (sprite_width, sprite_height) =
+                    if h == 0 && self.platform.instruction_set >= InstructionSet::SuperChip {
+                        (2 * SPRITE_WIDTH, 16)
+                    } else {
+                        (SPRITE_WIDTH, h as usize)
+                    };
+                let row_bytes = sprite_width / SPRITE_WIDTH;
+                let sprite_bytes = sprite_height * row_bytes;
                 let mut flipped = false;
+                // XO-CHIP reads one sprite per selected plane, one after the other
+                let planes = self.planes;
+                self.check_mem(
+                    usize::from(self.i),
+                    sprite_bytes * planes.count_ones() as usize,
+                )?;
 
                 /*
-                We need to paint a maximum 8x15 sprite, following some rules
+                We need to paint a maximum 8x15 (or 16x16) sprite, following some rules
 
-                1. We use modulo width|height to wrap-around the sprites on the display grid
+                1. We use modulo width|height to wrap-around the sprites on the display grid,
+                    unless the quirks ask for the parts that fall off the screen to be clipped
 
                 2. "Each row of 8 pixels is read as bit-coded starting from memory location I"
                     - for this, we start at memory location I, and at each iteration,
@@ -437,55 +793,68 @@ where
 
                 4. Sprites are XORed onto the existing screen
                 */
-                for row in 0..height {
-                    let y = (vy + row) % DISPLAY_HEIGHT;
-                    let px = self.mem.mem[usize::from(self.i) + row];
-                    for col in 0..SPRITE_WIDTH {
-                        let x = (vx + col) % DISPLAY_WIDTH;
-                        let bit = px >> (7 - col as u8) & 1;
-                        if bit == 1 && self.graphics.mem[y][x] == 1 {
-                            flipped |= true;
+                let clip = self.platform.quirks.clip_sprites;
+                let mut sprite = usize::from(self.i);
+                for plane in (0..PLANE_COUNT).map(|n| 1 << n) {
+                    if planes & plane == 0 {
+                        continue;
+                    }
+                    for row in 0..sprite_height {
+                        if clip && vy + row >= height {
+                            break;
+                        }
+                        let y = (vy + row) % height;
+                        for col in 0..sprite_width {
+                            if clip && vx + col >= width {
+                                break;
+                            }
+                            let x = (vx + col) % width;
+                            let px = self.mem.mem[sprite + row * row_bytes + col / SPRITE_WIDTH];
+                            let bit = px >> (7 - (col % SPRITE_WIDTH) as u8) & 1;
+                            if bit == 0 {
+                                continue;
+                            }
+                            let pixel = self.graphics.get(x, y);
+                            if pixel & plane != 0 {
+                                flipped |= true;
+                            }
+                            self.graphics.set(x, y, pixel ^ plane);
                         }
-                        self.graphics.mem[y][x] ^= bit;
                     }
+                    sprite += sprite_bytes;
                 }
-                if flipped {
-                    self.v[0xF] = 1;
-                }
-                trace!("{:?}", self.graphics);
-                if let Some(ref mut d) = self.display {
-                    d.draw(&self.graphics);
-                }
+                self.v[FLAG_REGISTER] = u8::from(flipped);
+                self.redraw = true;
             }
             Instruction::SkipKeyPress(reg) => {
-                let key = self.keyboard[self.v[usize::from(reg)] as usize];
+                let key = self.keyboard[usize::from(self.v[usize::from(reg)] & 0xF)];
                 if key {
-                    self.inc_pc();
+                    self.skip_next();
                 }
             }
             Instruction::SkipNotKeyPress(reg) => {
-                let key = self.keyboard[self.v[usize::from(reg)] as usize];
+                let key = self.keyboard[usize::from(self.v[usize::from(reg)] & 0xF)];
                 if !key {
-                    self.inc_pc();
+                    self.skip_next();
                 }
             }
             Instruction::LoadKeyPress(reg) => {
-                for key in self.keyboard.iter() {
-                    if *key {
-                        self.v[usize::from(reg)] = *key as u8; // store in the register
-                    }
+                // Execution halts until a key is pressed, so keep executing this instruction
+                match self.keyboard.iter().position(|key| *key) {
+                    Some(key) => self.v[usize::from(reg)] = key as u8, // store in the register
+                    None => self.skip_increment = true,
                 }
             }
         };
-        trace!("{:?}", self);
+        Ok(())
     }
 
     // Resets the machine back to the original state
     #[cfg(test)]
-    pub fn reset(&mut self) -> Result<(), String> {
+    pub fn reset(&mut self) -> Result<(), Chip8Error> {
         self.counter = 512;
         self.stack_ptr = 0;
-        self.mem.mem = [0; MEMORY_SIZE];
+        self.mem = Memory::new(vec![0; self.mem.mem.len()]);
         self.stack = [0; STACK_SIZE];
         self.v = [0; REGISTER_COUNT];
         self.i = 0;
@@ -494,135 +863,400 @@ where
         Ok(())
     }
 
-    fn instruction_fetch(&mut self) -> Result<u16, String> {
-        // we check for 4095 because we need to read 2 bytes.
-        if self.counter > 4095 {
-            return Err(String::from("PC out of bounds"));
-        }
-
-        if !self.skip_increment {
-            self.inc_pc();
-        }
-        self.skip_increment = false;
-
+    /*
+    Reads the opcode at the PC, along with the word after it for the
+    two word long F000 NNNN. For every other opcode the second word is 0.
+    */
+    fn instruction_fetch(&self) -> Result<(u16, u16), Chip8Error> {
         let pc: usize = usize::from(self.counter);
-        Ok(Self::get_opcode(&self.mem.mem[pc..=pc + 1]))
+        let opcode = self
+            .word_at(pc)
+            .ok_or(Chip8Error::PcOutOfBounds(self.counter))?;
+        if opcode != 0xF000 {
+            return Ok((opcode, 0));
+        }
+        let next = self
+            .word_at(pc + 2)
+            .ok_or(Chip8Error::PcOutOfBounds(self.counter))?;
+        Ok((opcode, next))
     }
 
-    fn instruction_decode(&mut self, opcode: u16) -> Option<Instruction> {
-        match self.instruction_parser.try_from(opcode) {
-            Ok(x) => Some(x),
-            Err(_) => None,
-        }
+    fn instruction_decode(&self, opcode: u16, next: u16) -> Result<Instruction, Chip8Error> {
+        self.instruction_parser
+            .try_from_words(opcode, next)
+            .map_err(|e| match e {
+                Chip8Error::InvalidOpcode { opcode, .. } => Chip8Error::InvalidOpcode {
+                    opcode,
+                    pc: Some(self.counter),
+                },
+                e => e,
+            })
     }
 
-    fn handle_timers(&mut self) {
-        let current_time = Instant::now();
-        let mut beep = false;
+    /*
+    Decrements the delay and sound timers. The timers count down at 60 Hz,
+    independently of the instruction clock, so this is called once per frame
+    by whoever drives the machine.
+    */
+    pub fn tick_timers(&mut self) {
+        if self.delay_register > 0 {
+            self.delay_register -= 1;
+        }
         if self.sound_register > 0 {
-            beep = true;
-            debug!("should beep...");
-            if self.sound_last.elapsed() >= self.timer_delay {
-                debug!("sound time elapsed > timer frequency, decrementing sound timer");
-                self.sound_register -= 1;
-                self.sound_last = current_time;
-            }
+            self.sound_register -= 1;
         }
+        self.vblank = true;
+    }
 
-        if !self.headless {
-            let audio_ref = self.audio.as_ref().unwrap();
-            if beep {
-                audio_ref.play()
-            } else {
-                audio_ref.stop()
-            }
-        } else {
-            debug!("BEEP!");
-        }
+    // The buzzer sounds as long as the sound timer is non-zero
+    pub fn sound_active(&self) -> bool {
+        self.sound_register > 0
+    }
 
-        if self.delay_register > 0 && self.delay_last.elapsed() >= self.timer_delay {
-            self.delay_register -= 1;
-            self.delay_last = current_time;
-        }
-        ::std::thread::sleep(self.instruction_delay);
-    }
-
-    // Start the virtual machine: This is the fun part!
-    pub fn start(&mut self) -> Result<(), String> {
-        loop {
-            match self.tick() {
-                Err(e) => return Err(e),
-                Ok(_) => {
-                    if !self.headless {
-                        if let Err(e) = self.poll_events() {
-                            return Err(e);
-                        } else {
-                            self.display.as_mut().unwrap().canvas.present();
-                        }
-                    }
-                }
-            }
+    // Whether the screen changed since the last call
+    pub fn take_redraw(&mut self) -> bool {
+        let redraw = self.redraw;
+        self.redraw = false;
+        redraw
+    }
+
+    // Whether the XO-CHIP audio pattern or pitch changed since the last call
+    pub fn take_audio_update(&mut self) -> bool {
+        let changed = self.audio_changed;
+        self.audio_changed = false;
+        changed
+    }
+
+    // The instruction the next tick is going to execute
+    pub fn next_instruction(&self) -> Result<Instruction, Chip8Error> {
+        let (opcode, next) = self.instruction_fetch()?;
+        match &self.mem.decoded[usize::from(self.counter)] {
+            Some(instruction) => Ok(instruction.clone()),
+            None => self.instruction_decode(opcode, next),
         }
     }
 
     // Single tick of the CPU
-    pub fn tick(&mut self) -> Result<(), String> {
-        let opcode = self.instruction_fetch()?;
-        if opcode != 0 {
-            trace!("PC: {}, opcode = {:X}", self.counter, opcode);
-        }
-        let instruction = self.instruction_decode(opcode);
-        match instruction {
-            Some(i) => {
-                debug!("Opcode = {}, Instruction: {:X?}", opcode, i);
-                debug!("PC = {:X?}", self.counter);
-                debug!("Stack = {:X?}", self.stack);
-                self.execute(&i);
-                self.handle_timers();
-                self.reset_keyboard();
-            }
+    pub fn tick(&mut self) -> Result<(), Chip8Error> {
+        if self.halted {
+            return Ok(());
+        }
+        let (opcode, next) = self.instruction_fetch()?;
+        let pc = usize::from(self.counter);
+        let instruction = match &self.mem.decoded[pc] {
+            Some(instruction) => instruction.clone(),
             None => {
-                error!("Possible bad opcode : {}", opcode);
-                self.inc_pc()
+                let instruction = self.instruction_decode(opcode, next)?;
+                self.mem.decoded[pc] = Some(instruction.clone());
+                instruction
             }
+        };
+        if let Some(mut tracer) = self.tracer.take() {
+            let traced = tracer.trace(self.counter, &instruction, |cycle| TraceRecord {
+                cycle,
+                pc: self.counter,
+                opcode,
+                next,
+                instruction: instruction.clone(),
+                v: self.v,
+                i: self.i,
+                sp: self.stack_ptr,
+                delay: self.delay_register,
+                sound: self.sound_register,
+            });
+            self.tracer = Some(tracer);
+            traced?;
+        }
+        self.execute(&instruction)?;
+        // Jumps, calls and returns have already moved the PC
+        if !self.skip_increment {
+            self.inc_pc(instruction.size());
         }
+        self.skip_increment = false;
         Ok(())
     }
 
-    pub fn reset_keyboard(&mut self) {
-        for key in self.keyboard.iter_mut() {
-            *key = false;
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Starts writing a record of every instruction executed from now on
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    // Stops tracing, handing the tracer back to be finished
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    // Identifies the loaded program, so save states can't be mixed up between ROMs
+    pub fn rom_hash(&self) -> u64 {
+        self.rom_hash
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            rom_hash: self.rom_hash,
+            counter: self.counter,
+            stack_ptr: self.stack_ptr,
+            stack: self.stack,
+            v: self.v,
+            i: self.i,
+            delay_timer: self.delay_register,
+            sound_timer: self.sound_register,
+            skip_increment: self.skip_increment,
+            vblank: self.vblank,
+            halted: self.halted,
+            hires: self.hires,
+            planes: self.planes,
+            flags: self.flags,
+            audio_pattern: self.audio_pattern,
+            pitch: self.pitch,
+            keyboard: self.keyboard,
+            rng_name: self.rng.name().to_string(),
+            rng_state: self.rng.state(),
+            memory: self.mem.mem.clone(),
+            graphics: self.graphics.clone(),
+        }
+    }
+
+    /*
+    Puts the machine back into the state of the snapshot. The snapshot has to
+    come from the same ROM on a machine with the same memory and random source,
+    otherwise the machine is left untouched.
+    */
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), Chip8Error> {
+        if snapshot.rom_hash != self.rom_hash {
+            return Err(Chip8Error::SaveStateMismatch {
+                expected: self.rom_hash,
+                found: snapshot.rom_hash,
+            });
+        }
+        if snapshot.memory.len() != self.mem.mem.len() {
+            return Err(Chip8Error::InvalidSaveState(format!(
+                "{} bytes of memory instead of {}",
+                snapshot.memory.len(),
+                self.mem.mem.len()
+            )));
+        }
+        if snapshot.rng_name != self.rng.name() {
+            return Err(Chip8Error::InvalidSaveState(format!(
+                "made with the {} random number generator instead of {}",
+                snapshot.rng_name,
+                self.rng.name()
+            )));
+        }
+        snapshot.validate()?;
+        let size = if snapshot.hires {
+            (self.platform.display_width, self.platform.display_height)
+        } else {
+            (DISPLAY_WIDTH, DISPLAY_HEIGHT)
+        };
+        let found = (snapshot.graphics.width(), snapshot.graphics.height());
+        if found != size {
+            return Err(Chip8Error::InvalidSaveState(format!(
+                "a {}x{} display instead of {}x{}",
+                found.0, found.1, size.0, size.1
+            )));
         }
+        self.counter = snapshot.counter;
+        self.stack_ptr = snapshot.stack_ptr;
+        self.stack = snapshot.stack;
+        self.v = snapshot.v;
+        self.i = snapshot.i;
+        self.delay_register = snapshot.delay_timer;
+        self.sound_register = snapshot.sound_timer;
+        self.skip_increment = snapshot.skip_increment;
+        self.vblank = snapshot.vblank;
+        self.halted = snapshot.halted;
+        self.hires = snapshot.hires;
+        self.planes = snapshot.planes;
+        self.flags = snapshot.flags;
+        self.audio_pattern = snapshot.audio_pattern;
+        self.pitch = snapshot.pitch;
+        self.keyboard = snapshot.keyboard;
+        self.rng.set_state(snapshot.rng_state);
+        self.mem.write(0, &snapshot.memory);
+        self.graphics = snapshot.graphics.clone();
+        self.redraw = true;
+        self.audio_changed = true;
+        Ok(())
+    }
+
+    // A hash of the complete state, to check that two runs are still in sync
+    pub fn state_hash(&self) -> u64 {
+        savestate::fnv1a(&self.save_state())
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        self.snapshot().to_bytes()
+    }
+
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), Chip8Error> {
+        self.restore(&Snapshot::from_bytes(bytes)?)
+    }
+
+    // Replaces the random source, e.g. with a seeded one to make a run reproducible
+    pub fn set_rng(&mut self, rng: Box<dyn RandomSource>) {
+        self.rng = rng;
+    }
+
+    pub fn rng(&self) -> &dyn RandomSource {
+        self.rng.as_ref()
+    }
+
+    pub fn rng_mut(&mut self) -> &mut dyn RandomSource {
+        self.rng.as_mut()
+    }
+
+    pub fn platform(&self) -> &Platform {
+        &self.platform
+    }
+
+    pub fn quirks(&self) -> &Quirks {
+        &self.platform.quirks
+    }
+
+    // Address of the next instruction to be executed
+    pub fn pc(&self) -> u16 {
+        self.counter
+    }
+
+    pub fn registers(&self) -> &[u8; REGISTER_COUNT] {
+        &self.v
+    }
+
+    pub fn i(&self) -> u16 {
+        self.i
+    }
+
+    pub fn stack(&self) -> &[u16; STACK_SIZE] {
+        &self.stack
+    }
+
+    pub fn stack_pointer(&self) -> u8 {
+        self.stack_ptr
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_register
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_register
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.mem.mem
+    }
+
+    /*
+    How many writes to memory there have been, and the addresses the last one
+    covered. Writing a byte that is already there counts too.
+    */
+    pub fn memory_writes(&self) -> (u64, Range<usize>) {
+        (self.mem.writes, self.mem.last_write.clone())
+    }
+
+    pub fn graphics(&self) -> &GraphicsMemory {
+        &self.graphics
+    }
+
+    pub fn hires(&self) -> bool {
+        self.hires
+    }
+
+    // SUPER-CHIP programs stop the machine with 00FD
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn flags(&self) -> &[u8; FLAG_COUNT] {
+        &self.flags
+    }
+
+    pub fn planes(&self) -> u8 {
+        self.planes
+    }
+
+    pub fn audio_pattern(&self) -> &[u8; AUDIO_PATTERN_SIZE] {
+        &self.audio_pattern
+    }
+
+    pub fn pitch(&self) -> u8 {
+        self.pitch
     }
 
-    // Poll for GUI events via the SDL context
-    pub fn poll_events(&mut self) -> Result<(), String> {
-        let mut pump = self.sdl_context.as_ref().unwrap().event_pump().unwrap();
-        for event in pump.poll_iter() {
-            if let sdl2::event::Event::Quit { .. } = event {
-                return Err(String::from("Quit"));
+    pub fn keyboard(&self) -> &[bool; KEY_SIZE] {
+        &self.keyboard
+    }
+
+    /*
+    Pokes for the debugger. They change the state behind the program's back,
+    which is the point.
+    */
+    // Returns false for registers past VF
+    pub fn set_register(&mut self, x: u8, value: u8) -> bool {
+        match self.v.get_mut(usize::from(x)) {
+            Some(register) => {
+                *register = value;
+                true
             }
+            None => false,
+        }
+    }
+
+    pub fn set_i(&mut self, value: u16) {
+        self.i = value;
+    }
+
+    pub fn set_pc(&mut self, addr: u16) {
+        self.counter = addr;
+    }
+
+    // Only depths the stack can have are taken, returns false for any other
+    pub fn set_stack_pointer(&mut self, depth: u8) -> bool {
+        if usize::from(depth) > STACK_SIZE {
+            return false;
         }
-        // ref: https://github.com/Rust-SDL2/rust-sdl2/blob/master/examples/keyboard-state.rs
-        let keys: Vec<sdl2::keyboard::Keycode> = pump
-            .keyboard_state()
-            .pressed_scancodes()
-            .filter_map(sdl2::keyboard::Keycode::from_scancode)
-            .collect();
-        self.handle_keys(&keys);
+        self.stack_ptr = depth;
+        true
+    }
+
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.delay_register = value;
+    }
+
+    pub fn set_sound_timer(&mut self, value: u8) {
+        self.sound_register = value;
+    }
+
+    pub fn write_memory(&mut self, addr: u16, bytes: &[u8]) -> Result<(), Chip8Error> {
+        let start = usize::from(addr);
+        self.check_mem(start, bytes.len())?;
+        self.mem.write(start, bytes);
         Ok(())
     }
 
-    pub fn handle_keys(&mut self, keys: &[sdl2::keyboard::Keycode]) {
-        if !keys.is_empty() {
-            let keymap = &mut self.keymap.as_mut().unwrap().keymap;
-            for key in keys.iter() {
-                if keymap.contains_key(key) {
-                    let chip8_key = keymap.get(&key).unwrap();
-                    self.keyboard[*chip8_key] = true; // store the activated key in the keyboard
-                    debug!("Got a chip8 key = {:?}", chip8_key);
-                }
+    // Returns false for keys past F
+    pub fn set_key(&mut self, key: u8, pressed: bool) -> bool {
+        match self.keyboard.get_mut(usize::from(key)) {
+            Some(state) => {
+                *state = pressed;
+                true
             }
+            None => false,
+        }
+    }
+
+    pub fn set_keys(&mut self, keys: &[bool; KEY_SIZE]) {
+        self.keyboard = *keys;
+    }
+
+    pub fn reset_keyboard(&mut self) {
+        for key in self.keyboard.iter_mut() {
+            *key = false;
         }
     }
 }
@@ -631,14 +1265,21 @@ where
 use std::io::{Seek, SeekFrom, Write};
 
 #[cfg(test)]
+#[allow(
+    clippy::bool_assert_comparison,
+    clippy::explicit_counter_loop,
+    clippy::let_unit_value
+)]
 mod tests {
     use super::*;
+    use crate::instructions::encode_program;
     use crate::opcodes::OpcodeMaskParser;
+    use crate::platform::{COSMAC_VIP, SCHIP_1_1, XO_CHIP};
 
     #[test]
     fn test_copy_into_mem_no_data() {
         let mut tmpfile = tempfile::tempfile().unwrap();
-        let mut vm = Machine::new("TestVM", OpcodeMaskParser {}, true, None);
+        let mut vm = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
         vm._copy_into_mem(&mut tmpfile).unwrap();
         assert_eq!(vm.mem.mem.len(), 4096);
         // every byte in memory is zero when file is empty
@@ -650,7 +1291,7 @@ mod tests {
     #[test]
     fn test_copy_into_mem_some_data() {
         let mut tmpfile = tempfile::tempfile().unwrap();
-        let mut vm = Machine::new("TestVM", OpcodeMaskParser {}, true, None);
+        let mut vm = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
         write!(tmpfile, "Hello World!").unwrap(); // Write
         tmpfile.seek(SeekFrom::Start(0)).unwrap(); // Seek to start
         vm._copy_into_mem(&mut tmpfile).unwrap();
@@ -662,6 +1303,25 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_load_program_and_tick() {
+        let mut vm = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
+        // LD V3, 0x20; LD I, 0x2A4
+        vm.load_program(&[0x63, 0x20, 0xA2, 0xA4]).unwrap();
+        assert_eq!(vm.pc(), 0x200);
+
+        vm.tick().unwrap();
+        assert_eq!(vm.pc(), 0x202);
+        assert_eq!(vm.registers()[3], 0x20);
+
+        vm.tick().unwrap();
+        assert_eq!(vm.pc(), 0x204);
+        assert_eq!(vm.i(), 0x2A4);
+
+        let too_big = vec![0; MEMORY_SIZE];
+        assert!(vm.load_program(&too_big).is_err());
+    }
+
     #[test]
     fn test_create_opcode() {
         assert_eq!(
@@ -685,8 +1345,8 @@ mod tests {
         // inspect the entire state of the machine for changes.
         // Each instruction has a primary task and might also potentially have
         // some side-effect. We need to test both
-        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, true, None);
-        machine.execute(&Instruction::ClearScreen);
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
+        machine.execute(&Instruction::ClearScreen).unwrap();
         assert_eq!(machine.counter, 512);
         assert_eq!(machine.stack_ptr, 0);
 
@@ -705,12 +1365,12 @@ mod tests {
 
     #[test]
     fn test_execute_ret() {
-        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, true, None);
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
         // Seems like it would be necessary otherwise a lot of behaviour can't be tested.
         // Modify the counter and the stack pointer before the machine execution starts
         machine.counter = 1;
         machine.stack_ptr = 1;
-        machine.execute(&Instruction::Return);
+        machine.execute(&Instruction::Return).unwrap();
         assert_eq!(machine.counter, 0);
         assert_eq!(machine.stack_ptr, 0);
         assert_eq!(machine.skip_increment, true);
@@ -728,8 +1388,8 @@ mod tests {
 
     #[test]
     fn test_execute_sys() {
-        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, true, None);
-        machine.execute(&Instruction::SYS);
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
+        machine.execute(&Instruction::SYS(0x123)).unwrap();
         assert_eq!(machine.counter, 512);
         assert_eq!(machine.stack_ptr, 0);
         assert_eq!(machine.skip_increment, false);
@@ -747,14 +1407,14 @@ mod tests {
 
     #[test]
     fn test_execute_jump() {
-        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, true, None);
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
 
         assert_eq!(machine.counter, 512); // before machine executes instruction
 
-        machine.execute(&Instruction::Jump(0x0222));
+        machine.execute(&Instruction::Jump(0x0222)).unwrap();
         assert_eq!(machine.counter, 0x0222);
 
-        machine.execute(&Instruction::Jump(4095));
+        machine.execute(&Instruction::Jump(4095)).unwrap();
         assert_eq!(machine.counter, 4095);
 
         assert_eq!(machine.stack_ptr, 0);
@@ -773,13 +1433,13 @@ mod tests {
 
     #[test]
     fn test_execute_call() {
-        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, true, None);
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
 
         assert_eq!(machine.counter, 512); // before machine executes instruction
         assert_eq!(machine.stack_ptr, 0);
 
         machine.counter = 25;
-        machine.execute(&Instruction::Call(0x0222));
+        machine.execute(&Instruction::Call(0x0222)).unwrap();
         assert_eq!(machine.stack_ptr, 1); // increments the stack pointer
         assert_eq!(machine.counter, 0x0222); // pushes the current pc to the stack
         assert_eq!(machine.skip_increment, true); // we're gonna skip the next automatic pc increment
@@ -796,16 +1456,85 @@ mod tests {
         assert_eq!(machine.sound_register, 0);
     }
 
+    #[test]
+    fn test_call_from_the_last_address() {
+        let mut machine = Machine::with_platform("TestVM", OpcodeMaskParser {}, &XO_CHIP);
+        machine.counter = 0xFFFE;
+        machine.execute(&Instruction::Call(0x222)).unwrap();
+        assert_eq!(machine.stack[0], 0x0000);
+        assert_eq!(machine.counter, 0x222);
+    }
+
+    #[test]
+    fn test_execute_stack_errors() {
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
+        assert!(matches!(
+            machine.execute(&Instruction::Return),
+            Err(Chip8Error::StackUnderflow)
+        ));
+
+        for _ in 0..STACK_SIZE {
+            machine.execute(&Instruction::Call(0x0222)).unwrap();
+        }
+        assert!(matches!(
+            machine.execute(&Instruction::Call(0x0222)),
+            Err(Chip8Error::StackOverflow)
+        ));
+        assert_eq!(usize::from(machine.stack_ptr), STACK_SIZE);
+    }
+
+    #[test]
+    fn test_execute_memory_out_of_bounds() {
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
+        machine.i = 0xFFE;
+        assert!(matches!(
+            machine.execute(&Instruction::StoreRegisters(0x3)),
+            Err(Chip8Error::MemoryOutOfBounds { addr: 0x1000 })
+        ));
+        assert!(matches!(
+            machine.execute(&Instruction::LoadIBCD(0x0)),
+            Err(Chip8Error::MemoryOutOfBounds { addr: 0x1000 })
+        ));
+        assert!(machine.execute(&Instruction::LoadRegisters(0x1)).is_ok());
+        assert!(matches!(
+            machine.execute(&Instruction::DisplaySprite(0, 0, 16)),
+            Err(Chip8Error::InvalidSpriteHeight(16))
+        ));
+    }
+
+    #[test]
+    fn test_tick_errors() {
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
+        machine.load_program(&[0xE1, 0x23]).unwrap();
+        assert!(matches!(
+            machine.tick(),
+            Err(Chip8Error::InvalidOpcode {
+                opcode: 0xE123,
+                pc: Some(0x200)
+            })
+        ));
+
+        machine.counter = 0xFFF;
+        assert!(matches!(
+            machine.tick(),
+            Err(Chip8Error::PcOutOfBounds(0xFFF))
+        ));
+    }
+
     #[test]
     fn test_execute_se() {
-        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, true, None);
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
 
         assert_eq!(machine.counter, 512); // before machine executes instruction
-        machine.execute(&Instruction::SkipEqualsByte(machine.v[1], 0x0001)); // nothing should happen
+        machine
+            .execute(&Instruction::SkipEqualsByte(machine.v[1], 0x0001))
+            .unwrap(); // nothing should happen
         assert_eq!(machine.counter, 512);
 
         machine.v[1] = 0x0001;
-        machine.execute(&Instruction::SkipEqualsByte(machine.v[1], 0x0001)); // nothing should happen
+        machine
+            .execute(&Instruction::SkipEqualsByte(machine.v[1], 0x0001))
+            .unwrap(); // nothing should happen
         assert_eq!(machine.counter, 514);
 
         assert_eq!(machine.mem.mem.len(), 4096);
@@ -820,17 +1549,21 @@ mod tests {
 
     #[test]
     fn test_execute_sne() {
-        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, true, None);
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
 
         assert_eq!(machine.counter, 512); // before machine executes instruction
         machine.v[1] = 0x0001;
-        machine.execute(&Instruction::SkipNotEqualsByte(machine.v[1], 0x0001));
+        machine
+            .execute(&Instruction::SkipNotEqualsByte(machine.v[1], 0x0001))
+            .unwrap();
         assert_eq!(machine.counter, 512);
 
         machine.reset().unwrap();
         machine.v[1] = 0x0001;
 
-        machine.execute(&Instruction::SkipNotEqualsByte(machine.v[1], 0x0002));
+        machine
+            .execute(&Instruction::SkipNotEqualsByte(machine.v[1], 0x0002))
+            .unwrap();
         assert_eq!(machine.counter, 514);
 
         assert_eq!(machine.mem.mem.len(), 4096);
@@ -845,22 +1578,26 @@ mod tests {
 
     #[test]
     fn test_execute_se_reg() {
-        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, true, None);
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
 
         assert_eq!(machine.counter, 512); // before machine executes instruction
         machine.v[1] = 0x0001;
         machine.v[12] = 0x0001;
-        machine.execute(&Instruction::SkipEqualsRegister(
-            machine.v[1],
-            machine.v[12],
-        ));
+        machine
+            .execute(&Instruction::SkipEqualsRegister(
+                machine.v[1],
+                machine.v[12],
+            ))
+            .unwrap();
         assert_eq!(machine.counter, 514);
 
         machine.v[1] = 0x0002;
-        machine.execute(&Instruction::SkipEqualsRegister(
-            machine.v[1],
-            machine.v[12],
-        ));
+        machine
+            .execute(&Instruction::SkipEqualsRegister(
+                machine.v[1],
+                machine.v[12],
+            ))
+            .unwrap();
         assert_eq!(machine.counter, 514);
 
         assert_eq!(machine.mem.mem.len(), 4096);
@@ -876,7 +1613,7 @@ mod tests {
     #[test]
     fn test_execute_display_sprite() {
         let _ = env_logger::init();
-        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, true, None);
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
 
         // Set up the coordinate values (X, Y) in the V registers
         machine.v[0x08] = 0x1c; // 29..36 (8-bit wide)
@@ -891,7 +1628,7 @@ mod tests {
         machine.i = 0x258;
         for i in 0..32 {
             for j in 0..64 {
-                assert_eq!(machine.graphics.mem[i][j], 0);
+                assert_eq!(machine.graphics.get(j, i), 0);
             }
         }
 
@@ -899,7 +1636,9 @@ mod tests {
         // the GPU. The shape of the sprite is read from the memory, starting from location at register I.
         // This is why before executing DXYN, we need to set the sprite in memory and point I to the location
         // of the sprite.
-        machine.execute(&Instruction::DisplaySprite(0x8, 0x9, 7));
+        machine
+            .execute(&Instruction::DisplaySprite(0x8, 0x9, 7))
+            .unwrap();
 
         // Question: How do we know what the correct value of a sprite is?
         // We use a simple sprite that just sets a rectangular block to 1
@@ -907,14 +1646,288 @@ mod tests {
         // TODO: We also need to validate both X and Y overflow and the subsequent wraparound
         for i in 22..28 {
             for j in 29..36 {
-                assert_eq!(machine.graphics.mem[i][j], 1);
+                assert_eq!(machine.graphics.get(j, i), 1);
             }
         }
     }
 
+    #[test]
+    fn test_execute_arithmetic_flags() {
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
+        machine.v[0x1] = 0xFF;
+        machine.v[0x2] = 0x02;
+
+        machine.execute(&Instruction::AddByte(0x1, 0x02)).unwrap();
+        assert_eq!(machine.v[0x1], 0x01);
+        assert_eq!(machine.v[0xF], 0); // 7XKK leaves VF alone
+
+        machine.v[0x1] = 0xFF;
+        machine
+            .execute(&Instruction::AddRegister(0x1, 0x2))
+            .unwrap();
+        assert_eq!((machine.v[0x1], machine.v[0xF]), (0x01, 1));
+
+        machine
+            .execute(&Instruction::SubRegister(0x1, 0x2))
+            .unwrap();
+        assert_eq!((machine.v[0x1], machine.v[0xF]), (0xFF, 0));
+        machine
+            .execute(&Instruction::SubRegister(0x1, 0x2))
+            .unwrap();
+        assert_eq!((machine.v[0x1], machine.v[0xF]), (0xFD, 1));
+
+        machine
+            .execute(&Instruction::SubNRegister(0x2, 0x1))
+            .unwrap();
+        assert_eq!((machine.v[0x2], machine.v[0xF]), (0xFB, 1));
+
+        // the flag wins when VF is the target register
+        machine.v[0xF] = 0x80;
+        machine.execute(&Instruction::ShiftLeft(0xF, 0xF)).unwrap();
+        assert_eq!(machine.v[0xF], 1);
+    }
+
+    #[test]
+    fn test_execute_shift_quirk() {
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
+        machine.v[0x1] = 0b0000_0110;
+        machine.v[0x2] = 0b1000_0001;
+        machine.execute(&Instruction::ShiftRight(0x1, 0x2)).unwrap();
+        assert_eq!((machine.v[0x1], machine.v[0xF]), (0b0000_0011, 0));
+
+        let quirks = Quirks {
+            shift_uses_vy: true,
+            ..Quirks::default()
+        };
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, quirks);
+        machine.v[0x1] = 0b0000_0110;
+        machine.v[0x2] = 0b1000_0001;
+        machine.execute(&Instruction::ShiftRight(0x1, 0x2)).unwrap();
+        assert_eq!((machine.v[0x1], machine.v[0xF]), (0b0100_0000, 1));
+        machine.execute(&Instruction::ShiftLeft(0x1, 0x2)).unwrap();
+        assert_eq!((machine.v[0x1], machine.v[0xF]), (0b0000_0010, 1));
+        assert_eq!(machine.v[0x2], 0b1000_0001);
+    }
+
+    #[test]
+    fn test_execute_load_store_quirk() {
+        for (increments, expected_i) in [(false, 0x300), (true, 0x304)].iter() {
+            let quirks = Quirks {
+                load_store_increments_i: *increments,
+                ..Quirks::default()
+            };
+            let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, quirks);
+            machine.v[..4].copy_from_slice(&[1, 2, 3, 4]);
+            machine.i = 0x300;
+            machine.execute(&Instruction::StoreRegisters(0x3)).unwrap();
+            assert_eq!(machine.mem.mem[0x300..0x304], [1, 2, 3, 4]);
+            assert_eq!(machine.i, *expected_i);
+
+            machine.i = 0x300;
+            machine.v = [0; REGISTER_COUNT];
+            machine.execute(&Instruction::LoadRegisters(0x3)).unwrap();
+            assert_eq!(machine.v[..4], [1, 2, 3, 4]);
+            assert_eq!(machine.i, *expected_i);
+        }
+    }
+
+    #[test]
+    fn test_execute_jump_base_quirk() {
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
+        machine.v[0x0] = 0x10;
+        machine.v[0x3] = 0x20;
+        machine.execute(&Instruction::JumpBase(0x300)).unwrap();
+        assert_eq!(machine.counter, 0x310);
+        assert!(machine.skip_increment);
+
+        let quirks = Quirks {
+            jump_uses_vx: true,
+            ..Quirks::default()
+        };
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, quirks);
+        machine.v[0x0] = 0x10;
+        machine.v[0x3] = 0x20;
+        machine.execute(&Instruction::JumpBase(0x300)).unwrap();
+        assert_eq!(machine.counter, 0x320);
+    }
+
+    #[test]
+    fn test_jump_base_runs_the_target() {
+        // JP V0, 0x202 lands on LD V2, 0x22 and runs it next, VF untouched
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
+        machine
+            .load_program(&[0xB2, 0x02, 0x61, 0x11, 0x62, 0x22, 0x63, 0x33])
+            .unwrap();
+        machine.v[0x0] = 0x02;
+        machine.v[0xF] = 0x55;
+        machine.tick().unwrap();
+        assert_eq!(machine.counter, 0x204);
+        assert_eq!(machine.v[0xF], 0x55);
+        machine.tick().unwrap();
+        assert_eq!(machine.counter, 0x206);
+        assert_eq!(machine.v[0x1..0x4], [0x00, 0x22, 0x00]);
+    }
+
+    #[test]
+    fn test_execute_logic_vf_reset_quirk() {
+        for (resets, expected_vf) in [(false, 1), (true, 0)].iter() {
+            let quirks = Quirks {
+                logic_resets_vf: *resets,
+                ..Quirks::default()
+            };
+            let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, quirks);
+            machine.v[0x1] = 0b1100;
+            machine.v[0x2] = 0b1010;
+            machine.v[0xF] = 1;
+            machine.execute(&Instruction::Or(0x1, 0x2)).unwrap();
+            assert_eq!((machine.v[0x1], machine.v[0xF]), (0b1110, *expected_vf));
+            machine.v[0xF] = 1;
+            machine.execute(&Instruction::And(0x1, 0x2)).unwrap();
+            assert_eq!((machine.v[0x1], machine.v[0xF]), (0b1010, *expected_vf));
+            machine.v[0xF] = 1;
+            machine.execute(&Instruction::Xor(0x1, 0x2)).unwrap();
+            assert_eq!((machine.v[0x1], machine.v[0xF]), (0b0000, *expected_vf));
+        }
+    }
+
+    #[test]
+    fn test_execute_bcd() {
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
+        machine.v[0x4] = 137;
+        machine.i = 0x300;
+        machine.execute(&Instruction::LoadIBCD(0x4)).unwrap();
+        assert_eq!(machine.mem.mem[0x300..0x303], [1, 3, 7]);
+    }
+
+    #[test]
+    fn test_bcd_of_vx_not_x() {
+        // the digits come from the value in VX, whatever the number X is
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
+        machine.i = 0x300;
+        for &(x, value, digits) in [
+            (0x0, 255, [2, 5, 5]),
+            (0xF, 9, [0, 0, 9]),
+            (0xC, 0, [0, 0, 0]),
+        ]
+        .iter()
+        {
+            machine.v[usize::from(x)] = value;
+            machine.execute(&Instruction::LoadIBCD(x)).unwrap();
+            assert_eq!(machine.mem.mem[0x300..0x303], digits);
+            assert_eq!(machine.i, 0x300);
+        }
+    }
+
+    #[test]
+    fn test_add_byte_leaves_vf_alone() {
+        // 7XKK has no carry, so the flag of the instruction before survives it
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
+        for &(vx, vf) in [(0xFF, 0x55), (0x01, 0x55), (0xFF, 0x00)].iter() {
+            machine.v[0x1] = vx;
+            machine.v[0xF] = vf;
+            machine.execute(&Instruction::AddByte(0x1, 0x02)).unwrap();
+            assert_eq!((machine.v[0x1], machine.v[0xF]), (vx.wrapping_add(2), vf));
+        }
+
+        // adding to VF itself is an addition like any other
+        machine.v[0xF] = 0xFF;
+        machine.execute(&Instruction::AddByte(0xF, 0x02)).unwrap();
+        assert_eq!(machine.v[0xF], 0x01);
+    }
+
+    #[test]
+    fn test_subtract_flags_compare_values() {
+        /*
+        VF is 1 when there is no borrow, which depends on the values in the
+        registers, not on their numbers. V1 holds the larger value here, and
+        only the last case has them equal.
+        */
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
+        let cases = [
+            (Instruction::SubRegister(0x1, 0x2), 0x1, 5, (0x02, 1)),
+            (Instruction::SubRegister(0x2, 0x1), 0x2, 5, (0xFE, 0)),
+            (Instruction::SubNRegister(0x1, 0x2), 0x1, 5, (0xFE, 0)),
+            (Instruction::SubNRegister(0x2, 0x1), 0x2, 5, (0x02, 1)),
+            (Instruction::SubRegister(0x1, 0x2), 0x1, 3, (0x00, 1)),
+        ];
+        for (instruction, x, v1, expected) in cases.iter() {
+            machine.v[0x1] = *v1;
+            machine.v[0x2] = 3;
+            machine.execute(instruction).unwrap();
+            assert_eq!(
+                (machine.v[*x], machine.v[0xF]),
+                *expected,
+                "{}",
+                instruction
+            );
+        }
+    }
+
+    #[test]
+    fn test_execute_display_sprite_clip_quirk() {
+        for clip in [false, true].iter() {
+            let quirks = Quirks {
+                clip_sprites: *clip,
+                ..Quirks::default()
+            };
+            let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, quirks);
+            machine.mem.mem[0x300] = 0xFF;
+            machine.i = 0x300;
+            // the starting position wraps around in both cases
+            machine.v[0x0] = 60 + DISPLAY_WIDTH as u8;
+            machine.v[0x1] = 31;
+            machine
+                .execute(&Instruction::DisplaySprite(0x0, 0x1, 1))
+                .unwrap();
+            assert_eq!(machine.graphics.row(31)[60..], [1, 1, 1, 1]);
+            assert_eq!(machine.graphics.row(31)[..4], [!clip as u8; 4]);
+            assert_eq!(machine.v[0xF], 0);
+
+            machine
+                .execute(&Instruction::DisplaySprite(0x0, 0x1, 1))
+                .unwrap();
+            assert_eq!(machine.graphics.row(31)[60..], [0, 0, 0, 0]);
+            assert_eq!(machine.v[0xF], 1);
+        }
+    }
+
+    #[test]
+    fn test_display_wait_quirk() {
+        let quirks = Quirks {
+            display_wait: true,
+            ..Quirks::default()
+        };
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, quirks);
+        // DRW V0, V0, 1; DRW V0, V0, 1
+        machine.load_program(&[0xD0, 0x01, 0xD0, 0x01]).unwrap();
+        machine.tick().unwrap();
+        assert_eq!(machine.counter, 0x202);
+        // the second sprite has to wait for the next frame
+        machine.tick().unwrap();
+        machine.tick().unwrap();
+        assert_eq!(machine.counter, 0x202);
+        machine.tick_timers();
+        machine.tick().unwrap();
+        assert_eq!(machine.counter, 0x204);
+    }
+
+    #[test]
+    fn test_machine_with_platform() {
+        let machine = Machine::with_platform("TestVM", OpcodeMaskParser {}, &COSMAC_VIP);
+        assert_eq!(machine.quirks(), &COSMAC_VIP.quirks);
+        assert_eq!(machine.memory().len(), 4096);
+        assert_eq!(machine.memory()[..80], Font::CosmacVip.glyphs()[..]);
+
+        let mut machine = Machine::with_platform("TestVM", OpcodeMaskParser {}, &XO_CHIP);
+        assert_eq!(machine.memory().len(), 65536);
+        machine.load_program(&[0xAA; 0x8000]).unwrap();
+        machine.i = 0xFFF0;
+        machine.execute(&Instruction::StoreRegisters(0xF)).unwrap();
+    }
+
     #[test]
     fn test_font_sprites_loaded_on_machine_init() {
-        let machine = Machine::new("TestVM", OpcodeMaskParser {}, true, None);
+        let machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
 
         assert_eq!(machine.counter, 512); // before machine executes instruction
         assert_eq!(machine.mem.mem.len(), 4096);
@@ -922,8 +1935,13 @@ mod tests {
         for byte in machine.mem.mem[..80].iter() {
             assert_ne!(*byte, 0);
         }
+        // followed by the big font
+        assert_eq!(
+            machine.mem.mem[BIG_FONT_ADDRESS..BIG_FONT_ADDRESS + 160],
+            Font::Chip48.big_glyphs()[..]
+        );
         // Subsequent memory is empty
-        for byte in machine.mem.mem[80..4096].iter() {
+        for byte in machine.mem.mem[BIG_FONT_ADDRESS + 160..4096].iter() {
             assert_eq!(*byte, 0);
         }
         assert!(machine.mem.mem[..80].iter().eq([
@@ -946,4 +1964,380 @@ mod tests {
         ]
         .iter()));
     }
+
+    #[test]
+    fn test_execute_resolution_switch() {
+        let mut machine = Machine::with_platform("TestVM", OpcodeMaskParser {}, &SCHIP_1_1);
+        machine.graphics.set(3, 3, 1);
+        machine.execute(&Instruction::HighRes).unwrap();
+        assert!(machine.hires());
+        assert_eq!(machine.graphics_size(), (128, 64));
+        assert_eq!(machine.graphics.get(3, 3), 0);
+        machine.execute(&Instruction::LowRes).unwrap();
+        assert!(!machine.hires());
+        assert_eq!(machine.graphics_size(), (DISPLAY_WIDTH, DISPLAY_HEIGHT));
+
+        // plain CHIP-8 treats the SUPER-CHIP 00xx instructions as SYS calls
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
+        machine.execute(&Instruction::HighRes).unwrap();
+        assert_eq!(machine.graphics_size(), (DISPLAY_WIDTH, DISPLAY_HEIGHT));
+    }
+
+    #[test]
+    fn test_execute_scroll() {
+        let mut machine = Machine::with_platform("TestVM", OpcodeMaskParser {}, &SCHIP_1_1);
+        machine.execute(&Instruction::HighRes).unwrap();
+        machine.graphics.set(10, 10, 1);
+        machine.execute(&Instruction::ScrollDown(3)).unwrap();
+        assert_eq!(machine.graphics.get(10, 13), 1);
+        assert_eq!(machine.graphics.get(10, 10), 0);
+        machine.execute(&Instruction::ScrollRight).unwrap();
+        assert_eq!(machine.graphics.get(14, 13), 1);
+        machine.execute(&Instruction::ScrollLeft).unwrap();
+        machine.execute(&Instruction::ScrollLeft).unwrap();
+        assert_eq!(machine.graphics.get(6, 13), 1);
+        assert_eq!(machine.graphics.row(13).iter().sum::<u8>(), 1);
+
+        // pixels scrolled off the screen are gone
+        machine.execute(&Instruction::ScrollLeft).unwrap();
+        machine.execute(&Instruction::ScrollLeft).unwrap();
+        machine.execute(&Instruction::ScrollRight).unwrap();
+        assert_eq!(machine.graphics.row(13).iter().sum::<u8>(), 0);
+    }
+
+    #[test]
+    fn test_execute_display_big_sprite() {
+        let mut machine = Machine::with_platform("TestVM", OpcodeMaskParser {}, &SCHIP_1_1);
+        machine.execute(&Instruction::HighRes).unwrap();
+        for (n, byte) in machine.mem.mem[0x300..0x320].iter_mut().enumerate() {
+            *byte = if n % 2 == 0 { 0xFF } else { 0x01 };
+        }
+        machine.i = 0x300;
+        machine.v[0x0] = 120;
+        machine
+            .execute(&Instruction::DisplaySprite(0x0, 0x1, 0))
+            .unwrap();
+        for y in 0..16 {
+            assert_eq!(machine.graphics.row(y)[120..], [1; 8]);
+            // SUPER-CHIP clips the right half at the edge of the screen
+            assert_eq!(machine.graphics.row(y)[..8], [0; 8]);
+        }
+        assert_eq!(machine.graphics.get(0, 16), 0);
+        assert_eq!(machine.v[0xF], 0);
+    }
+
+    #[test]
+    fn test_execute_big_font_and_flags() {
+        let mut machine = Machine::with_platform("TestVM", OpcodeMaskParser {}, &SCHIP_1_1);
+        machine.v[0x0] = 0x7;
+        machine
+            .execute(&Instruction::LoadBigFontSprite(0x0))
+            .unwrap();
+        assert_eq!(usize::from(machine.i), BIG_FONT_ADDRESS + 7 * 10);
+
+        machine.v[..4].copy_from_slice(&[1, 2, 3, 4]);
+        machine.execute(&Instruction::StoreFlags(0x2)).unwrap();
+        assert_eq!(machine.flags()[..4], [1, 2, 3, 0]);
+        machine.v[..4].copy_from_slice(&[0; 4]);
+        machine.execute(&Instruction::LoadFlags(0x3)).unwrap();
+        assert_eq!(machine.v[..4], [1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn test_super_chip_unsupported_on_chip8() {
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
+        // LD R, V0
+        machine.load_program(&[0xF0, 0x75]).unwrap();
+        assert!(matches!(
+            machine.tick(),
+            Err(Chip8Error::UnsupportedInstruction {
+                instruction: Instruction::StoreFlags(0x0),
+                pc: 0x200
+            })
+        ));
+    }
+
+    #[test]
+    fn test_exit_halts() {
+        let mut machine = Machine::with_platform("TestVM", OpcodeMaskParser {}, &SCHIP_1_1);
+        // EXIT; LD V0, 1
+        machine.load_program(&[0x00, 0xFD, 0x60, 0x01]).unwrap();
+        machine.tick().unwrap();
+        machine.tick().unwrap();
+        assert!(machine.halted());
+        assert_eq!(machine.counter, 0x200);
+        assert_eq!(machine.v[0x0], 0);
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        let mut machine = Machine::with_platform("TestVM", OpcodeMaskParser {}, &XO_CHIP);
+        let program = encode_program(&[
+            Instruction::AddByte(0x2, 1),
+            Instruction::LoadImmediate(0x200),
+            Instruction::LoadByte(0x0, 0x72),
+            Instruction::LoadByte(0x1, 0x05),
+            Instruction::StoreRegisters(0x1), // ADD V2, 5 over the first instruction
+            Instruction::Jump(0x200),
+        ])
+        .unwrap();
+        machine.load_program(&program).unwrap();
+        for _ in 0..6 {
+            machine.tick().unwrap();
+        }
+        assert_eq!(machine.v[0x2], 1);
+        assert_eq!(
+            machine.next_instruction().unwrap(),
+            Instruction::AddByte(0x2, 5)
+        );
+        machine.tick().unwrap();
+        assert_eq!(machine.v[0x2], 6);
+
+        // writes from outside the program count too, as does the second word of F000 NNNN
+        machine
+            .write_memory(0x200, &[0xF0, 0x00, 0x12, 0x34])
+            .unwrap();
+        machine.set_pc(0x200);
+        machine.tick().unwrap();
+        assert_eq!(machine.i, 0x1234);
+        machine.write_memory(0x203, &[0x56]).unwrap();
+        machine.set_pc(0x200);
+        assert_eq!(
+            machine.next_instruction().unwrap(),
+            Instruction::LoadLongImmediate(0x1256)
+        );
+    }
+
+    #[test]
+    fn test_long_load_and_skip() {
+        let mut machine = Machine::with_platform("TestVM", OpcodeMaskParser {}, &XO_CHIP);
+        machine
+            .load_program(
+                &encode_program(&[
+                    Instruction::LoadLongImmediate(0xBEEF),
+                    Instruction::SkipEqualsByte(0x0, 0),
+                    Instruction::LoadLongImmediate(0x1234),
+                    Instruction::LoadByte(0x0, 1),
+                ])
+                .unwrap(),
+            )
+            .unwrap();
+        machine.tick().unwrap();
+        assert_eq!(machine.i, 0xBEEF);
+        assert_eq!(machine.counter, 0x204);
+        // the skip steps over all 4 bytes of the long load
+        machine.tick().unwrap();
+        assert_eq!(machine.counter, 0x20A);
+        machine.tick().unwrap();
+        assert_eq!(machine.i, 0xBEEF);
+        assert_eq!(machine.v[0x0], 1);
+
+        // the second word has to be in memory as well
+        let mut machine = Machine::with_platform("TestVM", OpcodeMaskParser {}, &XO_CHIP);
+        machine.counter = 0xFFFE;
+        machine.mem.mem[0xFFFE] = 0xF0;
+        assert!(matches!(
+            machine.tick(),
+            Err(Chip8Error::PcOutOfBounds(0xFFFE))
+        ));
+    }
+
+    #[test]
+    fn test_xo_chip_unsupported_on_super_chip() {
+        let mut machine = Machine::with_platform("TestVM", OpcodeMaskParser {}, &SCHIP_1_1);
+        machine.load_program(&[0xF0, 0x00, 0x12, 0x34]).unwrap();
+        assert!(matches!(
+            machine.tick(),
+            Err(Chip8Error::UnsupportedInstruction {
+                instruction: Instruction::LoadLongImmediate(0x1234),
+                pc: 0x200
+            })
+        ));
+        assert!(machine.execute(&Instruction::SelectPlanes(3)).is_err());
+        assert!(machine.execute(&Instruction::SaveRange(0, 1)).is_err());
+    }
+
+    #[test]
+    fn test_execute_save_load_range() {
+        let mut machine = Machine::with_platform("TestVM", OpcodeMaskParser {}, &XO_CHIP);
+        machine.v[..5].copy_from_slice(&[1, 2, 3, 4, 5]);
+        machine.i = 0x300;
+        machine.execute(&Instruction::SaveRange(0x1, 0x3)).unwrap();
+        assert_eq!(machine.mem.mem[0x300..0x304], [2, 3, 4, 0]);
+        assert_eq!(machine.i, 0x300);
+        // a descending range is stored back to front
+        machine.execute(&Instruction::SaveRange(0x4, 0x2)).unwrap();
+        assert_eq!(machine.mem.mem[0x300..0x304], [5, 4, 3, 0]);
+
+        machine.execute(&Instruction::LoadRange(0x0, 0x2)).unwrap();
+        assert_eq!(machine.v[..5], [5, 4, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_execute_draw_planes() {
+        let mut machine = Machine::with_platform("TestVM", OpcodeMaskParser {}, &XO_CHIP);
+        // a sprite for the first plane, followed by one for the second
+        machine.mem.mem[0x300] = 0xF0;
+        machine.mem.mem[0x301] = 0x3C;
+        machine.i = 0x300;
+        machine.execute(&Instruction::SelectPlanes(3)).unwrap();
+        machine
+            .execute(&Instruction::DisplaySprite(0x0, 0x0, 1))
+            .unwrap();
+        assert_eq!(machine.graphics.row(0)[..8], [1, 1, 3, 3, 2, 2, 0, 0]);
+        assert_eq!(machine.v[0xF], 0);
+
+        // only the second plane is touched, and it reports the collision
+        machine.execute(&Instruction::SelectPlanes(2)).unwrap();
+        machine.i = 0x301;
+        machine
+            .execute(&Instruction::DisplaySprite(0x0, 0x0, 1))
+            .unwrap();
+        assert_eq!(machine.graphics.row(0)[..8], [1, 1, 1, 1, 0, 0, 0, 0]);
+        assert_eq!(machine.v[0xF], 1);
+
+        // with no planes selected nothing is drawn
+        machine.execute(&Instruction::SelectPlanes(0)).unwrap();
+        machine
+            .execute(&Instruction::DisplaySprite(0x0, 0x0, 1))
+            .unwrap();
+        assert_eq!(machine.graphics.row(0)[..8], [1, 1, 1, 1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_scroll_and_clear_selected_planes() {
+        let mut machine = Machine::with_platform("TestVM", OpcodeMaskParser {}, &XO_CHIP);
+        machine.graphics.set(8, 8, 3);
+        machine.execute(&Instruction::SelectPlanes(2)).unwrap();
+        machine.execute(&Instruction::ScrollUp(2)).unwrap();
+        assert_eq!(machine.graphics.get(8, 8), 1);
+        assert_eq!(machine.graphics.get(8, 6), 2);
+        machine.execute(&Instruction::ScrollRight).unwrap();
+        assert_eq!(machine.graphics.get(12, 6), 2);
+        machine.execute(&Instruction::ClearScreen).unwrap();
+        assert_eq!(machine.graphics.get(12, 6), 0);
+        assert_eq!(machine.graphics.get(8, 8), 1);
+    }
+
+    #[test]
+    fn test_execute_audio_pattern_and_pitch() {
+        let mut machine = Machine::with_platform("TestVM", OpcodeMaskParser {}, &XO_CHIP);
+        assert!(!machine.take_audio_update());
+        for (n, byte) in machine.mem.mem[0x400..0x410].iter_mut().enumerate() {
+            *byte = n as u8;
+        }
+        machine.i = 0x400;
+        machine.execute(&Instruction::LoadAudioPattern).unwrap();
+        assert_eq!(machine.audio_pattern()[15], 15);
+        machine.v[0x3] = 112;
+        machine.execute(&Instruction::LoadPitch(0x3)).unwrap();
+        assert_eq!(machine.pitch(), 112);
+        assert!(machine.take_audio_update());
+        assert!(!machine.take_audio_update());
+    }
+
+    #[test]
+    fn test_execute_random_is_seedable() {
+        let run = |seed| {
+            let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
+            machine.set_rng(Box::new(XorShift::new(seed)));
+            (0..16)
+                .map(|_| {
+                    machine.execute(&Instruction::Random(0x0, 0xFF)).unwrap();
+                    machine.v[0x0]
+                })
+                .collect::<Vec<u8>>()
+        };
+        assert_eq!(run(1234), run(1234));
+        assert_ne!(run(1234), run(4321));
+
+        // the mask is applied to the random byte
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
+        for _ in 0..16 {
+            machine.execute(&Instruction::Random(0x1, 0x0F)).unwrap();
+            assert_eq!(machine.v[0x1] & 0xF0, 0);
+        }
+    }
+
+    #[test]
+    fn test_pokes_refuse_missing_registers_and_keys() {
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
+        assert!(machine.set_register(0xF, 3));
+        assert_eq!(machine.registers()[0xF], 3);
+        assert!(!machine.set_register(0x10, 3));
+        assert!(machine.set_key(0xF, true));
+        assert!(machine.keyboard()[0xF]);
+        assert!(!machine.set_key(0x10, true));
+        assert!(!machine.set_stack_pointer(STACK_SIZE as u8 + 1));
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let program = encode_program(&[
+            Instruction::Random(0x0, 0xFF),
+            Instruction::AddByte(0x1, 1),
+            Instruction::Jump(0x200),
+        ])
+        .unwrap();
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
+        machine.set_rng(Box::new(XorShift::new(99)));
+        machine.load_program(&program).unwrap();
+        for _ in 0..7 {
+            machine.tick().unwrap();
+        }
+        let state = machine.save_state();
+        let run = |machine: &mut Machine<OpcodeMaskParser>| {
+            (0..30)
+                .map(|_| {
+                    machine.tick().unwrap();
+                    (machine.counter, machine.v)
+                })
+                .collect::<Vec<_>>()
+        };
+        let expected = run(&mut machine);
+        // the random numbers come out the same the second time around
+        machine.load_state(&state).unwrap();
+        assert_eq!(run(&mut machine), expected);
+
+        let mut other = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
+        other.load_program(&[0x12, 0x00]).unwrap();
+        assert!(matches!(
+            other.load_state(&state),
+            Err(Chip8Error::SaveStateMismatch { .. })
+        ));
+        let mut xo_chip = Machine::with_platform("TestVM", OpcodeMaskParser {}, &XO_CHIP);
+        xo_chip.load_program(&program).unwrap();
+        assert!(matches!(
+            xo_chip.load_state(&state),
+            Err(Chip8Error::InvalidSaveState(_))
+        ));
+
+        // values that would make the machine panic later are refused up front
+        let snapshot = machine.snapshot();
+        let invalid = [
+            Snapshot {
+                stack_ptr: STACK_SIZE as u8 + 1,
+                ..snapshot.clone()
+            },
+            Snapshot {
+                planes: 4,
+                ..snapshot.clone()
+            },
+            Snapshot {
+                graphics: GraphicsMemory::new(128, 64),
+                ..snapshot.clone()
+            },
+            Snapshot {
+                graphics: GraphicsMemory::new(0, 0),
+                ..snapshot.clone()
+            },
+        ];
+        for snapshot in invalid.iter() {
+            assert!(matches!(
+                machine.restore(snapshot),
+                Err(Chip8Error::InvalidSaveState(_))
+            ));
+        }
+        assert!(machine.restore(&snapshot).is_ok());
+    }
 }
@@ -0,0 +1,821 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+use std::time::Instant;
+
+use crate::audio::AudioSink;
+use crate::core::{Machine, REGISTER_COUNT};
+use crate::display::Display;
+use crate::error::Chip8Error;
+use crate::instructions::{Instruction, InstructionParser};
+use crate::keyboard::InputSource;
+use crate::runner::Runner;
+
+const HELP: &str = "Commands:
+    step [N]          s    Execute N instructions (default: 1)
+    continue          c    Run until a breakpoint or watch triggers
+    break ADDR        b    Stop before executing the instruction at ADDR
+    break-on NAME          Stop before any instruction of that kind, e.g. DisplaySprite
+    watch ADDR [LEN]  w    Stop on writes to LEN bytes of memory at ADDR (default: 1)
+    watch vX | i           Stop when a register changes
+    delete [ADDR|NAME]     Remove a breakpoint, or every breakpoint and watch
+    info                   List the breakpoints and watches
+    regs              r    Print the registers, the stack, I and the timers
+    dump ADDR [LEN]   x    Print LEN bytes of memory from ADDR (default: 64)
+    poke ADDR BYTE...      Write bytes to memory
+    poke vX BYTE | i ADDR  Set a register
+    help              h    Show this
+    quit              q    Stop the machine
+
+Addresses and values are hexadecimal, counts are decimal. An empty line repeats
+the last command. F12 in the window breaks into the debugger.";
+
+/*
+Something that can change while the program runs. Memory watches stop on
+every write to their bytes, even one of the value that was already there.
+Registers are compared before and after every instruction instead, so they
+only stop when the value changes.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum Watch {
+    Memory { addr: u16, len: u16 },
+    Register(u8),
+    I,
+}
+
+impl Watch {
+    // Whether the instruction that was just run triggers the watch
+    fn triggered<T: InstructionParser>(
+        &self,
+        machine: &Machine<T>,
+        writes: u64,
+        old: &[u8],
+        new: &[u8],
+    ) -> bool {
+        match *self {
+            Watch::Memory { addr, len } => {
+                let (count, written) = machine.memory_writes();
+                let start = usize::from(addr);
+                count != writes && written.start < start + usize::from(len) && start < written.end
+            }
+            Watch::Register(_) | Watch::I => old != new,
+        }
+    }
+
+    fn read<T: InstructionParser>(&self, machine: &Machine<T>) -> Vec<u8> {
+        match *self {
+            Watch::Memory { addr, len } => {
+                let memory = machine.memory();
+                let start = usize::from(addr).min(memory.len());
+                let end = (start + usize::from(len)).min(memory.len());
+                memory[start..end].to_vec()
+            }
+            Watch::Register(x) => vec![machine.registers()[usize::from(x)]],
+            Watch::I => machine.i().to_be_bytes().to_vec(),
+        }
+    }
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Watch::Memory { addr, len: 1 } => write!(f, "{:#06X}", addr),
+            Watch::Memory { addr, len } => {
+                let end = u32::from(*addr) + u32::from(*len);
+                write!(f, "{:#06X}..{:#06X}", addr, end)
+            }
+            Watch::Register(x) => write!(f, "V{:X}", x),
+            Watch::I => write!(f, "I"),
+        }
+    }
+}
+
+// Why the machine stopped running
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stop {
+    Stepped,            // done with the requested number of instructions
+    Breakpoint(u16),    // the PC reached a breakpoint
+    Break(Instruction), // the next instruction is of a kind to break on
+    Watch {
+        watch: Watch,
+        old: Vec<u8>,
+        new: Vec<u8>,
+    },
+    Requested, // the user pressed the break key
+    Halted,    // the program exited through 00FD
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stop::Stepped => Ok(()),
+            Stop::Breakpoint(addr) => write!(f, "Breakpoint at {:#06X}", addr),
+            Stop::Break(instruction) => write!(f, "Break on {}", variant_name(instruction)),
+            Stop::Watch {
+                watch: watch @ Watch::Memory { .. },
+                old,
+                new,
+            } => write!(f, "{} written, from {:02X?} to {:02X?}", watch, old, new),
+            Stop::Watch { watch, old, new } => {
+                write!(f, "{} changed from {:02X?} to {:02X?}", watch, old, new)
+            }
+            Stop::Requested => write!(f, "Interrupted"),
+            Stop::Halted => write!(f, "The program has exited"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DebugCommand {
+    Step(u64),
+    Continue,
+    Break(u16),
+    BreakOn(String),
+    Watch(Watch),
+    Delete(Option<String>),
+    Info,
+    Registers,
+    Dump { addr: u16, len: u16 },
+    Poke(Watch, Vec<u8>), // what to write and the bytes to write there
+    Help,
+    Quit,
+}
+
+// Addresses and values are written in hex, with or without a 0x or $ in front
+fn parse_hex(word: &str) -> Result<u16, String> {
+    let digits = word
+        .strip_prefix("0x")
+        .or_else(|| word.strip_prefix("0X"))
+        .or_else(|| word.strip_prefix('$'))
+        .unwrap_or(word);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Invalid number: {}", word))
+}
+
+fn parse_byte(word: &str) -> Result<u8, String> {
+    let value = parse_hex(word)?;
+    if value > 0xFF {
+        return Err(format!("Not a byte: {}", word));
+    }
+    Ok(value as u8)
+}
+
+fn parse_register(word: &str) -> Option<Result<Watch, String>> {
+    let lower = word.to_ascii_lowercase();
+    if lower == "i" {
+        return Some(Ok(Watch::I));
+    }
+    let x = lower.strip_prefix('v')?;
+    Some(match u8::from_str_radix(x, 16) {
+        Ok(x) if usize::from(x) < REGISTER_COUNT => Ok(Watch::Register(x)),
+        _ => Err(format!("Invalid register: {}", word)),
+    })
+}
+
+impl FromStr for DebugCommand {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (name, args) = match words.split_first() {
+            Some((name, args)) => (*name, args),
+            None => return Err(String::from("Empty command")),
+        };
+        let arg = |n: usize| {
+            args.get(n)
+                .copied()
+                .ok_or_else(|| format!("{} is missing an argument, try help", name))
+        };
+        let command = match name {
+            "step" | "s" => match args.first() {
+                Some(n) => DebugCommand::Step(
+                    n.parse()
+                        .map_err(|_| format!("Invalid number of steps: {}", n))?,
+                ),
+                None => DebugCommand::Step(1),
+            },
+            "continue" | "c" => DebugCommand::Continue,
+            "break" | "b" => DebugCommand::Break(parse_hex(arg(0)?)?),
+            "break-on" => DebugCommand::BreakOn(String::from(arg(0)?)),
+            "watch" | "w" => match parse_register(arg(0)?) {
+                Some(register) => DebugCommand::Watch(register?),
+                None => DebugCommand::Watch(Watch::Memory {
+                    addr: parse_hex(arg(0)?)?,
+                    len: args.get(1).map_or(Ok(1), |len| parse_hex(len))?.max(1),
+                }),
+            },
+            "delete" | "d" => DebugCommand::Delete(args.first().map(|arg| String::from(*arg))),
+            "info" => DebugCommand::Info,
+            "regs" | "r" => DebugCommand::Registers,
+            "dump" | "x" => DebugCommand::Dump {
+                addr: parse_hex(arg(0)?)?,
+                len: args.get(1).map_or(Ok(0x40), |len| parse_hex(len))?,
+            },
+            "poke" => {
+                let target = arg(0)?;
+                arg(1)?;
+                match parse_register(target) {
+                    Some(register) => {
+                        let register = register?;
+                        let value = match register {
+                            Watch::I => parse_hex(args[1])?.to_be_bytes().to_vec(),
+                            _ => vec![parse_byte(args[1])?],
+                        };
+                        DebugCommand::Poke(register, value)
+                    }
+                    None => {
+                        let bytes = args[1..].iter().map(|b| parse_byte(b)).collect::<Result<
+                            Vec<u8>,
+                            String,
+                        >>(
+                        )?;
+                        let watch = Watch::Memory {
+                            addr: parse_hex(target)?,
+                            len: bytes.len() as u16,
+                        };
+                        DebugCommand::Poke(watch, bytes)
+                    }
+                }
+            }
+            "help" | "h" => DebugCommand::Help,
+            "quit" | "q" => DebugCommand::Quit,
+            _ => return Err(format!("Unknown command: {}, try help", name)),
+        };
+        Ok(command)
+    }
+}
+
+// The name of the variant, e.g. DisplaySprite for DisplaySprite(0, 1, 5)
+pub fn variant_name(instruction: &Instruction) -> String {
+    let name = format!("{:?}", instruction);
+    match name.find('(') {
+        Some(end) => String::from(&name[..end]),
+        None => name,
+    }
+}
+
+/*
+An interactive debugger that drives a Runner one instruction at a time. The
+runner keeps the frame timing, input and output exactly as they would be
+without the debugger, so a bug that shows up on a particular frame can be
+stopped right there.
+*/
+#[derive(Debug, Default)]
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    instruction_breaks: BTreeSet<String>,
+    watches: Vec<Watch>,
+    realtime: bool, // keep continue at the speed of the machine
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Runs at the speed of the machine while continuing, for use with a window
+    pub fn realtime() -> Self {
+        Debugger {
+            realtime: true,
+            ..Self::default()
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
+
+    // Breaks before every instruction whose variant_name is `name`, ignoring case
+    pub fn break_on(&mut self, name: &str) {
+        self.instruction_breaks.insert(name.to_ascii_lowercase());
+    }
+
+    pub fn add_watch(&mut self, watch: Watch) {
+        if !self.watches.contains(&watch) {
+            self.watches.push(watch);
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<u16> {
+        &self.breakpoints
+    }
+
+    pub fn watches(&self) -> &[Watch] {
+        &self.watches
+    }
+
+    /*
+    Runs the machine until something makes it stop, or for `steps`
+    instructions. The breakpoints are not checked before the first
+    instruction, so continuing from a breakpoint gets past it.
+    */
+    pub fn resume<T, D, A, I>(
+        &mut self,
+        runner: &mut Runner<T, D, A, I>,
+        steps: Option<u64>,
+    ) -> Result<Stop, Chip8Error>
+    where
+        T: InstructionParser,
+        D: Display,
+        A: AudioSink,
+        I: InputSource,
+    {
+        self.resume_until(runner, steps, || false)
+    }
+
+    /*
+    Like resume, but also asks `interrupted` at the end of every frame whether
+    to stop, for frontends that have their own way of breaking in.
+    */
+    pub fn resume_until<T, D, A, I, F>(
+        &mut self,
+        runner: &mut Runner<T, D, A, I>,
+        steps: Option<u64>,
+        mut interrupted: F,
+    ) -> Result<Stop, Chip8Error>
+    where
+        T: InstructionParser,
+        D: Display,
+        A: AudioSink,
+        I: InputSource,
+        F: FnMut() -> bool,
+    {
+        let mut remaining = steps;
+        let mut first = true;
+        let mut frame_start = Instant::now();
+        loop {
+            if runner.machine().halted() {
+                return Ok(Stop::Halted);
+            }
+            if remaining == Some(0) {
+                return Ok(Stop::Stepped);
+            }
+            if !first {
+                if let Some(stop) = self.check_breakpoints(runner.machine()) {
+                    return Ok(stop);
+                }
+            }
+            first = false;
+            if runner.at_frame_start() {
+                frame_start = Instant::now();
+            }
+            let before: Vec<Vec<u8>> = self
+                .watches
+                .iter()
+                .map(|w| w.read(runner.machine()))
+                .collect();
+            let (writes, _) = runner.machine().memory_writes();
+            let frame_done = runner.step()?;
+            remaining = remaining.map(|n| n - 1);
+            for (watch, old) in self.watches.iter().zip(before) {
+                let new = watch.read(runner.machine());
+                if watch.triggered(runner.machine(), writes, &old, &new) {
+                    let watch = watch.clone();
+                    return Ok(Stop::Watch { watch, old, new });
+                }
+            }
+            if frame_done {
+                if runner.take_break_request() || interrupted() {
+                    return Ok(Stop::Requested);
+                }
+                if self.realtime && steps.is_none() {
+                    runner.wait_for_frame(frame_start);
+                }
+            }
+        }
+    }
+
+    fn check_breakpoints<T: InstructionParser>(&self, machine: &Machine<T>) -> Option<Stop> {
+        let pc = machine.pc();
+        if self.breakpoints.contains(&pc) {
+            return Some(Stop::Breakpoint(pc));
+        }
+        if self.instruction_breaks.is_empty() {
+            return None;
+        }
+        // an opcode that doesn't decode is left for tick to report
+        let instruction = machine.next_instruction().ok()?;
+        if self
+            .instruction_breaks
+            .contains(&variant_name(&instruction).to_ascii_lowercase())
+        {
+            return Some(Stop::Break(instruction));
+        }
+        None
+    }
+
+    /*
+    Reads commands from `input` until it runs out or the user quits. Errors of
+    the machine are reported and leave it where it stopped, to be looked at;
+    only Chip8Error::Quit, i.e. a closed window, ends the session.
+    */
+    pub fn run<T, D, A, I, R, W>(
+        &mut self,
+        runner: &mut Runner<T, D, A, I>,
+        input: R,
+        mut output: W,
+    ) -> Result<(), Chip8Error>
+    where
+        T: InstructionParser,
+        D: Display,
+        A: AudioSink,
+        I: InputSource,
+        R: BufRead,
+        W: Write,
+    {
+        let mut last = None;
+        print_location(&mut output, runner.machine())?;
+        let mut lines = input.lines();
+        loop {
+            write!(output, "(chip8) ")?;
+            output.flush()?;
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => return Ok(()),
+            };
+            let command = if line.trim().is_empty() {
+                match last.clone() {
+                    Some(command) => command,
+                    None => continue,
+                }
+            } else {
+                match line.parse::<DebugCommand>() {
+                    Ok(command) => command,
+                    Err(e) => {
+                        writeln!(output, "{}", e)?;
+                        continue;
+                    }
+                }
+            };
+            if command == DebugCommand::Quit {
+                return Ok(());
+            }
+            last = Some(command.clone());
+            match self.execute(runner, command, &mut output) {
+                Ok(()) => {}
+                Err(Chip8Error::Quit) => return Err(Chip8Error::Quit),
+                Err(Chip8Error::Io(e)) => return Err(Chip8Error::Io(e)),
+                Err(e) => writeln!(output, "Error: {}", e)?,
+            }
+        }
+    }
+
+    pub fn execute<T, D, A, I, W>(
+        &mut self,
+        runner: &mut Runner<T, D, A, I>,
+        command: DebugCommand,
+        output: &mut W,
+    ) -> Result<(), Chip8Error>
+    where
+        T: InstructionParser,
+        D: Display,
+        A: AudioSink,
+        I: InputSource,
+        W: Write,
+    {
+        match command {
+            DebugCommand::Step(n) => self.report(runner, Some(n), output)?,
+            DebugCommand::Continue => self.report(runner, None, output)?,
+            DebugCommand::Break(addr) => {
+                self.add_breakpoint(addr);
+                writeln!(output, "Breakpoint at {:#06X}", addr)?;
+            }
+            DebugCommand::BreakOn(name) => {
+                self.break_on(&name);
+                writeln!(output, "Breaking on {}", name)?;
+            }
+            DebugCommand::Watch(watch) => {
+                writeln!(output, "Watching {}", watch)?;
+                self.add_watch(watch);
+            }
+            DebugCommand::Delete(None) => {
+                self.breakpoints.clear();
+                self.instruction_breaks.clear();
+                self.watches.clear();
+            }
+            DebugCommand::Delete(Some(what)) => {
+                let removed = match parse_hex(&what) {
+                    Ok(addr) if self.breakpoints.remove(&addr) => true,
+                    _ => self.instruction_breaks.remove(&what.to_ascii_lowercase()),
+                };
+                if !removed {
+                    writeln!(output, "No breakpoint at {}", what)?;
+                }
+            }
+            DebugCommand::Info => {
+                for addr in self.breakpoints.iter() {
+                    writeln!(output, "break {:#06X}", addr)?;
+                }
+                for name in self.instruction_breaks.iter() {
+                    writeln!(output, "break-on {}", name)?;
+                }
+                for watch in self.watches.iter() {
+                    writeln!(output, "watch {}", watch)?;
+                }
+            }
+            DebugCommand::Registers => print_registers(output, runner.machine())?,
+            DebugCommand::Dump { addr, len } => print_memory(output, runner.machine(), addr, len)?,
+            DebugCommand::Poke(target, bytes) => {
+                let machine = runner.machine_mut();
+                match target {
+                    Watch::Memory { addr, .. } => machine.write_memory(addr, &bytes)?,
+                    Watch::Register(x) => {
+                        if !machine.set_register(x, bytes[0]) {
+                            writeln!(output, "No register V{:X}", x)?;
+                        }
+                    }
+                    Watch::I => machine.set_i(u16::from_be_bytes([bytes[0], bytes[1]])),
+                }
+            }
+            DebugCommand::Help => writeln!(output, "{}", HELP)?,
+            DebugCommand::Quit => {}
+        }
+        Ok(())
+    }
+
+    fn report<T, D, A, I, W>(
+        &mut self,
+        runner: &mut Runner<T, D, A, I>,
+        steps: Option<u64>,
+        output: &mut W,
+    ) -> Result<(), Chip8Error>
+    where
+        T: InstructionParser,
+        D: Display,
+        A: AudioSink,
+        I: InputSource,
+        W: Write,
+    {
+        let stop = self.resume(runner, steps)?;
+        if stop != Stop::Stepped {
+            writeln!(output, "{}", stop)?;
+        }
+        print_location(output, runner.machine())
+    }
+}
+
+// Where the machine is and what it is about to do
+fn print_location<T: InstructionParser, W: Write>(
+    output: &mut W,
+    machine: &Machine<T>,
+) -> Result<(), Chip8Error> {
+    match machine.next_instruction() {
+        Ok(instruction) => writeln!(output, "{:#06X}: {}", machine.pc(), instruction)?,
+        Err(e) => writeln!(output, "{:#06X}: {}", machine.pc(), e)?,
+    }
+    Ok(())
+}
+
+fn print_registers<T: InstructionParser, W: Write>(
+    output: &mut W,
+    machine: &Machine<T>,
+) -> Result<(), Chip8Error> {
+    writeln!(
+        output,
+        "PC {:#06X}  I {:#06X}  DT {:02X}  ST {:02X}",
+        machine.pc(),
+        machine.i(),
+        machine.delay_timer(),
+        machine.sound_timer()
+    )?;
+    for row in machine.registers().chunks(8).enumerate() {
+        let (n, values) = row;
+        let line: Vec<String> = values
+            .iter()
+            .enumerate()
+            .map(|(x, value)| format!("V{:X} {:02X}", n * 8 + x, value))
+            .collect();
+        writeln!(output, "{}", line.join("  "))?;
+    }
+    let depth = usize::from(machine.stack_pointer());
+    let stack: Vec<String> = machine.stack()[..depth]
+        .iter()
+        .map(|addr| format!("{:#06X}", addr))
+        .collect();
+    writeln!(output, "Stack [{}]", stack.join(", "))?;
+    Ok(())
+}
+
+fn print_memory<T: InstructionParser, W: Write>(
+    output: &mut W,
+    machine: &Machine<T>,
+    addr: u16,
+    len: u16,
+) -> Result<(), Chip8Error> {
+    let memory = machine.memory();
+    let start = usize::from(addr);
+    if start >= memory.len() {
+        return Err(Chip8Error::MemoryOutOfBounds { addr: start });
+    }
+    let end = (start + usize::from(len)).min(memory.len());
+    for (n, line) in memory[start..end].chunks(16).enumerate() {
+        let bytes: Vec<String> = line.iter().map(|b| format!("{:02X}", b)).collect();
+        writeln!(output, "{:#06X}: {}", start + n * 16, bytes.join(" "))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::NullAudio;
+    use crate::display::NullDisplay;
+    use crate::instructions::encode_program;
+    use crate::keyboard::NullInput;
+    use crate::opcodes::OpcodeMaskParser;
+    use crate::quirks::Quirks;
+
+    type TestRunner = Runner<OpcodeMaskParser, NullDisplay, NullAudio, NullInput>;
+
+    fn runner() -> TestRunner {
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
+        let program = encode_program(&[
+            Instruction::AddByte(0x0, 1),
+            Instruction::LoadImmediate(0x300),
+            Instruction::StoreRegisters(0x0),
+            Instruction::Jump(0x200),
+        ])
+        .unwrap();
+        machine.load_program(&program).unwrap();
+        Runner::new(machine, NullDisplay, NullAudio, NullInput)
+    }
+
+    fn session(runner: &mut TestRunner, script: &str) -> String {
+        let mut output = Vec::new();
+        Debugger::new()
+            .run(runner, script.as_bytes(), &mut output)
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!("s".parse(), Ok(DebugCommand::Step(1)));
+        assert_eq!("step 10".parse(), Ok(DebugCommand::Step(10)));
+        assert_eq!("b 0x20A".parse(), Ok(DebugCommand::Break(0x20A)));
+        assert_eq!(
+            "watch vf".parse(),
+            Ok(DebugCommand::Watch(Watch::Register(0xF)))
+        );
+        assert_eq!(
+            "w $300 2".parse(),
+            Ok(DebugCommand::Watch(Watch::Memory {
+                addr: 0x300,
+                len: 2
+            }))
+        );
+        assert_eq!(
+            "poke i 345".parse(),
+            Ok(DebugCommand::Poke(Watch::I, vec![0x03, 0x45]))
+        );
+        assert_eq!(
+            "poke 300 1 ff".parse(),
+            Ok(DebugCommand::Poke(
+                Watch::Memory {
+                    addr: 0x300,
+                    len: 2
+                },
+                vec![0x01, 0xFF]
+            ))
+        );
+        assert!("poke v3 100".parse::<DebugCommand>().is_err());
+        assert!("watch vg".parse::<DebugCommand>().is_err());
+        assert!("break".parse::<DebugCommand>().is_err());
+        assert!("jump".parse::<DebugCommand>().is_err());
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut runner = runner();
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint(0x204);
+        assert_eq!(
+            debugger.resume(&mut runner, None).unwrap(),
+            Stop::Breakpoint(0x204)
+        );
+        assert_eq!(runner.machine().i(), 0x300);
+        // continuing gets past the breakpoint and round the loop to it again
+        assert_eq!(
+            debugger.resume(&mut runner, None).unwrap(),
+            Stop::Breakpoint(0x204)
+        );
+        assert_eq!(runner.machine().registers()[0], 2);
+
+        let mut debugger = Debugger::new();
+        debugger.break_on("jump");
+        assert_eq!(
+            debugger.resume(&mut runner, None).unwrap(),
+            Stop::Break(Instruction::Jump(0x200))
+        );
+        assert_eq!(
+            debugger.resume(&mut runner, Some(3)).unwrap(),
+            Stop::Stepped
+        );
+        assert_eq!(runner.machine().pc(), 0x204);
+    }
+
+    #[test]
+    fn test_watches() {
+        let mut runner = runner();
+        let mut debugger = Debugger::new();
+        debugger.add_watch(Watch::Memory {
+            addr: 0x300,
+            len: 1,
+        });
+        assert_eq!(
+            debugger.resume(&mut runner, None).unwrap(),
+            Stop::Watch {
+                watch: Watch::Memory {
+                    addr: 0x300,
+                    len: 1
+                },
+                old: vec![0],
+                new: vec![1],
+            }
+        );
+        assert_eq!(runner.machine().pc(), 0x206);
+
+        let mut debugger = Debugger::new();
+        debugger.add_watch(Watch::Register(0));
+        assert_eq!(
+            debugger.resume(&mut runner, None).unwrap(),
+            Stop::Watch {
+                watch: Watch::Register(0),
+                old: vec![1],
+                new: vec![2],
+            }
+        );
+        assert_eq!(runner.machine().pc(), 0x202);
+    }
+
+    #[test]
+    fn test_watch_stops_on_writes_of_the_same_value() {
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
+        let program = encode_program(&[
+            Instruction::LoadImmediate(0x2FF),
+            Instruction::StoreRegisters(0x1),
+            Instruction::LoadByte(0x0, 0),
+            Instruction::Jump(0x204),
+        ])
+        .unwrap();
+        machine.load_program(&program).unwrap();
+        let mut runner = Runner::new(machine, NullDisplay, NullAudio, NullInput);
+        let mut debugger = Debugger::new();
+        debugger.add_watch(Watch::Memory {
+            addr: 0x300,
+            len: 2,
+        });
+        let stop = debugger.resume(&mut runner, None).unwrap();
+        assert_eq!(
+            stop,
+            Stop::Watch {
+                watch: Watch::Memory {
+                    addr: 0x300,
+                    len: 2
+                },
+                old: vec![0, 0],
+                new: vec![0, 0],
+            }
+        );
+        assert_eq!(
+            stop.to_string(),
+            "0x0300..0x0302 written, from [00, 00] to [00, 00]"
+        );
+        assert_eq!(runner.machine().pc(), 0x204);
+
+        // a register that is set to what it already was doesn't stop
+        debugger.add_watch(Watch::Register(0x0));
+        assert_eq!(
+            debugger.resume(&mut runner, Some(10)).unwrap(),
+            Stop::Stepped
+        );
+    }
+
+    #[test]
+    fn test_session() {
+        let mut runner = runner();
+        let output = session(
+            &mut runner,
+            "poke v0 41\nwatch v0\nc\nregs\n\nstep 2\ndump 300 2\nbogus\nq\nstep\n",
+        );
+        assert!(output.starts_with("0x0200: ADD V0, 0x01\n"));
+        assert!(output.contains("V0 changed from [41] to [42]"));
+        assert!(output.contains("V0 42  V1 00"));
+        assert!(output.contains("0x0300: 42 00\n"));
+        assert!(output.contains("Unknown command: bogus"));
+        // the empty line repeated regs, then the two steps ran; q ended it
+        assert_eq!(output.matches("PC 0x").count(), 2);
+        assert_eq!(runner.machine().pc(), 0x206);
+    }
+
+    #[test]
+    fn test_poke_out_of_bounds_is_reported() {
+        let mut runner = runner();
+        let output = session(&mut runner, "poke fff 1 2\nx 1000\n");
+        assert_eq!(output.matches("Error: ").count(), 2);
+    }
+}
@@ -0,0 +1,633 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use crate::cfg::{self, LabelKind};
+use crate::instructions::{Instruction, InstructionParser};
+use crate::platform::InstructionSet;
+
+/*
+Names for registers after what the program does with them, e.g. the
+coordinates it draws at. VF is left alone, it is a flag more than anything.
+*/
+fn role(instruction: &Instruction) -> Vec<(u8, &'static str)> {
+    match *instruction {
+        Instruction::DisplaySprite(x, y, _) => vec![(x, "px"), (y, "py")],
+        Instruction::LoadFontSprite(x)
+        | Instruction::LoadBigFontSprite(x)
+        | Instruction::LoadIBCD(x) => vec![(x, "digit")],
+        Instruction::LoadFromDelay(x) | Instruction::LoadDelay(x) => vec![(x, "timer")],
+        Instruction::LoadKeyPress(x)
+        | Instruction::SkipKeyPress(x)
+        | Instruction::SkipNotKeyPress(x) => vec![(x, "input")],
+        Instruction::Random(x, _) => vec![(x, "rnd")],
+        Instruction::LoadSound(x) => vec![(x, "sound")],
+        _ => vec![],
+    }
+}
+
+/*
+The condition C of an Octo `if C then` that compiles to `skip`, which skips
+the next instruction when C is false.
+*/
+fn condition(skip: &Instruction, reg: &dyn Fn(u8) -> String) -> Option<String> {
+    Some(match *skip {
+        Instruction::SkipEqualsByte(x, kk) => format!("{} != {:#04X}", reg(x), kk),
+        Instruction::SkipNotEqualsByte(x, kk) => format!("{} == {:#04X}", reg(x), kk),
+        Instruction::SkipEqualsRegister(x, y) => format!("{} != {}", reg(x), reg(y)),
+        Instruction::SkipNotEqualRegister(x, y) => format!("{} == {}", reg(x), reg(y)),
+        Instruction::SkipNotKeyPress(x) => format!("{} key", reg(x)),
+        Instruction::SkipKeyPress(x) => format!("{} -key", reg(x)),
+        _ => return None,
+    })
+}
+
+fn is_skip(instruction: &Instruction) -> bool {
+    condition(instruction, &|x| x.to_string()).is_some()
+}
+
+// The skip that skips when `skip` doesn't, what `begin` and `while` compile to
+fn inverted(skip: &Instruction) -> Instruction {
+    match *skip {
+        Instruction::SkipEqualsByte(x, kk) => Instruction::SkipNotEqualsByte(x, kk),
+        Instruction::SkipNotEqualsByte(x, kk) => Instruction::SkipEqualsByte(x, kk),
+        Instruction::SkipEqualsRegister(x, y) => Instruction::SkipNotEqualRegister(x, y),
+        Instruction::SkipNotEqualRegister(x, y) => Instruction::SkipEqualsRegister(x, y),
+        Instruction::SkipKeyPress(x) => Instruction::SkipNotKeyPress(x),
+        Instruction::SkipNotKeyPress(x) => Instruction::SkipKeyPress(x),
+        ref other => other.clone(),
+    }
+}
+
+fn bytes(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|b| format!("{:#04X}", b)).collect();
+    bytes.join(" ")
+}
+
+enum Line {
+    Label(u16),
+    Text(usize, String),
+}
+
+struct Branch {
+    else_at: Option<u16>,
+    end: u16,
+}
+
+impl Branch {
+    // Where what is nested in the current part of the branch has to end
+    fn limit(&self) -> u16 {
+        self.else_at.unwrap_or(self.end)
+    }
+}
+
+struct Decompiler<'a> {
+    rom: &'a [u8],
+    origin: u16,
+    // The ROM in order, as instructions and as single bytes of data
+    items: Vec<(u16, Option<Instruction>)>,
+    boundaries: BTreeSet<u16>,
+    targets: BTreeSet<u16>,
+    subroutines: BTreeSet<u16>,
+    aliases: BTreeMap<u8, String>,
+    sprites: BTreeMap<u16, (usize, usize)>, // rows and bytes per row
+    loops: BTreeMap<u16, Vec<u16>>,         // the agains of the loops at each address, outer first
+    referenced: BTreeSet<u16>,
+    lines: Vec<Line>,
+    then: Option<String>,
+    open_loops: Vec<u16>,
+    branches: Vec<Branch>,
+}
+
+impl Decompiler<'_> {
+    fn reg(&self, x: u8) -> String {
+        match self.aliases.get(&x) {
+            Some(name) => name.clone(),
+            None => format!("v{:x}", x),
+        }
+    }
+
+    fn name(&self, addr: u16) -> String {
+        let kind = if addr == self.origin {
+            LabelKind::Main
+        } else if self.subroutines.contains(&addr) {
+            LabelKind::Subroutine
+        } else if self.sprites.contains_key(&addr) {
+            LabelKind::Sprite
+        } else {
+            LabelKind::Code
+        };
+        cfg::label_name(kind, addr)
+    }
+
+    // A label if there is an item to put it at, otherwise the address
+    fn target(&mut self, addr: u16) -> String {
+        if self.boundaries.contains(&addr) {
+            self.referenced.insert(addr);
+            self.name(addr)
+        } else {
+            format!("{:#05X}", addr)
+        }
+    }
+
+    fn word(&self, addr: u16) -> &[u8] {
+        let pos = usize::from(addr - self.origin);
+        &self.rom[pos..pos + 2]
+    }
+
+    fn depth(&self) -> usize {
+        self.open_loops.len() + self.branches.len()
+    }
+
+    fn flush_then(&mut self) {
+        if let Some(then) = self.then.take() {
+            self.lines.push(Line::Text(self.depth(), then));
+        }
+    }
+
+    fn push(&mut self, text: String) {
+        let text = match self.then.take() {
+            Some(then) => format!("{} {}", then, text),
+            None => text,
+        };
+        self.lines.push(Line::Text(self.depth(), text));
+    }
+
+    fn structure(&mut self, text: &str) {
+        self.flush_then();
+        self.lines
+            .push(Line::Text(self.depth(), String::from(text)));
+    }
+
+    fn statement(&mut self, addr: u16, instruction: &Instruction) -> String {
+        let reg = |x| self.reg(x);
+        match *instruction {
+            Instruction::ClearScreen => String::from("clear"),
+            Instruction::Return => String::from("return"),
+            Instruction::SYS(target) => format!("native {:#05X}", target),
+            Instruction::ScrollDown(n) => format!("scroll-down {}", n),
+            Instruction::ScrollUp(n) => format!("scroll-up {}", n),
+            Instruction::ScrollRight => String::from("scroll-right"),
+            Instruction::ScrollLeft => String::from("scroll-left"),
+            Instruction::Exit => String::from("exit"),
+            Instruction::LowRes => String::from("lores"),
+            Instruction::HighRes => String::from("hires"),
+            Instruction::Jump(target) => format!("jump {}", self.target(target)),
+            // only a label can be called, anything else stays as it was
+            Instruction::Call(target) if self.boundaries.contains(&target) => self.target(target),
+            Instruction::Call(_) => bytes(self.word(addr)),
+            Instruction::LoadByte(x, kk) => format!("{} := {:#04X}", reg(x), kk),
+            Instruction::AddByte(x, kk) => format!("{} += {:#04X}", reg(x), kk),
+            Instruction::LoadRegister(x, y) => format!("{} := {}", reg(x), reg(y)),
+            Instruction::Or(x, y) => format!("{} |= {}", reg(x), reg(y)),
+            Instruction::And(x, y) => format!("{} &= {}", reg(x), reg(y)),
+            Instruction::Xor(x, y) => format!("{} ^= {}", reg(x), reg(y)),
+            Instruction::AddRegister(x, y) => format!("{} += {}", reg(x), reg(y)),
+            Instruction::SubRegister(x, y) => format!("{} -= {}", reg(x), reg(y)),
+            Instruction::ShiftRight(x, y) => format!("{} >>= {}", reg(x), reg(y)),
+            Instruction::SubNRegister(x, y) => format!("{} =- {}", reg(x), reg(y)),
+            Instruction::ShiftLeft(x, y) => format!("{} <<= {}", reg(x), reg(y)),
+            Instruction::LoadImmediate(target) => format!("i := {}", self.target(target)),
+            Instruction::LoadLongImmediate(target) => {
+                format!("i := long {}", self.target(target))
+            }
+            Instruction::JumpBase(target) => format!("jump0 {}", self.target(target)),
+            Instruction::Random(x, kk) => format!("{} := random {:#04X}", reg(x), kk),
+            Instruction::DisplaySprite(x, y, n) => format!("sprite {} {} {}", reg(x), reg(y), n),
+            Instruction::SelectPlanes(n) => format!("plane {}", n),
+            Instruction::LoadAudioPattern => String::from("audio"),
+            Instruction::LoadFromDelay(x) => format!("{} := delay", reg(x)),
+            Instruction::LoadKeyPress(x) => format!("{} := key", reg(x)),
+            Instruction::LoadDelay(x) => format!("delay := {}", reg(x)),
+            Instruction::LoadSound(x) => format!("buzzer := {}", reg(x)),
+            Instruction::AddI(x) => format!("i += {}", reg(x)),
+            Instruction::LoadFontSprite(x) => format!("i := hex {}", reg(x)),
+            Instruction::LoadBigFontSprite(x) => format!("i := bighex {}", reg(x)),
+            Instruction::LoadIBCD(x) => format!("bcd {}", reg(x)),
+            Instruction::LoadPitch(x) => format!("pitch := {}", reg(x)),
+            Instruction::StoreRegisters(x) => format!("save {}", reg(x)),
+            Instruction::LoadRegisters(x) => format!("load {}", reg(x)),
+            Instruction::SaveRange(x, y) => format!("save {} - {}", reg(x), reg(y)),
+            Instruction::LoadRange(x, y) => format!("load {} - {}", reg(x), reg(y)),
+            Instruction::StoreFlags(x) => format!("saveflags {}", reg(x)),
+            Instruction::LoadFlags(x) => format!("loadflags {}", reg(x)),
+            // skips are written as conditions
+            Instruction::SkipEqualsByte(..)
+            | Instruction::SkipNotEqualsByte(..)
+            | Instruction::SkipEqualsRegister(..)
+            | Instruction::SkipNotEqualRegister(..)
+            | Instruction::SkipKeyPress(_)
+            | Instruction::SkipNotKeyPress(_) => bytes(self.word(addr)),
+        }
+    }
+
+    // Whether every loop is either inside of `start..end`, around it or apart from it
+    fn nests(&self, start: u16, end: u16) -> bool {
+        self.loops.iter().all(|(&head, agains)| {
+            agains.iter().all(|&again| {
+                again < start
+                    || head >= end
+                    || (start <= head && again < end)
+                    || (head <= start && again >= end)
+            })
+        })
+    }
+
+    // The jump right after the item at `index`, unless anything else flows to it
+    fn jump_after(&self, index: usize) -> Option<(u16, u16)> {
+        match self.items.get(index + 1) {
+            Some(&(addr, Some(Instruction::Jump(target))))
+                if !self.targets.contains(&addr) && !self.loops.contains_key(&addr) =>
+            {
+                Some((addr, target))
+            }
+            _ => None,
+        }
+    }
+
+    /*
+    A skip and a jump are a `while` when the jump leaves the innermost loop,
+    and an `if C begin` when it goes forward without leaving what it is in.
+    With a jump forward right before where the first one lands, there's an
+    `else` too. Returns how many items the skip took.
+    */
+    fn skip(&mut self, index: usize, skip: &Instruction) -> usize {
+        let reg = |x| self.reg(x);
+        let then = condition(skip, &reg).expect("not a skip");
+        let begin = condition(&inverted(skip), &reg).expect("not a skip");
+        if let Some((jump, target)) = self.jump_after(index) {
+            if self.open_loops.last().map(|again| again + 2) == Some(target) {
+                self.structure(&format!("while {}", begin));
+                return 2;
+            }
+            let limit = self
+                .branches
+                .last()
+                .map(Branch::limit)
+                .into_iter()
+                .chain(self.open_loops.last().copied())
+                .min()
+                .unwrap_or(u16::MAX);
+            if target > jump && target <= limit && self.boundaries.contains(&target) {
+                let start = jump - 2;
+                let else_jump = target
+                    .checked_sub(2)
+                    .filter(|&at| at > jump)
+                    .and_then(|at| self.items.iter().position(|item| item.0 == at))
+                    .filter(|&at| !self.items[at - 1].1.as_ref().is_some_and(is_skip))
+                    .and_then(|at| self.jump_after(at - 1));
+                let branch = match else_jump {
+                    Some((at, end))
+                        if end >= target
+                            && end <= limit
+                            && self.boundaries.contains(&end)
+                            && self.nests(start, end)
+                            && self.nests(start, target)
+                            && self.nests(target, end) =>
+                    {
+                        Branch {
+                            else_at: Some(at),
+                            end,
+                        }
+                    }
+                    _ => Branch {
+                        else_at: None,
+                        end: target,
+                    },
+                };
+                if self.nests(start, branch.limit()) {
+                    self.structure(&format!("if {} begin", begin));
+                    self.branches.push(branch);
+                    return 2;
+                }
+            }
+        }
+        self.flush_then();
+        self.then = Some(format!("if {} then", then));
+        1
+    }
+
+    fn data(&mut self, index: usize) -> usize {
+        let addr = self.items[index].0;
+        let run = self.items[index..]
+            .iter()
+            .enumerate()
+            .take_while(|&(n, (at, item))| {
+                item.is_none()
+                    && (n == 0
+                        || !(self.targets.contains(at)
+                            || self.sprites.contains_key(at)
+                            || self.loops.contains_key(at)))
+            })
+            .count();
+        let pos = usize::from(addr - self.origin);
+        match self.sprites.get(&addr) {
+            Some(&(rows, width)) if rows * width <= run => {
+                for row in self.rom[pos..pos + rows * width].chunks(width) {
+                    let bitmap: String = row
+                        .iter()
+                        .flat_map(|byte| (0..8).rev().map(move |bit| byte >> bit & 1))
+                        .map(|bit| if bit == 1 { '#' } else { '.' })
+                        .collect();
+                    self.push(format!("{}  # {}", bytes(row), bitmap));
+                }
+                rows * width
+            }
+            _ => {
+                let run = run.min(8);
+                self.push(bytes(&self.rom[pos..pos + run]));
+                run
+            }
+        }
+    }
+
+    fn run(&mut self) {
+        let mut index = 0;
+        while index < self.items.len() {
+            let (addr, item) = self.items[index].clone();
+            while self.branches.last().map(|b| b.end) == Some(addr) {
+                self.branches.pop();
+                self.structure("end");
+            }
+            if self.targets.contains(&addr) || addr == self.origin {
+                self.flush_then();
+                self.lines.push(Line::Label(addr));
+            }
+            for again in self.loops.get(&addr).cloned().unwrap_or_default() {
+                self.structure("loop");
+                self.open_loops.push(again);
+            }
+            let instruction = match item {
+                Some(instruction) => instruction,
+                None => {
+                    index += self.data(index);
+                    continue;
+                }
+            };
+            index += 1;
+            if self.open_loops.last() == Some(&addr) {
+                self.open_loops.pop();
+                self.structure("again");
+                continue;
+            }
+            if self.branches.last().and_then(|b| b.else_at) == Some(addr) {
+                self.branches.pop();
+                self.structure("else");
+                self.branches.push(Branch {
+                    else_at: None,
+                    end: instruction.target().expect("else without a jump"),
+                });
+                continue;
+            }
+            if is_skip(&instruction) {
+                index += self.skip(index - 1, &instruction) - 1;
+                continue;
+            }
+            let text = self.statement(addr, &instruction);
+            self.push(text);
+        }
+        self.flush_then();
+        while self.branches.pop().is_some() {
+            self.structure("end");
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut text = String::new();
+        for (x, name) in self.aliases.iter() {
+            let _ = writeln!(text, ":alias {} v{:x}", name, x);
+        }
+        for line in self.lines.iter() {
+            match line {
+                Line::Label(addr) if self.referenced.contains(addr) || *addr == self.origin => {
+                    if *addr == self.origin || self.subroutines.contains(addr) {
+                        text.push('\n');
+                    }
+                    let _ = writeln!(text, ": {}", self.name(*addr));
+                }
+                Line::Label(_) => {}
+                Line::Text(depth, line) => {
+                    let _ = writeln!(text, "{}{}", "  ".repeat(depth + 1), line);
+                }
+            }
+        }
+        text
+    }
+}
+
+/*
+Lifts the code of a ROM, loaded at `origin`, into Octo: loops, ifs and whiles
+where the jumps and skips line up as Octo would have compiled them, named
+subroutines and registers, and the sprites it draws as pictures. Assembling
+the result gives back the same ROM. The code is followed as a platform with
+the instruction set `set` would run it.
+*/
+pub fn decompile<T: InstructionParser>(
+    parser: &T,
+    rom: &[u8],
+    origin: u16,
+    set: InstructionSet,
+) -> String {
+    let graph = cfg::analyze(parser, rom, origin, set);
+    let code: BTreeMap<u16, Instruction> = graph
+        .blocks
+        .values()
+        .flat_map(|block| block.instructions.iter().cloned())
+        .collect();
+
+    let end = usize::from(origin) + rom.len();
+    let mut items = Vec::new();
+    let mut addr = usize::from(origin);
+    while addr < end {
+        match code.get(&(addr as u16)) {
+            Some(instruction) if addr + usize::from(instruction.size()) <= end => {
+                items.push((addr as u16, Some(instruction.clone())));
+                addr += usize::from(instruction.size());
+            }
+            _ => {
+                items.push((addr as u16, None));
+                addr += 1;
+            }
+        }
+    }
+    let mut boundaries: BTreeSet<u16> = items.iter().map(|item| item.0).collect();
+    boundaries.remove(&origin);
+    let targets: BTreeSet<u16> = items
+        .iter()
+        .filter_map(|(_, item)| item.as_ref().and_then(Instruction::target))
+        .collect();
+
+    // The most common use of each register names it
+    let mut roles: BTreeMap<u8, BTreeMap<&str, usize>> = BTreeMap::new();
+    for instruction in items.iter().filter_map(|item| item.1.as_ref()) {
+        for (x, role) in role(instruction) {
+            *roles.entry(x).or_default().entry(role).or_default() += 1;
+        }
+    }
+    let mut aliases = BTreeMap::new();
+    let mut taken: BTreeMap<&str, usize> = BTreeMap::new();
+    for (x, counts) in roles.into_iter().filter(|&(x, _)| x != 0xF) {
+        let (role, _) = counts
+            .into_iter()
+            .max_by_key(|&(role, count)| (count, std::cmp::Reverse(role)))
+            .expect("no roles");
+        let count = taken.entry(role).or_default();
+        *count += 1;
+        let name = match count {
+            1 => String::from(role),
+            n => format!("{}{}", role, n),
+        };
+        aliases.insert(x, name);
+    }
+
+    // What I points at when a sprite is drawn, from the load of I before it
+    let mut sprites = BTreeMap::new();
+    for block in graph.blocks.values() {
+        let mut i = None;
+        for (_, instruction) in block.instructions.iter() {
+            match *instruction {
+                Instruction::LoadImmediate(addr) => i = Some(addr),
+                Instruction::DisplaySprite(_, _, n) => {
+                    if let Some(addr) = i {
+                        let sprite = if n == 0 { (16, 2) } else { (usize::from(n), 1) };
+                        sprites.insert(addr, sprite);
+                    }
+                }
+                Instruction::AddI(_)
+                | Instruction::LoadLongImmediate(_)
+                | Instruction::LoadFontSprite(_)
+                | Instruction::LoadBigFontSprite(_)
+                | Instruction::StoreRegisters(_)
+                | Instruction::LoadRegisters(_) => i = None,
+                _ => {}
+            }
+        }
+    }
+
+    // A jump back is a loop, as long as the loops nest
+    let mut accepted: Vec<(u16, u16)> = Vec::new();
+    for &(again, ref item) in items.iter() {
+        if let Some(Instruction::Jump(head)) = item {
+            let head = *head;
+            let nests = accepted.iter().all(|&(h, a)| {
+                a < head || again < h || (h <= head && again <= a) || (head <= h && a <= again)
+            });
+            if head <= again && (head == origin || boundaries.contains(&head)) && nests {
+                accepted.push((head, again));
+            }
+        }
+    }
+    let mut loops: BTreeMap<u16, Vec<u16>> = BTreeMap::new();
+    for (head, again) in accepted {
+        loops.entry(head).or_default().push(again);
+    }
+    for agains in loops.values_mut() {
+        agains.sort_unstable_by(|a, b| b.cmp(a));
+    }
+
+    let mut decompiler = Decompiler {
+        rom,
+        origin,
+        items,
+        boundaries,
+        targets,
+        subroutines: graph.subroutines.keys().copied().collect(),
+        aliases,
+        sprites,
+        loops,
+        referenced: BTreeSet::new(),
+        lines: Vec::new(),
+        then: None,
+        open_loops: Vec::new(),
+        branches: Vec::new(),
+    };
+    decompiler.run();
+    decompiler.render()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::core::PROGRAM_OFFSET;
+    use crate::opcodes::OpcodeMaskParser;
+
+    // Decompiles the ROM, checking that it assembles back to the same bytes
+    fn round_trip(rom: &[u8]) -> String {
+        let source = decompile(
+            &OpcodeMaskParser {},
+            rom,
+            PROGRAM_OFFSET as u16,
+            InstructionSet::XoChip,
+        );
+        let assembly = assemble(&source).unwrap_or_else(|e| panic!("{}\n{}", e, source));
+        assert_eq!(assembly.rom, rom, "\n{}", source);
+        source
+    }
+
+    #[test]
+    fn test_structure() {
+        let rom = assemble(
+            ": main
+               v0 := 0
+               draw
+               loop
+                 v0 += 1
+                 if v0 == 5 then v1 := 2
+                 if v1 != 3 begin
+                   v2 := 1
+                 else
+                   v2 := 2
+                 end
+                 while v0 != 10
+                 v3 := random 0x3F
+               again
+             : draw
+               i := smile
+               sprite v3 v4 3
+               return
+             : smile
+               0x3C 0x42 0x81 0xFF",
+        )
+        .unwrap()
+        .rom;
+        let source = round_trip(&rom);
+        for expected in [
+            ":alias px v3",
+            ":alias py v4",
+            "    if v0 == 0x05 then v1 := 0x02",
+            "    if v1 != 0x03 begin",
+            "    else",
+            "    while v0 != 0x0A",
+            "  again",
+            ": sub_21C",
+            "  i := sprite_222",
+            "  sprite px py 3",
+            "  0x3C  # ..####..",
+            "  0x81  # #......#",
+            "  0xFF",
+        ] {
+            assert!(
+                source.lines().any(|line| line == expected),
+                "{}\n{}",
+                expected,
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_anything_round_trips() {
+        // jumps into the middle of instructions, stray data and odd targets
+        let mut rom = vec![
+            0x12, 0x05, 0x22, 0x07, 0x00, 0x13, 0x01, 0x60, 0x01, 0x3F, 0x00,
+        ];
+        rom.extend_from_slice(&[0xB2, 0x01, 0x00, 0x00, 0xFD, 0xA2, 0x03, 0xD0, 0x10, 0x12]);
+        round_trip(&rom);
+        for seed in 0..64u32 {
+            let rom: Vec<u8> = (0..64u32)
+                .map(|n| ((n + 1).wrapping_mul(2654435761).wrapping_mul(seed + 1) >> 13) as u8)
+                .collect();
+            round_trip(&rom);
+        }
+    }
+}
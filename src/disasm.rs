@@ -0,0 +1,222 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::cfg;
+use crate::instructions::{Instruction, InstructionParser};
+use crate::platform::InstructionSet;
+
+/*
+What a line of the disassembly holds: an instruction, or bytes that control
+flow never reaches as one and are taken for data.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum Item {
+    Instruction(Instruction),
+    Data,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub item: Item,
+}
+
+/*
+A disassembled ROM: the instructions that control flow reaches from the start
+of the ROM, in order, and the bytes in between them as data. Labels mark the
+addresses that jumps, calls and loads of I refer to.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Disassembly {
+    pub lines: Vec<Line>,
+    pub labels: BTreeMap<u16, String>,
+}
+
+/*
+Disassembles `rom` as loaded at `origin`, normally PROGRAM_OFFSET, following
+its control flow as a platform with the instruction set `set` would. Data
+comes two bytes to a line, less before an instruction or at the end.
+*/
+pub fn disassemble<T: InstructionParser>(
+    parser: &T,
+    rom: &[u8],
+    origin: u16,
+    set: InstructionSet,
+) -> Disassembly {
+    let graph = cfg::analyze(parser, rom, origin, set);
+    let code: BTreeMap<u16, &Instruction> = graph
+        .blocks
+        .values()
+        .flat_map(|block| block.instructions.iter().map(|(addr, ins)| (*addr, ins)))
+        .collect();
+
+    let mut lines = Vec::new();
+    let mut pos = 0;
+    while pos < rom.len() {
+        let addr = origin.wrapping_add(pos as u16);
+        let (size, item) = match code.get(&addr) {
+            Some(&instruction) if pos + usize::from(instruction.size()) <= rom.len() => (
+                usize::from(instruction.size()),
+                Item::Instruction(instruction.clone()),
+            ),
+            _ => {
+                let next = addr.wrapping_add(1);
+                let size = if code.contains_key(&next) { 1 } else { 2 };
+                (size.min(rom.len() - pos), Item::Data)
+            }
+        };
+        lines.push(Line {
+            addr,
+            bytes: rom[pos..pos + size].to_vec(),
+            item,
+        });
+        pos += size;
+    }
+
+    // Only addresses a line starts at get a label, the rest stay numbers
+    let starts: Vec<u16> = lines.iter().map(|line| line.addr).collect();
+    let labels = lines
+        .iter()
+        .filter_map(|line| match &line.item {
+            Item::Instruction(instruction) => instruction.target(),
+            Item::Data => None,
+        })
+        .filter(|target| starts.binary_search(target).is_ok())
+        .map(|target| (target, cfg::label_name(graph.label_kind(target), target)))
+        .collect();
+    Disassembly { lines, labels }
+}
+
+struct WithLabel<'a>(&'a Instruction, &'a str);
+
+impl fmt::Display for WithLabel<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt_with_target(f, self.1)
+    }
+}
+
+impl Line {
+    // The line as it would be written in assembly, with labels for the targets
+    pub fn text(&self, labels: &BTreeMap<u16, String>) -> String {
+        match &self.item {
+            Item::Instruction(instruction) => {
+                match instruction.target().and_then(|target| labels.get(&target)) {
+                    Some(label) => WithLabel(instruction, label).to_string(),
+                    None => instruction.to_string(),
+                }
+            }
+            Item::Data => {
+                let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:#04X}", b)).collect();
+                format!("DB {}", bytes.join(", "))
+            }
+        }
+    }
+}
+
+// One line per instruction with the address and the raw bytes in front of it
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in self.lines.iter() {
+            if let Some(label) = self.labels.get(&line.addr) {
+                writeln!(f, "{}:", label)?;
+            }
+            let raw: Vec<String> = line.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            writeln!(
+                f,
+                "{:04X}  {:<12}{}",
+                line.addr,
+                raw.join(" "),
+                line.text(&self.labels)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::PROGRAM_OFFSET;
+    use crate::opcodes::OpcodeMaskParser;
+
+    fn disassemble_rom(rom: &[u8]) -> Disassembly {
+        disassemble(
+            &OpcodeMaskParser {},
+            rom,
+            PROGRAM_OFFSET as u16,
+            InstructionSet::XoChip,
+        )
+    }
+
+    #[test]
+    fn test_labels() {
+        // CALL 0x206; JP 0x202; CLS, never reached; 0x206: LD I, 0x20A; RET; sprite
+        let rom = [
+            0x22, 0x06, 0x12, 0x02, 0x00, 0xE0, 0xA2, 0x0A, 0x00, 0xEE, 0xF0, 0x90,
+        ];
+        let disassembly = disassemble_rom(&rom);
+        assert_eq!(disassembly.labels.len(), 3);
+        assert_eq!(disassembly.labels[&0x206], "sub_206");
+        assert_eq!(
+            disassembly.lines[0].text(&disassembly.labels),
+            "CALL sub_206"
+        );
+        assert_eq!(
+            disassembly.lines[1].text(&disassembly.labels),
+            "JP label_202"
+        );
+        assert_eq!(
+            disassembly.lines[2].text(&disassembly.labels),
+            "DB 0x00, 0xE0"
+        );
+        assert_eq!(
+            disassembly.lines[3].text(&disassembly.labels),
+            "LD I, data_20A"
+        );
+        assert_eq!(disassembly.lines[5].item, Item::Data);
+        let text = disassembly.to_string();
+        assert!(text.starts_with("0200  22 06       CALL sub_206\nlabel_202:\n0202  12 02"));
+        assert!(text.contains("sub_206:\n0206  A2 0A       LD I, data_20A\n"));
+    }
+
+    #[test]
+    fn test_data_and_targets_outside_the_rom() {
+        // SYS 0x123; LD I, 0xFFFF; JP 0x300; an opcode that doesn't decode; an odd byte
+        let rom = [
+            0x01, 0x23, 0xF0, 0x00, 0xFF, 0xFF, 0x13, 0x00, 0xE0, 0x00, 0xAB,
+        ];
+        let disassembly = disassemble_rom(&rom);
+        assert!(disassembly.labels.is_empty());
+        let text: Vec<String> = disassembly
+            .lines
+            .iter()
+            .map(|line| line.text(&disassembly.labels))
+            .collect();
+        assert_eq!(
+            text,
+            [
+                "SYS 0x123",
+                "LD I, 0xFFFF",
+                "JP 0x300",
+                "DB 0xE0, 0x00",
+                "DB 0xAB"
+            ]
+        );
+        assert_eq!(disassembly.lines[1].bytes.len(), 4);
+        assert_eq!(disassembly.lines[4].addr, 0x20A);
+    }
+
+    #[test]
+    fn test_data_stops_at_code() {
+        // JP 0x203; a byte of padding; CLS; JP 0x203
+        let rom = [0x12, 0x03, 0xFF, 0x00, 0xE0, 0x12, 0x03];
+        let disassembly = disassemble_rom(&rom);
+        let text: Vec<String> = disassembly
+            .lines
+            .iter()
+            .map(|line| line.text(&disassembly.labels))
+            .collect();
+        assert_eq!(text, ["JP label_203", "DB 0xFF", "CLS", "JP label_203"]);
+    }
+}
@@ -1,20 +1,58 @@
-extern crate sdl2;
+#[cfg(feature = "sdl")]
+use sdl2::render::Canvas;
+#[cfg(feature = "sdl")]
+use sdl2::video::Window;
 
-use self::sdl2::render::Canvas;
-use self::sdl2::video::Window;
+use crate::core::GraphicsMemory;
+#[cfg(feature = "sdl")]
+use crate::core::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
 
-use crate::core::{GraphicsMemory, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+/*
+Anything that can show the contents of the VRAM to the user.
+*/
+pub trait Display {
+    fn draw(&mut self, graphics: &GraphicsMemory);
+}
+
+/*
+A display that throws every frame away, for running headless.
+*/
+#[derive(Default)]
+pub struct NullDisplay;
+
+impl Display for NullDisplay {
+    fn draw(&mut self, _graphics: &GraphicsMemory) {}
+}
+
+/*
+A display that keeps a copy of every frame it was asked to draw, so that
+tests and tools can inspect what would have been on screen.
+*/
+#[derive(Default)]
+pub struct RecordingDisplay {
+    pub frames: Vec<GraphicsMemory>,
+}
 
-/**
+impl Display for RecordingDisplay {
+    fn draw(&mut self, graphics: &GraphicsMemory) {
+        self.frames.push(graphics.clone());
+    }
+}
+
+/*
 The video sub-system used to render things on a canvas via sdl2
 
 SDL2 Reference: https://docs.rs/sdl2/0.32.2/sdl2/
 */
 
+#[cfg(feature = "sdl")]
 const SCALE: u32 = 16;
+#[cfg(feature = "sdl")]
 const WINDOW_WIDTH: u32 = (DISPLAY_WIDTH as u32) * SCALE;
+#[cfg(feature = "sdl")]
 const WINDOW_HEIGHT: u32 = (DISPLAY_HEIGHT as u32) * SCALE;
 
+#[cfg(feature = "sdl")]
 lazy_static! {
     static ref COLOR_RED: sdl2::pixels::Color = sdl2::pixels::Color::RGB(255, 0, 0);
     static ref COLOR_BLUE: sdl2::pixels::Color = sdl2::pixels::Color::RGB(0, 0, 255);
@@ -23,15 +61,23 @@ lazy_static! {
     static ref COLOR_WHITE: sdl2::pixels::Color = sdl2::pixels::Color::RGB(255, 255, 255);
 }
 
+#[cfg(feature = "sdl")]
 pub struct VideoDisplay {
     pub canvas: sdl2::render::Canvas<Window>,
+    // Size of a pixel in the window, the window stays the same size when the
+    // machine switches to the 128x64 mode of SUPER-CHIP
+    scale: u32,
 }
 
+#[cfg(feature = "sdl")]
 impl VideoDisplay {
     pub fn new(sdl_context: &sdl2::Sdl) -> Self {
         let video_subsystem: sdl2::VideoSubsystem = sdl_context.video().unwrap();
         let canvas: Canvas<Window> = VideoDisplay::get_canvas(&video_subsystem);
-        VideoDisplay { canvas }
+        VideoDisplay {
+            canvas,
+            scale: SCALE,
+        }
     }
 
     pub fn init_window(video: &sdl2::VideoSubsystem) -> Window {
@@ -56,10 +102,10 @@ impl VideoDisplay {
     Draw a 1x1 rectangle to represent a pixel. Might not work but lets give it a try.
     */
     pub fn draw_pixel(&mut self, x: usize, y: usize) {
-        let rect_x = (x * SCALE as usize) as i32;
-        let rect_y = (y * SCALE as usize) as i32;
-        let rect_width = SCALE;
-        let rect_height = SCALE;
+        let rect_x = (x * self.scale as usize) as i32;
+        let rect_y = (y * self.scale as usize) as i32;
+        let rect_width = self.scale;
+        let rect_height = self.scale;
         let rect = sdl2::rect::Rect::new(rect_y, rect_x, rect_width, rect_height);
         let canvas = &mut self.canvas;
         match canvas.fill_rect(rect) {
@@ -67,34 +113,29 @@ impl VideoDisplay {
             Err(e) => error!("Could not fill in the rectangle: {}", e),
         }
     }
+}
 
+#[cfg(feature = "sdl")]
+impl Display for VideoDisplay {
     /*
     Iterate over the entire VRAM and draw each pixel as a rectangle.
     */
-    pub fn draw(&mut self, graphics: &GraphicsMemory) {
-        for i in 0..DISPLAY_HEIGHT {
-            for j in 0..DISPLAY_WIDTH {
-                let pixel = graphics.mem[i][j];
-                if pixel != 0 {
-                    let canvas = &mut self.canvas;
-                    canvas.set_draw_color(*COLOR_RED);
-                } else {
-                    let canvas = &mut self.canvas;
-                    canvas.set_draw_color(*COLOR_GREEN);
-                }
+    fn draw(&mut self, graphics: &GraphicsMemory) {
+        self.scale = WINDOW_WIDTH / graphics.width() as u32;
+        for i in 0..graphics.height() {
+            for j in 0..graphics.width() {
+                // XO-CHIP pixels can be set in either plane, or both
+                let color = match graphics.get(j, i) {
+                    0 => *COLOR_GREEN,
+                    1 => *COLOR_RED,
+                    2 => *COLOR_BLUE,
+                    _ => *COLOR_BLACK,
+                };
+                self.canvas.set_draw_color(color);
                 self.draw_pixel(i, j);
             }
         }
         let canvas = &mut self.canvas;
         canvas.present();
     }
-
-    pub fn clear(&mut self, graphics: &mut GraphicsMemory) {
-        self.canvas.clear();
-        for i in 0..DISPLAY_HEIGHT {
-            for j in 0..DISPLAY_WIDTH {
-                graphics.mem[i][j] = 0;
-            }
-        }
-    }
 }
@@ -0,0 +1,155 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::instructions::Instruction;
+
+/*
+Everything that can go wrong while loading or running a program.
+*/
+#[derive(Debug)]
+pub enum Chip8Error {
+    // A CALL with all 16 stack entries in use
+    StackOverflow,
+    // A RET with an empty stack
+    StackUnderflow,
+    // An instruction tried to read or write memory past the end of RAM
+    MemoryOutOfBounds {
+        addr: usize,
+    },
+    // The opcode does not decode to any instruction. The PC is unknown when the
+    // error comes straight from an InstructionParser.
+    InvalidOpcode {
+        opcode: u16,
+        pc: Option<u16>,
+    },
+    // The instruction belongs to an extension the platform does not have
+    UnsupportedInstruction {
+        instruction: Instruction,
+        pc: u16,
+    },
+    // An operand of the instruction is too large for its field in the opcode
+    InvalidOperand {
+        instruction: Instruction,
+        operand: &'static str,
+    },
+    // The PC points outside of memory
+    PcOutOfBounds(u16),
+    // A DRW instruction with a height that does not fit in a nibble
+    InvalidSpriteHeight(u8),
+    // The ROM does not fit in the memory after the program offset
+    RomTooLarge {
+        size: usize,
+        max: usize,
+    },
+    Io(io::Error),
+    // A save state that can't be read, e.g. from another version
+    InvalidSaveState(String),
+    // A save state that was made with a different ROM, identified by their hashes
+    SaveStateMismatch {
+        expected: u64,
+        found: u64,
+    },
+    // A movie file that can't be parsed or doesn't fit the ROM; line 0 is the header
+    InvalidMovie {
+        line: usize,
+        reason: String,
+    },
+    // A replay ended up in another state than the recording did
+    MovieDesync {
+        frame: u64,
+        expected: u64,
+        found: u64,
+    },
+    // A binary trace that can't be read back
+    InvalidTrace(String),
+    // Assembly source that doesn't assemble, at a 1-based line and column
+    Assembly {
+        line: usize,
+        column: usize,
+        reason: String,
+    },
+    // The frontend asked the machine to stop, e.g. the window was closed
+    Quit,
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Chip8Error::StackOverflow => write!(f, "stack overflow"),
+            Chip8Error::StackUnderflow => write!(f, "stack underflow"),
+            Chip8Error::MemoryOutOfBounds { addr } => {
+                write!(f, "memory access out of bounds at {:#X}", addr)
+            }
+            Chip8Error::InvalidOpcode {
+                opcode,
+                pc: Some(pc),
+            } => write!(f, "invalid opcode {:04X} at {:#X}", opcode, pc),
+            Chip8Error::InvalidOpcode { opcode, pc: None } => {
+                write!(f, "invalid opcode {:04X}", opcode)
+            }
+            Chip8Error::InvalidOperand {
+                instruction,
+                operand,
+            } => write!(
+                f,
+                "the {} of {:?} doesn't fit in its field",
+                operand, instruction
+            ),
+            Chip8Error::UnsupportedInstruction { instruction, pc } => write!(
+                f,
+                "{:?} at {:#X} is not supported on this platform",
+                instruction, pc
+            ),
+            Chip8Error::PcOutOfBounds(pc) => write!(f, "PC out of bounds: {:#X}", pc),
+            Chip8Error::InvalidSpriteHeight(h) => write!(f, "invalid sprite height {}", h),
+            Chip8Error::RomTooLarge { size, max } => write!(
+                f,
+                "ROM of {} bytes does not fit in {} bytes of memory",
+                size, max
+            ),
+            Chip8Error::Io(e) => write!(f, "{}", e),
+            Chip8Error::InvalidSaveState(reason) => write!(f, "invalid save state: {}", reason),
+            Chip8Error::SaveStateMismatch { expected, found } => write!(
+                f,
+                "save state belongs to ROM {:016X}, not to the loaded ROM {:016X}",
+                found, expected
+            ),
+            Chip8Error::InvalidMovie { line: 0, reason } => write!(f, "invalid movie: {}", reason),
+            Chip8Error::InvalidMovie { line, reason } => {
+                write!(f, "invalid movie, line {}: {}", line, reason)
+            }
+            Chip8Error::InvalidTrace(reason) => write!(f, "invalid trace: {}", reason),
+            Chip8Error::Assembly {
+                line,
+                column,
+                reason,
+            } => write!(f, "line {}, column {}: {}", line, column, reason),
+            Chip8Error::MovieDesync {
+                frame,
+                expected,
+                found,
+            } => write!(
+                f,
+                "replay out of sync at frame {}: state {:016X} instead of {:016X}",
+                frame, found, expected
+            ),
+            Chip8Error::Quit => write!(f, "quit"),
+        }
+    }
+}
+
+impl Error for Chip8Error {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Chip8Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Chip8Error {
+    fn from(e: io::Error) -> Self {
+        Chip8Error::Io(e)
+    }
+}
@@ -0,0 +1,542 @@
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::audio::AudioSink;
+use crate::core::Machine;
+use crate::debugger::{Debugger, Stop};
+use crate::display::Display;
+use crate::error::Chip8Error;
+use crate::instructions::InstructionParser;
+use crate::keyboard::InputSource;
+use crate::runner::Runner;
+
+/*
+The registers as gdb sees them, in the order of `g` packets: V0 to VF, I, PC,
+the depth of the stack and the two timers. 16-bit registers are sent in
+little endian.
+*/
+const REGISTER_SIZES: [usize; 21] = [
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 1, 1, 1,
+];
+const I_REGISTER: usize = 16;
+const PC_REGISTER: usize = 17;
+const SP_REGISTER: usize = 18;
+const DT_REGISTER: usize = 19;
+const ST_REGISTER: usize = 20;
+
+// Signals in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+// Sent on 0x03 by gdb to stop a running target
+const INTERRUPT: u8 = 0x03;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chip8.core">
+    <reg name="v0" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="v1" bitsize="8" type="uint8"/>
+    <reg name="v2" bitsize="8" type="uint8"/>
+    <reg name="v3" bitsize="8" type="uint8"/>
+    <reg name="v4" bitsize="8" type="uint8"/>
+    <reg name="v5" bitsize="8" type="uint8"/>
+    <reg name="v6" bitsize="8" type="uint8"/>
+    <reg name="v7" bitsize="8" type="uint8"/>
+    <reg name="v8" bitsize="8" type="uint8"/>
+    <reg name="v9" bitsize="8" type="uint8"/>
+    <reg name="va" bitsize="8" type="uint8"/>
+    <reg name="vb" bitsize="8" type="uint8"/>
+    <reg name="vc" bitsize="8" type="uint8"/>
+    <reg name="vd" bitsize="8" type="uint8"/>
+    <reg name="ve" bitsize="8" type="uint8"/>
+    <reg name="vf" bitsize="8" type="uint8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="dt" bitsize="8" type="uint8"/>
+    <reg name="st" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|n| u8::from_str_radix(text.get(n..n + 2)?, 16).ok())
+        .collect()
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0, u8::wrapping_add)
+}
+
+// "addr,len" as in m and M packets
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let (addr, len) = text.split_once(',')?;
+    Some((
+        usize::from_str_radix(addr, 16).ok()?,
+        usize::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+fn read_register<T: InstructionParser>(machine: &Machine<T>, n: usize) -> Option<Vec<u8>> {
+    Some(match n {
+        0..=15 => vec![machine.registers()[n]],
+        I_REGISTER => machine.i().to_le_bytes().to_vec(),
+        PC_REGISTER => machine.pc().to_le_bytes().to_vec(),
+        SP_REGISTER => vec![machine.stack_pointer()],
+        DT_REGISTER => vec![machine.delay_timer()],
+        ST_REGISTER => vec![machine.sound_timer()],
+        _ => return None,
+    })
+}
+
+// Returns false when there is no such register or it can't take the value
+fn write_register<T: InstructionParser>(machine: &mut Machine<T>, n: usize, value: &[u8]) -> bool {
+    if REGISTER_SIZES.get(n) != Some(&value.len()) {
+        return false;
+    }
+    match n {
+        0..=15 => return machine.set_register(n as u8, value[0]),
+        I_REGISTER => machine.set_i(u16::from_le_bytes([value[0], value[1]])),
+        PC_REGISTER => machine.set_pc(u16::from_le_bytes([value[0], value[1]])),
+        SP_REGISTER => return machine.set_stack_pointer(value[0]),
+        DT_REGISTER => machine.set_delay_timer(value[0]),
+        ST_REGISTER => machine.set_sound_timer(value[0]),
+        _ => return false,
+    }
+    true
+}
+
+/*
+Reads the next packet, acknowledging it. Anything outside of a packet, e.g.
+acks or a stray interrupt, is skipped. Returns None once gdb hangs up.
+*/
+fn read_packet(stream: &mut TcpStream) -> Result<Option<String>, Chip8Error> {
+    let mut byte = [0];
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        if byte[0] != b'$' {
+            continue;
+        }
+        let mut data = Vec::new();
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+        let mut sum = [0; 2];
+        stream.read_exact(&mut sum)?;
+        let data = String::from_utf8_lossy(&data).into_owned();
+        let expected = std::str::from_utf8(&sum)
+            .ok()
+            .and_then(|sum| u8::from_str_radix(sum, 16).ok());
+        if expected == Some(checksum(&data)) {
+            stream.write_all(b"+")?;
+            return Ok(Some(data));
+        }
+        stream.write_all(b"-")?;
+    }
+}
+
+// Sends a packet and waits for gdb to acknowledge it, resending it on a nak
+fn write_packet(stream: &mut TcpStream, data: &str) -> Result<(), Chip8Error> {
+    let packet = format!("${}#{:02x}", data, checksum(data));
+    let mut byte = [0];
+    loop {
+        stream.write_all(packet.as_bytes())?;
+        stream.flush()?;
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(());
+            }
+            match byte[0] {
+                b'+' => return Ok(()),
+                b'-' => break,
+                _ => {}
+            }
+        }
+    }
+}
+
+// Whether gdb sent an interrupt, without waiting for one
+fn poll_interrupt(stream: &mut TcpStream) -> bool {
+    let mut byte = [0];
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let mut interrupted = false;
+    loop {
+        match stream.read(&mut byte) {
+            Ok(1) if byte[0] == INTERRUPT => interrupted = true,
+            Ok(1) => {}
+            // nothing to read, or a hang up that the next read_packet notices
+            _ => break,
+        }
+    }
+    let _ = stream.set_nonblocking(false);
+    interrupted
+}
+
+/*
+Serves a single gdb connection over the GDB remote serial protocol: register
+and memory reads and writes, single steps, continuing and software
+breakpoints. There is no CHIP-8 architecture in gdb, so the registers are
+described by the target.xml it asks for; frontends that go by that
+description can show them by name.
+*/
+#[derive(Default)]
+pub struct GdbStub {
+    debugger: Debugger,
+}
+
+impl GdbStub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Runs at the speed of the machine while continuing, for use with a window
+    pub fn realtime() -> Self {
+        GdbStub {
+            debugger: Debugger::realtime(),
+        }
+    }
+
+    // Waits for gdb to connect to the port on localhost, then serves it
+    pub fn listen<T, D, A, I>(
+        &mut self,
+        runner: &mut Runner<T, D, A, I>,
+        port: u16,
+    ) -> Result<(), Chip8Error>
+    where
+        T: InstructionParser,
+        D: Display,
+        A: AudioSink,
+        I: InputSource,
+    {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        info!("Waiting for gdb on {}", listener.local_addr()?);
+        let (stream, addr) = listener.accept()?;
+        info!("gdb connected from {}", addr);
+        self.serve(runner, stream)
+    }
+
+    // Returns when gdb detaches, kills the target or hangs up
+    pub fn serve<T, D, A, I>(
+        &mut self,
+        runner: &mut Runner<T, D, A, I>,
+        mut stream: TcpStream,
+    ) -> Result<(), Chip8Error>
+    where
+        T: InstructionParser,
+        D: Display,
+        A: AudioSink,
+        I: InputSource,
+    {
+        stream.set_nodelay(true)?;
+        while let Some(packet) = read_packet(&mut stream)? {
+            debug!("gdb: {}", packet);
+            match packet.as_str() {
+                "D" => return write_packet(&mut stream, "OK"),
+                "k" => return Ok(()),
+                _ => {}
+            }
+            let reply = self.handle(runner, &packet, &mut stream)?;
+            write_packet(&mut stream, &reply)?;
+        }
+        Ok(())
+    }
+
+    fn handle<T, D, A, I>(
+        &mut self,
+        runner: &mut Runner<T, D, A, I>,
+        packet: &str,
+        stream: &mut TcpStream,
+    ) -> Result<String, Chip8Error>
+    where
+        T: InstructionParser,
+        D: Display,
+        A: AudioSink,
+        I: InputSource,
+    {
+        const ERROR: &str = "E01";
+        let args = packet.get(1..).unwrap_or("");
+        let reply = match packet.chars().next() {
+            Some('?') => format!("S{:02x}", SIGTRAP),
+            Some('g') => {
+                let machine = runner.machine();
+                (0..REGISTER_SIZES.len())
+                    .filter_map(|n| read_register(machine, n))
+                    .map(|bytes| to_hex(&bytes))
+                    .collect()
+            }
+            Some('G') => match from_hex(args) {
+                Some(bytes) if bytes.len() == REGISTER_SIZES.iter().sum::<usize>() => {
+                    let mut rest = &bytes[..];
+                    let mut ok = true;
+                    for (n, size) in REGISTER_SIZES.iter().enumerate() {
+                        let (value, tail) = rest.split_at(*size);
+                        ok &= write_register(runner.machine_mut(), n, value);
+                        rest = tail;
+                    }
+                    String::from(if ok { "OK" } else { ERROR })
+                }
+                _ => String::from(ERROR),
+            },
+            Some('p') => usize::from_str_radix(args, 16)
+                .ok()
+                .and_then(|n| read_register(runner.machine(), n))
+                .map_or_else(|| String::from(ERROR), |bytes| to_hex(&bytes)),
+            Some('P') => {
+                let written = args.split_once('=').and_then(|(n, value)| {
+                    let n = usize::from_str_radix(n, 16).ok()?;
+                    Some(write_register(runner.machine_mut(), n, &from_hex(value)?))
+                });
+                String::from(if written == Some(true) { "OK" } else { ERROR })
+            }
+            Some('m') => {
+                let memory = runner.machine().memory();
+                // a length near usize::MAX must not wrap around past the end
+                match parse_range(args)
+                    .and_then(|(addr, len)| memory.get(addr..addr.checked_add(len)?))
+                {
+                    Some(bytes) => to_hex(bytes),
+                    None => String::from(ERROR),
+                }
+            }
+            Some('M') => {
+                let written = args.split_once(':').and_then(|(range, data)| {
+                    let (addr, len) = parse_range(range)?;
+                    let bytes = from_hex(data).filter(|bytes| bytes.len() == len)?;
+                    let addr = u16::try_from(addr).ok()?;
+                    runner.machine_mut().write_memory(addr, &bytes).ok()
+                });
+                String::from(if written.is_some() { "OK" } else { ERROR })
+            }
+            Some(c @ 's') | Some(c @ 'c') => {
+                // both may give an address to resume at
+                if let Ok(addr) = u16::from_str_radix(args, 16) {
+                    runner.machine_mut().set_pc(addr);
+                }
+                let steps = if c == 's' { Some(1) } else { None };
+                self.resume(runner, steps, stream)?
+            }
+            Some('Z') | Some('z') => match args.strip_prefix("0,").and_then(parse_range) {
+                Some((addr, _)) => match u16::try_from(addr) {
+                    Ok(addr) if packet.starts_with('Z') => {
+                        self.debugger.add_breakpoint(addr);
+                        String::from("OK")
+                    }
+                    Ok(addr) => {
+                        self.debugger.remove_breakpoint(addr);
+                        String::from("OK")
+                    }
+                    Err(_) => String::from(ERROR),
+                },
+                // hardware breakpoints and watchpoints are not supported
+                None => String::new(),
+            },
+            Some('H') | Some('T') => String::from("OK"),
+            Some('q') => self.query(packet),
+            // everything else is unsupported, which gdb knows to deal with
+            _ => String::new(),
+        };
+        Ok(reply)
+    }
+
+    fn query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return String::from("PacketSize=1000;qXfer:features:read+");
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return match parse_range(range) {
+                Some((offset, len)) => {
+                    let xml = TARGET_XML.as_bytes();
+                    let start = offset.min(xml.len());
+                    let end = start.saturating_add(len).min(xml.len());
+                    let more = if end < xml.len() { "m" } else { "l" };
+                    format!("{}{}", more, &TARGET_XML[start..end])
+                }
+                None => String::from("E01"),
+            };
+        }
+        match packet {
+            "qAttached" => String::from("1"),
+            "qC" => String::from("QC1"),
+            "qfThreadInfo" => String::from("m1"),
+            "qsThreadInfo" => String::from("l"),
+            _ => String::new(),
+        }
+    }
+
+    /*
+    Runs the machine and turns the reason it stopped into a stop reply. An
+    error of the machine is reported to gdb as an illegal instruction, so the
+    state it stopped in can be looked at.
+    */
+    fn resume<T, D, A, I>(
+        &mut self,
+        runner: &mut Runner<T, D, A, I>,
+        steps: Option<u64>,
+        stream: &mut TcpStream,
+    ) -> Result<String, Chip8Error>
+    where
+        T: InstructionParser,
+        D: Display,
+        A: AudioSink,
+        I: InputSource,
+    {
+        let stop = self
+            .debugger
+            .resume_until(runner, steps, || poll_interrupt(stream));
+        Ok(match stop {
+            Ok(Stop::Halted) => String::from("W00"),
+            Ok(Stop::Requested) => format!("S{:02x}", SIGINT),
+            Ok(_) => format!("S{:02x}", SIGTRAP),
+            Err(Chip8Error::Quit) => return Err(Chip8Error::Quit),
+            Err(e) => {
+                error!("Stopped on an error: {}", e);
+                format!("S{:02x}", SIGILL)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::NullAudio;
+    use crate::display::NullDisplay;
+    use crate::keyboard::NullInput;
+    use crate::opcodes::OpcodeMaskParser;
+    use crate::platform::SCHIP_1_1;
+    use crate::quirks::Quirks;
+    use std::thread;
+
+    // A scripted gdb: sends each packet and collects the replies
+    fn client(stream: &mut TcpStream, packets: &[&str]) -> Vec<String> {
+        packets
+            .iter()
+            .map(|packet| {
+                write!(stream, "${}#{:02x}", packet, checksum(packet)).unwrap();
+                let mut byte = [0];
+                stream.read_exact(&mut byte).unwrap();
+                assert_eq!(byte[0], b'+');
+                if *packet == "k" {
+                    return String::new();
+                }
+                read_packet(stream).unwrap().unwrap()
+            })
+            .collect()
+    }
+
+    fn session(
+        mut machine: Machine<OpcodeMaskParser>,
+        program: &[u8],
+        packets: &'static [&'static str],
+    ) -> Vec<String> {
+        machine.load_program(program).unwrap();
+        let mut runner = Runner::new(machine, NullDisplay, NullAudio, NullInput);
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let gdb = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.set_nodelay(true).unwrap();
+            client(&mut stream, packets)
+        });
+        let (stream, _) = listener.accept().unwrap();
+        GdbStub::new().serve(&mut runner, stream).unwrap();
+        gdb.join().unwrap()
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(to_hex(&[0x00, 0xAB]), "00ab");
+        assert_eq!(from_hex("00aB"), Some(vec![0x00, 0xAB]));
+        assert_eq!(from_hex("0"), None);
+        assert_eq!(from_hex("zz"), None);
+        assert_eq!(checksum("OK"), 0x9A);
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        // LD V0, 0x12; LD I, 0x345
+        let machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
+        let replies = session(
+            machine,
+            &[0x60, 0x12, 0xA3, 0x45],
+            &[
+                "qSupported:xmlRegisters=i386",
+                "?",
+                "s",
+                "s",
+                "g",
+                "p11",
+                "P3=7f",
+                "p3",
+                "P11=0003",
+                "p11",
+                "M300,2:beef",
+                "m300,2",
+                "m1000,1",
+                "qXfer:features:read:target.xml:0,10",
+                "vMustReplyEmpty",
+                "m1,ffffffffffffffff",
+                "qXfer:features:read:target.xml:1,ffffffffffffffff",
+                "k",
+            ],
+        );
+        assert!(replies[0].contains("qXfer:features:read+"));
+        assert_eq!(replies[1], "S05");
+        assert_eq!(replies[2], "S05");
+        assert_eq!(
+            replies[4],
+            format!("12{}{}{}", "00".repeat(15), "4503", "0402000000")
+        );
+        assert_eq!(replies[5], "0402");
+        assert_eq!(replies[6], "OK");
+        assert_eq!(replies[7], "7f");
+        assert_eq!(replies[9], "0003");
+        assert_eq!(replies[10], "OK");
+        assert_eq!(replies[11], "beef");
+        assert_eq!(replies[12], "E01");
+        assert_eq!(replies[13], "m<?xml version=\"1");
+        assert_eq!(replies[14], "");
+        assert_eq!(replies[15], "E01");
+        assert_eq!(replies[16], format!("l{}", &TARGET_XML[1..]));
+    }
+
+    #[test]
+    fn test_breakpoints_and_exit() {
+        // ADD V0, 1; SE V0, 3; JP 0x200; EXIT
+        let machine = Machine::with_platform("TestVM", OpcodeMaskParser {}, &SCHIP_1_1);
+        let replies = session(
+            machine,
+            &[0x70, 0x01, 0x30, 0x03, 0x12, 0x00, 0x00, 0xFD],
+            &["Z0,204,2", "c", "p0", "z0,204,2", "Z1,204,2", "c", "p0"],
+        );
+        assert_eq!(replies[0], "OK");
+        assert_eq!(replies[1], "S05");
+        assert_eq!(replies[2], "01");
+        assert_eq!(replies[3], "OK");
+        assert_eq!(replies[4], "");
+        assert_eq!(replies[5], "W00");
+        assert_eq!(replies[6], "03");
+    }
+}
@@ -1,3 +1,11 @@
+use std::fmt;
+
+use crate::error::Chip8Error;
+use crate::isa;
+use crate::lookup::LookupTableParser;
+use crate::opcodes::OpcodeMaskParser;
+use crate::opcodesv2::OpcodeTable;
+
 type Address = u16;
 type Register = u8;
 type Data = u8;
@@ -6,12 +14,21 @@ type Data = u8;
 pub enum Instruction {
     ClearScreen,                              // 00E0 - CLS
     Return,                                   // 00EE - RET
-    SYS,                                      // 0nnn - SYS addr
+    SYS(Address),                             // 0nnn - SYS addr
+    ScrollDown(u8),                           // 00Cn - SCD nibble (SUPER-CHIP)
+    ScrollRight,                              // 00FB - SCR (SUPER-CHIP)
+    ScrollLeft,                               // 00FC - SCL (SUPER-CHIP)
+    Exit,                                     // 00FD - EXIT (SUPER-CHIP)
+    LowRes,                                   // 00FE - LOW (SUPER-CHIP)
+    HighRes,                                  // 00FF - HIGH (SUPER-CHIP)
+    ScrollUp(u8),                             // 00Dn - SCU nibble (XO-CHIP)
     Jump(Address),                            // 1nnn - JP addr
     Call(Address),                            // 2nnn - CALL addr
     SkipEqualsByte(Register, Data),           // 3xkk - SE vx, byte
     SkipNotEqualsByte(Register, Data),        // 4xkk - SNE Vx, byte
     SkipEqualsRegister(Register, Register),   // 5xy0 - SE Vx, Vy
+    SaveRange(Register, Register),            // 5xy2 - SAVE Vx - Vy (XO-CHIP)
+    LoadRange(Register, Register),            // 5xy3 - LOAD Vx - Vy (XO-CHIP)
     LoadByte(Register, Data),                 // 6xkk - LD Vx, byte
     AddByte(Register, Data),                  // 7xkk - ADD Vx, byte
     LoadRegister(Register, Register),         // 8xy0 - LD Vx, Vy
@@ -20,27 +37,319 @@ pub enum Instruction {
     Xor(Register, Register),                  // 8xy3 - XOR Vx, Vy
     AddRegister(Register, Register),          // 8xy4 - ADD Vx, Vy
     SubRegister(Register, Register),          // 8xy5 - SUB Vx, Vy
-    ShiftRight(Register),                     // 8xy6 - SHR Vx
+    ShiftRight(Register, Register),           // 8xy6 - SHR Vx {, Vy}
     SubNRegister(Register, Register),         // 8xy7 - SUBN Vx, Vy
-    ShiftLeft(Register),                      // 8xyE - SHL Vx
+    ShiftLeft(Register, Register),            // 8xyE - SHL Vx {, Vy}
     SkipNotEqualRegister(Register, Register), // 9xy0 - SNE Vx, Vy
     LoadImmediate(Address),                   // Annn - LD I, addr
+    LoadLongImmediate(Address),               // F000 nnnn - LD I, long addr (XO-CHIP)
     JumpBase(Address),                        // Bnnn - JP V0, address
     Random(Register, Data),                   // Cxkk - RND Vx, byte
-    DisplaySprite(Register, Register, u8),    // Dxyn - DRW Vx, Vy, nibble
-    SkipKeyPress(Register),                   // Ex9E - SKP Vx
-    SkipNotKeyPress(Register),                // ExA1 - SKNP Vx
-    LoadFromDelay(Register),                  // Fx07 - LD Vx, DT
-    LoadKeyPress(Register),                   // Fx0A - LD Vx, K
-    LoadDelay(Register),                      // Fx15 - LD DT, Vx
-    LoadSound(Register),                      // Fx18 - LD ST, Vx
-    AddI(Register),                           // Fx1E - ADD I, Vx
-    LoadFontSprite(Register),                 // Fx29 - LD F, Vx
-    LoadIBCD(Register),                       // Fx33 - LD B, Vx
-    StoreRegisters(Register),                 // Fx55 - LD [I], Vx
-    LoadRegisters(Register),                  // Fx65 - LD Vx, [I]
+    DisplaySprite(Register, Register, u8), // Dxyn - DRW Vx, Vy, nibble (Dxy0 is 16x16 on SUPER-CHIP)
+    SkipKeyPress(Register),                // Ex9E - SKP Vx
+    SkipNotKeyPress(Register),             // ExA1 - SKNP Vx
+    SelectPlanes(u8),                      // Fn01 - PLANE n (XO-CHIP)
+    LoadAudioPattern,                      // F002 - AUDIO (XO-CHIP)
+    LoadFromDelay(Register),               // Fx07 - LD Vx, DT
+    LoadKeyPress(Register),                // Fx0A - LD Vx, K
+    LoadDelay(Register),                   // Fx15 - LD DT, Vx
+    LoadSound(Register),                   // Fx18 - LD ST, Vx
+    AddI(Register),                        // Fx1E - ADD I, Vx
+    LoadFontSprite(Register),              // Fx29 - LD F, Vx
+    LoadBigFontSprite(Register),           // Fx30 - LD HF, Vx (SUPER-CHIP)
+    LoadIBCD(Register),                    // Fx33 - LD B, Vx
+    LoadPitch(Register),                   // Fx3A - PITCH Vx (XO-CHIP)
+    StoreRegisters(Register),              // Fx55 - LD [I], Vx
+    LoadRegisters(Register),               // Fx65 - LD Vx, [I]
+    StoreFlags(Register),                  // Fx75 - LD R, Vx (SUPER-CHIP)
+    LoadFlags(Register),                   // Fx85 - LD Vx, R (SUPER-CHIP)
+}
+
+impl Instruction {
+    // Number of bytes the instruction takes up in memory
+    pub fn size(&self) -> u16 {
+        match self {
+            Instruction::LoadLongImmediate(_) => 4,
+            _ => 2,
+        }
+    }
+
+    /*
+    The inverse of InstructionParser: the bytes of the opcode, four of them for
+    F000 NNNN and two for everything else. Fails when an operand doesn't fit
+    its field, e.g. a register above VF or an address above 0xFFF.
+    */
+    pub fn encode(&self) -> Result<Vec<u8>, Chip8Error> {
+        isa::encode(self)
+    }
+
+    // The address of code or data the instruction refers to, if any
+    pub fn target(&self) -> Option<Address> {
+        match *self {
+            Instruction::Jump(addr)
+            | Instruction::Call(addr)
+            | Instruction::JumpBase(addr)
+            | Instruction::LoadImmediate(addr)
+            | Instruction::LoadLongImmediate(addr) => Some(addr),
+            _ => None,
+        }
+    }
+
+    /*
+    Writes the instruction in assembly, with the target written as `target`
+    instead of as an address. The disassembler uses this for labels.
+    */
+    pub fn fmt_with_target(&self, f: &mut fmt::Formatter<'_>, target: &str) -> fmt::Result {
+        isa::format(self, f, self.target().map(|_| target))
+    }
+}
+
+/*
+Encodes the instructions one after the other into a program, e.g. for tests.
+*/
+pub fn encode_program(instructions: &[Instruction]) -> Result<Vec<u8>, Chip8Error> {
+    let mut program = Vec::new();
+    for instruction in instructions {
+        program.extend(instruction.encode()?);
+    }
+    Ok(program)
+}
+
+/*
+The mnemonics of Cowan's CHIP-8 technical reference, which most assemblers
+understand, extended with those of SUPER-CHIP and XO-CHIP. They are listed
+with the opcodes in isa.rs.
+*/
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        isa::format(self, f, None)
+    }
 }
 
 pub trait InstructionParser {
-    fn try_from(&self, opcode: u16) -> Result<Instruction, String>;
+    fn try_from(&self, opcode: u16) -> Result<Instruction, Chip8Error>;
+
+    /*
+    Decodes an instruction given the word after it as well. XO-CHIP's
+    F000 NNNN is the only instruction that is two words long, everything
+    else is left to try_from.
+    */
+    fn try_from_words(&self, opcode: u16, next: u16) -> Result<Instruction, Chip8Error> {
+        match opcode {
+            0xF000 => Ok(Instruction::LoadLongImmediate(next)),
+            _ => self.try_from(opcode),
+        }
+    }
+}
+
+// So that machines can pick their parser at run time
+impl<T: InstructionParser + ?Sized> InstructionParser for Box<T> {
+    fn try_from(&self, opcode: u16) -> Result<Instruction, Chip8Error> {
+        (**self).try_from(opcode)
+    }
+
+    fn try_from_words(&self, opcode: u16, next: u16) -> Result<Instruction, Chip8Error> {
+        (**self).try_from_words(opcode, next)
+    }
+}
+
+pub const PARSERS: [&str; 3] = ["mask", "table", "lookup"];
+
+// The parser called `name`
+pub fn parser_by_name(name: &str) -> Option<Box<dyn InstructionParser>> {
+    match name {
+        "mask" => Some(Box::new(OpcodeMaskParser {})),
+        "table" => Some(Box::new(OpcodeTable {})),
+        "lookup" => Some(Box::new(LookupTableParser::new())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every instruction with every operand that fits
+    fn all_instructions() -> Vec<Instruction> {
+        use Instruction::*;
+        let mut all = vec![
+            ClearScreen,
+            Return,
+            SYS(0x000),
+            SYS(0x123),
+            SYS(0xFFF),
+            ScrollRight,
+            ScrollLeft,
+            Exit,
+            LowRes,
+            HighRes,
+            LoadAudioPattern,
+        ];
+        let nibbles = || 0..=0xFu8;
+        for n in nibbles() {
+            all.extend(vec![ScrollDown(n), ScrollUp(n), SelectPlanes(n)]);
+        }
+        for addr in 0..=0xFFF {
+            all.extend(vec![
+                Jump(addr),
+                Call(addr),
+                LoadImmediate(addr),
+                JumpBase(addr),
+            ]);
+        }
+        all.extend((0..=0xFFFF).map(LoadLongImmediate));
+        let byte_ops: [fn(u8, u8) -> Instruction; 5] =
+            [SkipEqualsByte, SkipNotEqualsByte, LoadByte, AddByte, Random];
+        let pair_ops: [fn(u8, u8) -> Instruction; 14] = [
+            SkipEqualsRegister,
+            SaveRange,
+            LoadRange,
+            LoadRegister,
+            Or,
+            And,
+            Xor,
+            AddRegister,
+            SubRegister,
+            ShiftRight,
+            SubNRegister,
+            ShiftLeft,
+            SkipNotEqualRegister,
+            |x, y| DisplaySprite(x, y, 0),
+        ];
+        let register_ops: [fn(u8) -> Instruction; 17] = [
+            SkipKeyPress,
+            SkipNotKeyPress,
+            LoadFromDelay,
+            LoadKeyPress,
+            LoadDelay,
+            LoadSound,
+            AddI,
+            LoadFontSprite,
+            LoadBigFontSprite,
+            LoadIBCD,
+            LoadPitch,
+            StoreRegisters,
+            LoadRegisters,
+            StoreFlags,
+            LoadFlags,
+            |x| DisplaySprite(x, x, 0xF),
+            |x| DisplaySprite(0, 0, x),
+        ];
+        for x in nibbles() {
+            for op in register_ops.iter() {
+                all.push(op(x));
+            }
+            for kk in 0..=0xFF {
+                all.extend(byte_ops.iter().map(|op| op(x, kk)));
+            }
+            for y in nibbles() {
+                all.extend(pair_ops.iter().map(|op| op(x, y)));
+            }
+        }
+        all
+    }
+
+    #[test]
+    fn test_encode_round_trips() {
+        let parsers: [&dyn InstructionParser; 2] = [&OpcodeMaskParser {}, &OpcodeTable {}];
+        for instruction in all_instructions() {
+            let bytes = instruction.encode().unwrap();
+            assert_eq!(bytes.len(), usize::from(instruction.size()));
+            let opcode = u16::from_be_bytes([bytes[0], bytes[1]]);
+            let next = match bytes[..] {
+                [_, _, high, low] => u16::from_be_bytes([high, low]),
+                _ => 0,
+            };
+            for parser in parsers.iter() {
+                assert_eq!(
+                    parser.try_from_words(opcode, next).unwrap(),
+                    instruction,
+                    "{:04X}",
+                    opcode
+                );
+            }
+        }
+    }
+
+    /*
+    Every parser has to decode every opcode, with any word after it, the same
+    way. Disagreements are listed as a table of what each parser made of them.
+    */
+    #[test]
+    fn test_parsers_agree() {
+        let parsers: [(&str, &dyn InstructionParser); 3] = [
+            ("OpcodeMaskParser", &OpcodeMaskParser {}),
+            ("OpcodeTable", &OpcodeTable {}),
+            ("LookupTableParser", &LookupTableParser::new()),
+        ];
+        let mut table = String::new();
+        for opcode in 0..=u16::MAX {
+            for &next in [0x0000, 0x1234].iter() {
+                let decoded: Vec<String> = parsers
+                    .iter()
+                    .map(|(_, parser)| match parser.try_from_words(opcode, next) {
+                        Ok(instruction) => format!("{:?}", instruction),
+                        Err(_) => String::from("invalid"),
+                    })
+                    .collect();
+                if decoded.iter().any(|d| *d != decoded[0]) {
+                    table.push_str(&format!("{:04X} {:04X}", opcode, next));
+                    for d in decoded {
+                        table.push_str(&format!(" | {:<26}", d));
+                    }
+                    table.push('\n');
+                }
+            }
+        }
+        let names: Vec<String> = parsers
+            .iter()
+            .map(|(name, _)| format!("{:<26}", name))
+            .collect();
+        assert!(
+            table.is_empty(),
+            "the parsers disagree on:\nopcode    | {}\n{}",
+            names.join(" | "),
+            table
+        );
+    }
+
+    #[test]
+    fn test_encode_rejects_operands_that_dont_fit() {
+        let too_large = [
+            Instruction::Jump(0x1000),
+            Instruction::LoadByte(0x10, 0),
+            Instruction::Or(0, 0x10),
+            Instruction::DisplaySprite(0, 0, 0x10),
+            Instruction::ScrollDown(0x10),
+            Instruction::SelectPlanes(0x10),
+            Instruction::LoadFlags(0xFF),
+        ];
+        for instruction in too_large.iter() {
+            assert!(matches!(
+                instruction.encode(),
+                Err(Chip8Error::InvalidOperand { .. })
+            ));
+        }
+        assert_eq!(
+            encode_program(&[Instruction::LoadByte(3, 0x20), Instruction::Jump(0x2A4)]).unwrap(),
+            [0x63, 0x20, 0x12, 0xA4]
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Instruction::LoadByte(3, 0x20).to_string(), "LD V3, 0x20");
+        assert_eq!(
+            Instruction::DisplaySprite(0, 1, 5).to_string(),
+            "DRW V0, V1, 5"
+        );
+        assert_eq!(Instruction::Jump(0x2A4).to_string(), "JP 0x2A4");
+        assert_eq!(Instruction::LoadImmediate(0x050).to_string(), "LD I, 0x050");
+        assert_eq!(
+            Instruction::LoadLongImmediate(0xABCD).to_string(),
+            "LD I, 0xABCD"
+        );
+        assert_eq!(Instruction::StoreRegisters(0xF).to_string(), "LD [I], VF");
+        assert_eq!(Instruction::SaveRange(1, 0xA).to_string(), "SAVE V1 - VA");
+    }
 }
@@ -0,0 +1,376 @@
+use std::fmt;
+
+use crate::error::Chip8Error;
+use crate::instructions::Instruction;
+
+/*
+An operand field of an opcode pattern: the run of one letter in it, e.g. the
+`kk` of 3xkk. Patterns are laid out in 32 bits, the opcode in the high word and
+the word after it, if any, in the low one.
+*/
+#[derive(Debug, Clone, Copy)]
+pub struct Field {
+    letter: u8,
+    shift: u32,
+    width: u32,
+}
+
+impl Field {
+    // Finds the field named `name` in the pattern, failing to compile if it isn't there
+    pub const fn new(pattern: &str, name: &str) -> Field {
+        let letter = name.as_bytes()[0];
+        let pattern = pattern.as_bytes();
+        let mut i = 0;
+        let mut last = None;
+        let mut width = 0;
+        while i < pattern.len() {
+            if pattern[i] == letter {
+                last = Some(i);
+                width += 1;
+            }
+            i += 1;
+        }
+        match last {
+            Some(last) => Field {
+                letter,
+                shift: 4 * (7 - last as u32),
+                width,
+            },
+            None => panic!("operand missing from the opcode pattern"),
+        }
+    }
+
+    fn max(&self) -> u32 {
+        (1 << (4 * self.width)) - 1
+    }
+
+    fn is_register(&self) -> bool {
+        self.letter == b'x' || self.letter == b'y'
+    }
+
+    fn kind(&self) -> &'static str {
+        match self.width {
+            _ if self.is_register() => "register",
+            1 => "nibble",
+            2 => "byte",
+            _ => "address",
+        }
+    }
+
+    pub fn extract(&self, words: u32) -> u32 {
+        (words >> self.shift) & self.max()
+    }
+
+    fn insert(&self, instruction: &Instruction, value: u32) -> Result<u32, Chip8Error> {
+        if value > self.max() {
+            return Err(Chip8Error::InvalidOperand {
+                instruction: instruction.clone(),
+                operand: self.kind(),
+            });
+        }
+        Ok(value << self.shift)
+    }
+
+    /*
+    Registers are written as VX, nibbles in decimal and anything wider in hex
+    with all of its digits.
+    */
+    fn write(&self, f: &mut fmt::Formatter<'_>, value: u32) -> fmt::Result {
+        match self.width {
+            _ if self.is_register() => write!(f, "V{:X}", value),
+            1 => write!(f, "{}", value),
+            width => write!(f, "{:#0w$X}", value, w = width as usize + 2),
+        }
+    }
+}
+
+const fn pattern_bits(pattern: &str, fixed: bool) -> u32 {
+    let pattern = pattern.as_bytes();
+    let mut bits = 0;
+    let mut i = 0;
+    while i < pattern.len() {
+        let nibble = match pattern[i] {
+            digit @ b'0'..=b'9' => Some(digit - b'0'),
+            digit @ b'A'..=b'F' => Some(digit - b'A' + 10),
+            _ => None,
+        };
+        let value = match nibble {
+            Some(_) if !fixed => 0xF,
+            Some(nibble) => nibble as u32,
+            None => 0,
+        };
+        bits |= value << (4 * (7 - i));
+        i += 1;
+    }
+    bits
+}
+
+/*
+One opcode of the instruction set as the isa! table below describes it.
+*/
+pub struct Opcode {
+    pub name: &'static str,
+    pub pattern: &'static str,
+    pub mnemonic: &'static str,
+    pub extension: &'static str,
+    bits: u32,
+    mask: u32,
+    decode: fn(u32) -> Instruction,
+}
+
+impl Opcode {
+    // Number of bytes the opcode takes up in memory
+    pub fn size(&self) -> u16 {
+        self.pattern.len() as u16 / 2
+    }
+
+    // Decodes the opcode, given the word after it, if it matches the pattern
+    pub fn decode(&self, opcode: u16, next: u16) -> Option<Instruction> {
+        let words = u32::from(opcode) << 16 | u32::from(next);
+        if words & self.mask == self.bits {
+            Some((self.decode)(words))
+        } else {
+            None
+        }
+    }
+}
+
+/*
+Writes a mnemonic with its {x} style placeholders filled in, or with the
+address replaced by `target` if one is given.
+*/
+fn write_mnemonic(
+    f: &mut fmt::Formatter<'_>,
+    mnemonic: &str,
+    operands: &[(Field, u32)],
+    target: Option<&str>,
+) -> fmt::Result {
+    let mut parts = mnemonic.split(['{', '}']);
+    while let Some(text) = parts.next() {
+        f.write_str(text)?;
+        let name = match parts.next() {
+            Some(name) => name.as_bytes()[0],
+            None => break,
+        };
+        let (field, value) = operands
+            .iter()
+            .find(|(field, _)| field.letter == name)
+            .expect("mnemonic placeholder without an operand");
+        match target {
+            Some(target) if field.width >= 3 => f.write_str(target)?,
+            _ => field.write(f, *value)?,
+        }
+    }
+    Ok(())
+}
+
+macro_rules! field {
+    ($pattern:literal, $field:ident) => {{
+        const FIELD: Field = Field::new($pattern, stringify!($field));
+        FIELD
+    }};
+}
+
+// Matches any operand, for patterns that only care about the variant
+macro_rules! any {
+    ($field:ident) => {
+        _
+    };
+}
+
+/*
+Generates, from one line per opcode, the OPCODES table that OpcodeTable decodes
+with, the encoder behind Instruction::encode, the mnemonics of Instruction's
+Display and the names of the variants. Operands are named after the letters
+of the pattern they fill.
+*/
+macro_rules! isa {
+    ($($variant:ident $(($($field:ident),+))? = $pattern:literal, $mnemonic:literal, $extension:literal;)+) => {
+        pub static OPCODES: &[Opcode] = &[$(
+            Opcode {
+                name: stringify!($variant),
+                pattern: $pattern,
+                mnemonic: $mnemonic,
+                extension: $extension,
+                bits: pattern_bits($pattern, true),
+                mask: pattern_bits($pattern, false),
+                decode: |_words| {
+                    Instruction::$variant $(($(field!($pattern, $field).extract(_words) as _),+))?
+                },
+            },
+        )+];
+
+        pub(crate) fn encode(instruction: &Instruction) -> Result<Vec<u8>, Chip8Error> {
+            let (words, pattern) = match *instruction {
+                $(Instruction::$variant $(($($field),+))? => {
+                    let words = pattern_bits($pattern, true);
+                    $($(
+                        let words =
+                            words | field!($pattern, $field).insert(instruction, u32::from($field))?;
+                    )+)?
+                    (words, $pattern)
+                })+
+            };
+            Ok(words.to_be_bytes()[..pattern.len() / 2].to_vec())
+        }
+
+        pub(crate) fn format(
+            instruction: &Instruction,
+            f: &mut fmt::Formatter<'_>,
+            target: Option<&str>,
+        ) -> fmt::Result {
+            match *instruction {
+                $(Instruction::$variant $(($($field),+))? => write_mnemonic(
+                    f,
+                    $mnemonic,
+                    &[$($((field!($pattern, $field), u32::from($field))),+)?],
+                    target,
+                ),)+
+            }
+        }
+
+        // The name of the variant, e.g. LoadImmediate, which OPCODES has it under
+        pub fn name(instruction: &Instruction) -> &'static str {
+            match *instruction {
+                $(Instruction::$variant $(($(any!($field)),+))? => stringify!($variant),)+
+            }
+        }
+
+        // The mnemonic with its operands as placeholders, e.g. LD I, {n}
+        pub fn mnemonic(instruction: &Instruction) -> &'static str {
+            match *instruction {
+                $(Instruction::$variant $(($(any!($field)),+))? => $mnemonic,)+
+            }
+        }
+    };
+}
+
+/*
+The instruction set. Hex digits in a pattern are fixed, letters are operands:
+x and y registers, kk a byte and n a nibble or, three or four wide, an address.
+The first pattern that matches decodes the opcode, so 0nnn comes after the
+other 0 opcodes.
+*/
+isa! {
+    ClearScreen = "00E0", "CLS", "CHIP-8";
+    Return = "00EE", "RET", "CHIP-8";
+    ScrollDown(n) = "00Cn", "SCD {n}", "SUPER-CHIP";
+    ScrollUp(n) = "00Dn", "SCU {n}", "XO-CHIP";
+    ScrollRight = "00FB", "SCR", "SUPER-CHIP";
+    ScrollLeft = "00FC", "SCL", "SUPER-CHIP";
+    Exit = "00FD", "EXIT", "SUPER-CHIP";
+    LowRes = "00FE", "LOW", "SUPER-CHIP";
+    HighRes = "00FF", "HIGH", "SUPER-CHIP";
+    SYS(n) = "0nnn", "SYS {n}", "CHIP-8";
+    Jump(n) = "1nnn", "JP {n}", "CHIP-8";
+    Call(n) = "2nnn", "CALL {n}", "CHIP-8";
+    SkipEqualsByte(x, k) = "3xkk", "SE {x}, {k}", "CHIP-8";
+    SkipNotEqualsByte(x, k) = "4xkk", "SNE {x}, {k}", "CHIP-8";
+    SkipEqualsRegister(x, y) = "5xy0", "SE {x}, {y}", "CHIP-8";
+    SaveRange(x, y) = "5xy2", "SAVE {x} - {y}", "XO-CHIP";
+    LoadRange(x, y) = "5xy3", "LOAD {x} - {y}", "XO-CHIP";
+    LoadByte(x, k) = "6xkk", "LD {x}, {k}", "CHIP-8";
+    AddByte(x, k) = "7xkk", "ADD {x}, {k}", "CHIP-8";
+    LoadRegister(x, y) = "8xy0", "LD {x}, {y}", "CHIP-8";
+    Or(x, y) = "8xy1", "OR {x}, {y}", "CHIP-8";
+    And(x, y) = "8xy2", "AND {x}, {y}", "CHIP-8";
+    Xor(x, y) = "8xy3", "XOR {x}, {y}", "CHIP-8";
+    AddRegister(x, y) = "8xy4", "ADD {x}, {y}", "CHIP-8";
+    SubRegister(x, y) = "8xy5", "SUB {x}, {y}", "CHIP-8";
+    ShiftRight(x, y) = "8xy6", "SHR {x}, {y}", "CHIP-8";
+    SubNRegister(x, y) = "8xy7", "SUBN {x}, {y}", "CHIP-8";
+    ShiftLeft(x, y) = "8xyE", "SHL {x}, {y}", "CHIP-8";
+    SkipNotEqualRegister(x, y) = "9xy0", "SNE {x}, {y}", "CHIP-8";
+    LoadImmediate(n) = "Annn", "LD I, {n}", "CHIP-8";
+    JumpBase(n) = "Bnnn", "JP V0, {n}", "CHIP-8";
+    Random(x, k) = "Cxkk", "RND {x}, {k}", "CHIP-8";
+    DisplaySprite(x, y, n) = "Dxyn", "DRW {x}, {y}, {n}", "CHIP-8";
+    SkipKeyPress(x) = "Ex9E", "SKP {x}", "CHIP-8";
+    SkipNotKeyPress(x) = "ExA1", "SKNP {x}", "CHIP-8";
+    LoadLongImmediate(n) = "F000nnnn", "LD I, {n}", "XO-CHIP";
+    SelectPlanes(n) = "Fn01", "PLANE {n}", "XO-CHIP";
+    LoadAudioPattern = "F002", "AUDIO", "XO-CHIP";
+    LoadFromDelay(x) = "Fx07", "LD {x}, DT", "CHIP-8";
+    LoadKeyPress(x) = "Fx0A", "LD {x}, K", "CHIP-8";
+    LoadDelay(x) = "Fx15", "LD DT, {x}", "CHIP-8";
+    LoadSound(x) = "Fx18", "LD ST, {x}", "CHIP-8";
+    AddI(x) = "Fx1E", "ADD I, {x}", "CHIP-8";
+    LoadFontSprite(x) = "Fx29", "LD F, {x}", "CHIP-8";
+    LoadBigFontSprite(x) = "Fx30", "LD HF, {x}", "SUPER-CHIP";
+    LoadIBCD(x) = "Fx33", "LD B, {x}", "CHIP-8";
+    LoadPitch(x) = "Fx3A", "PITCH {x}", "XO-CHIP";
+    StoreRegisters(x) = "Fx55", "LD [I], {x}", "CHIP-8";
+    LoadRegisters(x) = "Fx65", "LD {x}, [I]", "CHIP-8";
+    StoreFlags(x) = "Fx75", "LD R, {x}", "SUPER-CHIP";
+    LoadFlags(x) = "Fx85", "LD {x}, R", "SUPER-CHIP";
+}
+
+/*
+A reference of every opcode as a Markdown table, with the operands of the
+mnemonics written the way the patterns name them.
+*/
+pub fn reference() -> String {
+    let mut text = String::from(
+        "| Opcode | Mnemonic | Instruction | Extension |\n|--------|----------|-------------|-----------|\n",
+    );
+    for opcode in OPCODES.iter() {
+        let mut mnemonic = String::new();
+        for (i, part) in opcode.mnemonic.split(['{', '}']).enumerate() {
+            if i % 2 == 0 {
+                mnemonic.push_str(part);
+                continue;
+            }
+            let field = Field::new(opcode.pattern, part);
+            if field.is_register() {
+                mnemonic.push('V');
+                mnemonic.push_str(part);
+            } else {
+                mnemonic.extend(std::iter::repeat_n(
+                    char::from(field.letter),
+                    field.width as usize,
+                ));
+            }
+        }
+        text.push_str(&format!(
+            "| {} | {} | {} | {} |\n",
+            opcode.pattern, mnemonic, opcode.name, opcode.extension
+        ));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fields() {
+        let field = Field::new("3xkk", "k");
+        assert_eq!(field.extract(0x3A42_0000), 0x42);
+        let field = Field::new("F000nnnn", "n");
+        assert_eq!(field.extract(0xF000_BEEF), 0xBEEF);
+        assert_eq!(pattern_bits("Fn01", true), 0xF001_0000);
+        assert_eq!(pattern_bits("Fn01", false), 0xF0FF_0000);
+    }
+
+    #[test]
+    fn test_reference() {
+        let reference = reference();
+        assert_eq!(reference.lines().count(), OPCODES.len() + 2);
+        assert!(reference.contains("| 3xkk | SE Vx, kk | SkipEqualsByte | CHIP-8 |\n"));
+        assert!(reference.contains("| F000nnnn | LD I, nnnn | LoadLongImmediate | XO-CHIP |\n"));
+        assert!(reference.contains("| Dxyn | DRW Vx, Vy, n | DisplaySprite | CHIP-8 |\n"));
+    }
+
+    #[test]
+    fn test_names() {
+        let instruction = Instruction::LoadLongImmediate(0xBEEF);
+        assert_eq!(name(&instruction), "LoadLongImmediate");
+        assert_eq!(mnemonic(&instruction), "LD I, {n}");
+        for opcode in OPCODES.iter() {
+            let instruction = (opcode.decode)(pattern_bits(opcode.pattern, true));
+            assert_eq!(name(&instruction), opcode.name);
+            assert_eq!(mnemonic(&instruction), opcode.mnemonic);
+        }
+    }
+}
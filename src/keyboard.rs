@@ -1,30 +1,192 @@
-extern crate sdl2;
-
+#[cfg(feature = "sdl")]
 use std::collections::HashMap;
 
+use crate::core::KEY_SIZE;
+use crate::error::Chip8Error;
+
+/*
+Requests from the user to the emulator itself rather than to the program.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    SaveState(u8), // quick-save to the numbered slot
+    LoadState(u8), // quick-load from the numbered slot
+    Rewind,        // step back in time, given every frame for as long as it lasts
+    Break,         // pause and drop into the debugger, when there is one
+}
+
+/*
+Anything that can tell the machine which of the 16 keys are held down.
+Returning an error stops the machine, e.g. Chip8Error::Quit when the user
+closes the window.
+*/
+pub trait InputSource {
+    fn poll(&mut self) -> Result<[bool; KEY_SIZE], Chip8Error>;
+
+    // The commands given since the last call, for input sources that have any
+    fn commands(&mut self) -> Vec<Command> {
+        Vec::new()
+    }
+}
+
+/*
+An input source on which no key is ever pressed, for running headless.
+*/
+#[derive(Default)]
+pub struct NullInput;
+
+impl InputSource for NullInput {
+    fn poll(&mut self) -> Result<[bool; KEY_SIZE], Chip8Error> {
+        Ok([false; KEY_SIZE])
+    }
+}
+
+/*
+Wraps another input source and remembers the key state it reported on every poll.
+*/
+pub struct RecordingInput<I: InputSource> {
+    pub inner: I,
+    pub history: Vec<[bool; KEY_SIZE]>,
+}
+
+impl<I: InputSource> RecordingInput<I> {
+    pub fn new(inner: I) -> Self {
+        Self {
+            inner,
+            history: Vec::new(),
+        }
+    }
+}
+
+impl<I: InputSource> InputSource for RecordingInput<I> {
+    fn poll(&mut self) -> Result<[bool; KEY_SIZE], Chip8Error> {
+        let keys = self.inner.poll()?;
+        self.history.push(keys);
+        Ok(keys)
+    }
+
+    fn commands(&mut self) -> Vec<Command> {
+        self.inner.commands()
+    }
+}
+
+#[cfg(feature = "sdl")]
 pub struct KeyMap {
     pub keymap: HashMap<sdl2::keyboard::Keycode, usize>,
 }
 
+#[cfg(feature = "sdl")]
 impl KeyMap {
     pub fn new() -> Self {
         let mut keys: HashMap<sdl2::keyboard::Keycode, usize> = HashMap::new();
-        keys.insert(sdl2::keyboard::Keycode::Num0, 0x0 as usize);
-        keys.insert(sdl2::keyboard::Keycode::Num1, 0x1 as usize);
-        keys.insert(sdl2::keyboard::Keycode::Num2, 0x2 as usize);
-        keys.insert(sdl2::keyboard::Keycode::Num3, 0x3 as usize);
-        keys.insert(sdl2::keyboard::Keycode::Num4, 0x4 as usize);
-        keys.insert(sdl2::keyboard::Keycode::Num5, 0x5 as usize);
-        keys.insert(sdl2::keyboard::Keycode::Num6, 0x6 as usize);
-        keys.insert(sdl2::keyboard::Keycode::Num7, 0x7 as usize);
-        keys.insert(sdl2::keyboard::Keycode::Num8, 0x8 as usize);
-        keys.insert(sdl2::keyboard::Keycode::Num9, 0x9 as usize);
-        keys.insert(sdl2::keyboard::Keycode::A, 0xA as usize);
-        keys.insert(sdl2::keyboard::Keycode::B, 0xB as usize);
-        keys.insert(sdl2::keyboard::Keycode::C, 0xC as usize);
-        keys.insert(sdl2::keyboard::Keycode::D, 0xD as usize);
-        keys.insert(sdl2::keyboard::Keycode::E, 0xE as usize);
-        keys.insert(sdl2::keyboard::Keycode::F, 0xF as usize);
+        keys.insert(sdl2::keyboard::Keycode::Num0, 0x0);
+        keys.insert(sdl2::keyboard::Keycode::Num1, 0x1);
+        keys.insert(sdl2::keyboard::Keycode::Num2, 0x2);
+        keys.insert(sdl2::keyboard::Keycode::Num3, 0x3);
+        keys.insert(sdl2::keyboard::Keycode::Num4, 0x4);
+        keys.insert(sdl2::keyboard::Keycode::Num5, 0x5);
+        keys.insert(sdl2::keyboard::Keycode::Num6, 0x6);
+        keys.insert(sdl2::keyboard::Keycode::Num7, 0x7);
+        keys.insert(sdl2::keyboard::Keycode::Num8, 0x8);
+        keys.insert(sdl2::keyboard::Keycode::Num9, 0x9);
+        keys.insert(sdl2::keyboard::Keycode::A, 0xA);
+        keys.insert(sdl2::keyboard::Keycode::B, 0xB);
+        keys.insert(sdl2::keyboard::Keycode::C, 0xC);
+        keys.insert(sdl2::keyboard::Keycode::D, 0xD);
+        keys.insert(sdl2::keyboard::Keycode::E, 0xE);
+        keys.insert(sdl2::keyboard::Keycode::F, 0xF);
         KeyMap { keymap: keys }
     }
 }
+
+#[cfg(feature = "sdl")]
+impl Default for KeyMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/*
+Reads the keyboard of the SDL window. F1 to F9 load the quick-save slots 1 to
+9, and save to them with Shift held down. Holding Backspace rewinds.
+*/
+#[cfg(feature = "sdl")]
+pub struct SdlInput {
+    pump: sdl2::EventPump,
+    keymap: KeyMap,
+    commands: Vec<Command>,
+}
+
+#[cfg(feature = "sdl")]
+impl SdlInput {
+    pub fn new(sdl_context: &sdl2::Sdl) -> Self {
+        Self {
+            pump: sdl_context.event_pump().unwrap(),
+            keymap: KeyMap::new(),
+            commands: Vec::new(),
+        }
+    }
+}
+
+#[cfg(feature = "sdl")]
+impl InputSource for SdlInput {
+    fn poll(&mut self) -> Result<[bool; KEY_SIZE], Chip8Error> {
+        use sdl2::event::Event;
+        use sdl2::keyboard::{Keycode, Mod};
+
+        const SLOT_KEYS: [Keycode; 9] = [
+            Keycode::F1,
+            Keycode::F2,
+            Keycode::F3,
+            Keycode::F4,
+            Keycode::F5,
+            Keycode::F6,
+            Keycode::F7,
+            Keycode::F8,
+            Keycode::F9,
+        ];
+        for event in self.pump.poll_iter() {
+            match event {
+                Event::Quit { .. } => return Err(Chip8Error::Quit),
+                Event::KeyDown {
+                    keycode: Some(key),
+                    keymod,
+                    repeat: false,
+                    ..
+                } => {
+                    if key == Keycode::F12 {
+                        self.commands.push(Command::Break);
+                    } else if let Some(slot) = SLOT_KEYS.iter().position(|k| *k == key) {
+                        let slot = slot as u8 + 1;
+                        if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                            self.commands.push(Command::SaveState(slot));
+                        } else {
+                            self.commands.push(Command::LoadState(slot));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        // ref: https://github.com/Rust-SDL2/rust-sdl2/blob/master/examples/keyboard-state.rs
+        let mut keyboard = [false; KEY_SIZE];
+        let state = self.pump.keyboard_state();
+        let keys = state
+            .pressed_scancodes()
+            .filter_map(sdl2::keyboard::Keycode::from_scancode);
+        for key in keys {
+            if key == Keycode::Backspace {
+                self.commands.push(Command::Rewind);
+            }
+            if let Some(chip8_key) = self.keymap.keymap.get(&key) {
+                keyboard[*chip8_key] = true; // store the activated key in the keyboard
+                debug!("Got a chip8 key = {:?}", chip8_key);
+            }
+        }
+        Ok(keyboard)
+    }
+
+    fn commands(&mut self) -> Vec<Command> {
+        std::mem::take(&mut self.commands)
+    }
+}
@@ -0,0 +1,64 @@
+/*!
+A CHIP-8 virtual machine.
+
+The crate is split into the emulator core, which can be driven one instruction
+at a time from any program, and frontends that connect it to a display, an
+audio sink and an input source. The SDL frontend used by the `chip8` binary is
+behind the default `sdl` feature; headless null and recording frontends are
+always available.
+
+```no_run
+use chip8::core::Machine;
+use chip8::opcodes::OpcodeMaskParser;
+use chip8::quirks::Quirks;
+
+let mut vm = Machine::new("Chip8", OpcodeMaskParser {}, Quirks::default());
+vm.load_rom("roms/games/Pong.ch8").unwrap();
+vm.tick().unwrap();
+println!("PC = {:X}, V = {:?}", vm.pc(), vm.registers());
+```
+*/
+
+#[macro_use]
+extern crate log;
+#[cfg(feature = "sdl")]
+#[macro_use]
+extern crate lazy_static;
+
+pub mod assembler;
+pub mod audio;
+pub(crate) mod bitmasks;
+pub mod cfg;
+pub mod core;
+pub mod debugger;
+pub mod decompiler;
+pub mod disasm;
+pub mod display;
+pub mod error;
+pub mod font;
+pub mod gdbstub;
+pub mod instructions;
+pub mod isa;
+pub mod keyboard;
+pub mod lint;
+pub mod lockstep;
+pub mod lookup;
+pub mod movie;
+pub mod opcodes;
+pub mod opcodesv2;
+pub mod platform;
+pub mod quirks;
+pub mod rewind;
+pub mod rng;
+pub mod runner;
+pub mod savestate;
+pub mod tracer;
+
+pub use crate::core::Machine;
+pub use crate::error::Chip8Error;
+pub use crate::instructions::{Instruction, InstructionParser};
+pub use crate::opcodes::OpcodeMaskParser;
+pub use crate::platform::Platform;
+pub use crate::quirks::Quirks;
+pub use crate::rng::RandomSource;
+pub use crate::runner::Runner;
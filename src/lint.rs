@@ -0,0 +1,366 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::cfg::{self, ControlFlowGraph};
+use crate::core::STACK_SIZE;
+use crate::font::{BIG_FONT_ADDRESS, BIG_FONT_GLYPH_SIZE, FONT_ADDRESS};
+use crate::instructions::{Instruction, InstructionParser};
+use crate::platform::InstructionSet;
+
+// Both fonts, which programs read their digits from and shouldn't write over
+const FONT_END: usize = BIG_FONT_ADDRESS + 16 * BIG_FONT_GLYPH_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning, // works, but not the same way everywhere
+    Error,   // breaks the program on some or all interpreters
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Lint {
+    OddJump {
+        target: u16,
+    },
+    OutOfRange {
+        target: u16,
+    },
+    CallDepth {
+        depth: usize,
+    },
+    Recursion {
+        target: u16,
+    },
+    Sys,
+    UndefinedOpcode {
+        opcode: u16,
+    },
+    FontWrite {
+        addr: u16,
+    },
+    QuirkSensitive {
+        instruction: Instruction,
+        quirk: &'static str,
+    },
+}
+
+impl Lint {
+    pub fn severity(&self) -> Severity {
+        match self {
+            Lint::OddJump { .. } | Lint::Sys | Lint::QuirkSensitive { .. } => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Lint::OddJump { target } => write!(
+                f,
+                "flows to the odd address {:#05X}, which not every interpreter can fetch from",
+                target
+            ),
+            Lint::OutOfRange { target } => {
+                write!(f, "flows to {:#05X}, outside of the ROM", target)
+            }
+            Lint::CallDepth { depth } => write!(
+                f,
+                "calls nest {} deep, more than the {} entries of the stack",
+                depth, STACK_SIZE
+            ),
+            Lint::Recursion { target } => write!(
+                f,
+                "recursive call to {:#05X} can overflow the {} entry stack",
+                target, STACK_SIZE
+            ),
+            Lint::Sys => write!(f, "0NNN calls machine code, which only the COSMAC VIP runs"),
+            Lint::UndefinedOpcode { opcode } => write!(f, "undefined opcode {:04X}", opcode),
+            Lint::FontWrite { addr } => {
+                write!(f, "writes to {:#05X}, over the built-in font", addr)
+            }
+            Lint::QuirkSensitive { instruction, quirk } => write!(
+                f,
+                "{} behaves differently depending on the {} quirk",
+                instruction, quirk
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub addr: u16,
+    pub lint: Lint,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.lint.severity() {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{:04X}: {}: {}", self.addr, severity, self.lint)
+    }
+}
+
+fn quirk(instruction: &Instruction) -> Option<&'static str> {
+    match instruction {
+        Instruction::ShiftRight(..) | Instruction::ShiftLeft(..) => Some("shift"),
+        Instruction::StoreRegisters(_) | Instruction::LoadRegisters(_) => Some("load-store"),
+        Instruction::JumpBase(_) => Some("jump"),
+        _ => None,
+    }
+}
+
+// The bytes an instruction writes to memory, starting at I
+fn write_size(instruction: &Instruction) -> Option<usize> {
+    match *instruction {
+        Instruction::StoreRegisters(x) => Some(usize::from(x) + 1),
+        Instruction::LoadIBCD(_) => Some(3),
+        Instruction::SaveRange(x, y) => Some(usize::from(x.max(y) - x.min(y)) + 1),
+        _ => None,
+    }
+}
+
+/*
+Finds the depth of the deepest chain of calls from `entry`, reporting calls
+back into a subroutine that is still running on the way.
+*/
+fn call_depth(
+    graph: &ControlFlowGraph,
+    entry: u16,
+    running: &mut BTreeSet<u16>,
+    depths: &mut BTreeMap<u16, usize>,
+    findings: &mut Vec<Finding>,
+) -> usize {
+    if let Some(&depth) = depths.get(&entry) {
+        return depth;
+    }
+    running.insert(entry);
+    let mut deepest = 0;
+    let blocks = graph.subroutines[&entry].blocks.iter();
+    for block in blocks.map(|start| &graph.blocks[start]) {
+        if let Some(&(addr, Instruction::Call(target))) = block.instructions.last() {
+            if running.contains(&target) {
+                findings.push(Finding {
+                    addr,
+                    lint: Lint::Recursion { target },
+                });
+                continue;
+            }
+            let depth = if graph.subroutines.contains_key(&target) {
+                1 + call_depth(graph, target, running, depths, findings)
+            } else {
+                1
+            };
+            deepest = deepest.max(depth);
+        }
+    }
+    running.remove(&entry);
+    depths.insert(entry, deepest);
+    deepest
+}
+
+/*
+Checks the code reachable from the start of the ROM, loaded at `origin`, for
+what breaks it or makes it behave differently between interpreters. The code
+is followed as a platform with the instruction set `set` would run it. The
+findings are in address order.
+*/
+pub fn lint<T: InstructionParser>(
+    parser: &T,
+    rom: &[u8],
+    origin: u16,
+    set: InstructionSet,
+) -> Vec<Finding> {
+    let graph = cfg::analyze(parser, rom, origin, set);
+    let end = usize::from(origin) + rom.len();
+    let in_rom = |addr: u16| (usize::from(origin)..end).contains(&usize::from(addr));
+    let mut findings = Vec::new();
+
+    for block in graph.blocks.values() {
+        let (last, instruction) = block.instructions.last().expect("empty block");
+        let mut targets: Vec<u16> = block.successors.iter().map(|&(to, _)| to).collect();
+        if let Instruction::Call(target) = instruction {
+            targets.push(*target);
+        }
+        for target in targets {
+            let lint = if !in_rom(target) {
+                Lint::OutOfRange { target }
+            } else if target % 2 == 1 {
+                Lint::OddJump { target }
+            } else {
+                continue;
+            };
+            findings.push(Finding { addr: *last, lint });
+        }
+
+        // I is only followed within a block, from the load that sets it
+        let mut i = None;
+        for (addr, instruction) in block.instructions.iter() {
+            let addr = *addr;
+            if let Some(quirk) = quirk(instruction) {
+                findings.push(Finding {
+                    addr,
+                    lint: Lint::QuirkSensitive {
+                        instruction: instruction.clone(),
+                        quirk,
+                    },
+                });
+            }
+            if let Instruction::SYS(_) = instruction {
+                findings.push(Finding {
+                    addr,
+                    lint: Lint::Sys,
+                });
+            }
+            if let (Some(size), Some(start)) = (write_size(instruction), i) {
+                let start = usize::from(start);
+                if start < FONT_END && start + size > FONT_ADDRESS {
+                    findings.push(Finding {
+                        addr,
+                        lint: Lint::FontWrite { addr: start as u16 },
+                    });
+                }
+            }
+            i = match *instruction {
+                Instruction::LoadImmediate(addr) | Instruction::LoadLongImmediate(addr) => {
+                    Some(addr)
+                }
+                Instruction::AddI(_)
+                | Instruction::StoreRegisters(_)
+                | Instruction::LoadRegisters(_) => None,
+                _ => i,
+            };
+        }
+    }
+
+    for &addr in graph.dead_ends.iter().filter(|&&addr| in_rom(addr)) {
+        let pos = usize::from(addr - origin);
+        if let Some(bytes) = rom.get(pos..pos + 2) {
+            let opcode = u16::from_be_bytes([bytes[0], bytes[1]]);
+            findings.push(Finding {
+                addr,
+                lint: Lint::UndefinedOpcode { opcode },
+            });
+        }
+    }
+
+    if graph.subroutines.contains_key(&origin) {
+        let mut depths = BTreeMap::new();
+        let depth = call_depth(
+            &graph,
+            origin,
+            &mut BTreeSet::new(),
+            &mut depths,
+            &mut findings,
+        );
+        if depth > STACK_SIZE {
+            findings.push(Finding {
+                addr: origin,
+                lint: Lint::CallDepth { depth },
+            });
+        }
+    }
+
+    findings.sort_by_key(|finding| finding.addr);
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::PROGRAM_OFFSET;
+    use crate::instructions::encode_program;
+    use crate::opcodes::OpcodeMaskParser;
+
+    fn lint_rom(rom: &[u8]) -> Vec<Finding> {
+        lint(
+            &OpcodeMaskParser {},
+            rom,
+            PROGRAM_OFFSET as u16,
+            InstructionSet::XoChip,
+        )
+    }
+
+    fn lint_program(program: &[Instruction]) -> Vec<Finding> {
+        let rom = encode_program(program).unwrap();
+        lint_rom(&rom)
+    }
+
+    #[test]
+    fn test_findings() {
+        let mut rom = encode_program(&[
+            Instruction::LoadImmediate(0x050),   // 200
+            Instruction::LoadIBCD(0x0),          // 202
+            Instruction::ShiftLeft(0x1, 0x2),    // 204
+            Instruction::SYS(0x123),             // 206
+            Instruction::SkipEqualsByte(0x0, 1), // 208
+            Instruction::Jump(0x20F),            // 20A
+            Instruction::Jump(0x400),            // 20C
+        ])
+        .unwrap();
+        rom.extend_from_slice(&[0x00, 0x00, 0xFD]); // 20F: EXIT
+        let findings = lint_rom(&rom);
+        let text: Vec<String> = findings.iter().map(Finding::to_string).collect();
+        assert_eq!(
+            text,
+            [
+                "0202: error: writes to 0x050, over the built-in font",
+                "0204: warning: SHL V1, V2 behaves differently depending on the shift quirk",
+                "0206: warning: 0NNN calls machine code, which only the COSMAC VIP runs",
+                "020A: warning: flows to the odd address 0x20F, which not every interpreter can fetch from",
+                "020C: error: flows to 0x400, outside of the ROM",
+            ]
+        );
+
+        // a clean program has nothing to report
+        assert!(
+            lint_program(&[Instruction::LoadByte(0x0, 1), Instruction::Jump(0x200)]).is_empty()
+        );
+        let mut rom = encode_program(&[Instruction::Jump(0x202)]).unwrap();
+        rom.extend_from_slice(&[0x80, 0x08]);
+        let findings = lint_rom(&rom);
+        assert_eq!(
+            findings,
+            [Finding {
+                addr: 0x202,
+                lint: Lint::UndefinedOpcode { opcode: 0x8008 }
+            }]
+        );
+    }
+
+    #[test]
+    fn test_call_depth() {
+        // every subroutine calls the next one, 17 deep
+        let mut program: Vec<Instruction> = (0..=STACK_SIZE as u16)
+            .map(|n| Instruction::Call(0x202 + 2 * n))
+            .collect();
+        program.push(Instruction::Return);
+        let findings = lint_program(&program);
+        assert_eq!(
+            findings,
+            [Finding {
+                addr: 0x200,
+                lint: Lint::CallDepth {
+                    depth: STACK_SIZE + 1
+                }
+            }]
+        );
+
+        let findings = lint_program(&[
+            Instruction::Call(0x204),
+            Instruction::Exit,
+            Instruction::Call(0x204),
+            Instruction::Return,
+        ]);
+        assert_eq!(
+            findings,
+            [Finding {
+                addr: 0x204,
+                lint: Lint::Recursion { target: 0x204 }
+            }]
+        );
+        assert_eq!(findings[0].lint.severity(), Severity::Error);
+    }
+}
@@ -0,0 +1,364 @@
+use std::fmt;
+
+use crate::audio::NullAudio;
+use crate::core::{Machine, REGISTER_COUNT};
+use crate::display::NullDisplay;
+use crate::error::Chip8Error;
+use crate::instructions::{Instruction, InstructionParser};
+use crate::keyboard::InputSource;
+use crate::runner::Runner;
+use crate::savestate::Snapshot;
+
+/*
+One of the two machines right after the instruction they disagree on, along
+with that instruction as the machine decoded it.
+*/
+#[derive(Debug, Clone)]
+pub struct Side {
+    pub name: String,
+    pub executed: Option<Instruction>,
+    pub error: Option<String>,
+    pub state: Snapshot,
+}
+
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    pub cycle: u64, // instructions run before the one they disagree on
+    pub frame: u64,
+    pub left: Side,
+    pub right: Side,
+    pub memory: Option<(usize, u8, u8)>, // see first_difference
+}
+
+/*
+The first address the memories differ at, with the byte in each, ignoring the
+bytes that have differed from the start, such as the fonts of two platforms.
+Memory past the end of the smaller one reads as 0.
+*/
+fn first_difference(
+    start: &(Vec<u8>, Vec<u8>),
+    left: &[u8],
+    right: &[u8],
+) -> Option<(usize, u8, u8)> {
+    if left == right {
+        return None;
+    }
+    let byte = |memory: &[u8], addr: usize| memory.get(addr).copied().unwrap_or(0);
+    (0..left.len().max(right.len()))
+        .map(|addr| (addr, byte(left, addr), byte(right, addr)))
+        .find(|&(addr, l, r)| l != r && (l != byte(&start.0, addr) || r != byte(&start.1, addr)))
+}
+
+/*
+Both states side by side, with a * in front of every line they differ on:
+
+    diverged at cycle 3 (frame 0)
+    *     mask                    broken
+    *     SHR V1, V2              SHL V1, V2
+       PC  0208                    0208
+    *  V1  03                      0E
+*/
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (left, right) = (&self.left, &self.right);
+        writeln!(f, "diverged at cycle {} (frame {})", self.cycle, self.frame)?;
+        let mut row = |label: &str, l: String, r: String| {
+            let marker = if l == r { ' ' } else { '*' };
+            writeln!(f, "{}  {:<4}{:<24}{}", marker, label, l, r)
+        };
+        row("", left.name.clone(), right.name.clone())?;
+        let executed = |side: &Side| match &side.executed {
+            Some(instruction) => instruction.to_string(),
+            None => String::from("-"),
+        };
+        row("", executed(left), executed(right))?;
+        if left.error.is_some() || right.error.is_some() {
+            let error = |side: &Side| side.error.clone().unwrap_or_else(|| String::from("-"));
+            row("", error(left), error(right))?;
+        }
+        let (l, r) = (&left.state, &right.state);
+        row(
+            "PC",
+            format!("{:04X}", l.counter),
+            format!("{:04X}", r.counter),
+        )?;
+        row("I", format!("{:04X}", l.i), format!("{:04X}", r.i))?;
+        for x in 0..REGISTER_COUNT {
+            let label = format!("V{:X}", x);
+            row(&label, format!("{:02X}", l.v[x]), format!("{:02X}", r.v[x]))?;
+        }
+        let stack = |s: &Snapshot| {
+            let entries: Vec<String> = s.stack[..usize::from(s.stack_ptr)]
+                .iter()
+                .map(|addr| format!("{:04X}", addr))
+                .collect();
+            format!("[{}]", entries.join(" "))
+        };
+        row("SP", stack(l), stack(r))?;
+        row("DT", l.delay_timer.to_string(), r.delay_timer.to_string())?;
+        row("ST", l.sound_timer.to_string(), r.sound_timer.to_string())?;
+        row(
+            "RNG",
+            format!("{:016X}", l.rng_state),
+            format!("{:016X}", r.rng_state),
+        )?;
+        if let Some((addr, l, r)) = self.memory {
+            writeln!(
+                f,
+                "*  memory differs first at {:04X}: {:02X} and {:02X}",
+                addr, l, r
+            )?;
+        }
+        if l.graphics != r.graphics {
+            writeln!(f, "*  the screens differ")?;
+        }
+        if (l.halted, l.hires, l.planes) != (r.halted, r.hires, r.planes) {
+            writeln!(f, "*  the display modes or halted states differ")?;
+        }
+        Ok(())
+    }
+}
+
+/*
+Runs two machines on the same ROM, with the same input and random seed, one
+instruction at a time, and stops at the first one after which they are in different states.
+The machines can differ in anything, e.g. their parser, platform or quirks,
+which makes it easy to tell where a change in behaviour comes from.
+*/
+pub struct Lockstep<A, B, I>
+where
+    A: InstructionParser,
+    B: InstructionParser,
+    I: InputSource,
+{
+    left: Runner<A, NullDisplay, NullAudio, I>,
+    right: Runner<B, NullDisplay, NullAudio, I>,
+    cycle: u64,
+    memory: (Vec<u8>, Vec<u8>), // as it was before the first step
+}
+
+impl<A, B, I> Lockstep<A, B, I>
+where
+    A: InstructionParser,
+    B: InstructionParser,
+    I: InputSource,
+{
+    // The inputs have to give both machines the same keys, and their random sources the same numbers
+    pub fn new(left: Machine<A>, right: Machine<B>, left_input: I, right_input: I) -> Self {
+        let memory = (left.snapshot().memory, right.snapshot().memory);
+        Lockstep {
+            left: Runner::new(left, NullDisplay, NullAudio, left_input),
+            right: Runner::new(right, NullDisplay, NullAudio, right_input),
+            cycle: 0,
+            memory,
+        }
+    }
+
+    pub fn cycle(&self) -> u64 {
+        self.cycle
+    }
+
+    pub fn left(&self) -> &Machine<A> {
+        self.left.machine()
+    }
+
+    pub fn right(&self) -> &Machine<B> {
+        self.right.machine()
+    }
+
+    /*
+    Executes one instruction on both machines. A machine failing is part of
+    its state, so only both failing the same way is an error.
+    */
+    pub fn step(&mut self) -> Result<Option<Divergence>, Chip8Error> {
+        let executed = (
+            self.left.machine().next_instruction().ok(),
+            self.right.machine().next_instruction().ok(),
+        );
+        let frame = self.left.frame();
+        let results = (self.left.step(), self.right.step());
+        let cycle = self.cycle;
+        self.cycle += 1;
+        let left = Side {
+            name: self.left.machine().name().to_string(),
+            executed: executed.0,
+            error: results.0.as_ref().err().map(Chip8Error::to_string),
+            state: self.left.machine().snapshot(),
+        };
+        let mut right = Side {
+            name: self.right.machine().name().to_string(),
+            executed: executed.1,
+            error: results.1.as_ref().err().map(Chip8Error::to_string),
+            state: self.right.machine().snapshot(),
+        };
+        let memory = first_difference(&self.memory, &left.state.memory, &right.state.memory);
+        // everything else has to match exactly
+        let right_memory = std::mem::replace(&mut right.state.memory, left.state.memory.clone());
+        let same = left.state == right.state;
+        right.state.memory = right_memory;
+        if left.executed == right.executed && left.error == right.error && same && memory.is_none()
+        {
+            return match results.0 {
+                Err(e) => Err(e),
+                Ok(_) => Ok(None),
+            };
+        }
+        Ok(Some(Divergence {
+            cycle,
+            frame,
+            left,
+            right,
+            memory,
+        }))
+    }
+
+    // Runs until the machines diverge, both halt or `cycles` instructions have run
+    pub fn run(&mut self, cycles: u64) -> Result<Option<Divergence>, Chip8Error> {
+        for _ in 0..cycles {
+            if self.left().halted() && self.right().halted() {
+                break;
+            }
+            if let Some(divergence) = self.step()? {
+                return Ok(Some(divergence));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instructions::encode_program;
+    use crate::keyboard::NullInput;
+    use crate::lookup::LookupTableParser;
+    use crate::opcodes::OpcodeMaskParser;
+    use crate::opcodesv2::OpcodeTable;
+    use crate::quirks::Quirks;
+    use crate::rng::XorShift;
+
+    const PROGRAM: [Instruction; 6] = [
+        Instruction::LoadByte(0x1, 0x03),
+        Instruction::LoadByte(0x2, 0x04),
+        Instruction::AddRegister(0x1, 0x2),
+        Instruction::ShiftRight(0x1, 0x2),
+        Instruction::Call(0x20C),
+        Instruction::Jump(0x20A),
+    ];
+
+    fn machine<T: InstructionParser>(name: &str, parser: T, quirks: Quirks) -> Machine<T> {
+        let mut program = encode_program(&PROGRAM).unwrap();
+        program.extend_from_slice(&encode_program(&[Instruction::Return]).unwrap());
+        let mut machine = Machine::new(name, parser, quirks);
+        machine.load_program(&program).unwrap();
+        machine.set_rng(Box::new(XorShift::new(1)));
+        machine
+    }
+
+    // Decodes 8XY6 as 8XYE
+    struct BrokenParser;
+
+    impl InstructionParser for BrokenParser {
+        fn try_from(&self, opcode: u16) -> Result<Instruction, Chip8Error> {
+            let parser = OpcodeMaskParser {};
+            match parser.try_from(opcode)? {
+                Instruction::ShiftRight(x, y) => Ok(Instruction::ShiftLeft(x, y)),
+                instruction => Ok(instruction),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parsers_agree_on_a_program() {
+        let quirks = Quirks::default();
+        let mut lockstep = Lockstep::new(
+            machine("mask", OpcodeMaskParser {}, quirks),
+            machine("table", OpcodeTable {}, quirks),
+            NullInput,
+            NullInput,
+        );
+        assert!(lockstep.run(100).unwrap().is_none());
+        assert_eq!(lockstep.cycle(), 100);
+
+        let mut lockstep = Lockstep::new(
+            machine("mask", OpcodeMaskParser {}, quirks),
+            machine("broken", BrokenParser, quirks),
+            NullInput,
+            NullInput,
+        );
+        let divergence = lockstep.run(100).unwrap().unwrap();
+        assert_eq!(divergence.cycle, 3);
+        assert_eq!(
+            divergence.left.executed,
+            Some(Instruction::ShiftRight(1, 2))
+        );
+        assert_eq!(
+            divergence.right.executed,
+            Some(Instruction::ShiftLeft(1, 2))
+        );
+        let text = divergence.to_string();
+        assert!(text.starts_with("diverged at cycle 3 (frame 0)\n"));
+        assert!(
+            text.contains("*  V1  03                      0E\n"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("   V2  04                      04\n"),
+            "{}",
+            text
+        );
+    }
+
+    #[test]
+    fn test_quirks_diverge() {
+        let shift = Quirks {
+            shift_uses_vy: true,
+            ..Quirks::default()
+        };
+        let mut lockstep = Lockstep::new(
+            machine("modern", LookupTableParser::new(), Quirks::default()),
+            machine("shift", LookupTableParser::new(), shift),
+            NullInput,
+            NullInput,
+        );
+        let divergence = lockstep.run(100).unwrap().unwrap();
+        assert_eq!(divergence.cycle, 3);
+        assert_eq!(divergence.left.state.v[1], 0x03);
+        assert_eq!(divergence.right.state.v[1], 0x02);
+        assert_eq!(divergence.left.error, None);
+        // the machines were left where they disagreed
+        assert_eq!(lockstep.left().pc(), 0x208);
+    }
+
+    #[test]
+    fn test_fonts_are_not_a_divergence() {
+        let quirks = Quirks::default();
+        let font = |machine: &mut Machine<OpcodeMaskParser>, byte| {
+            let mut state = machine.snapshot();
+            state.memory[0x50] = byte;
+            machine.restore(&state).unwrap();
+        };
+        let (mut left, mut right) = (
+            machine("left", OpcodeMaskParser {}, quirks),
+            machine("right", OpcodeMaskParser {}, quirks),
+        );
+        font(&mut left, 0xF0);
+        font(&mut right, 0xE0);
+        let mut lockstep = Lockstep::new(left, right, NullInput, NullInput);
+        assert!(lockstep.run(100).unwrap().is_none());
+
+        // writing to memory shows up, even where it differed to begin with
+        let start = (vec![1, 2, 3], vec![1, 5, 3, 0]);
+        assert_eq!(first_difference(&start, &[1, 2, 3], &[1, 5, 3, 0]), None);
+        assert_eq!(
+            first_difference(&start, &[1, 2, 3], &[1, 6, 3, 0]),
+            Some((1, 2, 6))
+        );
+        assert_eq!(
+            first_difference(&start, &[1, 2, 3], &[1, 5, 3, 4]),
+            Some((3, 0, 4))
+        );
+        assert_eq!(first_difference(&start, &[1, 5, 3], &[1, 5, 3, 0]), None);
+    }
+}
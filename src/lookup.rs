@@ -0,0 +1,79 @@
+use std::sync::Arc;
+
+use crate::error::Chip8Error;
+use crate::instructions::{Instruction, InstructionParser};
+use crate::opcodesv2::OpcodeTable;
+
+/*
+Decodes with a single index into the decoded form of all 65536 opcodes,
+worked out once by another parser, by default from the isa! table. The table
+is shared between clones, so many machines can decode with one table.
+*/
+#[derive(Clone)]
+pub struct LookupTableParser {
+    table: Arc<[Option<Instruction>]>,
+}
+
+impl LookupTableParser {
+    pub fn new() -> Self {
+        Self::from_parser(&OpcodeTable {})
+    }
+
+    // Takes what `parser` decodes every opcode to, invalid ones included
+    pub fn from_parser<T: InstructionParser>(parser: &T) -> Self {
+        let table = (0..=u16::MAX)
+            .map(|opcode| parser.try_from(opcode).ok())
+            .collect();
+        LookupTableParser { table }
+    }
+}
+
+impl Default for LookupTableParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InstructionParser for LookupTableParser {
+    fn try_from(&self, opcode: u16) -> Result<Instruction, Chip8Error> {
+        self.table[usize::from(opcode)]
+            .clone()
+            .ok_or(Chip8Error::InvalidOpcode { opcode, pc: None })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcodes::OpcodeMaskParser;
+
+    #[test]
+    fn test_matches_the_parser_it_was_built_from() {
+        let parsers: [&dyn InstructionParser; 2] = [&OpcodeTable {}, &OpcodeMaskParser {}];
+        let tables = [
+            LookupTableParser::new(),
+            LookupTableParser::from_parser(&OpcodeMaskParser {}),
+        ];
+        for (parser, table) in parsers.iter().zip(tables.iter()) {
+            for opcode in 0..=u16::MAX {
+                assert_eq!(
+                    parser.try_from(opcode).ok(),
+                    table.try_from(opcode).ok(),
+                    "{:04X}",
+                    opcode
+                );
+            }
+        }
+        assert!(matches!(
+            tables[0].try_from(0x8008),
+            Err(Chip8Error::InvalidOpcode {
+                opcode: 0x8008,
+                pc: None
+            })
+        ));
+        assert_eq!(
+            tables[0].try_from_words(0xF000, 0x1234).unwrap(),
+            Instruction::LoadLongImmediate(0x1234)
+        );
+    }
+}
@@ -0,0 +1,525 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::audio::NullAudio;
+use crate::core::{Machine, KEY_SIZE};
+use crate::display::NullDisplay;
+use crate::error::Chip8Error;
+use crate::instructions::InstructionParser;
+use crate::keyboard::InputSource;
+use crate::platform::Platform;
+use crate::quirks::Quirks;
+use crate::rng::{self, RandomSource};
+use crate::runner::Runner;
+
+/*
+A movie is everything needed to play a run again: the settings of the machine
+and the key presses, frame by frame. It is a plain text file, so inputs can be
+written by hand as well as recorded:
+
+    chip8-movie 1
+    platform cosmac-vip
+    quirks shift,load-store,vf-reset,clip,display-wait
+    rng xorshift 1234
+    rom CBF29CE484222325
+    length 600
+    # hold 5 for 3 frames, starting at frame 120
+    at 120 press 5 for 3
+    at 200 down A
+    at 260 up A
+    at 300 check 0123456789ABCDEF
+
+Everything that happens "at" a frame happens before that frame runs. A check
+line is the state hash of the machine at that point, which a replay compares
+against to notice when it has gone out of sync. The rom and length lines are
+optional.
+*/
+pub const MOVIE_HEADER: &str = "chip8-movie 1";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    Down(u8),
+    Up(u8),
+    Check(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Event {
+    pub frame: u64,
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Movie {
+    pub platform: String,
+    pub quirks: Quirks,
+    pub rng: String,
+    pub seed: u64,
+    pub rom_hash: Option<u64>,
+    pub length: Option<u64>, // frames
+    pub events: Vec<Event>,  // in order of their frames
+}
+
+impl Movie {
+    pub fn new(platform: &Platform, rng: &str, seed: u64) -> Self {
+        Movie {
+            platform: platform.name.to_string(),
+            quirks: platform.quirks,
+            rng: rng.to_string(),
+            seed,
+            rom_hash: None,
+            length: None,
+            events: Vec::new(),
+        }
+    }
+
+    // The platform the movie was made on, with its quirks
+    pub fn platform(&self) -> Result<Platform, Chip8Error> {
+        let mut platform =
+            Platform::by_name(&self.platform).ok_or_else(|| Chip8Error::InvalidMovie {
+                line: 0,
+                reason: format!("unknown platform {}", self.platform),
+            })?;
+        platform.quirks = self.quirks;
+        Ok(platform)
+    }
+
+    pub fn random_source(&self) -> Result<Box<dyn RandomSource>, Chip8Error> {
+        rng::by_name(&self.rng, self.seed).ok_or_else(|| Chip8Error::InvalidMovie {
+            line: 0,
+            reason: format!("unknown random number generator {}", self.rng),
+        })
+    }
+
+    // Fails unless the movie was recorded with the ROM the hash is of, or doesn't say
+    pub fn check_rom(&self, rom_hash: u64) -> Result<(), Chip8Error> {
+        match self.rom_hash {
+            Some(hash) if hash != rom_hash => Err(Chip8Error::InvalidMovie {
+                line: 0,
+                reason: format!("recorded with ROM {:016X}, not {:016X}", hash, rom_hash),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    // How many frames a replay runs for
+    pub fn frames(&self) -> u64 {
+        self.length
+            .unwrap_or_else(|| self.events.last().map_or(0, |e| e.frame.saturating_add(1)))
+    }
+}
+
+fn parse_key(s: &str) -> Result<u8, String> {
+    match u8::from_str_radix(s, 16) {
+        Ok(key) if usize::from(key) < KEY_SIZE => Ok(key),
+        _ => Err(format!("invalid key {}", s)),
+    }
+}
+
+fn parse_number(s: &str) -> Result<u64, String> {
+    s.parse().map_err(|_| format!("invalid number {}", s))
+}
+
+/*
+The frame some frames after another. The frame after it has to exist too, as
+that is where a movie ending in the event ends.
+*/
+fn later(frame: u64, frames: u64) -> Result<u64, String> {
+    frame
+        .checked_add(frames)
+        .filter(|&frame| frame < u64::MAX)
+        .ok_or_else(|| String::from("frame out of range"))
+}
+
+fn parse_hash(s: &str) -> Result<u64, String> {
+    u64::from_str_radix(s, 16).map_err(|_| format!("invalid hash {}", s))
+}
+
+impl FromStr for Movie {
+    type Err = Chip8Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut lines = s
+            .lines()
+            .enumerate()
+            .map(|(n, line)| (n + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+        match lines.next() {
+            Some((_, MOVIE_HEADER)) => {}
+            other => {
+                return Err(Chip8Error::InvalidMovie {
+                    line: other.map_or(0, |(n, _)| n),
+                    reason: format!("expected \"{}\"", MOVIE_HEADER),
+                })
+            }
+        }
+        let mut movie = Movie::new(&Platform::default(), "xorshift", 0);
+        for (n, line) in lines {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let result = match words.as_slice() {
+                ["platform", name] => {
+                    movie.platform = name.to_string();
+                    Ok(())
+                }
+                ["quirks"] => {
+                    movie.quirks = Quirks::default();
+                    Ok(())
+                }
+                ["quirks", list] => list.parse().map(|quirks| movie.quirks = quirks),
+                ["rng", name, seed] => parse_number(seed).map(|seed| {
+                    movie.rng = name.to_string();
+                    movie.seed = seed;
+                }),
+                ["rom", hash] => parse_hash(hash).map(|hash| movie.rom_hash = Some(hash)),
+                ["length", frames] => parse_number(frames).map(|n| movie.length = Some(n)),
+                ["at", frame, rest @ ..] => parse_number(frame).and_then(|frame| {
+                    let frame = later(frame, 0)?;
+                    let kinds = match rest {
+                        ["down", key] => vec![(frame, EventKind::Down(parse_key(key)?))],
+                        ["up", key] => vec![(frame, EventKind::Up(parse_key(key)?))],
+                        ["press", key] => {
+                            let key = parse_key(key)?;
+                            vec![
+                                (frame, EventKind::Down(key)),
+                                (later(frame, 1)?, EventKind::Up(key)),
+                            ]
+                        }
+                        ["press", key, "for", frames] => {
+                            let key = parse_key(key)?;
+                            let frames = parse_number(frames)?.max(1);
+                            vec![
+                                (frame, EventKind::Down(key)),
+                                (later(frame, frames)?, EventKind::Up(key)),
+                            ]
+                        }
+                        ["check", hash] => vec![(frame, EventKind::Check(parse_hash(hash)?))],
+                        _ => return Err(String::from("unknown event")),
+                    };
+                    movie
+                        .events
+                        .extend(kinds.into_iter().map(|(frame, kind)| Event { frame, kind }));
+                    Ok(())
+                }),
+                _ => Err(String::from("unknown line")),
+            };
+            result.map_err(|reason| Chip8Error::InvalidMovie { line: n, reason })?;
+        }
+        // hand-written lines don't have to be in order
+        movie.events.sort_by_key(|e| e.frame);
+        Ok(movie)
+    }
+}
+
+impl fmt::Display for Movie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", MOVIE_HEADER)?;
+        writeln!(f, "platform {}", self.platform)?;
+        writeln!(f, "quirks {}", self.quirks)?;
+        writeln!(f, "rng {} {}", self.rng, self.seed)?;
+        if let Some(hash) = self.rom_hash {
+            writeln!(f, "rom {:016X}", hash)?;
+        }
+        if let Some(length) = self.length {
+            writeln!(f, "length {}", length)?;
+        }
+        for event in self.events.iter() {
+            match event.kind {
+                EventKind::Down(key) => writeln!(f, "at {} down {:X}", event.frame, key)?,
+                EventKind::Up(key) => writeln!(f, "at {} up {:X}", event.frame, key)?,
+                EventKind::Check(hash) => writeln!(f, "at {} check {:016X}", event.frame, hash)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/*
+Turns the key states of a run into a movie. The runner hands it the keys at
+the start of every frame; only the changes are written down.
+*/
+pub struct MovieRecorder {
+    movie: Movie,
+    keys: [bool; KEY_SIZE],
+    checkpoint_interval: u64, // frames between state hashes, 0 for none
+}
+
+impl MovieRecorder {
+    pub fn new(movie: Movie, checkpoint_interval: u64) -> Self {
+        MovieRecorder {
+            movie,
+            keys: [false; KEY_SIZE],
+            checkpoint_interval,
+        }
+    }
+
+    pub fn record_frame<T: InstructionParser>(
+        &mut self,
+        frame: u64,
+        machine: &Machine<T>,
+        keys: &[bool; KEY_SIZE],
+    ) {
+        if frame == 0 {
+            self.movie.rom_hash = Some(machine.rom_hash());
+        }
+        if self.checkpoint_interval > 0 && frame.is_multiple_of(self.checkpoint_interval) {
+            let kind = EventKind::Check(machine.state_hash());
+            self.movie.events.push(Event { frame, kind });
+        }
+        for (key, (was, is)) in self.keys.iter().zip(keys.iter()).enumerate() {
+            let kind = match (was, is) {
+                (false, true) => EventKind::Down(key as u8),
+                (true, false) => EventKind::Up(key as u8),
+                _ => continue,
+            };
+            self.movie.events.push(Event { frame, kind });
+        }
+        self.keys = *keys;
+    }
+
+    pub fn finish(mut self, frames: u64) -> Movie {
+        self.movie.length = Some(frames);
+        self.movie
+    }
+}
+
+/*
+Plays the key presses of a movie back, one frame per poll.
+*/
+pub struct MoviePlayer {
+    events: Vec<Event>,
+    next: usize,
+    frame: u64,
+    keys: [bool; KEY_SIZE],
+}
+
+impl MoviePlayer {
+    pub fn new(movie: &Movie) -> Self {
+        MoviePlayer {
+            events: movie.events.clone(),
+            next: 0,
+            frame: 0,
+            keys: [false; KEY_SIZE],
+        }
+    }
+}
+
+impl InputSource for MoviePlayer {
+    fn poll(&mut self) -> Result<[bool; KEY_SIZE], Chip8Error> {
+        while let Some(event) = self.events.get(self.next) {
+            if event.frame > self.frame {
+                break;
+            }
+            match event.kind {
+                EventKind::Down(key) => self.keys[usize::from(key)] = true,
+                EventKind::Up(key) => self.keys[usize::from(key)] = false,
+                EventKind::Check(_) => {}
+            }
+            self.next += 1;
+        }
+        self.frame += 1;
+        Ok(self.keys)
+    }
+}
+
+/*
+Replays a movie on a machine that already has the ROM loaded, without any
+display or sound, and returns the machine as it is at the end of the movie.
+Fails as soon as the machine's state hash differs from a checkpoint.
+*/
+pub fn replay<T: InstructionParser>(
+    movie: &Movie,
+    mut machine: Machine<T>,
+) -> Result<Machine<T>, Chip8Error> {
+    movie.check_rom(machine.rom_hash())?;
+    machine.set_rng(movie.random_source()?);
+    let checkpoints: Vec<(u64, u64)> = movie
+        .events
+        .iter()
+        .filter_map(|e| match e.kind {
+            EventKind::Check(hash) => Some((e.frame, hash)),
+            _ => None,
+        })
+        .collect();
+    let mut checkpoints = checkpoints.iter().peekable();
+    let mut runner = Runner::new(machine, NullDisplay, NullAudio, MoviePlayer::new(movie));
+    for frame in 0..movie.frames() {
+        while let Some((_, expected)) = checkpoints.next_if(|c| c.0 <= frame) {
+            let found = runner.machine().state_hash();
+            if found != *expected {
+                return Err(Chip8Error::MovieDesync {
+                    frame,
+                    expected: *expected,
+                    found,
+                });
+            }
+        }
+        runner.run_frame()?;
+    }
+    Ok(runner.into_machine())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyboard::NullInput;
+    use crate::opcodes::OpcodeMaskParser;
+    use crate::platform::CHIP_48;
+
+    // Adds a random number to V1 while key 5 is held, and counts frames in V2
+    const PROGRAM: [u8; 12] = [
+        0x60, 0x05, // LD V0, 5
+        0xE0, 0xA1, // SKNP V0
+        0xC1, 0xFF, // RND V1, 0xFF
+        0x72, 0x01, // ADD V2, 1
+        0x12, 0x02, // JP 0x202
+        0x00, 0x00,
+    ];
+
+    fn machine() -> Machine<OpcodeMaskParser> {
+        let mut machine = Machine::with_platform("TestVM", OpcodeMaskParser {}, &CHIP_48);
+        machine.load_program(&PROGRAM).unwrap();
+        machine
+    }
+
+    // Plays the given key states, one per frame
+    struct Script(Vec<[bool; KEY_SIZE]>, usize);
+
+    impl InputSource for Script {
+        fn poll(&mut self) -> Result<[bool; KEY_SIZE], Chip8Error> {
+            self.1 += 1;
+            Ok(self.0.get(self.1 - 1).copied().unwrap_or([false; KEY_SIZE]))
+        }
+    }
+
+    fn record(frames: usize) -> (Movie, Machine<OpcodeMaskParser>) {
+        let mut script = vec![[false; KEY_SIZE]; frames];
+        for keys in script.iter_mut().take(7).skip(3) {
+            keys[5] = true;
+        }
+        let mut machine = machine();
+        machine.set_rng(rng::by_name("xorshift", 77).unwrap());
+        let mut runner = Runner::new(machine, NullDisplay, NullAudio, Script(script, 0));
+        let movie = Movie::new(&CHIP_48, "xorshift", 77);
+        runner.start_recording(MovieRecorder::new(movie, 4));
+        for _ in 0..frames {
+            runner.run_frame().unwrap();
+        }
+        let movie = runner.stop_recording().unwrap();
+        (movie, runner.into_machine())
+    }
+
+    #[test]
+    fn test_parse_hand_written_movie() {
+        let text = "chip8-movie 1
+            # a comment
+            platform chip-48
+            quirks jump,clip
+            rng vip-like 42
+            at 120 press 5 for 3
+            at 10 press A
+            at 200 check 00000000000000FF
+        ";
+        let movie: Movie = text.parse().unwrap();
+        assert_eq!(movie.platform().unwrap(), CHIP_48);
+        assert_eq!(movie.rng, "vip-like");
+        assert_eq!(movie.seed, 42);
+        assert_eq!(movie.rom_hash, None);
+        assert_eq!(
+            movie.events,
+            vec![
+                Event {
+                    frame: 10,
+                    kind: EventKind::Down(0xA)
+                },
+                Event {
+                    frame: 11,
+                    kind: EventKind::Up(0xA)
+                },
+                Event {
+                    frame: 120,
+                    kind: EventKind::Down(5)
+                },
+                Event {
+                    frame: 123,
+                    kind: EventKind::Up(5)
+                },
+                Event {
+                    frame: 200,
+                    kind: EventKind::Check(0xFF)
+                },
+            ]
+        );
+        assert_eq!(movie.frames(), 201);
+        assert_eq!(movie.to_string().parse::<Movie>().unwrap(), movie);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |text: &str| match text.parse::<Movie>() {
+            Err(Chip8Error::InvalidMovie { line, .. }) => line,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(error(""), 0);
+        assert_eq!(error("chip8-movie 2"), 1);
+        assert_eq!(error("chip8-movie 1\nat 5 press G"), 2);
+        assert_eq!(error("chip8-movie 1\n\nquirks wrap"), 3);
+        assert_eq!(error("chip8-movie 1\nat x up 1"), 2);
+        assert_eq!(error("chip8-movie 1\nfly"), 2);
+        assert_eq!(error("chip8-movie 1\nat 18446744073709551615 press 5"), 2);
+        assert_eq!(error("chip8-movie 1\nat 18446744073709551614 press 5"), 2);
+        assert_eq!(
+            error("chip8-movie 1\nat 1 press 5 for 18446744073709551615"),
+            2
+        );
+        assert_eq!(error("chip8-movie 1\nat 18446744073709551615 up 5"), 2);
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let (movie, recorded) = record(12);
+        assert_eq!(movie.length, Some(12));
+        assert_eq!(movie.rom_hash, Some(machine().rom_hash()));
+        let keys: Vec<&Event> = movie
+            .events
+            .iter()
+            .filter(|e| !matches!(e.kind, EventKind::Check(_)))
+            .collect();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].kind, EventKind::Down(5));
+        assert_eq!(keys[1].frame, 7);
+
+        // the movie survives being written to text
+        let movie: Movie = movie.to_string().parse().unwrap();
+        let replayed = replay(&movie, machine()).unwrap();
+        assert_eq!(replayed.state_hash(), recorded.state_hash());
+        assert_ne!(recorded.registers()[1], 0);
+    }
+
+    #[test]
+    fn test_replay_detects_desync() {
+        let (mut movie, _) = record(12);
+        // without the key press at frame 3 the checkpoint at 4 no longer matches
+        movie
+            .events
+            .retain(|e| matches!(e.kind, EventKind::Check(_)));
+        match replay(&movie, machine()) {
+            Err(Chip8Error::MovieDesync { frame, .. }) => assert_eq!(frame, 4),
+            other => panic!("unexpected {:?}", other.map(|m| m.state_hash())),
+        }
+
+        let (movie, _) = record(2);
+        let mut other = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
+        other.load_program(&[0x12, 0x00]).unwrap();
+        assert!(matches!(
+            replay(&movie, other),
+            Err(Chip8Error::InvalidMovie { line: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn test_player_without_events() {
+        let movie: Movie = "chip8-movie 1\nlength 3".parse().unwrap();
+        let mut player = MoviePlayer::new(&movie);
+        assert_eq!(player.poll().unwrap(), NullInput.poll().unwrap());
+    }
+}
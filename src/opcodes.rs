@@ -1,16 +1,29 @@
 use crate::bitmasks::*;
+use crate::error::Chip8Error;
 use crate::instructions::{Instruction, InstructionParser};
 
+/*
+Decodes by hand, one match on the opcode's nibbles. The parsers built from the
+isa! table are what runs by default; this one is written separately from it on
+purpose, so test_parsers_agree catches mistakes in either.
+*/
 #[allow(dead_code)]
 pub struct OpcodeMaskParser {}
 
 impl InstructionParser for OpcodeMaskParser {
-    fn try_from(&self, opcode: u16) -> Result<Instruction, String> {
+    fn try_from(&self, opcode: u16) -> Result<Instruction, Chip8Error> {
         match mask_F000(opcode) {
-            0x0 => match mask_00FF(opcode) {
-                0xE0 => Ok(Instruction::ClearScreen),
-                0xEE => Ok(Instruction::Return),
-                _ => Ok(Instruction::SYS),
+            0x0 => match mask_0FFF(opcode) {
+                0x0E0 => Ok(Instruction::ClearScreen),
+                0x0EE => Ok(Instruction::Return),
+                0x0C0..=0x0CF => Ok(Instruction::ScrollDown(mask_000F(opcode))),
+                0x0D0..=0x0DF => Ok(Instruction::ScrollUp(mask_000F(opcode))),
+                0x0FB => Ok(Instruction::ScrollRight),
+                0x0FC => Ok(Instruction::ScrollLeft),
+                0x0FD => Ok(Instruction::Exit),
+                0x0FE => Ok(Instruction::LowRes),
+                0x0FF => Ok(Instruction::HighRes),
+                _ => Ok(Instruction::SYS(mask_0FFF(opcode))),
             },
             0x1 => Ok(Instruction::Jump(mask_0FFF(opcode))),
             0x2 => Ok(Instruction::Call(mask_0FFF(opcode))),
@@ -22,10 +35,21 @@ impl InstructionParser for OpcodeMaskParser {
                 mask_0F00(opcode),
                 mask_00FF(opcode),
             )),
-            0x5 => Ok(Instruction::SkipEqualsRegister(
-                mask_0F00(opcode),
-                mask_00F0(opcode),
-            )),
+            /*
+            Only 5XY0 and 9XY0 are skips. The other low nibbles aren't any
+            instruction, XO-CHIP took 5XY2 and 5XY3 from them, so they are
+            invalid rather than skips that ignore the nibble.
+            */
+            0x5 => {
+                let r1 = mask_0F00(opcode);
+                let r2 = mask_00F0(opcode);
+                match mask_000F(opcode) {
+                    0x2 => Ok(Instruction::SaveRange(r1, r2)),
+                    0x3 => Ok(Instruction::LoadRange(r1, r2)),
+                    0x0 => Ok(Instruction::SkipEqualsRegister(r1, r2)),
+                    _ => Err(Chip8Error::InvalidOpcode { opcode, pc: None }),
+                }
+            }
             0x6 => Ok(Instruction::LoadByte(mask_0F00(opcode), mask_00FF(opcode))),
             0x7 => Ok(Instruction::AddByte(mask_0F00(opcode), mask_00FF(opcode))),
             0x8 => {
@@ -38,16 +62,19 @@ impl InstructionParser for OpcodeMaskParser {
                     0x3 => Ok(Instruction::Xor(r1, r2)),
                     0x4 => Ok(Instruction::AddRegister(r1, r2)),
                     0x5 => Ok(Instruction::SubRegister(r1, r2)),
-                    0x6 => Ok(Instruction::ShiftRight(r1)),
+                    0x6 => Ok(Instruction::ShiftRight(r1, r2)),
                     0x7 => Ok(Instruction::SubNRegister(r1, r2)),
-                    0xE => Ok(Instruction::ShiftLeft(r1)),
-                    _ => Err(format!("Invalid opcode: {:X}", opcode)),
+                    0xE => Ok(Instruction::ShiftLeft(r1, r2)),
+                    _ => Err(Chip8Error::InvalidOpcode { opcode, pc: None }),
                 }
             }
-            0x9 => Ok(Instruction::SkipNotEqualRegister(
-                mask_0F00(opcode),
-                mask_00F0(opcode),
-            )),
+            0x9 => match mask_000F(opcode) {
+                0x0 => Ok(Instruction::SkipNotEqualRegister(
+                    mask_0F00(opcode),
+                    mask_00F0(opcode),
+                )),
+                _ => Err(Chip8Error::InvalidOpcode { opcode, pc: None }),
+            },
             0xA => Ok(Instruction::LoadImmediate(mask_0FFF(opcode))),
             0xB => Ok(Instruction::JumpBase(mask_0FFF(opcode))),
             0xC => Ok(Instruction::Random(mask_0F00(opcode), mask_00FF(opcode))),
@@ -61,25 +88,58 @@ impl InstructionParser for OpcodeMaskParser {
                 match mask_00FF(opcode) {
                     0x9E => Ok(Instruction::SkipKeyPress(register)),
                     0xA1 => Ok(Instruction::SkipNotKeyPress(register)),
-                    _ => Err(format!("Invalid opcode: {:X}", opcode)),
+                    _ => Err(Chip8Error::InvalidOpcode { opcode, pc: None }),
                 }
             }
             0xF => {
                 let register = mask_0F00(opcode);
                 match mask_00FF(opcode) {
+                    0x01 => Ok(Instruction::SelectPlanes(register)),
+                    0x02 if register == 0 => Ok(Instruction::LoadAudioPattern),
                     0x07 => Ok(Instruction::LoadFromDelay(register)),
                     0x0A => Ok(Instruction::LoadKeyPress(register)),
                     0x15 => Ok(Instruction::LoadDelay(register)),
                     0x18 => Ok(Instruction::LoadSound(register)),
                     0x1E => Ok(Instruction::AddI(register)),
                     0x29 => Ok(Instruction::LoadFontSprite(register)),
+                    0x30 => Ok(Instruction::LoadBigFontSprite(register)),
                     0x33 => Ok(Instruction::LoadIBCD(register)),
+                    0x3A => Ok(Instruction::LoadPitch(register)),
                     0x55 => Ok(Instruction::StoreRegisters(register)),
                     0x65 => Ok(Instruction::LoadRegisters(register)),
-                    _ => Err(format!("Invalid opcode: {:X}", opcode)),
+                    0x75 => Ok(Instruction::StoreFlags(register)),
+                    0x85 => Ok(Instruction::LoadFlags(register)),
+                    _ => Err(Chip8Error::InvalidOpcode { opcode, pc: None }),
                 }
             }
-            _ => Err(format!("opcode {:X} is invalid", opcode)),
+            _ => Err(Chip8Error::InvalidOpcode { opcode, pc: None }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_5xy0_and_9xy0_skip() {
+        let parser = OpcodeMaskParser {};
+        assert_eq!(
+            parser.try_from(0x5120).unwrap(),
+            Instruction::SkipEqualsRegister(1, 2)
+        );
+        assert_eq!(
+            parser.try_from(0x9120).unwrap(),
+            Instruction::SkipNotEqualRegister(1, 2)
+        );
+        let invalid = (0x5121..=0x512F)
+            .filter(|opcode| !matches!(opcode, 0x5122 | 0x5123))
+            .chain(0x9121..=0x912F);
+        for opcode in invalid {
+            assert!(matches!(
+                parser.try_from(opcode),
+                Err(Chip8Error::InvalidOpcode { opcode: o, .. }) if o == opcode
+            ));
         }
     }
 }
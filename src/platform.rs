@@ -0,0 +1,202 @@
+use std::fmt;
+
+use crate::core::{CLOCK_SPEED, DISPLAY_HEIGHT, DISPLAY_WIDTH, MEMORY_SIZE};
+use crate::font::Font;
+use crate::quirks::Quirks;
+
+/*
+The instruction set extensions a platform understands. Every set includes the
+ones before it, so they can be compared with < and >=.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum InstructionSet {
+    Chip8,
+    SuperChip,
+    XoChip,
+}
+
+impl InstructionSet {
+    pub fn name(self) -> &'static str {
+        match self {
+            InstructionSet::Chip8 => "chip-8",
+            InstructionSet::SuperChip => "super-chip",
+            InstructionSet::XoChip => "xo-chip",
+        }
+    }
+}
+
+/*
+A named CHIP-8 interpreter: everything a ROM written for that interpreter
+expects of the machine running it.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Platform {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub quirks: Quirks,
+    pub instruction_set: InstructionSet,
+    pub clock_speed: u64, // instructions per second
+    pub memory_size: usize,
+    pub font: Font,
+    pub display_width: usize,
+    pub display_height: usize,
+}
+
+pub const COSMAC_VIP: Platform = Platform {
+    name: "cosmac-vip",
+    description: "The original interpreter on the RCA COSMAC VIP (1977)",
+    quirks: Quirks {
+        shift_uses_vy: true,
+        load_store_increments_i: true,
+        jump_uses_vx: false,
+        logic_resets_vf: true,
+        clip_sprites: true,
+        display_wait: true,
+    },
+    instruction_set: InstructionSet::Chip8,
+    clock_speed: 600,
+    memory_size: 4096,
+    font: Font::CosmacVip,
+    display_width: 64,
+    display_height: 32,
+};
+
+pub const CHIP_48: Platform = Platform {
+    name: "chip-48",
+    description: "CHIP-48 on the HP-48 calculators (1990)",
+    quirks: Quirks {
+        shift_uses_vy: false,
+        load_store_increments_i: false,
+        jump_uses_vx: true,
+        logic_resets_vf: false,
+        clip_sprites: true,
+        display_wait: false,
+    },
+    instruction_set: InstructionSet::Chip8,
+    clock_speed: 900,
+    memory_size: 4096,
+    font: Font::Chip48,
+    display_width: 64,
+    display_height: 32,
+};
+
+pub const SCHIP_1_0: Platform = Platform {
+    name: "schip-1.0",
+    description: "SUPER-CHIP 1.0 on the HP-48 calculators (1991)",
+    quirks: Quirks {
+        shift_uses_vy: false,
+        load_store_increments_i: true,
+        jump_uses_vx: true,
+        logic_resets_vf: false,
+        clip_sprites: true,
+        display_wait: false,
+    },
+    instruction_set: InstructionSet::SuperChip,
+    clock_speed: 1800,
+    memory_size: 4096,
+    font: Font::Chip48,
+    display_width: 128,
+    display_height: 64,
+};
+
+pub const SCHIP_1_1: Platform = Platform {
+    name: "schip-1.1",
+    description: "SUPER-CHIP 1.1 on the HP-48 calculators (1991)",
+    quirks: Quirks {
+        load_store_increments_i: false,
+        ..SCHIP_1_0.quirks
+    },
+    ..SCHIP_1_0
+};
+
+pub const XO_CHIP: Platform = Platform {
+    name: "xo-chip",
+    description: "XO-CHIP as implemented by Octo (2014)",
+    quirks: Quirks {
+        shift_uses_vy: true,
+        load_store_increments_i: true,
+        jump_uses_vx: false,
+        logic_resets_vf: false,
+        clip_sprites: false,
+        display_wait: false,
+    },
+    instruction_set: InstructionSet::XoChip,
+    clock_speed: 60_000,
+    memory_size: 65536,
+    font: Font::Chip48,
+    display_width: 128,
+    display_height: 64,
+};
+
+pub const MODERN: Platform = Platform {
+    name: "modern",
+    description: "CHIP-8 the way most current emulators and ROMs understand it",
+    quirks: Quirks {
+        shift_uses_vy: false,
+        load_store_increments_i: false,
+        jump_uses_vx: false,
+        logic_resets_vf: false,
+        clip_sprites: false,
+        display_wait: false,
+    },
+    instruction_set: InstructionSet::Chip8,
+    clock_speed: CLOCK_SPEED,
+    memory_size: MEMORY_SIZE,
+    font: Font::Chip48,
+    display_width: DISPLAY_WIDTH,
+    display_height: DISPLAY_HEIGHT,
+};
+
+pub const PLATFORMS: [Platform; 6] = [COSMAC_VIP, CHIP_48, SCHIP_1_0, SCHIP_1_1, XO_CHIP, MODERN];
+
+impl Platform {
+    pub fn by_name(name: &str) -> Option<Platform> {
+        PLATFORMS.iter().find(|p| p.name == name).copied()
+    }
+}
+
+impl Default for Platform {
+    fn default() -> Self {
+        MODERN
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}: {}", self.name, self.description)?;
+        writeln!(f, "\tinstruction set: {}", self.instruction_set.name())?;
+        writeln!(f, "\tclock speed: {} Hz", self.clock_speed)?;
+        writeln!(f, "\tmemory: {} bytes", self.memory_size)?;
+        writeln!(f, "\tfont: {}", self.font.name())?;
+        writeln!(
+            f,
+            "\tdisplay: {}x{}",
+            self.display_width, self.display_height
+        )?;
+        write!(f, "\tquirks: {}", self.quirks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_platform_by_name() {
+        for platform in PLATFORMS.iter() {
+            assert_eq!(Platform::by_name(platform.name), Some(*platform));
+        }
+        assert_eq!(Platform::by_name("chip-9"), None);
+        assert_eq!(Platform::default(), MODERN);
+    }
+
+    #[test]
+    fn test_platform_quirks_round_trip() {
+        // the listing prints quirks in the syntax --quirks accepts
+        for platform in PLATFORMS.iter() {
+            let listing = platform.to_string();
+            let quirks = listing.rsplit("quirks: ").next().unwrap();
+            assert_eq!(quirks.parse(), Ok(platform.quirks));
+        }
+    }
+}
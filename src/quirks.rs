@@ -0,0 +1,109 @@
+use std::fmt;
+use std::str::FromStr;
+
+/*
+Behaviour that CHIP-8 interpreters disagree on. ROMs are usually written
+against one particular interpreter and rely on its interpretation of these
+instructions, so the machine needs to be told which one to follow.
+
+The default leaves every quirk off, which is what most modern ROMs expect.
+*/
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Quirks {
+    // 8XY6/8XYE shift VY and store the result in VX (COSMAC VIP),
+    // instead of shifting VX in place (CHIP-48, SUPER-CHIP)
+    pub shift_uses_vy: bool,
+    // FX55/FX65 leave I pointing after the last register they touched (COSMAC VIP)
+    pub load_store_increments_i: bool,
+    // BNNN behaves as BXNN and jumps to XNN + VX (CHIP-48, SUPER-CHIP),
+    // instead of NNN + V0
+    pub jump_uses_vx: bool,
+    // 8XY1/8XY2/8XY3 reset VF to 0 (COSMAC VIP)
+    pub logic_resets_vf: bool,
+    // Sprites are cut off at the edges of the screen instead of wrapping around
+    pub clip_sprites: bool,
+    // DXYN waits for the start of the next frame, so at most one sprite is
+    // drawn per frame (COSMAC VIP)
+    pub display_wait: bool,
+}
+
+/*
+Parses a comma separated list of the quirks to turn on, e.g. "shift,clip".
+*/
+impl FromStr for Quirks {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut quirks = Quirks::default();
+        for name in s.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            match name {
+                "shift" => quirks.shift_uses_vy = true,
+                "load-store" => quirks.load_store_increments_i = true,
+                "jump" => quirks.jump_uses_vx = true,
+                "vf-reset" => quirks.logic_resets_vf = true,
+                "clip" => quirks.clip_sprites = true,
+                "display-wait" => quirks.display_wait = true,
+                _ => return Err(format!("Unknown quirk: {}", name)),
+            }
+        }
+        Ok(quirks)
+    }
+}
+
+/*
+Formats the quirks that are on in the syntax FromStr accepts.
+*/
+impl fmt::Display for Quirks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let enabled: Vec<&str> = [
+            (self.shift_uses_vy, "shift"),
+            (self.load_store_increments_i, "load-store"),
+            (self.jump_uses_vx, "jump"),
+            (self.logic_resets_vf, "vf-reset"),
+            (self.clip_sprites, "clip"),
+            (self.display_wait, "display-wait"),
+        ]
+        .iter()
+        .filter(|(on, _)| *on)
+        .map(|(_, name)| *name)
+        .collect();
+        write!(f, "{}", enabled.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_quirks() {
+        assert_eq!("".parse(), Ok(Quirks::default()));
+        assert_eq!(
+            "shift, clip,display-wait".parse(),
+            Ok(Quirks {
+                shift_uses_vy: true,
+                clip_sprites: true,
+                display_wait: true,
+                ..Quirks::default()
+            })
+        );
+        assert_eq!(
+            "load-store,jump,vf-reset".parse(),
+            Ok(Quirks {
+                load_store_increments_i: true,
+                jump_uses_vx: true,
+                logic_resets_vf: true,
+                ..Quirks::default()
+            })
+        );
+        assert!("wrap".parse::<Quirks>().is_err());
+
+        let quirks = Quirks {
+            jump_uses_vx: true,
+            clip_sprites: true,
+            ..Quirks::default()
+        };
+        assert_eq!(quirks.to_string(), "jump,clip");
+        assert_eq!(quirks.to_string().parse(), Ok(quirks));
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::VecDeque;
+
+use crate::error::Chip8Error;
+use crate::savestate::Snapshot;
+
+/*
+How much history the rewind buffer keeps.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RewindConfig {
+    // Take a snapshot every this many frames
+    pub interval: u32,
+    // Older snapshots are dropped once the buffer uses more memory than this
+    pub max_bytes: usize,
+}
+
+impl Default for RewindConfig {
+    // One snapshot per frame in 8 MiB, which is minutes of most games
+    fn default() -> Self {
+        RewindConfig {
+            interval: 1,
+            max_bytes: 8 << 20,
+        }
+    }
+}
+
+/*
+A step back from one snapshot to the one before it. Consecutive snapshots are
+mostly identical, so they are stored as the XOR of the two, with the runs of
+zeroes compressed away. When the sizes differ, e.g. after a switch to the
+high resolution mode, the older snapshot is kept as a whole.
+*/
+enum Delta {
+    Xor(Vec<u8>),
+    Full(Vec<u8>),
+}
+
+impl Delta {
+    fn new(older: &[u8], newer: &[u8]) -> Self {
+        if older.len() != newer.len() {
+            return Delta::Full(older.to_vec());
+        }
+        let xor: Vec<u8> = older.iter().zip(newer).map(|(a, b)| a ^ b).collect();
+        Delta::Xor(rle_encode(&xor))
+    }
+
+    fn apply(&self, newer: &[u8]) -> Vec<u8> {
+        match self {
+            Delta::Full(older) => older.clone(),
+            Delta::Xor(encoded) => {
+                let xor = rle_decode(encoded, newer.len());
+                xor.iter().zip(newer).map(|(a, b)| a ^ b).collect()
+            }
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Delta::Xor(bytes) | Delta::Full(bytes) => bytes.len(),
+        }
+    }
+}
+
+fn push_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[*pos];
+        *pos += 1;
+        value |= usize::from(byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+/*
+Encodes the data as pairs of (number of zeroes, number of literal bytes),
+each followed by the literal bytes themselves.
+*/
+fn rle_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let zeroes = data[pos..].iter().take_while(|b| **b == 0).count();
+        pos += zeroes;
+        let literals = data[pos..].iter().take_while(|b| **b != 0).count();
+        push_varint(&mut out, zeroes);
+        push_varint(&mut out, literals);
+        out.extend_from_slice(&data[pos..pos + literals]);
+        pos += literals;
+    }
+    out
+}
+
+fn rle_decode(encoded: &[u8], len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len);
+    let mut pos = 0;
+    while pos < encoded.len() {
+        let zeroes = read_varint(encoded, &mut pos);
+        out.resize(out.len() + zeroes, 0);
+        let literals = read_varint(encoded, &mut pos);
+        out.extend_from_slice(&encoded[pos..pos + literals]);
+        pos += literals;
+    }
+    out.resize(len, 0);
+    out
+}
+
+/*
+A ring buffer of the recent states of a machine, newest last. Only the newest
+snapshot is kept as a whole, everything before it is a chain of deltas.
+*/
+pub struct RewindBuffer {
+    config: RewindConfig,
+    frames: u32, // frames since the last snapshot
+    newest: Option<Vec<u8>>,
+    deltas: VecDeque<Delta>, // oldest first
+    delta_bytes: usize,
+}
+
+impl RewindBuffer {
+    pub fn new(config: RewindConfig) -> Self {
+        RewindBuffer {
+            config,
+            frames: 0,
+            newest: None,
+            deltas: VecDeque::new(),
+            delta_bytes: 0,
+        }
+    }
+
+    pub fn config(&self) -> &RewindConfig {
+        &self.config
+    }
+
+    /*
+    Called once per frame, takes a snapshot every `interval` frames. Snapshots
+    are made only when they are taken, as making one copies all of memory.
+    */
+    pub fn record(&mut self, snapshot: impl FnOnce() -> Snapshot) {
+        self.frames += 1;
+        if self.frames < self.config.interval.max(1) {
+            return;
+        }
+        self.frames = 0;
+        self.push(&snapshot());
+    }
+
+    pub fn push(&mut self, snapshot: &Snapshot) {
+        let bytes = snapshot.to_bytes();
+        if let Some(previous) = self.newest.take() {
+            let delta = Delta::new(&previous, &bytes);
+            self.delta_bytes += delta.size();
+            self.deltas.push_back(delta);
+        }
+        self.newest = Some(bytes);
+        while self.memory_usage() > self.config.max_bytes {
+            match self.deltas.pop_front() {
+                Some(oldest) => self.delta_bytes -= oldest.size(),
+                None => {
+                    // not even a single snapshot fits
+                    self.newest = None;
+                    break;
+                }
+            }
+        }
+    }
+
+    /*
+    Takes the newest snapshot out of the buffer, so the one before it is next.
+    */
+    pub fn pop(&mut self) -> Result<Option<Snapshot>, Chip8Error> {
+        let newest = match self.newest.take() {
+            Some(newest) => newest,
+            None => return Ok(None),
+        };
+        if let Some(delta) = self.deltas.pop_back() {
+            self.delta_bytes -= delta.size();
+            self.newest = Some(delta.apply(&newest));
+        }
+        self.frames = 0;
+        Snapshot::from_bytes(&newest).map(Some)
+    }
+
+    // Number of snapshots that can be rewound to
+    pub fn len(&self) -> usize {
+        self.newest.as_ref().map_or(0, |_| self.deltas.len() + 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    // Bytes taken up by the snapshots, not counting bookkeeping
+    pub fn memory_usage(&self) -> usize {
+        self.newest.as_ref().map_or(0, Vec::len) + self.delta_bytes
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+        self.delta_bytes = 0;
+        self.frames = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::Machine;
+    use crate::opcodes::OpcodeMaskParser;
+    use crate::quirks::Quirks;
+
+    fn counting_machine() -> Machine<OpcodeMaskParser> {
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
+        // ADD V0, 1; LD I, 0x300; LD [I], V0; JP 0x200
+        machine
+            .load_program(&[0x70, 0x01, 0xA3, 0x00, 0xF0, 0x55, 0x12, 0x00])
+            .unwrap();
+        machine
+    }
+
+    #[test]
+    fn test_rle_round_trip() {
+        let data = [0, 0, 0, 5, 6, 0, 7, 0, 0];
+        let encoded = rle_encode(&data);
+        assert_eq!(rle_decode(&encoded, data.len()), data);
+        let zeroes = vec![0; 5000];
+        assert!(rle_encode(&zeroes).len() < 4);
+        assert_eq!(rle_decode(&rle_encode(&zeroes), 5000), zeroes);
+        assert_eq!(rle_decode(&[], 3), [0, 0, 0]);
+    }
+
+    #[test]
+    fn test_rewind_in_order() {
+        let mut machine = counting_machine();
+        let mut buffer = RewindBuffer::new(RewindConfig::default());
+        let mut states = Vec::new();
+        for _ in 0..10 {
+            for _ in 0..4 {
+                machine.tick().unwrap();
+            }
+            let snapshot = machine.snapshot();
+            buffer.record(|| snapshot.clone());
+            states.push(snapshot);
+        }
+        assert_eq!(buffer.len(), 10);
+        // the deltas are a lot smaller than the 4K of memory
+        assert!(buffer.memory_usage() < 2 * states[0].to_bytes().len());
+        for expected in states.iter().rev() {
+            assert_eq!(buffer.pop().unwrap().as_ref(), Some(expected));
+        }
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.pop().unwrap(), None);
+    }
+
+    #[test]
+    fn test_rewind_interval_and_budget() {
+        let mut machine = counting_machine();
+        let size = machine.snapshot().to_bytes().len();
+        let mut buffer = RewindBuffer::new(RewindConfig {
+            interval: 3,
+            max_bytes: size + 100,
+        });
+        let mut taken = 0;
+        for _ in 0..9 {
+            machine.tick().unwrap();
+            buffer.record(|| {
+                taken += 1;
+                machine.snapshot()
+            });
+        }
+        assert_eq!(taken, 3);
+        assert!(buffer.len() <= 3 && buffer.len() > 1);
+        assert!(buffer.memory_usage() <= size + 100);
+        assert_eq!(buffer.pop().unwrap().unwrap(), machine.snapshot());
+
+        let mut tiny = RewindBuffer::new(RewindConfig {
+            interval: 1,
+            max_bytes: 10,
+        });
+        tiny.record(|| machine.snapshot());
+        assert!(tiny.is_empty());
+    }
+
+    #[test]
+    fn test_rewind_across_resolution_change() {
+        use crate::platform::SCHIP_1_1;
+        let mut machine = Machine::with_platform("TestVM", OpcodeMaskParser {}, &SCHIP_1_1);
+        // HIGH; JP 0x202
+        machine.load_program(&[0x00, 0xFF, 0x12, 0x02]).unwrap();
+        let mut buffer = RewindBuffer::new(RewindConfig::default());
+        let before = machine.snapshot();
+        buffer.push(&before);
+        machine.tick().unwrap();
+        buffer.push(&machine.snapshot());
+        buffer.pop().unwrap();
+        assert_eq!(buffer.pop().unwrap(), Some(before));
+    }
+}
@@ -0,0 +1,204 @@
+use std::fmt;
+
+/*
+Where CXKK gets its random bytes from. Every source has to be able to hand out
+its state and pick up from it again, so a run can be reproduced from a seed or
+from a snapshot of the machine.
+*/
+pub trait RandomSource {
+    fn next_byte(&mut self) -> u8;
+    fn state(&self) -> u64;
+    fn set_state(&mut self, state: u64);
+    fn name(&self) -> &'static str;
+}
+
+impl fmt::Debug for dyn RandomSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {{ state: {:#X} }}", self.name(), self.state())
+    }
+}
+
+/*
+A xorshift64* generator. Small, fast and good enough for games, which is all
+CXKK is ever used for.
+*/
+pub struct XorShift {
+    state: u64,
+}
+
+impl XorShift {
+    pub fn new(seed: u64) -> Self {
+        let mut rng = XorShift { state: 0 };
+        rng.set_state(seed);
+        rng
+    }
+
+    // Seeded differently on every run
+    pub fn from_entropy() -> Self {
+        Self::new(rand::random())
+    }
+}
+
+impl RandomSource for XorShift {
+    fn next_byte(&mut self) -> u8 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+    }
+
+    fn state(&self) -> u64 {
+        self.state
+    }
+
+    fn set_state(&mut self, state: u64) {
+        // xorshift gets stuck on 0
+        self.state = if state == 0 {
+            0x9E37_79B9_7F4A_7C15
+        } else {
+            state
+        };
+    }
+
+    fn name(&self) -> &'static str {
+        "xorshift"
+    }
+}
+
+/*
+Works like the random number routine of the COSMAC VIP interpreter, which
+steps an 8-bit pointer through page 1 of its own code and folds the byte it
+finds there into the previous result.
+
+The VIP read that page from the interpreter itself, which we don't ship, so by
+default the table is filled from a fixed xorshift sequence, and the numbers
+only look like the VIP's, hence the name. Pass a dump of 0x0100-0x01FF of the
+VIP interpreter to with_table to get its sequence, which has not been verified
+against real hardware.
+*/
+pub struct VipLikeRandom {
+    table: [u8; 256],
+    index: u8,
+    value: u8,
+}
+
+impl VipLikeRandom {
+    // The state is only 16 bits, so the whole seed is hashed down to it
+    pub fn new(seed: u64) -> Self {
+        let mut filler = XorShift::new(0x1802);
+        let mut table = [0; 256];
+        table.iter_mut().for_each(|b| *b = filler.next_byte());
+        Self::with_table(table, seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 48)
+    }
+
+    pub fn with_table(table: [u8; 256], seed: u64) -> Self {
+        let mut rng = VipLikeRandom {
+            table,
+            index: 0,
+            value: 0,
+        };
+        rng.set_state(seed);
+        rng
+    }
+}
+
+impl RandomSource for VipLikeRandom {
+    fn next_byte(&mut self) -> u8 {
+        self.index = self.index.wrapping_add(1);
+        self.value = self
+            .value
+            .rotate_right(1)
+            .wrapping_add(self.table[usize::from(self.index)]);
+        self.value
+    }
+
+    fn state(&self) -> u64 {
+        u64::from(self.index) << 8 | u64::from(self.value)
+    }
+
+    fn set_state(&mut self, state: u64) {
+        self.index = (state >> 8) as u8;
+        self.value = state as u8;
+    }
+
+    fn name(&self) -> &'static str {
+        "vip-like"
+    }
+}
+
+pub const RANDOM_SOURCES: [&str; 2] = ["xorshift", "vip-like"];
+
+// The random source called `name`, seeded with `seed`
+pub fn by_name(name: &str, seed: u64) -> Option<Box<dyn RandomSource>> {
+    match name {
+        "xorshift" => Some(Box::new(XorShift::new(seed))),
+        "vip-like" => Some(Box::new(VipLikeRandom::new(seed))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(rng: &mut dyn RandomSource, n: usize) -> Vec<u8> {
+        (0..n).map(|_| rng.next_byte()).collect()
+    }
+
+    #[test]
+    fn test_same_seed_same_sequence() {
+        for name in RANDOM_SOURCES.iter() {
+            let mut a = by_name(name, 42).unwrap();
+            let mut b = by_name(name, 42).unwrap();
+            assert_eq!(bytes(a.as_mut(), 64), bytes(b.as_mut(), 64));
+            assert_eq!(a.name(), *name);
+        }
+        let mut a = XorShift::new(1);
+        let mut b = XorShift::new(2);
+        assert_ne!(bytes(&mut a, 16), bytes(&mut b, 16));
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        for name in RANDOM_SOURCES.iter() {
+            let mut rng = by_name(name, 7).unwrap();
+            bytes(rng.as_mut(), 10);
+            let state = rng.state();
+            let expected = bytes(rng.as_mut(), 32);
+            rng.set_state(state);
+            assert_eq!(bytes(rng.as_mut(), 32), expected);
+        }
+    }
+
+    #[test]
+    fn test_xorshift_covers_every_byte() {
+        let mut rng = XorShift::new(0);
+        let mut seen = [false; 256];
+        for _ in 0..10_000 {
+            seen[usize::from(rng.next_byte())] = true;
+        }
+        assert!(seen.iter().all(|s| *s));
+    }
+
+    #[test]
+    fn test_vip_random_follows_table() {
+        let mut table = [0; 256];
+        table[1] = 0x10;
+        table[2] = 0x01;
+        let mut rng = VipLikeRandom::with_table(table, 0);
+        assert_eq!(rng.next_byte(), 0x10);
+        assert_eq!(rng.next_byte(), 0x09);
+        assert_eq!(rng.state(), 0x0209);
+    }
+
+    #[test]
+    fn test_vip_like_uses_the_whole_seed() {
+        let states: Vec<u64> = [1, 1 << 16 | 1, 1 << 32 | 1, 1 << 63 | 1]
+            .iter()
+            .map(|seed| VipLikeRandom::new(*seed).state())
+            .collect();
+        for (n, state) in states.iter().enumerate() {
+            assert!(!states[n + 1..].contains(state), "{:X?}", states);
+        }
+    }
+}
@@ -0,0 +1,444 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::audio::AudioSink;
+use crate::core::{Machine, TIMER_FREQ};
+use crate::display::Display;
+use crate::error::Chip8Error;
+use crate::instructions::InstructionParser;
+use crate::keyboard::{Command, InputSource};
+use crate::movie::{Movie, MovieRecorder};
+use crate::rewind::{RewindBuffer, RewindConfig};
+use crate::savestate::Snapshot;
+
+/*
+Drives a Machine in real time and connects it to a display, an audio sink and
+an input source. The machine itself knows nothing about the outside world;
+the runner works in frames of 1/60th of a second, which is also the rate of
+the delay and sound timers.
+*/
+pub struct Runner<T, D, A, I>
+where
+    T: InstructionParser,
+    D: Display,
+    A: AudioSink,
+    I: InputSource,
+{
+    machine: Machine<T>,
+    display: D,
+    audio: A,
+    input: I,
+    cycles_per_frame: u64,
+    frame_delay: Duration,
+    save_path: Option<PathBuf>, // quick-save slots are stored next to this path
+    rewind: Option<RewindBuffer>,
+    recorder: Option<MovieRecorder>,
+    frame: u64, // frames run so far
+    cycle: u64, // instructions run in the current frame
+    break_requested: bool,
+}
+
+impl<T, D, A, I> Runner<T, D, A, I>
+where
+    T: InstructionParser,
+    D: Display,
+    A: AudioSink,
+    I: InputSource,
+{
+    pub fn new(machine: Machine<T>, display: D, audio: A, input: I) -> Self {
+        // at least one instruction per frame, or step would never get anywhere
+        let cycles_per_frame = (machine.platform().clock_speed / TIMER_FREQ).max(1);
+        Self {
+            machine,
+            display,
+            audio,
+            input,
+            cycles_per_frame,
+            frame_delay: Duration::from_micros(1_000_000 / TIMER_FREQ),
+            save_path: None,
+            rewind: None,
+            recorder: None,
+            frame: 0,
+            cycle: 0,
+            break_requested: false,
+        }
+    }
+
+    pub fn into_machine(self) -> Machine<T> {
+        self.machine
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /*
+    Records the keys of every frame from now on. Rewinding and loading states
+    can't be replayed, so they should stay off while recording.
+    */
+    pub fn start_recording(&mut self, recorder: MovieRecorder) {
+        self.recorder = Some(recorder);
+    }
+
+    pub fn stop_recording(&mut self) -> Option<Movie> {
+        let frame = self.frame;
+        self.recorder.take().map(|recorder| recorder.finish(frame))
+    }
+
+    // Keeps the recent history of the machine around so it can be rewound
+    pub fn enable_rewind(&mut self, config: RewindConfig) {
+        self.rewind = Some(RewindBuffer::new(config));
+    }
+
+    pub fn rewind_buffer(&self) -> Option<&RewindBuffer> {
+        self.rewind.as_ref()
+    }
+
+    /*
+    Puts the machine back into the most recent state in the rewind buffer
+    that differs from the current one. The newest snapshot is usually taken
+    at the end of the frame just shown, and restoring it would hold the
+    first frame of rewinding still. Returns false when there is nothing left
+    to rewind to.
+    */
+    fn rewind(&mut self) -> Result<bool, Chip8Error> {
+        let buffer = match self.rewind.as_mut() {
+            Some(buffer) => buffer,
+            None => return Ok(false),
+        };
+        let mut snapshot = buffer.pop()?;
+        if snapshot.as_ref() == Some(&self.machine.snapshot()) {
+            snapshot = buffer.pop()?;
+        }
+        match snapshot {
+            Some(snapshot) => {
+                self.machine.restore(&snapshot)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /*
+    Enables the quick-save slots. Slot N is stored as `<path>.stateN`, where
+    the path is usually the one of the ROM.
+    */
+    pub fn set_save_path<P: AsRef<Path>>(&mut self, path: P) {
+        self.save_path = Some(path.as_ref().to_path_buf());
+    }
+
+    pub fn slot_path(&self, slot: u8) -> Option<PathBuf> {
+        self.save_path.as_ref().map(|path| {
+            let mut name = path.clone().into_os_string();
+            name.push(format!(".state{}", slot));
+            PathBuf::from(name)
+        })
+    }
+
+    /*
+    A failed quick-save or quick-load is reported and otherwise ignored: it is
+    no reason to stop the game.
+    */
+    fn handle_command(&mut self, command: Command) {
+        let result = match command {
+            Command::SaveState(slot) => match self.slot_path(slot) {
+                Some(path) => self.machine.snapshot().save(&path),
+                None => return,
+            },
+            Command::LoadState(slot) => match self.slot_path(slot) {
+                Some(path) => Snapshot::load(&path).and_then(|s| self.machine.restore(&s)),
+                None => return,
+            },
+            // handled by begin_frame
+            Command::Rewind | Command::Break => return,
+        };
+        match result {
+            Ok(()) => info!("{:?} done", command),
+            Err(e) => error!("{:?} failed: {}", command, e),
+        }
+    }
+
+    pub fn machine(&self) -> &Machine<T> {
+        &self.machine
+    }
+
+    pub fn machine_mut(&mut self) -> &mut Machine<T> {
+        &mut self.machine
+    }
+
+    pub fn display(&self) -> &D {
+        &self.display
+    }
+
+    pub fn audio(&self) -> &A {
+        &self.audio
+    }
+
+    pub fn input(&self) -> &I {
+        &self.input
+    }
+
+    // Run a single frame: read the keys, execute a frame worth of instructions,
+    // count the timers down and present the results.
+    pub fn run_frame(&mut self) -> Result<(), Chip8Error> {
+        while !self.step()? {}
+        Ok(())
+    }
+
+    /*
+    Executes a single instruction, along with the start or the end of the
+    frame when it is the first or the last one of it. Returns true once the
+    frame is complete. Stepping through a frame this way is exactly the same
+    as running it with run_frame, which is what the debugger relies on.
+    */
+    pub fn step(&mut self) -> Result<bool, Chip8Error> {
+        if self.cycle == 0 && !self.begin_frame()? {
+            self.end_frame();
+            return Ok(true);
+        }
+        self.machine.tick()?;
+        self.cycle += 1;
+        if self.cycle < self.cycles_per_frame {
+            return Ok(false);
+        }
+        self.cycle = 0;
+        self.machine.tick_timers();
+        if let Some(buffer) = self.rewind.as_mut() {
+            let machine = &self.machine;
+            buffer.record(|| machine.snapshot());
+        }
+        self.end_frame();
+        Ok(true)
+    }
+
+    // Whether the next step starts a new frame
+    pub fn at_frame_start(&self) -> bool {
+        self.cycle == 0
+    }
+
+    /*
+    Reads the input for the frame. Returns false when the frame shows an
+    earlier state from the rewind buffer instead of running.
+    */
+    fn begin_frame(&mut self) -> Result<bool, Chip8Error> {
+        let keys = self.input.poll()?;
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.record_frame(self.frame, &self.machine, &keys);
+        }
+        let mut rewinding = false;
+        for command in self.input.commands() {
+            match command {
+                Command::Rewind => rewinding = true,
+                Command::Break => self.break_requested = true,
+                _ => self.handle_command(command),
+            }
+        }
+        if rewinding && self.rewind()? {
+            return Ok(false);
+        }
+        self.machine.set_keys(&keys);
+        Ok(true)
+    }
+
+    fn end_frame(&mut self) {
+        if self.machine.take_redraw() {
+            self.display.draw(self.machine.graphics());
+        }
+        if self.machine.take_audio_update() {
+            self.audio
+                .set_pattern(self.machine.audio_pattern(), self.machine.pitch());
+        }
+        if self.machine.sound_active() {
+            self.audio.play();
+        } else {
+            self.audio.stop();
+        }
+        self.frame += 1;
+    }
+
+    // Whether the user asked to break into the debugger since the last call
+    pub fn take_break_request(&mut self) -> bool {
+        ::std::mem::replace(&mut self.break_requested, false)
+    }
+
+    // Sleeps for what is left of a frame that started at `frame_start`
+    pub fn wait_for_frame(&self, frame_start: Instant) {
+        if let Some(remaining) = self.frame_delay.checked_sub(frame_start.elapsed()) {
+            ::std::thread::sleep(remaining);
+        }
+    }
+
+    // Start the virtual machine: This is the fun part! Returns once the
+    // program exits through 00FD.
+    pub fn run(&mut self) -> Result<(), Chip8Error> {
+        while !self.machine.halted() {
+            let frame_start = Instant::now();
+            self.run_frame()?;
+            self.wait_for_frame(frame_start);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::{NullAudio, RecordingAudio};
+    use crate::core::KEY_SIZE;
+    use crate::display::{NullDisplay, RecordingDisplay};
+    use crate::keyboard::{NullInput, RecordingInput};
+    use crate::opcodes::OpcodeMaskParser;
+    use crate::platform::{SCHIP_1_1, XO_CHIP};
+    use crate::quirks::Quirks;
+    use std::collections::VecDeque;
+
+    struct QuitAfter(usize);
+
+    impl InputSource for QuitAfter {
+        fn poll(&mut self) -> Result<[bool; KEY_SIZE], Chip8Error> {
+            if self.0 == 0 {
+                return Err(Chip8Error::Quit);
+            }
+            self.0 -= 1;
+            Ok([false; KEY_SIZE])
+        }
+    }
+
+    // Gives one batch of commands per frame
+    struct Commands(VecDeque<Vec<Command>>);
+
+    impl InputSource for Commands {
+        fn poll(&mut self) -> Result<[bool; KEY_SIZE], Chip8Error> {
+            Ok([false; KEY_SIZE])
+        }
+
+        fn commands(&mut self) -> Vec<Command> {
+            self.0.pop_front().unwrap_or_default()
+        }
+    }
+
+    fn runner_with<I: InputSource>(
+        program: &[u8],
+        input: I,
+    ) -> Runner<OpcodeMaskParser, RecordingDisplay, RecordingAudio, I> {
+        let mut machine = Machine::new("TestVM", OpcodeMaskParser {}, Quirks::default());
+        machine.load_program(program).unwrap();
+        Runner::new(
+            machine,
+            RecordingDisplay::default(),
+            RecordingAudio::default(),
+            input,
+        )
+    }
+
+    #[test]
+    fn test_frame_draws_and_beeps() {
+        let program = [
+            0x60, 0x02, // LD V0, 2
+            0xF0, 0x18, // LD ST, V0
+            0xA0, 0x00, // LD I, 0x000 (font for "0")
+            0xD1, 0x15, // DRW V1, V1, 5
+            0x12, 0x08, // JP 0x208
+        ];
+        let mut runner = runner_with(&program, NullInput);
+
+        runner.run_frame().unwrap();
+        assert_eq!(runner.display().frames.len(), 1);
+        assert_eq!(runner.display().frames[0].row(0)[..4], [1, 1, 1, 1]);
+        assert_eq!(runner.audio().history, vec![true]);
+
+        // nothing new on screen, and the sound timer runs out after two frames
+        runner.run_frame().unwrap();
+        runner.run_frame().unwrap();
+        assert_eq!(runner.display().frames.len(), 1);
+        assert_eq!(runner.audio().history, vec![true, false, false]);
+    }
+
+    #[test]
+    fn test_run_stops_on_input_error() {
+        let mut runner = runner_with(&[0x12, 0x00], RecordingInput::new(QuitAfter(3)));
+        assert!(matches!(runner.run(), Err(Chip8Error::Quit)));
+        assert_eq!(runner.input().history.len(), 3);
+    }
+
+    #[test]
+    fn test_run_returns_on_exit() {
+        let mut machine = Machine::with_platform("TestVM", OpcodeMaskParser {}, &SCHIP_1_1);
+        machine.load_program(&[0x00, 0xFD]).unwrap();
+        let mut runner = Runner::new(machine, NullDisplay, NullAudio, NullInput);
+        runner.run().unwrap();
+        assert!(runner.machine().halted());
+    }
+
+    #[test]
+    fn test_frame_sets_audio_pattern() {
+        let mut machine = Machine::with_platform("TestVM", OpcodeMaskParser {}, &XO_CHIP);
+        machine
+            .load_program(&[
+                0xA2, 0x0A, // LD I, 0x20A
+                0xF0, 0x02, // AUDIO
+                0x12, 0x04, // JP 0x204
+                0x00, 0x00, 0x00, 0x00, // padding
+                0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA, // pattern
+                0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA,
+            ])
+            .unwrap();
+        let mut runner = Runner::new(machine, NullDisplay, RecordingAudio::default(), NullInput);
+        runner.run_frame().unwrap();
+        runner.run_frame().unwrap();
+        assert_eq!(runner.audio().patterns, vec![([0xAA; 16], 64)]);
+    }
+
+    #[test]
+    fn test_quick_save_slots() {
+        let dir = tempfile::tempdir().unwrap();
+        let rom = dir.path().join("count.ch8");
+        let commands = vec![
+            vec![Command::SaveState(1)],
+            vec![],
+            vec![Command::LoadState(1)],
+            vec![Command::LoadState(2)],
+        ];
+        // ADD V0, 1; JP 0x200
+        let mut runner = runner_with(&[0x70, 0x01, 0x12, 0x00], Commands(commands.into()));
+        runner.set_save_path(&rom);
+        assert_eq!(
+            runner.slot_path(3),
+            Some(dir.path().join("count.ch8.state3"))
+        );
+
+        // saved before the first frame runs
+        runner.run_frame().unwrap();
+        let v0 = runner.machine().registers()[0];
+        runner.run_frame().unwrap();
+        runner.run_frame().unwrap();
+        assert!(dir.path().join("count.ch8.state1").exists());
+        assert_eq!(runner.machine().registers()[0], v0);
+        // an empty slot leaves the machine alone
+        runner.run_frame().unwrap();
+        assert_eq!(runner.machine().registers()[0], v0.wrapping_mul(2));
+    }
+
+    #[test]
+    fn test_rewind_plays_backwards() {
+        let mut commands = vec![vec![]; 5];
+        commands.extend(vec![vec![Command::Rewind]; 7]);
+        // ADD V0, 1; JP 0x200
+        let mut runner = runner_with(&[0x70, 0x01, 0x12, 0x00], Commands(commands.into()));
+        runner.enable_rewind(RewindConfig::default());
+        let mut history = Vec::new();
+        for _ in 0..5 {
+            runner.run_frame().unwrap();
+            history.push(runner.machine().registers()[0]);
+        }
+        // the first frame of rewinding already shows the one before
+        for expected in history.iter().rev().skip(1) {
+            runner.run_frame().unwrap();
+            assert_eq!(runner.machine().registers()[0], *expected);
+        }
+        // out of history, so the game carries on
+        assert!(runner.rewind_buffer().unwrap().is_empty());
+        runner.run_frame().unwrap();
+        assert_ne!(runner.machine().registers()[0], history[0]);
+    }
+}
@@ -0,0 +1,347 @@
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+
+use crate::core::{
+    GraphicsMemory, ALL_PLANES, AUDIO_PATTERN_SIZE, FLAG_COUNT, KEY_SIZE, REGISTER_COUNT,
+    STACK_SIZE,
+};
+use crate::error::Chip8Error;
+
+/*
+Save states are stored as
+
+    magic "C8SS", format version (u16), ROM hash (u64), state...
+
+with every number in little endian. The version has to be bumped whenever the
+layout of the state changes, older states are refused rather than misread.
+*/
+pub const MAGIC: &[u8; 4] = b"C8SS";
+pub const VERSION: u16 = 1;
+
+/*
+Everything needed to put a Machine back into the exact state it was in.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub rom_hash: u64,
+    pub counter: u16,
+    pub stack_ptr: u8,
+    pub stack: [u16; STACK_SIZE],
+    pub v: [u8; REGISTER_COUNT],
+    pub i: u16,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub skip_increment: bool,
+    pub vblank: bool,
+    pub halted: bool,
+    pub hires: bool,
+    pub planes: u8,
+    pub flags: [u8; FLAG_COUNT],
+    pub audio_pattern: [u8; AUDIO_PATTERN_SIZE],
+    pub pitch: u8,
+    pub keyboard: [bool; KEY_SIZE],
+    pub rng_name: String,
+    pub rng_state: u64,
+    pub memory: Vec<u8>,
+    pub graphics: GraphicsMemory,
+}
+
+/*
+64-bit FNV-1a, to tell ROMs and machine states apart. Not meant to be
+collision resistant against anyone trying.
+*/
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xCBF2_9CE4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01B3)
+    })
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.u8(u8::from(value));
+    }
+
+    // A slice of variable length, prefixed with its length
+    fn slice(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.bytes.extend_from_slice(value);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Chip8Error> {
+        if self.bytes.len() < len {
+            return Err(Chip8Error::InvalidSaveState(String::from("truncated")));
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, Chip8Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Chip8Error> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, Chip8Error> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, Chip8Error> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bool(&mut self) -> Result<bool, Chip8Error> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            b => Err(Chip8Error::InvalidSaveState(format!("invalid bool {}", b))),
+        }
+    }
+
+    fn slice(&mut self) -> Result<&'a [u8], Chip8Error> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+impl Snapshot {
+    /*
+    Checks the values the machine indexes or divides with, so that a corrupt
+    or made up state is refused instead of panicking later on.
+    */
+    pub fn validate(&self) -> Result<(), Chip8Error> {
+        let invalid = |reason: String| Err(Chip8Error::InvalidSaveState(reason));
+        if usize::from(self.stack_ptr) > STACK_SIZE {
+            return invalid(format!("stack pointer {} past the stack", self.stack_ptr));
+        }
+        if self.planes > ALL_PLANES {
+            return invalid(format!("invalid planes {:#04b}", self.planes));
+        }
+        if self.graphics.width() == 0 || self.graphics.height() == 0 {
+            return invalid(String::from("empty display"));
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer { bytes: Vec::new() };
+        w.bytes.extend_from_slice(MAGIC);
+        w.u16(VERSION);
+        w.u64(self.rom_hash);
+        w.u16(self.counter);
+        w.u8(self.stack_ptr);
+        self.stack.iter().for_each(|addr| w.u16(*addr));
+        w.bytes.extend_from_slice(&self.v);
+        w.u16(self.i);
+        w.u8(self.delay_timer);
+        w.u8(self.sound_timer);
+        w.bool(self.skip_increment);
+        w.bool(self.vblank);
+        w.bool(self.halted);
+        w.bool(self.hires);
+        w.u8(self.planes);
+        w.bytes.extend_from_slice(&self.flags);
+        w.bytes.extend_from_slice(&self.audio_pattern);
+        w.u8(self.pitch);
+        self.keyboard.iter().for_each(|key| w.bool(*key));
+        w.slice(self.rng_name.as_bytes());
+        w.u64(self.rng_state);
+        w.slice(&self.memory);
+        w.u32(self.graphics.width() as u32);
+        w.u32(self.graphics.height() as u32);
+        w.slice(self.graphics.pixels());
+        w.bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Chip8Error> {
+        let mut r = Reader { bytes };
+        if r.take(MAGIC.len())? != MAGIC {
+            return Err(Chip8Error::InvalidSaveState(String::from(
+                "not a save state",
+            )));
+        }
+        let version = r.u16()?;
+        if version != VERSION {
+            return Err(Chip8Error::InvalidSaveState(format!(
+                "unsupported version {}",
+                version
+            )));
+        }
+        let rom_hash = r.u64()?;
+        let counter = r.u16()?;
+        let stack_ptr = r.u8()?;
+        let mut stack = [0; STACK_SIZE];
+        for addr in stack.iter_mut() {
+            *addr = r.u16()?;
+        }
+        let v = r.take(REGISTER_COUNT)?.try_into().unwrap();
+        let i = r.u16()?;
+        let delay_timer = r.u8()?;
+        let sound_timer = r.u8()?;
+        let skip_increment = r.bool()?;
+        let vblank = r.bool()?;
+        let halted = r.bool()?;
+        let hires = r.bool()?;
+        let planes = r.u8()?;
+        let flags = r.take(FLAG_COUNT)?.try_into().unwrap();
+        let audio_pattern = r.take(AUDIO_PATTERN_SIZE)?.try_into().unwrap();
+        let pitch = r.u8()?;
+        let mut keyboard = [false; KEY_SIZE];
+        for key in keyboard.iter_mut() {
+            *key = r.bool()?;
+        }
+        let rng_name = String::from_utf8(r.slice()?.to_vec())
+            .map_err(|_| Chip8Error::InvalidSaveState(String::from("invalid RNG name")))?;
+        let rng_state = r.u64()?;
+        let memory = r.slice()?.to_vec();
+        let width = r.u32()? as usize;
+        let height = r.u32()? as usize;
+        let graphics = GraphicsMemory::from_pixels(width, height, r.slice()?.to_vec())
+            .ok_or_else(|| Chip8Error::InvalidSaveState(String::from("invalid display size")))?;
+        if !r.bytes.is_empty() {
+            return Err(Chip8Error::InvalidSaveState(String::from("trailing data")));
+        }
+        let snapshot = Snapshot {
+            rom_hash,
+            counter,
+            stack_ptr,
+            stack,
+            v,
+            i,
+            delay_timer,
+            sound_timer,
+            skip_increment,
+            vblank,
+            halted,
+            hires,
+            planes,
+            flags,
+            audio_pattern,
+            pitch,
+            keyboard,
+            rng_name,
+            rng_state,
+            memory,
+            graphics,
+        };
+        snapshot.validate()?;
+        Ok(snapshot)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Chip8Error> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Chip8Error> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::Machine;
+    use crate::opcodes::OpcodeMaskParser;
+    use crate::platform::XO_CHIP;
+
+    fn snapshot() -> Snapshot {
+        let mut machine = Machine::with_platform("TestVM", OpcodeMaskParser {}, &XO_CHIP);
+        machine
+            .load_program(&[0x60, 0x12, 0xA3, 0x45, 0x00, 0xFF])
+            .unwrap();
+        for _ in 0..3 {
+            machine.tick().unwrap();
+        }
+        assert!(machine.set_key(0x7, true));
+        machine.snapshot()
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let snapshot = snapshot();
+        let bytes = snapshot.to_bytes();
+        assert_eq!(&bytes[..4], MAGIC);
+        assert_eq!(Snapshot::from_bytes(&bytes).unwrap(), snapshot);
+    }
+
+    #[test]
+    fn test_snapshot_rejects_bad_data() {
+        let bytes = snapshot().to_bytes();
+        assert!(matches!(
+            Snapshot::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(Chip8Error::InvalidSaveState(_))
+        ));
+        let mut wrong_version = bytes.clone();
+        wrong_version[4] = 0xFF;
+        assert!(Snapshot::from_bytes(&wrong_version).is_err());
+        assert!(Snapshot::from_bytes(b"ROM!").is_err());
+        let mut trailing = bytes;
+        trailing.push(0);
+        assert!(Snapshot::from_bytes(&trailing).is_err());
+    }
+
+    fn assert_invalid(snapshot: Snapshot) {
+        assert!(matches!(
+            Snapshot::from_bytes(&snapshot.to_bytes()),
+            Err(Chip8Error::InvalidSaveState(_))
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_rejects_bad_values() {
+        assert_invalid(Snapshot {
+            stack_ptr: STACK_SIZE as u8 + 1,
+            ..snapshot()
+        });
+        assert_invalid(Snapshot {
+            planes: ALL_PLANES + 1,
+            ..snapshot()
+        });
+        assert_invalid(Snapshot {
+            graphics: GraphicsMemory::from_pixels(0, 32, Vec::new()).unwrap(),
+            ..snapshot()
+        });
+        let full_stack = Snapshot {
+            stack_ptr: STACK_SIZE as u8,
+            ..snapshot()
+        };
+        assert!(Snapshot::from_bytes(&full_stack.to_bytes()).is_ok());
+    }
+
+    #[test]
+    fn test_fnv1a() {
+        // reference values of 64-bit FNV-1a
+        assert_eq!(fnv1a(b""), 0xCBF2_9CE4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xAF63_DC4C_8601_EC8C);
+    }
+}
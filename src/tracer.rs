@@ -0,0 +1,371 @@
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::str::FromStr;
+
+use crate::core::REGISTER_COUNT;
+use crate::error::Chip8Error;
+use crate::instructions::{Instruction, InstructionParser};
+use crate::isa;
+
+/*
+Binary traces start with the magic and the version of the record layout,
+which changes whenever the layout does.
+*/
+pub const BINARY_MAGIC: &[u8; 4] = b"C8TR";
+pub const BINARY_VERSION: u8 = 1;
+// cycle, PC, opcode, next word, V0-VF, I, SP, DT, ST
+pub const BINARY_RECORD_SIZE: usize = 8 + 2 + 2 + 2 + REGISTER_COUNT + 2 + 1 + 1 + 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    JsonLines,
+    Binary,
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" => Ok(TraceFormat::JsonLines),
+            "binary" => Ok(TraceFormat::Binary),
+            _ => Err(format!("Unknown trace format: {}", s)),
+        }
+    }
+}
+
+/*
+Which instructions make it into the trace: the ones at an address in the
+range, if there is one, and of one of the kinds, if there are any. A kind is
+the first word of the mnemonic, e.g. DRW or LD, or the name of the instruction
+in isa.rs, e.g. LoadImmediate, which tells LD I, NNN apart from the other LDs.
+Kinds are compared without regard to case.
+*/
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TraceFilter {
+    pub addresses: Option<(u16, u16)>, // inclusive
+    pub kinds: Vec<String>,
+}
+
+impl TraceFilter {
+    // Parses a range like 200-2FF, in hex
+    pub fn parse_range(s: &str) -> Result<(u16, u16), String> {
+        let parse = |n: &str| u16::from_str_radix(n.trim_start_matches("0x"), 16);
+        match s
+            .split_once('-')
+            .map(|(start, end)| (parse(start), parse(end)))
+        {
+            Some((Ok(start), Ok(end))) if start <= end => Ok((start, end)),
+            _ => Err(format!("Invalid address range: {}", s)),
+        }
+    }
+
+    // Parses a comma separated list of kinds, in any case
+    pub fn parse_kinds(s: &str) -> Vec<String> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|kind| !kind.is_empty())
+            .map(str::to_uppercase)
+            .collect()
+    }
+
+    pub fn matches(&self, pc: u16, instruction: &Instruction) -> bool {
+        let in_range = self
+            .addresses
+            .is_none_or(|(start, end)| (start..=end).contains(&pc));
+        in_range && (self.kinds.is_empty() || self.matches_kind(instruction))
+    }
+
+    fn matches_kind(&self, instruction: &Instruction) -> bool {
+        let name = isa::name(instruction);
+        let mnemonic = isa::mnemonic(instruction);
+        let kind = mnemonic.split_whitespace().next().unwrap_or_default();
+        self.kinds
+            .iter()
+            .any(|k| k == kind || k.eq_ignore_ascii_case(name))
+    }
+}
+
+/*
+The state of the machine right before it executes an instruction. `next` is
+the word after the opcode for F000 NNNN, and 0 for everything else.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceRecord {
+    pub cycle: u64,
+    pub pc: u16,
+    pub opcode: u16,
+    pub next: u16,
+    pub instruction: Instruction,
+    pub v: [u8; REGISTER_COUNT],
+    pub i: u16,
+    pub sp: u8,
+    pub delay: u8,
+    pub sound: u8,
+}
+
+impl TraceRecord {
+    /*
+    Big endian, in the order of the fields, without the instruction since the
+    opcode already says what it is.
+    */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(BINARY_RECORD_SIZE);
+        bytes.extend_from_slice(&self.cycle.to_be_bytes());
+        bytes.extend_from_slice(&self.pc.to_be_bytes());
+        bytes.extend_from_slice(&self.opcode.to_be_bytes());
+        bytes.extend_from_slice(&self.next.to_be_bytes());
+        bytes.extend_from_slice(&self.v);
+        bytes.extend_from_slice(&self.i.to_be_bytes());
+        bytes.extend_from_slice(&[self.sp, self.delay, self.sound]);
+        bytes
+    }
+}
+
+/*
+One line of JSON per record. The keys always come in this order and the
+addresses and opcodes are four hex digits, so that traces diff line by line:
+{"cycle":0,"pc":"0200","opcode":"6001","mnemonic":"LD V0, 0x01","v":[0,...],"i":"0000","sp":0,"dt":0,"st":0}
+*/
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let opcode = match self.instruction {
+            Instruction::LoadLongImmediate(_) => format!("{:04X}{:04X}", self.opcode, self.next),
+            _ => format!("{:04X}", self.opcode),
+        };
+        let v: Vec<String> = self.v.iter().map(u8::to_string).collect();
+        write!(
+            f,
+            "{{\"cycle\":{},\"pc\":\"{:04X}\",\"opcode\":\"{}\",\"mnemonic\":\"{}\",\"v\":[{}],\"i\":\"{:04X}\",\"sp\":{},\"dt\":{},\"st\":{}}}",
+            self.cycle,
+            self.pc,
+            opcode,
+            self.instruction,
+            v.join(","),
+            self.i,
+            self.sp,
+            self.delay,
+            self.sound
+        )
+    }
+}
+
+/*
+Writes a record of every instruction a machine executes that gets past the
+filter. Cycles count every instruction since the tracer was attached,
+filtered out or not, so they line up between traces of the same run.
+*/
+pub struct Tracer {
+    out: Box<dyn Write>,
+    format: TraceFormat,
+    filter: TraceFilter,
+    cycle: u64,
+}
+
+impl Tracer {
+    pub fn new<W: Write + 'static>(
+        out: W,
+        format: TraceFormat,
+        filter: TraceFilter,
+    ) -> Result<Self, Chip8Error> {
+        let mut out: Box<dyn Write> = Box::new(BufWriter::new(out));
+        if format == TraceFormat::Binary {
+            out.write_all(BINARY_MAGIC)?;
+            out.write_all(&[BINARY_VERSION])?;
+        }
+        Ok(Tracer {
+            out,
+            format,
+            filter,
+            cycle: 0,
+        })
+    }
+
+    pub fn create(
+        path: &str,
+        format: TraceFormat,
+        filter: TraceFilter,
+    ) -> Result<Self, Chip8Error> {
+        Tracer::new(File::create(path)?, format, filter)
+    }
+
+    // The record is only built if the filter lets it through
+    pub fn trace<F>(
+        &mut self,
+        pc: u16,
+        instruction: &Instruction,
+        record: F,
+    ) -> Result<(), Chip8Error>
+    where
+        F: FnOnce(u64) -> TraceRecord,
+    {
+        let cycle = self.cycle;
+        self.cycle += 1;
+        if !self.filter.matches(pc, instruction) {
+            return Ok(());
+        }
+        let record = record(cycle);
+        match self.format {
+            TraceFormat::JsonLines => writeln!(self.out, "{}", record)?,
+            TraceFormat::Binary => self.out.write_all(&record.to_bytes())?,
+        }
+        Ok(())
+    }
+
+    // Writes out what is still buffered
+    pub fn finish(mut self) -> Result<(), Chip8Error> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/*
+Reads a binary trace back, decoding the instructions with `parser`.
+*/
+pub fn read_binary<T: InstructionParser>(
+    parser: &T,
+    bytes: &[u8],
+) -> Result<Vec<TraceRecord>, Chip8Error> {
+    let invalid = |reason: &str| Chip8Error::InvalidTrace(reason.to_string());
+    let records = match bytes.strip_prefix(BINARY_MAGIC.as_slice()) {
+        Some([version, records @ ..]) if *version == BINARY_VERSION => records,
+        Some([_, ..]) => return Err(invalid("unsupported version")),
+        _ => return Err(invalid("not a binary trace")),
+    };
+    if records.len() % BINARY_RECORD_SIZE != 0 {
+        return Err(invalid("truncated record"));
+    }
+    records
+        .chunks(BINARY_RECORD_SIZE)
+        .map(|record| {
+            let word = |at: usize| u16::from_be_bytes([record[at], record[at + 1]]);
+            let (opcode, next) = (word(10), word(12));
+            let mut v = [0; REGISTER_COUNT];
+            v.copy_from_slice(&record[14..14 + REGISTER_COUNT]);
+            let rest = &record[14 + REGISTER_COUNT..];
+            Ok(TraceRecord {
+                cycle: u64::from_be_bytes(record[..8].try_into().expect("8 bytes")),
+                pc: word(8),
+                opcode,
+                next,
+                instruction: parser.try_from_words(opcode, next)?,
+                v,
+                i: u16::from_be_bytes([rest[0], rest[1]]),
+                sp: rest[2],
+                delay: rest[3],
+                sound: rest[4],
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::Machine;
+    use crate::instructions::encode_program;
+    use crate::opcodes::OpcodeMaskParser;
+    use crate::quirks::Quirks;
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    // A writer that can still be read after the tracer took it
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn trace(format: TraceFormat, filter: TraceFilter, ticks: usize) -> Vec<u8> {
+        let program = encode_program(&[
+            Instruction::LoadByte(0x0, 0x01),
+            Instruction::AddByte(0x0, 0x02),
+            Instruction::LoadImmediate(0x300),
+            Instruction::DisplaySprite(0x0, 0x0, 1),
+            Instruction::Jump(0x202),
+        ])
+        .unwrap();
+        let mut machine = Machine::new("trace", OpcodeMaskParser {}, Quirks::default());
+        machine.load_program(&program).unwrap();
+        let out = Shared::default();
+        machine.set_tracer(Tracer::new(out.clone(), format, filter).unwrap());
+        for _ in 0..ticks {
+            machine.tick().unwrap();
+        }
+        machine.take_tracer().unwrap().finish().unwrap();
+        let bytes = out.0.borrow().clone();
+        bytes
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let text =
+            String::from_utf8(trace(TraceFormat::JsonLines, TraceFilter::default(), 7)).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 7);
+        assert_eq!(
+            lines[0],
+            r#"{"cycle":0,"pc":"0200","opcode":"6001","mnemonic":"LD V0, 0x01","v":[0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],"i":"0000","sp":0,"dt":0,"st":0}"#
+        );
+        assert!(lines[3].starts_with(
+            r#"{"cycle":3,"pc":"0206","opcode":"D001","mnemonic":"DRW V0, V0, 1","v":[3,"#
+        ));
+        assert!(lines[3].ends_with(r#""i":"0300","sp":0,"dt":0,"st":0}"#));
+
+        // the cycles still count what was filtered out
+        let filter = TraceFilter {
+            addresses: Some(TraceFilter::parse_range("202-206").unwrap()),
+            kinds: TraceFilter::parse_kinds("drw, add"),
+        };
+        let text = String::from_utf8(trace(TraceFormat::JsonLines, filter, 7)).unwrap();
+        let cycles: Vec<&str> = text.lines().map(|line| &line[..11]).collect();
+        assert_eq!(
+            cycles,
+            [r#"{"cycle":1,"#, r#"{"cycle":3,"#, r#"{"cycle":5,"#]
+        );
+        assert!(TraceFilter::parse_range("2FF-200").is_err());
+
+        // LD I, NNN is only told apart from the other LDs by its name
+        let filter = TraceFilter {
+            addresses: None,
+            kinds: TraceFilter::parse_kinds("LoadImmediate"),
+        };
+        assert!(filter.matches(0x200, &Instruction::LoadImmediate(0x300)));
+        assert!(!filter.matches(0x200, &Instruction::LoadByte(0x0, 1)));
+        let filter = TraceFilter {
+            addresses: None,
+            kinds: TraceFilter::parse_kinds("ld"),
+        };
+        assert!(filter.matches(0x200, &Instruction::LoadImmediate(0x300)));
+        assert!(filter.matches(0x200, &Instruction::LoadByte(0x0, 1)));
+        assert_eq!(TraceFilter::parse_range("0x200-2ff"), Ok((0x200, 0x2FF)));
+    }
+
+    #[test]
+    fn test_binary() {
+        let bytes = trace(TraceFormat::Binary, TraceFilter::default(), 5);
+        assert_eq!(bytes.len(), 5 + 5 * BINARY_RECORD_SIZE);
+        let records = read_binary(&OpcodeMaskParser {}, &bytes).unwrap();
+        let text =
+            String::from_utf8(trace(TraceFormat::JsonLines, TraceFilter::default(), 5)).unwrap();
+        let lines: Vec<String> = records.iter().map(TraceRecord::to_string).collect();
+        assert_eq!(lines, text.lines().collect::<Vec<&str>>());
+        assert_eq!(records[4].instruction, Instruction::Jump(0x202));
+
+        assert!(matches!(
+            read_binary(&OpcodeMaskParser {}, &bytes[..bytes.len() - 1]),
+            Err(Chip8Error::InvalidTrace(_))
+        ));
+        assert!(read_binary(&OpcodeMaskParser {}, b"{\"cycle\"").is_err());
+    }
+}